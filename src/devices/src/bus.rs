@@ -140,6 +140,12 @@ impl Bus {
         Ok(())
     }
 
+    /// Removes the device put at the given base address, returning it if there was one.
+    pub fn remove(&mut self, base: u64) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        // The ranges are compared by their base only.
+        self.devices.remove(&BusRange(base, 0))
+    }
+
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
@@ -221,6 +227,21 @@ mod tests {
         assert!(bus.insert(dummy, 0x0, 0x10).is_ok());
     }
 
+    #[test]
+    fn bus_remove() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+
+        assert!(bus.remove(0x18).is_none());
+        assert!(bus.remove(0x10).is_some());
+        assert!(bus.get_device(0x10).is_none());
+        assert!(bus.remove(0x10).is_none());
+
+        // The range can be used again.
+        assert!(bus.insert(dummy, 0x10, 0x10).is_ok());
+    }
+
     #[test]
     fn bus_read_write() {
         let mut bus = Bus::new();
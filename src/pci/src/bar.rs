@@ -0,0 +1,127 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+/// The first Base Address Register within the configuration header space.
+pub const BAR0_REGISTER: usize = 4;
+
+/// A Type 0 header has six Base Address Registers.
+pub const NUM_BAR_REGISTERS: usize = 6;
+
+/// A Type 1 header has two Base Address Registers.
+pub const NUM_BRIDGE_BAR_REGISTERS: usize = 2;
+
+/// The Base Address Register bit which selects the IO space.
+pub const BAR_IO_SPACE: u32 = 0x1;
+
+/// The Base Address Register bits which mark a 64-bit memory region.
+pub const BAR_MEMORY_64BIT: u32 = 0x4;
+
+/// The Base Address Register bit which marks a prefetchable memory region.
+pub const BAR_PREFETCHABLE: u32 = 0x8;
+
+/// The address bits of an IO Base Address Register.
+pub const BAR_IO_ADDRESS_MASK: u32 = 0xFFFF_FFFC;
+
+/// The address bits of a memory Base Address Register.
+pub const BAR_MEMORY_ADDRESS_MASK: u32 = 0xFFFF_FFF0;
+
+/// The smallest IO region a Base Address Register can describe.
+const BAR_IO_MIN_SIZE: u64 = 0x4;
+
+/// The largest IO region a Base Address Register can describe.
+const BAR_IO_MAX_SIZE: u64 = 0x100;
+
+/// The smallest memory region a Base Address Register can describe.
+const BAR_MEMORY_MIN_SIZE: u64 = 0x10;
+
+/// The kinds of regions a Base Address Register can describe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciBarRegionType {
+    Memory32BitRegion = 0,
+    IoRegion = 1,
+    Memory64BitRegion = 2,
+}
+
+/// The construction-time description of a Base Address Register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PciBarConfiguration {
+    /// The index of the BAR (0-5). A 64-bit BAR also uses the next index.
+    pub index: usize,
+
+    /// The kind of region described by the BAR.
+    pub region_type: PciBarRegionType,
+
+    /// The size of the region, in bytes. It has to be a power of 2.
+    pub size: u64,
+
+    /// Whether the memory region is prefetchable. Ignored for IO regions.
+    pub prefetchable: bool,
+
+    /// The address of the region. The guest programs it after the BAR is declared, so it is
+    /// only meaningful in the BARs returned by `PciFunction::bars`.
+    pub address: u64,
+}
+
+impl PciBarConfiguration {
+    /// Return the number of registers used by the BAR.
+    pub fn register_count(&self) -> usize {
+        match self.region_type {
+            PciBarRegionType::Memory64BitRegion => 2,
+            _ => 1,
+        }
+    }
+
+    /// Return true if the size of the region can be described by the BAR.
+    pub fn is_size_valid(&self) -> bool {
+        if !self.size.is_power_of_two() {
+            return false;
+        }
+
+        match self.region_type {
+            PciBarRegionType::IoRegion => {
+                self.size >= BAR_IO_MIN_SIZE && self.size <= BAR_IO_MAX_SIZE
+            }
+            PciBarRegionType::Memory32BitRegion => {
+                self.size >= BAR_MEMORY_MIN_SIZE && self.size <= 1 << 31
+            }
+            PciBarRegionType::Memory64BitRegion => {
+                self.size >= BAR_MEMORY_MIN_SIZE && self.size <= 1 << 63
+            }
+        }
+    }
+
+    /// Return the read-only low bits of the (first) register of the BAR.
+    pub fn type_bits(&self) -> u32 {
+        match self.region_type {
+            PciBarRegionType::IoRegion => BAR_IO_SPACE,
+            PciBarRegionType::Memory32BitRegion if self.prefetchable => BAR_PREFETCHABLE,
+            PciBarRegionType::Memory32BitRegion => 0,
+            PciBarRegionType::Memory64BitRegion if self.prefetchable => {
+                BAR_MEMORY_64BIT | BAR_PREFETCHABLE
+            }
+            PciBarRegionType::Memory64BitRegion => BAR_MEMORY_64BIT,
+        }
+    }
+
+    /// Return the bits the guest can write in each register of the BAR, as (register, mask)
+    /// pairs. Writing all ones and reading back the register returns the size of the region.
+    pub fn write_masks(&self) -> Vec<(usize, u32)> {
+        let register = BAR0_REGISTER + self.index;
+        // The mask covers the whole 64-bit address, so the upper half of a 64-bit BAR sizes
+        // as all ones below 4GB, and keeps the upper size bits read-only above.
+        let size_mask = !(self.size - 1);
+
+        match self.region_type {
+            PciBarRegionType::IoRegion => {
+                vec![(register, size_mask as u32 & BAR_IO_ADDRESS_MASK)]
+            }
+            PciBarRegionType::Memory32BitRegion => {
+                vec![(register, size_mask as u32 & BAR_MEMORY_ADDRESS_MASK)]
+            }
+            PciBarRegionType::Memory64BitRegion => vec![
+                (register, size_mask as u32 & BAR_MEMORY_ADDRESS_MASK),
+                (register + 1, (size_mask >> 32) as u32),
+            ],
+        }
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::function::{
+    PciClassCode, PciFunction, PciHeaderType, HEADER_TYPE_OFFSET, HEADER_TYPE_REGISTER,
+};
+
+// https://pci-ids.ucw.cz/read/PC/1b36/0001
+pub const VENDOR_ID_PCI_BRIDGE: u16 = 0x1B36;
+pub const DEVICE_ID_PCI_BRIDGE: u16 = 0x0001;
+
+// Type 1 configuration header meanings as registers and offsets.
+pub const SECONDARY_BUS_REGISTER: usize = 6;
+pub const SECONDARY_BUS_OFFSET: usize = 1;
+
+pub const SUBORDINATE_BUS_REGISTER: usize = 6;
+pub const SUBORDINATE_BUS_OFFSET: usize = 2;
+
+pub const IO_BASE_REGISTER: usize = 7;
+pub const IO_BASE_OFFSET: usize = 0;
+
+pub const IO_LIMIT_REGISTER: usize = 7;
+pub const IO_LIMIT_OFFSET: usize = 1;
+
+pub const MEMORY_BASE_REGISTER: usize = 8;
+pub const MEMORY_BASE_OFFSET: usize = 0;
+
+pub const MEMORY_LIMIT_REGISTER: usize = 8;
+pub const MEMORY_LIMIT_OFFSET: usize = 2;
+
+pub const PREFETCHABLE_MEMORY_BASE_REGISTER: usize = 9;
+pub const PREFETCHABLE_MEMORY_BASE_OFFSET: usize = 0;
+
+pub const PREFETCHABLE_MEMORY_LIMIT_REGISTER: usize = 9;
+pub const PREFETCHABLE_MEMORY_LIMIT_OFFSET: usize = 2;
+
+pub const PREFETCHABLE_BASE_UPPER_REGISTER: usize = 10;
+pub const PREFETCHABLE_LIMIT_UPPER_REGISTER: usize = 11;
+
+pub const IO_BASE_UPPER_REGISTER: usize = 12;
+pub const IO_BASE_UPPER_OFFSET: usize = 0;
+
+pub const IO_LIMIT_UPPER_REGISTER: usize = 12;
+pub const IO_LIMIT_UPPER_OFFSET: usize = 2;
+
+pub const BRIDGE_CONTROL_REGISTER: usize = 15;
+pub const BRIDGE_CONTROL_OFFSET: usize = 2;
+
+/// The `ISA Enable` bit of the Bridge Control register. When set, the bridge does not forward
+/// the IO addresses of the first 64KB which alias the ISA devices (the top 768 bytes of each
+/// 1KB block), even if they are within the IO window.
+pub const BRIDGE_CONTROL_ISA_ENABLE: u16 = 1 << 2;
+
+/// The `VGA Enable` bit of the Bridge Control register. When set, the bridge forwards the
+/// legacy VGA memory and IO ranges to its secondary bus, whatever its windows.
+pub const BRIDGE_CONTROL_VGA_ENABLE: u16 = 1 << 3;
+
+/// The legacy VGA frame buffer, as (base, limit).
+pub const VGA_MEMORY_RANGE: (u64, u64) = (0xA_0000, 0xB_FFFF);
+
+/// The legacy VGA IO ports, as (base, limit) pairs.
+pub const VGA_IO_RANGES: [(u64, u64); 2] = [(0x3B0, 0x3BB), (0x3C0, 0x3DF)];
+
+/// The windows of a bridge are only decoded for the addresses between base and limit.
+/// The limit is inclusive, so (base, limit) pairs with base > limit disable the window.
+impl PciFunction {
+    /// Create a PCI-to-PCI bridge function, with a Type 1 header leading to a single bus.
+    /// The subordinate bus is the secondary bus, until buses are added behind it.
+    /// * `number` - The number of the function.
+    /// * `secondary` - The number of the bus connected behind the bridge.
+    pub fn new_bridge(number: usize, secondary: u8) -> PciFunction {
+        let mut function = PciFunction::new(
+            number,
+            DEVICE_ID_PCI_BRIDGE,
+            VENDOR_ID_PCI_BRIDGE,
+            PciClassCode::PciToPciBridge,
+            0,
+            PciHeaderType::Type1,
+            0,
+            0,
+        );
+
+        function.write_configuration_byte(SECONDARY_BUS_REGISTER, SECONDARY_BUS_OFFSET, secondary);
+        function.write_configuration_byte(
+            SUBORDINATE_BUS_REGISTER,
+            SUBORDINATE_BUS_OFFSET,
+            secondary,
+        );
+
+        // Close the windows (base above limit) until the guest programs them.
+        function.write_configuration_byte(IO_BASE_REGISTER, IO_BASE_OFFSET, 0xF0);
+        function.write_configuration_word(MEMORY_BASE_REGISTER, MEMORY_BASE_OFFSET, 0xFFF0);
+        function.write_configuration_word(
+            PREFETCHABLE_MEMORY_BASE_REGISTER,
+            PREFETCHABLE_MEMORY_BASE_OFFSET,
+            0xFFF0,
+        );
+
+        function
+    }
+
+    /// Return true if the function has a Type 1 (bridge) configuration header.
+    pub fn is_bridge(&self) -> bool {
+        self.read_configuration_byte(HEADER_TYPE_REGISTER, HEADER_TYPE_OFFSET)
+            .unwrap_or(0)
+            & 0x7F
+            == 0x01
+    }
+
+    /// Return the Secondary Bus Number of a bridge.
+    pub fn get_secondary_bus(&self) -> Option<u8> {
+        if !self.is_bridge() {
+            return None;
+        }
+
+        self.read_configuration_byte(SECONDARY_BUS_REGISTER, SECONDARY_BUS_OFFSET)
+    }
+
+    /// Return the Subordinate Bus Number of a bridge.
+    pub fn get_subordinate_bus(&self) -> Option<u8> {
+        if !self.is_bridge() {
+            return None;
+        }
+
+        self.read_configuration_byte(SUBORDINATE_BUS_REGISTER, SUBORDINATE_BUS_OFFSET)
+    }
+
+    /// Set the Secondary Bus Number of a bridge. It is ignored by the other functions.
+    /// * `secondary` - The number of the bus connected behind the bridge.
+    pub fn set_secondary_bus(&mut self, secondary: u8) {
+        if self.is_bridge() {
+            self.write_configuration_byte(SECONDARY_BUS_REGISTER, SECONDARY_BUS_OFFSET, secondary);
+        }
+    }
+
+    /// Set the Subordinate Bus Number of a bridge. It is ignored by the other functions.
+    /// * `subordinate` - The highest bus number behind the bridge.
+    pub fn set_subordinate_bus(&mut self, subordinate: u8) {
+        if self.is_bridge() {
+            self.write_configuration_byte(
+                SUBORDINATE_BUS_REGISTER,
+                SUBORDINATE_BUS_OFFSET,
+                subordinate,
+            );
+        }
+    }
+
+    /// Return the IO window of a bridge, as (base, limit), if it is open.
+    pub fn io_window(&self) -> Option<(u64, u64)> {
+        if !self.is_bridge() {
+            return None;
+        }
+
+        let base = self.read_configuration_byte(IO_BASE_REGISTER, IO_BASE_OFFSET)?;
+        let limit = self.read_configuration_byte(IO_LIMIT_REGISTER, IO_LIMIT_OFFSET)?;
+
+        let mut window_base = u64::from(base & 0xF0) << 8;
+        let mut window_limit = (u64::from(limit & 0xF0) << 8) | 0xFFF;
+
+        // The low nibble tells if the IO addressing is 32-bit.
+        if base & 0x0F == 0x01 {
+            window_base |= u64::from(
+                self.read_configuration_word(IO_BASE_UPPER_REGISTER, IO_BASE_UPPER_OFFSET)?,
+            ) << 16;
+            window_limit |= u64::from(
+                self.read_configuration_word(IO_LIMIT_UPPER_REGISTER, IO_LIMIT_UPPER_OFFSET)?,
+            ) << 16;
+        }
+
+        open_window(window_base, window_limit)
+    }
+
+    /// Return the non-prefetchable memory window of a bridge, as (base, limit), if it is open.
+    pub fn memory_window(&self) -> Option<(u64, u64)> {
+        if !self.is_bridge() {
+            return None;
+        }
+
+        let base = self.read_configuration_word(MEMORY_BASE_REGISTER, MEMORY_BASE_OFFSET)?;
+        let limit = self.read_configuration_word(MEMORY_LIMIT_REGISTER, MEMORY_LIMIT_OFFSET)?;
+
+        open_window(
+            u64::from(base & 0xFFF0) << 16,
+            (u64::from(limit & 0xFFF0) << 16) | 0xF_FFFF,
+        )
+    }
+
+    /// Return the prefetchable memory window of a bridge, as (base, limit), if it is open.
+    pub fn prefetchable_memory_window(&self) -> Option<(u64, u64)> {
+        if !self.is_bridge() {
+            return None;
+        }
+
+        let base = self.read_configuration_word(
+            PREFETCHABLE_MEMORY_BASE_REGISTER,
+            PREFETCHABLE_MEMORY_BASE_OFFSET,
+        )?;
+        let limit = self.read_configuration_word(
+            PREFETCHABLE_MEMORY_LIMIT_REGISTER,
+            PREFETCHABLE_MEMORY_LIMIT_OFFSET,
+        )?;
+
+        let mut window_base = u64::from(base & 0xFFF0) << 16;
+        let mut window_limit = (u64::from(limit & 0xFFF0) << 16) | 0xF_FFFF;
+
+        // The low nibble tells if the prefetchable memory addressing is 64-bit.
+        if base & 0x000F == 0x0001 {
+            window_base |=
+                u64::from(self.read_configuration_dword(PREFETCHABLE_BASE_UPPER_REGISTER)?) << 32;
+            window_limit |=
+                u64::from(self.read_configuration_dword(PREFETCHABLE_LIMIT_UPPER_REGISTER)?) << 32;
+        }
+
+        open_window(window_base, window_limit)
+    }
+
+    /// Return the Bridge Control register of a bridge.
+    pub fn get_bridge_control(&self) -> Option<u16> {
+        if !self.is_bridge() {
+            return None;
+        }
+
+        self.read_configuration_word(BRIDGE_CONTROL_REGISTER, BRIDGE_CONTROL_OFFSET)
+    }
+
+    /// Return true if the bridge forwards the legacy VGA ranges.
+    pub fn is_vga_enabled(&self) -> bool {
+        self.get_bridge_control().unwrap_or(0) & BRIDGE_CONTROL_VGA_ENABLE != 0
+    }
+
+    /// Return true if the bridge blocks the IO addresses aliasing the ISA devices.
+    pub fn is_isa_enabled(&self) -> bool {
+        self.get_bridge_control().unwrap_or(0) & BRIDGE_CONTROL_ISA_ENABLE != 0
+    }
+
+    /// Return true if the address is forwarded by one of the memory windows of a bridge,
+    /// or by the legacy VGA range.
+    /// * `address` - The physical address of the access.
+    pub fn memory_windows_contain(&self, address: u64) -> bool {
+        let vga = if self.is_vga_enabled() {
+            Some(VGA_MEMORY_RANGE)
+        } else {
+            None
+        };
+
+        [self.memory_window(), self.prefetchable_memory_window(), vga]
+            .iter()
+            .filter_map(|window| *window)
+            .any(|(base, limit)| address >= base && address <= limit)
+    }
+
+    /// Return true if the port is forwarded by the IO window of a bridge, or by the legacy VGA
+    /// ranges.
+    /// * `port` - The IO port of the access.
+    pub fn io_window_contains(&self, port: u64) -> bool {
+        if self.is_vga_enabled()
+            && VGA_IO_RANGES
+                .iter()
+                .any(|(base, limit)| port >= *base && port <= *limit)
+        {
+            return true;
+        }
+
+        // The ISA aliases are the addresses of the first 64KB with bit 8 or 9 set.
+        if self.is_isa_enabled() && port < 0x1_0000 && port & 0x300 != 0 {
+            return false;
+        }
+
+        match self.io_window() {
+            Some((base, limit)) => port >= base && port <= limit,
+            None => false,
+        }
+    }
+}
+
+/// Return the window if it is open (the base is not above the limit).
+fn open_window(base: u64, limit: u64) -> Option<(u64, u64)> {
+    if base > limit {
+        None
+    } else {
+        Some((base, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::CLASS_CODE_REGISTER;
+
+    #[test]
+    fn new_bridge() {
+        let bridge = PciFunction::new_bridge(2, 5);
+
+        assert_eq!(bridge.get_number(), 2);
+        assert!(bridge.is_bridge());
+        assert_eq!(
+            bridge
+                .read_configuration_dword(CLASS_CODE_REGISTER)
+                .unwrap()
+                >> 8,
+            0x06_04_00
+        );
+        assert_eq!(
+            bridge.identity(),
+            (VENDOR_ID_PCI_BRIDGE, DEVICE_ID_PCI_BRIDGE)
+        );
+        assert_eq!(bridge.get_secondary_bus(), Some(5));
+        assert_eq!(bridge.get_subordinate_bus(), Some(5));
+
+        // The windows are closed until the guest programs them.
+        assert_eq!(bridge.io_window(), None);
+        assert_eq!(bridge.memory_window(), None);
+        assert_eq!(bridge.prefetchable_memory_window(), None);
+    }
+
+    #[test]
+    fn bridge_control_legacy_ranges() {
+        let mut bridge = PciFunction::new_bridge(0, 1);
+        let set_bridge_control = |bridge: &mut PciFunction, control: u16| {
+            bridge.write_configuration_register(
+                BRIDGE_CONTROL_REGISTER,
+                BRIDGE_CONTROL_OFFSET,
+                &control.to_le_bytes(),
+            )
+        };
+
+        assert!(!bridge.memory_windows_contain(0xB_8000));
+        assert!(!bridge.io_window_contains(0x3D4));
+
+        set_bridge_control(&mut bridge, BRIDGE_CONTROL_VGA_ENABLE);
+        assert!(bridge.is_vga_enabled());
+        assert!(bridge.memory_windows_contain(0xB_8000));
+        assert!(!bridge.memory_windows_contain(0xC_0000));
+        assert!(bridge.io_window_contains(0x3D4));
+        assert!(!bridge.io_window_contains(0x3E0));
+
+        set_bridge_control(&mut bridge, 0);
+        assert!(!bridge.memory_windows_contain(0xB_8000));
+
+        // The IO window 0x1000-0x1FFF loses the ISA aliases.
+        bridge.write_configuration_register(IO_BASE_REGISTER, IO_BASE_OFFSET, &[0x10, 0x10]);
+        assert!(bridge.io_window_contains(0x1100));
+        set_bridge_control(&mut bridge, BRIDGE_CONTROL_ISA_ENABLE);
+        assert!(bridge.is_isa_enabled());
+        assert!(!bridge.io_window_contains(0x1100));
+        assert!(bridge.io_window_contains(0x1000));
+        assert!(bridge.io_window_contains(0x1C00));
+    }
+}
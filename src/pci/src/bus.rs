@@ -1,9 +1,11 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::device::PciDevice;
-use std::collections::HashMap;
+use crate::device::{PciDevice, MAX_FUNCTION_NUMBER};
+use crate::function::PciConfigurable;
+use std::collections::{BTreeMap, BTreeSet};
 use std::option::Option;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 
 /// There are up to 256 Bus numbers that can be assigned.
@@ -23,29 +25,64 @@ pub enum PciBusError {
     InvalidPciDeviceNumber(usize),
     /// Valid PCI device number but already used.
     AlreadyInUsePciDeviceSlot(usize),
+    /// Every device slot is either used or reserved.
+    NoFreePciDeviceSlot,
 }
 
 pub type Result<T> = std::result::Result<T, PciBusError>;
 
+/// Notify a function appearing or disappearing through a hotplug, receiving its bus, device
+/// and function numbers and whether it is now present. The higher layer raises the presence
+/// change interrupt of the slot, such as through the Slot Status register of PCI Express.
+pub type PciHotplugCallback = Arc<dyn Fn(usize, usize, usize, bool) + Send + Sync>;
+
 /// Each Bus must be assigned a unique bus number.
 /// The initial Bus Number, Bus 0, is typically assigned to the Root Complex.
+///
+/// The buses and the devices are kept sorted by their numbers, so every walk of the topology
+/// (including the one done for saving its state) visits them in the same order.
 pub struct PciBus {
     /// The number of the bus.
-    number: usize,
+    pub(crate) number: usize,
 
     /// The other buses that are connected to this bus.
-    buses: HashMap<usize, Arc<Mutex<PciBus>>>,
+    pub(crate) buses: BTreeMap<usize, Arc<Mutex<PciBus>>>,
 
     /// The device that are connected to this bus.
-    devices: HashMap<usize, Arc<Mutex<PciDevice>>>,
+    pub(crate) devices: BTreeMap<usize, Arc<Mutex<PciDevice>>>,
+
+    /// The empty device slots skipped by the automatic allocation.
+    pub(crate) reserved_slots: BTreeSet<usize>,
+
+    /// The bitmap of the devices connected to this bus.
+    device_present_mask: u32,
+
+    /// The bitmaps of the functions of each device, shared with the devices so they are read
+    /// without locking them. The enumeration of a sparse bus skips the absent slots fast.
+    function_present_masks: BTreeMap<usize, Arc<AtomicU8>>,
+
+    /// The number of configuration reads targeting this bus.
+    reads: AtomicU64,
+
+    /// The number of configuration writes targeting this bus.
+    writes: AtomicU64,
+
+    /// The function notified of the hotplug operations on this bus.
+    pub(crate) hotplug_callback: Option<PciHotplugCallback>,
 }
 
 impl PciBus {
     pub fn new(number: usize) -> PciBus {
         PciBus {
             number,
-            buses: HashMap::new(),
-            devices: HashMap::new(),
+            buses: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            reserved_slots: BTreeSet::new(),
+            device_present_mask: 0,
+            function_present_masks: BTreeMap::new(),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            hotplug_callback: None,
         }
     }
 
@@ -54,6 +91,14 @@ impl PciBus {
         self.number
     }
 
+    /// Return the number of configuration accesses targeting this bus, as (reads, writes).
+    pub fn get_access_counts(&self) -> (u64, u64) {
+        (
+            self.reads.load(Ordering::Relaxed),
+            self.writes.load(Ordering::Relaxed),
+        )
+    }
+
     /// Add a new bus to the current bus.
     /// * `bus` - The bus that will be wrapped in an Arc-Mutex struct and added.
     pub fn add_bus(&mut self, bus: PciBus) -> Result<()> {
@@ -102,11 +147,57 @@ impl PciBus {
             return Err(PciBusError::AlreadyInUsePciDeviceSlot(device_number));
         }
 
+        self.device_present_mask |= 1 << device_number;
+        self.function_present_masks
+            .insert(device_number, device.function_present_mask.clone());
         self.devices
             .insert(device_number, Arc::new(Mutex::new(device)));
         Ok(())
     }
 
+    /// Add a new device in the first free slot of the current bus, renumbering it.
+    /// Return the number of the slot.
+    /// * `device` - The device that will be wrapped in an Arc-Mutex struct and added.
+    pub fn attach_device(&mut self, mut device: PciDevice) -> Result<usize> {
+        let device_number = self
+            .next_free_slot()
+            .ok_or(PciBusError::NoFreePciDeviceSlot)?;
+
+        device.number = device_number;
+        self.add_device(device)?;
+
+        Ok(device_number)
+    }
+
+    /// Return the first slot which is neither used nor reserved, if any.
+    pub fn next_free_slot(&self) -> Option<usize> {
+        (0..MAX_DEVICE_NUMBER).find(|device| {
+            !self.devices.contains_key(device) && !self.reserved_slots.contains(device)
+        })
+    }
+
+    /// Keep an empty slot out of the automatic allocation. The guest still sees it as absent,
+    /// and a device can still be added in it explicitly with `add_device`.
+    /// * `device` - The number of the slot on the current bus.
+    pub fn reserve_slot(&mut self, device: usize) -> Result<()> {
+        if device >= MAX_DEVICE_NUMBER {
+            return Err(PciBusError::InvalidPciDeviceNumber(device));
+        }
+
+        if self.devices.contains_key(&device) {
+            return Err(PciBusError::AlreadyInUsePciDeviceSlot(device));
+        }
+
+        self.reserved_slots.insert(device);
+        Ok(())
+    }
+
+    /// Return a reserved slot to the automatic allocation.
+    /// * `device` - The number of the slot on the current bus.
+    pub fn release_slot(&mut self, device: usize) {
+        self.reserved_slots.remove(&device);
+    }
+
     /// Return a reference to the requested device if it exists.
     /// * `device` - The index of the device connected on the current bus.
     pub fn get_device(&self, device: usize) -> Option<&Arc<Mutex<PciDevice>>> {
@@ -122,9 +213,315 @@ impl PciBus {
     /// Remove the device from the current bus, returning the object, if it exists.
     /// * `device` - The index of the device connected on the current bus.
     pub fn remove_device(&mut self, device: usize) -> Option<Arc<Mutex<PciDevice>>> {
+        if device < MAX_DEVICE_NUMBER {
+            self.device_present_mask &= !(1 << device);
+        }
+        self.function_present_masks.remove(&device);
         self.devices.remove(&device)
     }
 
+    /// Notify the hotplug operations on this bus, or stop notifying them with `None`.
+    /// * `callback` - The function receiving the number of every function added or removed.
+    pub fn set_hotplug_callback(&mut self, callback: Option<PciHotplugCallback>) {
+        self.hotplug_callback = callback;
+    }
+
+    /// Add a device in the first free slot of the current bus, like `attach_device`, while the
+    /// guest is running. The hotplug callback is invoked for every function of the device.
+    /// Return the number of the slot.
+    /// * `device` - The device that will be wrapped in an Arc-Mutex struct and added.
+    pub fn hotplug_add(&mut self, device: PciDevice) -> Result<usize> {
+        let functions = device.function_present_mask();
+        let device = self.attach_device(device)?;
+
+        self.notify_hotplug(device, functions, true);
+
+        Ok(device)
+    }
+
+    /// Remove a device from the current bus, like `remove_device`, while the guest is running.
+    /// The hotplug callback is invoked for every function of the device, if it exists.
+    /// * `device` - The index of the device connected on the current bus.
+    pub fn hotplug_remove(&mut self, device: usize) -> Option<Arc<Mutex<PciDevice>>> {
+        let functions = self.function_present_mask(device);
+        let removed = self.remove_device(device)?;
+
+        self.notify_hotplug(device, functions, false);
+
+        Some(removed)
+    }
+
+    /// Invoke the hotplug callback for every function of the bitmap.
+    fn notify_hotplug(&self, device: usize, functions: u8, present: bool) {
+        if let Some(callback) = self.hotplug_callback.as_ref() {
+            for function in
+                (0..MAX_FUNCTION_NUMBER).filter(|function| functions & (1 << function) != 0)
+            {
+                callback(self.number, device, function, present);
+            }
+        }
+    }
+
+    /// Reset every function of this bus and of the buses connected to it.
+    pub fn reset(&mut self) {
+        for device in self.devices.values() {
+            device.lock().unwrap().reset_all_functions();
+        }
+
+        for bus in self.buses.values() {
+            bus.lock().unwrap().reset();
+        }
+    }
+
+    /// Return the bitmap of the devices connected to this bus.
+    pub fn device_present_mask(&self) -> u32 {
+        self.device_present_mask
+    }
+
+    /// Return the bitmap of the functions of a device connected to this bus. It does not lock
+    /// the device.
+    /// * `device` - The index of the device connected on the current bus.
+    pub fn function_present_mask(&self, device: usize) -> u8 {
+        self.function_present_masks
+            .get(&device)
+            .map_or(0, |mask| mask.load(Ordering::Acquire))
+    }
+
+    /// Return true if a function is registered in the slot, without locking its device.
+    fn is_slot_populated(&self, device: usize, function: usize) -> bool {
+        device < MAX_DEVICE_NUMBER
+            && function < MAX_FUNCTION_NUMBER
+            && self.device_present_mask & (1 << device) != 0
+            && self.function_present_mask(device) & (1 << function) != 0
+    }
+
+    /// Return every function on this bus and on the buses connected to it.
+    pub fn functions(&self) -> Vec<Arc<Mutex<dyn PciConfigurable>>> {
+        let mut functions = Vec::new();
+
+        for device in self.devices.values() {
+            functions.extend(device.lock().unwrap().functions.values().cloned());
+        }
+
+        for bus in self.buses.values() {
+            functions.extend(bus.lock().unwrap().functions());
+        }
+
+        functions
+    }
+
+    /// Return the highest bus number reachable from this bus, including itself.
+    /// * `excluded` - The numbers of the connected buses which are not descended into.
+    pub fn highest_bus_number(&self, excluded: &[usize]) -> usize {
+        self.buses
+            .iter()
+            .filter(|(number, _)| !excluded.contains(number))
+            .map(|(_, bus)| bus.lock().unwrap().highest_bus_number(excluded))
+            .fold(self.number, usize::max)
+    }
+
+    /// Return the numbers of this bus and of every bus reachable from it.
+    pub fn bus_numbers(&self) -> BTreeSet<usize> {
+        let mut numbers = BTreeSet::new();
+        numbers.insert(self.number);
+
+        for bus in self.buses.values() {
+            numbers.extend(bus.lock().unwrap().bus_numbers());
+        }
+
+        numbers
+    }
+
+    /// Find a bus reachable from this bus, as (number of its parent bus, bus).
+    /// * `bus` - The number of the requested bus.
+    pub(crate) fn find_bus(&self, bus: usize) -> Option<(usize, Arc<Mutex<PciBus>>)> {
+        if let Some(child) = self.get_bus(bus) {
+            return Some((self.number, child.clone()));
+        }
+
+        self.buses
+            .values()
+            .find_map(|child| child.lock().unwrap().find_bus(bus))
+    }
+
+    /// Renumber a bus reachable from this bus, moving the numbers of the buses behind it by
+    /// the same offset. The Secondary Bus Number of the bridges leading to the moved buses
+    /// follows them. Return false if the bus is not found.
+    /// * `old` - The current number of the bus.
+    /// * `new` - The new number of the bus.
+    pub(crate) fn renumber_connected_bus(&mut self, old: usize, new: usize) -> bool {
+        if let Some(child) = self.buses.remove(&old) {
+            child.lock().unwrap().shift_bus_numbers(old, new);
+            self.buses.insert(new, child);
+            self.renumber_bridges(old, new);
+            return true;
+        }
+
+        self.buses
+            .values()
+            .any(|child| child.lock().unwrap().renumber_connected_bus(old, new))
+    }
+
+    /// Move the number of this bus and of the buses behind it, from `old` to `new` based.
+    fn shift_bus_numbers(&mut self, old: usize, new: usize) {
+        let shift = |number: usize| number + new - old;
+
+        self.number = shift(self.number);
+
+        let buses = std::mem::take(&mut self.buses);
+        for (number, bus) in buses {
+            bus.lock().unwrap().shift_bus_numbers(old, new);
+            self.buses.insert(shift(number), bus);
+            self.renumber_bridges(number, shift(number));
+        }
+    }
+
+    /// Point the bridges of this bus which lead to the bus `old` to the bus `new`.
+    fn renumber_bridges(&self, old: usize, new: usize) {
+        for device in self.devices.values() {
+            device.lock().unwrap().for_each_function(|_, function| {
+                if function.get_secondary_bus() == Some(old as u8) {
+                    function.set_secondary_bus(new as u8);
+                }
+            });
+        }
+    }
+
+    /// Return the maximum number of bridge hops beneath this bus, 0 for a leaf bus.
+    pub fn depth(&self) -> usize {
+        self.depth_excluding(&[])
+    }
+
+    /// Return the maximum number of bridge hops beneath this bus, 0 for a leaf bus.
+    /// * `excluded` - The numbers of the connected buses which are not descended into.
+    pub(crate) fn depth_excluding(&self, excluded: &[usize]) -> usize {
+        self.buses
+            .iter()
+            .filter(|(number, _)| !excluded.contains(number))
+            .map(|(_, bus)| bus.lock().unwrap().depth_excluding(excluded) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Set the subordinate bus of every bridge behind this bus to the highest bus number
+    /// reachable through it, as the firmware does. A bridge without a bus connected behind it
+    /// gets its secondary bus as subordinate bus.
+    /// Return the highest bus number reachable from this bus, including itself.
+    pub fn recompute_subordinate_buses(&self) -> usize {
+        let reachable: BTreeMap<usize, usize> = self
+            .buses
+            .iter()
+            .map(|(number, bus)| (*number, bus.lock().unwrap().recompute_subordinate_buses()))
+            .collect();
+        let mut highest = reachable.values().cloned().fold(self.number, usize::max);
+
+        for device in self.devices.values() {
+            device.lock().unwrap().for_each_function(|_, function| {
+                if let Some(secondary) = function.get_secondary_bus() {
+                    let subordinate = reachable
+                        .get(&usize::from(secondary))
+                        .map_or(secondary, |reachable| {
+                            (*reachable).max(usize::from(secondary)) as u8
+                        });
+                    function.set_subordinate_bus(subordinate);
+                    highest = highest.max(usize::from(subordinate));
+                }
+            });
+        }
+
+        highest
+    }
+
+    /// Return the bus connected on the current bus which leads to the requested bus.
+    /// This is the bus itself if it is directly connected, otherwise the secondary bus of the
+    /// bridge whose bus range (secondary to subordinate) contains the requested bus.
+    /// * `bus` - The index of the requested bus.
+    fn route_bus(&self, bus: usize) -> Option<&Arc<Mutex<PciBus>>> {
+        if let Some(child) = self.get_bus(bus) {
+            return Some(child);
+        }
+
+        for device in self.devices.values() {
+            let device = device.lock().unwrap();
+
+            for function in device.functions.values() {
+                let function = function.lock().unwrap();
+                let function = match function.as_function() {
+                    Some(function) => function,
+                    None => continue,
+                };
+
+                if let (Some(secondary), Some(subordinate)) =
+                    (function.get_secondary_bus(), function.get_subordinate_bus())
+                {
+                    let secondary = usize::from(secondary);
+                    if (secondary..=usize::from(subordinate)).contains(&bus) {
+                        if let Some(child) = self.get_bus(secondary) {
+                            return Some(child);
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Check if a function is present on this bus or maybe on other busses connected.
+    /// * `bus` - The index of the bus.
+    /// * `device` - The index of the device of the bus.
+    /// * `function` - The index of the function of the device.
+    pub fn has_function(&self, bus: usize, device: usize, function: usize) -> bool {
+        if bus == self.number {
+            return match self.get_device(device) {
+                Some(device) => device.lock().unwrap().has_function(function),
+                _ => false,
+            };
+        }
+
+        if let Some(bridge) = self.route_bus(bus) {
+            bridge.lock().unwrap().has_function(bus, device, function)
+        } else {
+            false
+        }
+    }
+
+    /// Find the function which claims a memory access, as (bus, device, function, BAR).
+    /// The BARs of the functions on this bus are checked first, then the access descends
+    /// through the bridges whose memory windows forward it to their secondary bus.
+    /// * `address` - The physical address of the access.
+    pub fn route_mmio(&self, address: u64) -> Option<(usize, usize, usize, usize)> {
+        let mut secondary_buses = Vec::new();
+
+        for (device_number, device) in self.devices.iter() {
+            let device = device.lock().unwrap();
+
+            for (function_number, function) in device.functions.iter() {
+                let function = function.lock().unwrap();
+                let function = match function.as_function() {
+                    Some(function) => function,
+                    None => continue,
+                };
+
+                if let Some(bar) = function.find_memory_bar(address) {
+                    return Some((self.number, *device_number, *function_number, bar));
+                }
+
+                if function.is_memory_space_enabled() && function.memory_windows_contain(address) {
+                    if let Some(secondary) = function.get_secondary_bus() {
+                        secondary_buses.push(usize::from(secondary));
+                    }
+                }
+            }
+        }
+
+        secondary_buses
+            .into_iter()
+            .filter_map(|secondary| self.get_bus(secondary))
+            .filter_map(|bus| bus.lock().unwrap().route_mmio(address))
+            .next()
+    }
+
     /// Get a register from the configuration header space of a function of the device.
     /// Check if the device is on this bus or maybe on other busses connected.
     /// * `bus` - The index of the bus.
@@ -140,6 +537,13 @@ impl PciBus {
     ) -> Option<u32> {
         // Check if the message is for a device on this bus or check the other buses.
         if bus == self.number {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+
+            // The absent slots are skipped without locking anything.
+            if !self.is_slot_populated(device, function) {
+                return None;
+            }
+
             return match self.get_device(device) {
                 Some(device) => device
                     .lock()
@@ -149,7 +553,7 @@ impl PciBus {
             };
         }
 
-        if let Some(bridge) = self.get_bus(bus) {
+        if let Some(bridge) = self.route_bus(bus) {
             bridge
                 .lock()
                 .unwrap()
@@ -178,6 +582,8 @@ impl PciBus {
     ) {
         // Check if the message is for a device on this bus or check the other buses.
         if bus == self.number {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+
             if let Some(device) = self.get_device(device) {
                 device
                     .lock()
@@ -188,7 +594,7 @@ impl PciBus {
             return;
         }
 
-        if let Some(bridge) = self.get_bus(bus) {
+        if let Some(bridge) = self.route_bus(bus) {
             bridge
                 .lock()
                 .unwrap()
@@ -200,6 +606,7 @@ impl PciBus {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::function::PciFunction;
 
     #[test]
     fn bus_bus_add_get_remove() {
@@ -234,4 +641,255 @@ mod tests {
             assert!(bus.get_device(device).is_none());
         }
     }
+
+    #[test]
+    fn bus_has_function() {
+        let mut bus = PciBus::new(0);
+        let mut child = PciBus::new(1);
+
+        bus.add_device(PciDevice::new_dummy_host_bridge(0)).unwrap();
+        child
+            .add_device(PciDevice::new_dummy_host_bridge(2))
+            .unwrap();
+        bus.add_bus(child).unwrap();
+
+        assert!(bus.has_function(0, 0, 0));
+        assert!(bus.has_function(1, 2, 0));
+        assert!(!bus.has_function(0, 0, 1));
+        assert!(!bus.has_function(0, 1, 0));
+        assert!(!bus.has_function(2, 0, 0));
+    }
+
+    #[test]
+    fn bus_access_counts() {
+        let mut bus = PciBus::new(0);
+        bus.add_device(PciDevice::new_dummy_host_bridge(0)).unwrap();
+        bus.add_bus(PciBus::new(1)).unwrap();
+
+        for _ in 0..3 {
+            bus.read_configuration_register(0, 0, 0, 0);
+        }
+        // Accesses to absent devices still target the bus.
+        bus.read_configuration_register(0, 5, 0, 0);
+        bus.write_configuration_register(0, 0, 0, 1, 0, &[0x00]);
+        bus.write_configuration_register(1, 0, 0, 1, 0, &[0x00]);
+        bus.read_configuration_register(1, 0, 0, 0);
+
+        assert_eq!(bus.get_access_counts(), (4, 1));
+        assert_eq!(
+            bus.get_bus(1).unwrap().lock().unwrap().get_access_counts(),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn bus_write_behind_bridges() {
+        use crate::bridge::{
+            SECONDARY_BUS_OFFSET, SECONDARY_BUS_REGISTER, SUBORDINATE_BUS_OFFSET,
+            SUBORDINATE_BUS_REGISTER,
+        };
+        use crate::function::{PciClassCode, PciFunction, PciHeaderType};
+
+        let bridge = |number: usize, secondary: u8, subordinate: u8| {
+            let mut function = PciFunction::new(
+                0,
+                0x0001,
+                0x1B36,
+                PciClassCode::PciToPciBridge,
+                0,
+                PciHeaderType::Type1,
+                0,
+                0,
+            );
+            function.write_configuration_byte(
+                SECONDARY_BUS_REGISTER,
+                SECONDARY_BUS_OFFSET,
+                secondary,
+            );
+            function.write_configuration_byte(
+                SUBORDINATE_BUS_REGISTER,
+                SUBORDINATE_BUS_OFFSET,
+                subordinate,
+            );
+
+            let mut device = PciDevice::new(number);
+            device.add_function(function).unwrap();
+            device
+        };
+
+        // Bus 0 -> bridge (1-2) -> bus 1 -> bridge (2-2) -> bus 2 -> device 3.
+        let mut deepest = PciBus::new(2);
+        deepest
+            .add_device(PciDevice::new_dummy_host_bridge(3))
+            .unwrap();
+        let mut middle = PciBus::new(1);
+        middle.add_device(bridge(0, 2, 2)).unwrap();
+        middle.add_bus(deepest).unwrap();
+        let mut root = PciBus::new(0);
+        root.add_device(bridge(1, 1, 2)).unwrap();
+        root.add_bus(middle).unwrap();
+
+        assert!(root.has_function(2, 3, 0));
+        root.write_configuration_register(2, 3, 0, 1, 0, &[0x12, 0x04]);
+        assert_eq!(
+            root.read_configuration_register(2, 3, 0, 1).unwrap() & 0xFFFF,
+            0x0412
+        );
+        assert_eq!(
+            root.get_bus(1)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .get_bus(2)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .get_access_counts(),
+            (1, 1)
+        );
+
+        // The bus is outside the range of every bridge.
+        assert!(root.read_configuration_register(3, 3, 0, 1).is_none());
+    }
+
+    #[test]
+    fn bus_reserved_slots() {
+        let mut bus = PciBus::new(0);
+        bus.add_device(PciDevice::new_dummy_host_bridge(0)).unwrap();
+
+        assert!(bus.reserve_slot(0).is_err());
+        assert!(bus.reserve_slot(MAX_DEVICE_NUMBER).is_err());
+        bus.reserve_slot(1).unwrap();
+        assert_eq!(bus.next_free_slot(), Some(2));
+
+        // The reserved slot stays absent for the guest.
+        assert_eq!(
+            bus.attach_device(PciDevice::new_dummy_host_bridge(7))
+                .unwrap(),
+            2
+        );
+        assert!(!bus.has_function(0, 1, 0));
+        assert!(bus.has_function(0, 2, 0));
+        assert_eq!(bus.next_free_slot(), Some(3));
+
+        bus.release_slot(1);
+        assert_eq!(bus.next_free_slot(), Some(1));
+
+        for device in 1..MAX_DEVICE_NUMBER {
+            if device != 2 {
+                bus.reserve_slot(device).unwrap();
+            }
+        }
+        assert_eq!(bus.next_free_slot(), None);
+        assert!(bus.attach_device(PciDevice::new(0)).is_err());
+    }
+
+    #[test]
+    fn bus_depth() {
+        let mut deepest = PciBus::new(3);
+        deepest
+            .add_device(PciDevice::new_dummy_host_bridge(0))
+            .unwrap();
+        let mut middle = PciBus::new(2);
+        middle.add_bus(deepest).unwrap();
+        let mut first = PciBus::new(1);
+        first.add_bus(middle).unwrap();
+        let mut root = PciBus::new(0);
+        root.add_bus(first).unwrap();
+        root.add_bus(PciBus::new(4)).unwrap();
+
+        assert_eq!(root.depth(), 3);
+        assert_eq!(root.get_bus(1).unwrap().lock().unwrap().depth(), 2);
+        assert_eq!(root.get_bus(4).unwrap().lock().unwrap().depth(), 0);
+        assert_eq!(root.depth_excluding(&[1]), 1);
+    }
+
+    #[test]
+    fn bus_present_masks() {
+        let mut bus = PciBus::new(0);
+        bus.add_device(PciDevice::new_dummy_host_bridge(3)).unwrap();
+        bus.get_device(3)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .add_function(PciFunction::new_dummy_host_bridge(5))
+            .unwrap();
+        assert_eq!(bus.device_present_mask(), 1 << 3);
+        assert_eq!(bus.function_present_mask(3), 0b10_0001);
+        assert_eq!(bus.function_present_mask(4), 0);
+
+        // The absent slots read as absent without locking the device or its functions.
+        {
+            let device = bus.get_device(3).unwrap().lock().unwrap();
+            let _function = device.get_function(0).unwrap().lock().unwrap();
+            for function in 1..MAX_FUNCTION_NUMBER {
+                if function != 5 {
+                    assert_eq!(bus.read_configuration_register(0, 3, function, 0), None);
+                }
+            }
+            for device in (0..MAX_DEVICE_NUMBER + 1).filter(|device| *device != 3) {
+                assert_eq!(bus.read_configuration_register(0, device, 0, 0), None);
+            }
+        }
+        assert!(bus.read_configuration_register(0, 3, 5, 0).is_some());
+
+        bus.get_device(3)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .remove_function(5);
+        assert_eq!(bus.function_present_mask(3), 0b1);
+        assert_eq!(bus.read_configuration_register(0, 3, 5, 0), None);
+        bus.remove_device(3);
+        assert_eq!(bus.device_present_mask(), 0);
+        assert_eq!(bus.function_present_mask(3), 0);
+    }
+
+    #[test]
+    fn bus_hotplug() {
+        let mut bus = PciBus::new(2);
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        // Without a callback, the hotplug operations are plain additions and removals.
+        bus.hotplug_add(PciDevice::new_dummy_host_bridge(0))
+            .unwrap();
+        assert!(events.lock().unwrap().is_empty());
+
+        let recorded = events.clone();
+        bus.set_hotplug_callback(Some(Arc::new(move |bus, device, function, present| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((bus, device, function, present))
+        })));
+
+        let mut device = PciDevice::new_dummy_host_bridge(7);
+        device
+            .add_function(PciFunction::new_dummy_host_bridge(3))
+            .unwrap();
+        assert_eq!(bus.hotplug_add(device).unwrap(), 1);
+        assert!(bus.get_device(1).is_some());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(2, 1, 0, true), (2, 1, 3, true)]
+        );
+
+        events.lock().unwrap().clear();
+        assert!(bus.hotplug_remove(1).is_some());
+        assert!(bus.get_device(1).is_none());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(2, 1, 0, false), (2, 1, 3, false)]
+        );
+
+        // Nothing is notified for an empty slot.
+        events.lock().unwrap().clear();
+        assert!(bus.hotplug_remove(1).is_none());
+        assert!(events.lock().unwrap().is_empty());
+
+        bus.set_hotplug_callback(None);
+        bus.hotplug_add(PciDevice::new_dummy_host_bridge(0))
+            .unwrap();
+        assert!(events.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,456 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use utils::byte_order::{read_le_u16, read_le_u32};
+
+/// The IDs of the standard capabilities which can be built with this crate.
+///
+/// More information at:
+/// https://pcisig.com/sites/default/files/files/PCI_Code-ID_r_1_11__v24_Jan_2019.pdf
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciCapabilityId {
+    PowerManagement = 0x01,
+    VitalProductData = 0x03,
+    Msi = 0x05,
+    VendorSpecific = 0x09,
+    PciExpress = 0x10,
+    MsiX = 0x11,
+}
+
+impl PciCapabilityId {
+    /// Return the capability ID matching the raw value, if it is known.
+    pub fn from_raw(id: u8) -> Option<PciCapabilityId> {
+        match id {
+            0x01 => Some(PciCapabilityId::PowerManagement),
+            0x03 => Some(PciCapabilityId::VitalProductData),
+            0x05 => Some(PciCapabilityId::Msi),
+            0x09 => Some(PciCapabilityId::VendorSpecific),
+            0x10 => Some(PciCapabilityId::PciExpress),
+            0x11 => Some(PciCapabilityId::MsiX),
+            _ => None,
+        }
+    }
+}
+
+/// A capability which can be added in the capability list of a function.
+pub trait PciCapability {
+    /// Return the ID of the capability.
+    fn id(&self) -> PciCapabilityId;
+
+    /// Return the body of the capability, which is the data that follows the
+    /// `Capability ID` and the `Next Capability Pointer` bytes.
+    fn bytes(&self) -> Vec<u8>;
+}
+
+/// The Power Management capability (version 3 of the specification).
+pub struct PowerManagementCapability;
+
+impl PciCapability for PowerManagementCapability {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PowerManagement
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // Power Management Capabilities (version 3), Control/Status, Bridge Extension and Data.
+        vec![0x03, 0x00, 0x00, 0x00, 0x00, 0x00]
+    }
+}
+
+/// The Vital Product Data capability. The backing store of the data is kept by the function,
+/// which emulates the handshake of the VPD Address and VPD Data registers.
+pub struct VpdCapability;
+
+impl PciCapability for VpdCapability {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::VitalProductData
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // VPD Address (with the F flag) and VPD Data.
+        vec![0x00; 6]
+    }
+}
+
+/// The Message Signaled Interrupts capability.
+pub struct MsiCapability {
+    /// The number of requested vectors, as a power of 2 (from 0 to 5).
+    pub vectors_log2: u8,
+
+    /// Whether the function is capable of generating 64-bit message addresses.
+    pub address_64bit: bool,
+
+    /// Whether the function supports masking the vectors individually.
+    pub per_vector_masking: bool,
+}
+
+impl PciCapability for MsiCapability {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::Msi
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut control = u16::from(self.vectors_log2.min(5)) << 1;
+        if self.address_64bit {
+            control |= 1 << 7;
+        }
+        if self.per_vector_masking {
+            control |= 1 << 8;
+        }
+
+        // Message Control followed by the Message Address.
+        let mut bytes = control.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 4]);
+
+        // Message Upper Address.
+        if self.address_64bit {
+            bytes.extend_from_slice(&[0; 4]);
+        }
+
+        // Message Data and the reserved word after it.
+        bytes.extend_from_slice(&[0; 4]);
+
+        // Mask Bits and Pending Bits.
+        if self.per_vector_masking {
+            bytes.extend_from_slice(&[0; 8]);
+        }
+
+        bytes
+    }
+}
+
+/// The MSI-X capability.
+pub struct MsixCapability {
+    /// The number of entries in the MSI-X table (from 1 to 2048).
+    pub table_size: u16,
+
+    /// The BAR which contains the MSI-X table.
+    pub table_bar: u8,
+
+    /// The offset of the MSI-X table within its BAR (8 bytes aligned).
+    pub table_offset: u32,
+
+    /// The BAR which contains the Pending Bit Array.
+    pub pba_bar: u8,
+
+    /// The offset of the Pending Bit Array within its BAR (8 bytes aligned).
+    pub pba_offset: u32,
+}
+
+impl PciCapability for MsixCapability {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::MsiX
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // The table size is encoded as N - 1.
+        let control = self.table_size.saturating_sub(1) & 0x07FF;
+        let table = (self.table_offset & !0x7) | u32::from(self.table_bar & 0x7);
+        let pba = (self.pba_offset & !0x7) | u32::from(self.pba_bar & 0x7);
+
+        let mut bytes = control.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&table.to_le_bytes());
+        bytes.extend_from_slice(&pba.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// The device/port types a PCI Express function can advertise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciExpressDeviceType {
+    Endpoint = 0x0,
+    LegacyEndpoint = 0x1,
+    RootPort = 0x4,
+    UpstreamPort = 0x5,
+    DownstreamPort = 0x6,
+    RootComplexIntegratedEndpoint = 0x9,
+}
+
+/// The PCI Express capability (version 2 of the capability structure).
+pub struct PciExpressCapability {
+    pub device_type: PciExpressDeviceType,
+}
+
+/// The PCI Express capability structure has a length of 60 bytes.
+const PCI_EXPRESS_CAPABILITY_SIZE: usize = 60;
+
+impl PciCapability for PciExpressCapability {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PciExpress
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let capabilities = 0x2 | ((self.device_type as u16) << 4);
+
+        let mut bytes = capabilities.to_le_bytes().to_vec();
+        bytes.resize(PCI_EXPRESS_CAPABILITY_SIZE - 2, 0);
+
+        bytes
+    }
+}
+
+/// A Vendor Specific capability carrying opaque data.
+pub struct VendorCapability {
+    pub data: Vec<u8>,
+}
+
+impl PciCapability for VendorCapability {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::VendorSpecific
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // The length byte covers the whole capability, including the ID, the next pointer
+        // and the length itself.
+        let mut bytes = vec![(self.data.len() + 3) as u8];
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+}
+
+/// The IDs of the extended capabilities which can be built with this crate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciExtendedCapabilityId {
+    DeviceSerialNumber = 0x0003,
+    PowerBudgeting = 0x0004,
+    ResizableBar = 0x0015,
+    DesignatedVendorSpecific = 0x0023,
+}
+
+/// The bits of a Resizable BAR Control register which select the size of the BAR.
+pub const RESIZABLE_BAR_SIZE_SHIFT: u32 = 8;
+pub const RESIZABLE_BAR_SIZE_MASK: u32 = 0x3F << RESIZABLE_BAR_SIZE_SHIFT;
+
+/// The sizes of a Resizable BAR are encoded as powers of two from 1MB.
+pub const RESIZABLE_BAR_MIN_SIZE_SHIFT: u32 = 20;
+
+/// A capability which can be added in the extended capability list of a function.
+pub trait PciExtendedCapability {
+    /// Return the ID of the extended capability.
+    fn id(&self) -> PciExtendedCapabilityId;
+
+    /// Return the version of the extended capability structure.
+    fn version(&self) -> u8;
+
+    /// Return the body of the extended capability, which is the data that follows the
+    /// extended capability header dword.
+    fn bytes(&self) -> Vec<u8>;
+}
+
+/// The Designated Vendor-Specific extended capability.
+pub struct DvsecCapability {
+    /// The vendor which defines the layout of the capability.
+    pub vendor_id: u16,
+
+    /// The vendor-defined ID of the capability.
+    pub dvsec_id: u16,
+
+    /// The vendor-defined revision of the capability.
+    pub revision: u8,
+
+    /// The vendor-specific registers.
+    pub data: Vec<u8>,
+}
+
+impl PciExtendedCapability for DvsecCapability {
+    fn id(&self) -> PciExtendedCapabilityId {
+        PciExtendedCapabilityId::DesignatedVendorSpecific
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // The length covers the whole capability: the extended capability header,
+        // the two DVSEC headers and the vendor-specific registers.
+        let length = (10 + self.data.len()) as u32 & 0xFFF;
+        let header =
+            u32::from(self.vendor_id) | (u32::from(self.revision & 0xF) << 16) | (length << 20);
+
+        let mut bytes = header.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.dvsec_id.to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+}
+
+/// The Device Serial Number extended capability, a stable 64-bit identity of the device.
+pub struct SerialNumberCapability {
+    /// The serial number, an IEEE EUI-64 identifier.
+    pub serial: u64,
+}
+
+impl PciExtendedCapability for SerialNumberCapability {
+    fn id(&self) -> PciExtendedCapabilityId {
+        PciExtendedCapabilityId::DeviceSerialNumber
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // The lower dword of the serial number comes first.
+        self.serial.to_le_bytes().to_vec()
+    }
+}
+
+/// The Power Budgeting extended capability. The guest selects an entry of the power budget
+/// table through the Data Select register and reads it from the Data register.
+pub struct PowerBudgetingCapability;
+
+/// The Power Budgeting capability has a length of 16 bytes.
+const POWER_BUDGETING_CAPABILITY_SIZE: usize = 16;
+
+impl PciExtendedCapability for PowerBudgetingCapability {
+    fn id(&self) -> PciExtendedCapabilityId {
+        PciExtendedCapabilityId::PowerBudgeting
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // Data Select, Data and Power Budget Capability registers.
+        vec![0; POWER_BUDGETING_CAPABILITY_SIZE - 4]
+    }
+}
+
+/// An entry of the power budget table, as returned in the Data register.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PowerBudgetEntry {
+    /// The power, in watts, before the scale is applied.
+    pub base_power: u8,
+    /// The scale of the base power (0: 1x, 1: 0.1x, 2: 0.01x, 3: 0.001x).
+    pub data_scale: u8,
+    /// The power management sub state.
+    pub pm_sub_state: u8,
+    /// The power management state (0: D0, 3: D3).
+    pub pm_state: u8,
+    /// The type of operating condition (such as idle or sustained).
+    pub power_type: u8,
+    /// The power rail (0: 12V, 1: 3.3V, 2: 1.5V or 1.8V, 7: thermal).
+    pub power_rail: u8,
+}
+
+impl PowerBudgetEntry {
+    /// Return the value of the Data register for this entry.
+    pub fn data(&self) -> u32 {
+        u32::from(self.base_power)
+            | (u32::from(self.data_scale & 0x3) << 8)
+            | (u32::from(self.pm_sub_state & 0x7) << 10)
+            | (u32::from(self.pm_state & 0x3) << 13)
+            | (u32::from(self.power_type & 0x7) << 15)
+            | (u32::from(self.power_rail & 0x7) << 18)
+    }
+}
+
+/// The Resizable BAR extended capability, describing a single BAR.
+pub struct ResizableBarCapability {
+    /// The index of the resizable BAR.
+    pub bar_index: u8,
+
+    /// The supported sizes, bit `n` being set if 2^n MB is supported.
+    pub supported_sizes: u32,
+
+    /// The current size, as the encoding of 2^size MB.
+    pub size: u8,
+}
+
+impl PciExtendedCapability for ResizableBarCapability {
+    fn id(&self) -> PciExtendedCapabilityId {
+        PciExtendedCapabilityId::ResizableBar
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        // The Capability register lists the supported sizes from bit 4, and the Control
+        // register holds the BAR index, the number of resizable BARs and the current size.
+        let capability = self.supported_sizes << 4;
+        let control = u32::from(self.bar_index & 0x7)
+            | (1 << 5)
+            | ((u32::from(self.size) << RESIZABLE_BAR_SIZE_SHIFT) & RESIZABLE_BAR_SIZE_MASK);
+
+        let mut bytes = capability.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&control.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// Render the key fields of a capability in a human readable form.
+/// * `id` - The raw ID of the capability.
+/// * `bytes` - The capability, starting with the ID byte.
+pub fn describe_capability(id: u8, bytes: &[u8]) -> String {
+    // Make sure the decoders below never index out of bounds.
+    let mut bytes = bytes.to_vec();
+    bytes.resize(PCI_EXPRESS_CAPABILITY_SIZE, 0);
+
+    match PciCapabilityId::from_raw(id) {
+        Some(PciCapabilityId::PowerManagement) => format!(
+            "Power Management: version {}",
+            read_le_u16(&bytes[2..]) & 0x7
+        ),
+        Some(PciCapabilityId::VitalProductData) => {
+            let address = read_le_u16(&bytes[2..]);
+
+            format!(
+                "Vital Product Data: address 0x{:x}, flag {}",
+                address & 0x7FFF,
+                address >> 15
+            )
+        }
+        Some(PciCapabilityId::Msi) => {
+            let control = read_le_u16(&bytes[2..]);
+
+            format!(
+                "MSI: {} vector(s), {}-bit address, per-vector masking {}, {}",
+                1 << ((control >> 1) & 0x7),
+                if control & (1 << 7) != 0 { 64 } else { 32 },
+                if control & (1 << 8) != 0 { "on" } else { "off" },
+                if control & 0x1 != 0 {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            )
+        }
+        Some(PciCapabilityId::VendorSpecific) => format!("Vendor Specific: length {}", bytes[2]),
+        Some(PciCapabilityId::PciExpress) => {
+            let capabilities = read_le_u16(&bytes[2..]);
+
+            format!(
+                "PCI Express: version {}, device type 0x{:x}",
+                capabilities & 0xF,
+                (capabilities >> 4) & 0xF
+            )
+        }
+        Some(PciCapabilityId::MsiX) => {
+            let control = read_le_u16(&bytes[2..]);
+            let table = read_le_u32(&bytes[4..]);
+            let pba = read_le_u32(&bytes[8..]);
+
+            format!(
+                "MSI-X: table size {}, BAR {}, offset 0x{:x}, PBA BAR {}, offset 0x{:x}, {}",
+                (control & 0x07FF) + 1,
+                table & 0x7,
+                table & !0x7,
+                pba & 0x7,
+                pba & !0x7,
+                if control & (1 << 15) != 0 {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            )
+        }
+        None => format!("Unknown (0x{:02x})", id),
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The Programming Interfaces of the common classes of functions.
+//!
+//! More information at:
+//! https://pcisig.com/sites/default/files/files/PCI_Code-ID_r_1_11__v24_Jan_2019.pdf
+
+use crate::function::PciProgrammingInterface;
+
+/// The Programming Interfaces of an IDE controller (`PciClassCode::IdeController`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciIdeProgrammingInterface {
+    IsaCompatibilityMode = 0x00,
+    PciNativeMode = 0x05,
+    IsaCompatibilityModeBusMastering = 0x80,
+    PciNativeModeBusMastering = 0x85,
+}
+
+impl PciProgrammingInterface for PciIdeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// The Programming Interfaces of a Serial ATA controller (`PciClassCode::SerialAtaController`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciSataProgrammingInterface {
+    VendorSpecific = 0x00,
+    Ahci = 0x01,
+    SerialStorageBus = 0x02,
+}
+
+impl PciProgrammingInterface for PciSataProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// The Programming Interfaces of a Non-Volatile Memory controller
+/// (`PciClassCode::NonVolatileMemoryController`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciNvmProgrammingInterface {
+    NvmHci = 0x01,
+    NvmExpress = 0x02,
+}
+
+impl PciProgrammingInterface for PciNvmProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// The Programming Interfaces of a USB controller (`PciClassCode::UsbController`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciUsbProgrammingInterface {
+    Uhci = 0x00,
+    Ohci = 0x10,
+    Ehci = 0x20,
+    Xhci = 0x30,
+    Device = 0xFE,
+}
+
+impl PciProgrammingInterface for PciUsbProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::{
+        PciClassCode, PciFunctionBuilder, PciHeaderType, CLASS_CODE_REGISTER,
+        PROGRAMMING_INTERFACE_OFFSET, REVISION_ID_OFFSET,
+    };
+
+    #[test]
+    fn ahci_programming_interface() {
+        let function = PciFunctionBuilder::new(
+            0,
+            0x2922,
+            0x8086,
+            PciClassCode::SerialAtaController,
+            PciHeaderType::Type0,
+        )
+        .revision_id(0x02)
+        .programming_interface(&PciSataProgrammingInterface::Ahci)
+        .build()
+        .unwrap();
+
+        assert_eq!(
+            function.read_configuration_byte(CLASS_CODE_REGISTER, PROGRAMMING_INTERFACE_OFFSET),
+            Some(0x01)
+        );
+        // The base class, the subclass and the revision are left alone.
+        assert_eq!(
+            function.read_configuration_dword(CLASS_CODE_REGISTER),
+            Some(0x0106_0102)
+        );
+        assert_eq!(
+            function.read_configuration_byte(CLASS_CODE_REGISTER, REVISION_ID_OFFSET),
+            Some(0x02)
+        );
+        assert_eq!(function.header().class_code, 0x01_06_01);
+
+        // Without one, the Programming Interface is the one of the class code.
+        let function = PciFunctionBuilder::new(
+            0,
+            0x0001,
+            0x1033,
+            PciClassCode::UsbController,
+            PciHeaderType::Type0,
+        )
+        .build()
+        .unwrap();
+        assert_eq!(
+            function.read_configuration_byte(CLASS_CODE_REGISTER, PROGRAMMING_INTERFACE_OFFSET),
+            Some(0x00)
+        );
+        assert_eq!(PciUsbProgrammingInterface::Xhci.get_register_value(), 0x30);
+    }
+}
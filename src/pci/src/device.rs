@@ -1,11 +1,15 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::PciFunction;
-use std::collections::HashMap;
+use crate::function::{
+    access_mask, CLASS_CODE_REGISTER, HEADER_TYPE_MULTI_FUNCTION, HEADER_TYPE_OFFSET,
+    HEADER_TYPE_REGISTER,
+};
+use crate::{PciBarRegionType, PciConfigurable, PciFunction, PciFunctionHeader};
+use std::collections::BTreeMap;
 use std::option::Option;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
-use utils::byte_order::{read_le_u16, read_le_u32};
 
 /// A Device can have implemented up to 8 Functions (not necessarily sequentially).
 pub const MAX_FUNCTION_NUMBER: usize = 8;
@@ -25,10 +29,20 @@ pub type Result<T> = std::result::Result<T, PciDeviceError>;
 /// A Device might implement Functions 0, 2 and 7 and there is no need to be sequentially.
 pub struct PciDevice {
     /// The number of the device within the bus.
-    number: usize,
+    pub(crate) number: usize,
 
-    /// The functions registered within this device.
-    functions: HashMap<usize, Arc<Mutex<PciFunction>>>,
+    /// The functions registered within this device, sorted by their numbers. The built-in
+    /// functions and the ones implemented outside of this crate are handled the same way.
+    pub(crate) functions: BTreeMap<usize, Arc<Mutex<dyn PciConfigurable>>>,
+
+    /// The bitmap of the functions registered within this device. It is shared with the bus,
+    /// which reads it without locking the device.
+    pub(crate) function_present_mask: Arc<AtomicU8>,
+
+    /// The identification fields of the header of the built-in functions, shared with them
+    /// so they are read without locking the functions. The functions refresh them on every
+    /// change of their configuration space.
+    pub(crate) headers: BTreeMap<usize, Arc<AtomicU64>>,
 }
 
 impl PciDevice {
@@ -36,7 +50,9 @@ impl PciDevice {
     pub fn new(number: usize) -> PciDevice {
         PciDevice {
             number,
-            functions: HashMap::new(),
+            functions: BTreeMap::new(),
+            function_present_mask: Arc::new(AtomicU8::new(0)),
+            headers: BTreeMap::new(),
         }
     }
 
@@ -60,38 +76,231 @@ impl PciDevice {
     /// Add a new function to this device.
     /// * `function` - The function that will be wrapped in an Arc-Mutex struct and added.
     pub fn add_function(&mut self, function: PciFunction) -> Result<()> {
-        let function_number = function.get_number();
+        let number = function.get_number();
+        let header = function.shared_header.clone();
+
+        self.insert_function(number, Arc::new(Mutex::new(function)))?;
+        self.headers.insert(number, header);
+
+        Ok(())
+    }
 
-        if function_number >= MAX_FUNCTION_NUMBER {
-            return Err(PciDeviceError::InvalidPciFunctionNumber(function_number));
+    /// Add a function whose configuration space is implemented outside of this crate.
+    /// * `number` - The number of the function within the device.
+    /// * `configurable` - The configuration space of the function.
+    pub fn add_configurable(
+        &mut self,
+        number: usize,
+        configurable: Arc<Mutex<dyn PciConfigurable>>,
+    ) -> Result<()> {
+        self.insert_function(number, configurable)
+    }
+
+    fn insert_function(
+        &mut self,
+        number: usize,
+        function: Arc<Mutex<dyn PciConfigurable>>,
+    ) -> Result<()> {
+        if number >= MAX_FUNCTION_NUMBER {
+            return Err(PciDeviceError::InvalidPciFunctionNumber(number));
         }
 
-        if self.functions.contains_key(&function_number) {
-            return Err(PciDeviceError::AlreadyInUsePciFunctionSlot(function_number));
+        if self.functions.contains_key(&number) {
+            return Err(PciDeviceError::AlreadyInUsePciFunctionSlot(number));
         }
 
-        self.functions
-            .insert(function_number, Arc::new(Mutex::new(function)));
+        self.functions.insert(number, function);
+        self.function_present_mask
+            .fetch_or(1 << number, Ordering::Release);
+        self.update_multi_function();
 
         Ok(())
     }
 
-    /// Return a reference to the requested function if it exists.
+    /// Return true if the function exists and is present.
     /// * `function` - The index of the function of the device.
-    pub fn get_function(&self, function: usize) -> Option<&Arc<Mutex<PciFunction>>> {
+    pub fn has_function(&self, function: usize) -> bool {
+        match self.functions.get(&function) {
+            Some(function) => function.lock().unwrap().is_present(),
+            None => false,
+        }
+    }
+
+    /// Return a reference to the requested function if it exists. The built-in functions are
+    /// reached through `PciConfigurable::as_function`.
+    /// * `function` - The index of the function of the device.
+    pub fn get_function(&self, function: usize) -> Option<&Arc<Mutex<dyn PciConfigurable>>> {
         self.functions.get(&function)
     }
 
     /// Return a mutable reference to the requested function if it exists.
     /// * `function` - The index of the function of the device.
-    pub fn get_mut_function(&mut self, function: usize) -> Option<&mut Arc<Mutex<PciFunction>>> {
+    pub fn get_mut_function(
+        &mut self,
+        function: usize,
+    ) -> Option<&mut Arc<Mutex<dyn PciConfigurable>>> {
         self.functions.get_mut(&function)
     }
 
+    /// Call a closure on every built-in function of the device, with the number of the
+    /// function. The functions implemented outside of this crate are skipped.
+    /// * `f` - The closure called on each function, with its lock held.
+    pub fn for_each_function(&self, mut f: impl FnMut(usize, &mut PciFunction)) {
+        for (number, function) in self.functions.iter() {
+            if let Some(function) = function.lock().unwrap().as_function_mut() {
+                f(*number, function);
+            }
+        }
+    }
+
     /// Remove the function from this device, returning the function object, if it exists.
+    /// Function 0 is the one probed by the guests, so it can only be removed last: removing
+    /// it while other functions exist would leave the device present but undiscoverable.
+    /// In that case nothing is removed and `None` is returned.
+    ///
+    /// The hotplug removal sequence is:
+    /// 1. The function is marked absent (`set_present(false)`), under its lock, so the accesses
+    ///    which already hold a reference to it only read all ones and drop their writes.
+    /// 2. The function is dropped from the device, so the new accesses do not find it.
+    /// 3. The device model tears down the returned function once it is the last reference.
+    ///
+    /// This function does the first two steps, so the guest never observes a function which
+    /// is partially removed. The first step only applies to the built-in functions.
+    /// * `function` - The index of the function of the device.
+    pub fn remove_function(&mut self, function: usize) -> Option<Arc<Mutex<dyn PciConfigurable>>> {
+        if function == 0 && self.functions.keys().any(|number| *number != 0) {
+            return None;
+        }
+
+        if let Some(function) = self.functions.get(&function) {
+            if let Some(function) = function.lock().unwrap().as_function_mut() {
+                function.set_present(false);
+            }
+        }
+
+        if function < MAX_FUNCTION_NUMBER {
+            self.function_present_mask
+                .fetch_and(!(1 << function), Ordering::Release);
+        }
+        self.headers.remove(&function);
+        let function = self.functions.remove(&function);
+        self.update_multi_function();
+
+        function
+    }
+
+    /// Return the bitmap of the functions registered within this device.
+    pub fn function_present_mask(&self) -> u8 {
+        self.function_present_mask.load(Ordering::Acquire)
+    }
+
+    /// Return the identity of a function, as (vendor ID, device ID), if it exists.
+    /// * `function` - The index of the function of the device.
+    pub fn function_identity(&self, function: usize) -> Option<(u16, u16)> {
+        self.function_header(function)
+            .map(|header| (header.vendor_id, header.device_id))
+    }
+
+    /// Return the identification fields of the header of a function, if it exists.
+    /// The built-in functions are not locked, so it is cheap during the enumeration. The
+    /// functions implemented outside of this crate are read through their lock.
     /// * `function` - The index of the function of the device.
-    pub fn remove_function(&mut self, function: usize) -> Option<Arc<Mutex<PciFunction>>> {
-        self.functions.remove(&function)
+    pub fn function_header(&self, function: usize) -> Option<PciFunctionHeader> {
+        if let Some(header) = self.headers.get(&function) {
+            return Some(PciFunctionHeader::from_bits(header.load(Ordering::Acquire)));
+        }
+
+        let function = self.functions.get(&function)?.lock().unwrap();
+        let identity = function.read_config(0, 0, 4);
+
+        Some(PciFunctionHeader {
+            vendor_id: identity as u16,
+            device_id: (identity >> 16) as u16,
+            class_code: function.read_config(CLASS_CODE_REGISTER, 1, 3),
+            header_type: function.read_config(HEADER_TYPE_REGISTER, HEADER_TYPE_OFFSET, 1) as u8
+                & !HEADER_TYPE_MULTI_FUNCTION,
+        })
+    }
+
+    /// Check that the regions decoded by the BARs of the functions do not overlap, so the
+    /// MMIO and IO dispatch stay unambiguous. Return the conflicting pairs of function numbers
+    /// otherwise (two overlapping BARs of the same function are reported as `(n, n)`).
+    pub fn check_bar_overlaps(&self) -> std::result::Result<(), Vec<(usize, usize)>> {
+        let mut regions = Vec::new();
+        self.for_each_function(|number, function| {
+            for bar in function.enabled_bars() {
+                regions.push((number, bar));
+            }
+        });
+
+        let mut conflicts = Vec::new();
+        for (index, (first, first_bar)) in regions.iter().enumerate() {
+            for (second, second_bar) in regions[index + 1..].iter() {
+                let same_space = (first_bar.region_type == PciBarRegionType::IoRegion)
+                    == (second_bar.region_type == PciBarRegionType::IoRegion);
+                let overlap = first_bar.address
+                    < second_bar.address.saturating_add(second_bar.size)
+                    && second_bar.address < first_bar.address.saturating_add(first_bar.size);
+
+                if same_space && overlap && !conflicts.contains(&(*first, *second)) {
+                    conflicts.push((*first, *second));
+                }
+            }
+        }
+
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(conflicts)
+        }
+    }
+
+    /// Return the alignment the MMIO aperture of the device needs, so every memory BAR of its
+    /// functions can be placed at its natural alignment: the size of the largest memory BAR.
+    /// Return 0 if the device has no memory BAR.
+    pub fn preferred_base_alignment(&self) -> u64 {
+        let mut alignment = 0;
+        self.for_each_function(|_, function| {
+            for bar in function.bars() {
+                if bar.region_type != PciBarRegionType::IoRegion {
+                    alignment = alignment.max(bar.size);
+                }
+            }
+        });
+
+        alignment
+    }
+
+    /// Return true if the device implements more than one function.
+    pub fn is_multi_function(&self) -> bool {
+        self.functions.len() > 1
+    }
+
+    /// Keep the Multi-Function bit of every function in sync with the number of functions.
+    fn update_multi_function(&mut self) {
+        let multi_function = self.is_multi_function();
+
+        for function in self.functions.values() {
+            function.lock().unwrap().set_multi_function(multi_function);
+        }
+    }
+
+    /// Reset every function of the device to its initial state, including the functions
+    /// implemented outside of this crate.
+    pub fn reset_all_functions(&mut self) {
+        for function in self.functions.values() {
+            let notifications = {
+                let mut function = function.lock().unwrap();
+                function.reset();
+                function
+                    .as_function_mut()
+                    .map(PciFunction::take_mapping_notifications)
+            };
+
+            if let Some(notifications) = notifications {
+                notifications.fire();
+            }
+        }
     }
 
     /// Get a register from the configuration header space of a function of the device.
@@ -99,7 +308,14 @@ impl PciDevice {
     /// * `register` - The index of the register within configuration header space.
     pub fn read_configuration_register(&self, function: usize, register: usize) -> Option<u32> {
         if let Some(function) = self.get_function(function) {
-            function.lock().unwrap().read_configuration_dword(register)
+            let function = function.lock().unwrap();
+            // A function which is not present reads like a missing one, so the root complex
+            // answers with its absent read value.
+            if !function.is_present() {
+                return None;
+            }
+
+            Some(function.read_config(register, 0, 4))
         } else {
             None
         }
@@ -117,19 +333,22 @@ impl PciDevice {
         offset: usize,
         data: &[u8],
     ) {
-        // Make sure to be protected against overflow.
-        if offset + data.len() > 4 {
+        if access_mask(offset, data.len()).is_none() {
             return;
         }
 
-        if let Some(function) = self.get_mut_function(function) {
-            let mut function = function.lock().unwrap();
-
-            match data.len() {
-                1 => function.write_configuration_byte(register, offset, data[0]),
-                2 => function.write_configuration_word(register, offset, read_le_u16(data)),
-                4 => function.write_configuration_dword(register, read_le_u32(data)),
-                _ => (),
+        if let Some(function) = self.get_function(function) {
+            let notifications = {
+                let mut function = function.lock().unwrap();
+                function.write_config(register, offset, data);
+                function
+                    .as_function_mut()
+                    .map(PciFunction::take_mapping_notifications)
+            };
+
+            // The function is unlocked, so the mapping callback can access it.
+            if let Some(notifications) = notifications {
+                notifications.fire();
             }
         }
     }
@@ -138,6 +357,11 @@ impl PciDevice {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::function::{
+        COMMAND_RESERVED, HEADER_TYPE_MULTI_FUNCTION, HEADER_TYPE_OFFSET, HEADER_TYPE_REGISTER,
+        STATUS_INTERRUPT_STATUS,
+    };
+    use crate::{PciClassCode, PciFunctionBuilder, PciHeaderType, PciMappingEvent};
     use utils::byte_order::read_le_u32;
     use utils::rand::xor_rng_u32;
 
@@ -158,7 +382,8 @@ mod tests {
             .add_function(PciFunction::new_dummy_host_bridge(MAX_FUNCTION_NUMBER))
             .is_err());
 
-        for function in 0..MAX_FUNCTION_NUMBER {
+        // Function 0 has to be removed last.
+        for function in (0..MAX_FUNCTION_NUMBER).rev() {
             device.remove_function(function);
             assert!(device.get_function(function).is_none());
         }
@@ -170,11 +395,17 @@ mod tests {
         let data = [xor_rng_u32() as u8; 4];
 
         device.add_function(get_function(0)).unwrap();
+        // The Interrupt Status bit and the reserved bits of the Command register are
+        // read-only, and clear.
         device.write_configuration_register(0, 1, 0, &data);
 
         assert_eq!(
             device.read_configuration_register(0, 1),
-            Some(read_le_u32(&data))
+            Some(
+                read_le_u32(&data)
+                    & !(u32::from(STATUS_INTERRUPT_STATUS) << 16)
+                    & !u32::from(COMMAND_RESERVED)
+            )
         );
     }
 
@@ -184,12 +415,436 @@ mod tests {
         let data = [xor_rng_u32() as u8; 4];
 
         device.add_function(get_function(0)).unwrap();
+        // The Interrupt Status bit and the reserved bits of the Command register are
+        // read-only, and clear.
         device.write_configuration_register(0, 1, 0, &data);
 
         let function = device.get_function(0).unwrap();
         assert_eq!(
-            function.lock().unwrap().read_configuration_dword(1),
-            Some(read_le_u32(&data))
+            function
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .read_configuration_dword(1),
+            Some(
+                read_le_u32(&data)
+                    & !(u32::from(STATUS_INTERRUPT_STATUS) << 16)
+                    & !u32::from(COMMAND_RESERVED)
+            )
+        );
+    }
+
+    #[test]
+    fn device_multi_function_bit() {
+        let mut device = PciDevice::new(0);
+        let header_type = |device: &PciDevice, function: usize| {
+            device
+                .get_function(function)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .read_configuration_byte(HEADER_TYPE_REGISTER, HEADER_TYPE_OFFSET)
+                .unwrap()
+        };
+
+        device.add_function(get_function(0)).unwrap();
+        assert_eq!(header_type(&device, 0) & HEADER_TYPE_MULTI_FUNCTION, 0);
+
+        device.add_function(get_function(3)).unwrap();
+        assert!(device.is_multi_function());
+        assert_ne!(header_type(&device, 0) & HEADER_TYPE_MULTI_FUNCTION, 0);
+        assert_ne!(header_type(&device, 3) & HEADER_TYPE_MULTI_FUNCTION, 0);
+
+        device.remove_function(3);
+        assert_eq!(header_type(&device, 0) & HEADER_TYPE_MULTI_FUNCTION, 0);
+    }
+
+    #[test]
+    fn device_write_large_offset() {
+        let mut device = PciDevice::new(0);
+        device.add_function(get_function(0)).unwrap();
+        let before = device.read_configuration_register(0, 1);
+
+        device.write_configuration_register(0, 1, usize::MAX, &[0x00; 2]);
+        device.write_configuration_register(0, 1, usize::MAX - 1, &[0x00; 4]);
+        device.write_configuration_register(0, 1, 0, &[0x00; 8]);
+
+        assert_eq!(device.read_configuration_register(0, 1), before);
+    }
+
+    #[test]
+    fn device_function_identity() {
+        let device = PciDevice::new_dummy_host_bridge(0);
+
+        assert_eq!(device.function_identity(0), Some((0x1D94, 0x1452)));
+        assert_eq!(device.function_identity(1), None);
+    }
+
+    #[test]
+    fn device_function_header() {
+        let mut device = PciDevice::new_dummy_host_bridge(0);
+        device
+            .add_function(PciFunction::new_dummy_host_bridge(1))
+            .unwrap();
+
+        assert_eq!(
+            device.function_header(0),
+            Some(PciFunctionHeader {
+                vendor_id: 0x1D94,
+                device_id: 0x1452,
+                class_code: 0x06_00_00,
+                header_type: 0,
+            })
+        );
+        assert_eq!(device.function_identity(0), Some((0x1D94, 0x1452)));
+        assert_eq!(device.function_header(2), None);
+
+        // The header follows the writes of the VMM.
+        device
+            .get_function(1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_dword(CLASS_CODE_REGISTER, 0x0C03_3000);
+        assert_eq!(device.function_header(1).unwrap().class_code, 0x0C_03_30);
+
+        // The header of a removed function is dropped along with it.
+        device.remove_function(1).unwrap();
+        assert_eq!(device.function_header(1), None);
+    }
+
+    #[test]
+    fn device_function_header_without_lock() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        let device = PciDevice::new_dummy_host_bridge(0);
+        let function = device.get_function(0).unwrap().clone();
+        let (locked_sender, locked) = channel();
+        let (release, release_receiver) = channel::<()>();
+
+        // Another thread holds the function lock while the header is read.
+        let holder = thread::spawn(move || {
+            let _guard = function.lock().unwrap();
+            locked_sender.send(()).unwrap();
+            release_receiver.recv().unwrap();
+        });
+        locked.recv().unwrap();
+        assert_eq!(device.function_identity(0), Some((0x1D94, 0x1452)));
+        assert_eq!(device.function_header(0).unwrap().class_code, 0x06_00_00);
+        assert!(device.get_function(0).unwrap().try_lock().is_err());
+
+        release.send(()).unwrap();
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn device_mapping_events_after_write() {
+        let mut device = PciDevice::new(0);
+        device.add_function(get_function(0)).unwrap();
+        let function = Arc::downgrade(device.get_function(0).unwrap());
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let recorded = commands.clone();
+
+        // The callback reads the function through the shared Arc.
+        device
+            .get_function(0)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .set_mapping_callback(
+                Arc::new(move |event: &PciMappingEvent| {
+                    if let PciMappingEvent::Decode { .. } = event {
+                        let function = function.upgrade().unwrap();
+                        let command = function.lock().unwrap().read_config(1, 0, 2);
+                        recorded.lock().unwrap().push(command);
+                    }
+                }),
+                false,
+            );
+
+        device.write_configuration_register(0, 1, 0, &[0x02]);
+        device.write_configuration_register(0, 1, 0, &[0x00]);
+        assert_eq!(*commands.lock().unwrap(), vec![0x02, 0x00]);
+    }
+
+    #[test]
+    fn device_remove_function_zero() {
+        let mut device = PciDevice::new(0);
+        device.add_function(get_function(0)).unwrap();
+        device.add_function(get_function(1)).unwrap();
+
+        // Function 0 stays while function 1 exists, with its Multi-Function bit set.
+        assert!(device.remove_function(0).is_none());
+        assert!(device.get_function(0).is_some());
+        assert!(device.is_multi_function());
+        let header_type = device
+            .read_configuration_register(0, HEADER_TYPE_REGISTER)
+            .unwrap();
+        assert_ne!(
+            (header_type >> (HEADER_TYPE_OFFSET * 8)) as u8 & HEADER_TYPE_MULTI_FUNCTION,
+            0
+        );
+
+        assert!(device.remove_function(1).is_some());
+        assert!(device.remove_function(0).is_some());
+        assert!(device.get_function(0).is_none());
+    }
+
+    #[test]
+    fn device_remove_function_during_reads() {
+        let device = Arc::new(Mutex::new(PciDevice::new_dummy_host_bridge(0)));
+        device
+            .lock()
+            .unwrap()
+            .add_function(get_function(1))
+            .unwrap();
+
+        // The reader keeps its own reference to the function, like a racing access would.
+        let function = device.lock().unwrap().get_function(1).unwrap().clone();
+        let reader = std::thread::spawn(move || {
+            let mut removed = false;
+            for _ in 0..10_000 {
+                let value = function
+                    .lock()
+                    .unwrap()
+                    .as_function_mut()
+                    .unwrap()
+                    .read_configuration_register(0)
+                    .unwrap();
+
+                // Once the removal started, the function is never seen again.
+                if value == 0xFFFF_FFFF {
+                    removed = true;
+                } else {
+                    assert!(!removed);
+                    assert_eq!(value, 0x1452_1D94);
+                }
+            }
+        });
+
+        let removed = device.lock().unwrap().remove_function(1).unwrap();
+        assert!(!removed.lock().unwrap().is_present());
+        reader.join().unwrap();
+        assert_eq!(
+            removed
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .read_configuration_register(0),
+            Some(0xFFFF_FFFF)
+        );
+
+        // The writes to the removed function are dropped.
+        removed
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_register(15, 0, &[0xAA]);
+        assert_eq!(
+            removed
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .read_configuration_dword(15),
+            Some(0)
         );
+        assert_eq!(
+            device.lock().unwrap().read_configuration_register(1, 0),
+            None
+        );
+    }
+
+    #[test]
+    fn device_bar_overlaps() {
+        let build = |number: usize, address: u32| {
+            let mut function = PciFunctionBuilder::new(
+                number,
+                0x1234,
+                0x5678,
+                PciClassCode::OtherBridgeDevice,
+                PciHeaderType::Type0,
+            )
+            .bars(&[
+                (0, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+                (1, PciBarRegionType::IoRegion, 0x100, false),
+            ])
+            .build()
+            .unwrap();
+            function.write_configuration_dword(4, address);
+            // Both functions use the same IO range, but their IO space is disabled.
+            function.write_configuration_dword(5, 0x1000 | 0x1);
+            function.write_configuration_word(1, 0, 0x2);
+            function
+        };
+
+        let mut device = PciDevice::new(0);
+        device.add_function(build(0, 0xD000_0000)).unwrap();
+        device.add_function(build(1, 0xD000_1000)).unwrap();
+        assert_eq!(device.check_bar_overlaps(), Ok(()));
+
+        device.add_function(build(2, 0xD000_0800)).unwrap();
+        assert_eq!(device.check_bar_overlaps(), Err(vec![(0, 2), (1, 2)]));
+
+        // The BARs which are not decoded can not conflict.
+        device
+            .get_function(2)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_word(1, 0, 0x0);
+        assert_eq!(device.check_bar_overlaps(), Ok(()));
+    }
+
+    #[test]
+    fn device_function_tag() {
+        let mut function = get_function(1);
+        assert_eq!(function.tag(), None);
+        function.set_tag(0xDEAD_BEEF);
+
+        let mut device = PciDevice::new_dummy_host_bridge(0);
+        device.add_function(function).unwrap();
+
+        let function = device.get_function(1).unwrap().lock().unwrap();
+        assert_eq!(function.as_function().unwrap().tag(), Some(0xDEAD_BEEF));
+        assert_eq!(
+            device
+                .get_function(0)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .tag(),
+            None
+        );
+    }
+
+    #[test]
+    fn device_preferred_base_alignment() {
+        let build = |number: usize, bars: &[(usize, PciBarRegionType, u64, bool)]| {
+            PciFunctionBuilder::new(
+                number,
+                0x1234,
+                0x5678,
+                PciClassCode::OtherBridgeDevice,
+                PciHeaderType::Type0,
+            )
+            .bars(bars)
+            .build()
+            .unwrap()
+        };
+
+        let mut device = PciDevice::new(0);
+        assert_eq!(device.preferred_base_alignment(), 0);
+
+        device
+            .add_function(build(
+                0,
+                &[
+                    (0, PciBarRegionType::Memory32BitRegion, 0x10_0000, false),
+                    (1, PciBarRegionType::IoRegion, 0x100, false),
+                ],
+            ))
+            .unwrap();
+        assert_eq!(device.preferred_base_alignment(), 0x10_0000);
+
+        device
+            .add_function(build(
+                1,
+                &[(2, PciBarRegionType::Memory64BitRegion, 0x100_0000, true)],
+            ))
+            .unwrap();
+        assert_eq!(device.preferred_base_alignment(), 0x100_0000);
+    }
+
+    #[test]
+    fn configurable_dispatch() {
+        const SENTINEL: u32 = 0x5EA7_0000;
+
+        struct MockConfigurable {
+            writes: Vec<(usize, usize, Vec<u8>)>,
+        }
+
+        impl PciConfigurable for MockConfigurable {
+            fn read_config(&self, register: usize, _offset: usize, _width: usize) -> u32 {
+                // The register 0 is computed from the number of writes.
+                if register == 0 {
+                    SENTINEL | self.writes.len() as u32
+                } else {
+                    0
+                }
+            }
+
+            fn write_config(&mut self, register: usize, offset: usize, data: &[u8]) {
+                self.writes.push((register, offset, data.to_vec()));
+            }
+        }
+
+        let mut device = PciDevice::new(0);
+        device.add_function(get_function(0)).unwrap();
+        let mock = Arc::new(Mutex::new(MockConfigurable { writes: Vec::new() }));
+        device.add_configurable(1, mock.clone()).unwrap();
+        assert!(device.add_configurable(1, mock.clone()).is_err());
+        assert!(device
+            .add_configurable(MAX_FUNCTION_NUMBER, mock.clone())
+            .is_err());
+        assert_eq!(device.function_present_mask(), 0b11);
+
+        assert!(device.has_function(1));
+        assert_eq!(device.read_configuration_register(1, 0), Some(SENTINEL));
+        device.write_configuration_register(1, 4, 1, &[0xAA, 0xBB]);
+        assert_eq!(device.read_configuration_register(1, 0), Some(SENTINEL | 1));
+        assert_eq!(mock.lock().unwrap().writes, vec![(4, 1, vec![0xAA, 0xBB])]);
+
+        // The invalid accesses are still filtered before the dispatch.
+        device.write_configuration_register(1, 4, 3, &[0xAA, 0xBB]);
+        assert_eq!(mock.lock().unwrap().writes.len(), 1);
+
+        // The built-in functions are dispatched through the same trait.
+        assert_eq!(device.read_configuration_register(0, 0), Some(0x1452_1D94));
+        assert_eq!(
+            device
+                .get_function(0)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .read_config(0, 2, 2),
+            0x1452
+        );
+
+        // The guest probes the other functions, since function 0 has its Multi-Function bit.
+        assert!(device.is_multi_function());
+        let header_type = device
+            .read_configuration_register(0, HEADER_TYPE_REGISTER)
+            .unwrap();
+        assert_ne!(
+            (header_type >> (HEADER_TYPE_OFFSET * 8)) as u8 & HEADER_TYPE_MULTI_FUNCTION,
+            0
+        );
+        let mut functions = Vec::new();
+        device.for_each_function(|number, _| functions.push(number));
+        assert_eq!(functions, vec![0]);
+        assert_eq!(
+            device.function_identity(1),
+            Some(((SENTINEL | 1) as u16, (SENTINEL >> 16) as u16))
+        );
+
+        assert!(device.remove_function(1).is_some());
+        assert!(!device.has_function(1));
+        assert!(!device.is_multi_function());
+        assert_eq!(device.read_configuration_register(1, 0), None);
     }
 }
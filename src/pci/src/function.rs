@@ -1,12 +1,33 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::bar::{
+    PciBarConfiguration, PciBarRegionType, BAR0_REGISTER, BAR_IO_ADDRESS_MASK,
+    BAR_MEMORY_ADDRESS_MASK, NUM_BAR_REGISTERS, NUM_BRIDGE_BAR_REGISTERS,
+};
+use crate::bridge::{BRIDGE_CONTROL_OFFSET, BRIDGE_CONTROL_REGISTER};
+use crate::capability::{
+    describe_capability, DvsecCapability, PciCapability, PciCapabilityId, PciExtendedCapability,
+    PciExtendedCapabilityId, PowerBudgetEntry, PowerBudgetingCapability, ResizableBarCapability,
+    SerialNumberCapability, VpdCapability, RESIZABLE_BAR_MIN_SIZE_SHIFT, RESIZABLE_BAR_SIZE_MASK,
+    RESIZABLE_BAR_SIZE_SHIFT,
+};
+use crate::device::MAX_FUNCTION_NUMBER;
+use devices::{Bus, BusDevice};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use utils::byte_order::{read_le_u16, read_le_u32};
+
 /// The PCIe Configuration Header Space has a length of 64 bytes, so 16 dwords.
 pub const CONFIGURATION_HEADER_SIZE: usize = 16;
 
 /// The PCIe optional registers (device specific) has a length of 192 bytes, so 48 dwords.
 pub const CAPABILITY_REGISTERS_SIZE: usize = 48;
 
+/// The default maximum number of capabilities of a function: each one needs a dword at least.
+pub const MAX_CAPABILITIES: usize = CAPABILITY_REGISTERS_SIZE;
+
 /// The PCIe Extended Configuration Registers Space has a length 3840 bytes, so 960 dwords.
 pub const EXTENDED_CONFIGURATION_SIZE: usize = 960;
 
@@ -23,27 +44,375 @@ pub const DEVICE_ID_OFFSET: usize = 2;
 pub const COMMAND_REGISTER: usize = 1;
 pub const COMMAND_OFFSET: usize = 0;
 
+/// The `IO Space` bit of the Command register.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+
+/// The `Memory Space` bit of the Command register.
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+
+/// The `Interrupt Disable` bit of the Command register, which prevents the function from
+/// asserting its INTx pin.
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// The reserved bits of the Command register, which are hardwired to 0.
+pub const COMMAND_RESERVED: u16 = 0xF800;
+
 pub const STATUS_REGISTER: usize = 1;
 pub const STATUS_OFFSET: usize = 2;
 
+/// The `Interrupt Status` bit of the Status register, set while the function has a pending
+/// legacy interrupt, whatever the `Interrupt Disable` bit of the Command register.
+pub const STATUS_INTERRUPT_STATUS: u16 = 1 << 3;
+
+/// The Expansion ROM Base Address register of a Type 0 header.
+pub const EXPANSION_ROM_REGISTER: usize = 12;
+
+/// The `Expansion ROM Enable` bit of the Expansion ROM Base Address register.
+pub const EXPANSION_ROM_ENABLE: u32 = 1 << 0;
+
+/// The address bits of the Expansion ROM Base Address register.
+pub const EXPANSION_ROM_ADDRESS_MASK: u32 = 0xFFFF_F800;
+
 pub const CLASS_CODE_REGISTER: usize = 2;
 
+/// The byte of the Class Code register holding the Programming Interface.
+pub const PROGRAMMING_INTERFACE_OFFSET: usize = 1;
+
 pub const REVISION_ID_REGISTER: usize = 2;
 pub const REVISION_ID_OFFSET: usize = 0;
 
 pub const HEADER_TYPE_REGISTER: usize = 3;
 pub const HEADER_TYPE_OFFSET: usize = 2;
 
+/// The `Multi-Function` bit of the Header Type register.
+pub const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+pub const BIST_REGISTER: usize = 3;
+pub const BIST_OFFSET: usize = 3;
+
+/// The `BIST Capable` bit of the BIST register.
+pub const BIST_CAPABLE: u8 = 1 << 7;
+
+/// The `Start BIST` bit of the BIST register.
+pub const BIST_START: u8 = 1 << 6;
+
+/// The `Completion Code` bits of the BIST register (0 means the self-test passed).
+pub const BIST_COMPLETION_CODE: u8 = 0x0F;
+
 pub const SUBSYSTEM_ID_REGISTER: usize = 11;
 pub const SUBSYSTEM_ID_OFFSET: usize = 2;
 
 pub const SUBSYSTEM_VENDOR_ID_REGISTER: usize = 11;
 pub const SUBSYSTEM_VENDOR_ID_OFFSET: usize = 0;
 
+pub const CAPABILITIES_POINTER_REGISTER: usize = 13;
+pub const CAPABILITIES_POINTER_OFFSET: usize = 0;
+
+pub const INTERRUPT_LINE_REGISTER: usize = 15;
+pub const INTERRUPT_LINE_OFFSET: usize = 0;
+
+/// The `Min_Gnt` register of a Type 0 header.
+pub const MIN_GNT_REGISTER: usize = 15;
+pub const MIN_GNT_OFFSET: usize = 2;
+
+/// The `Max_Lat` register of a Type 0 header.
+pub const MAX_LAT_REGISTER: usize = 15;
+pub const MAX_LAT_OFFSET: usize = 3;
+
+/// The `MSI-X Enable` bit of the MSI-X Message Control register.
+pub const MSIX_ENABLE: u16 = 1 << 15;
+
+/// The `Function Mask` bit of the MSI-X Message Control register.
+pub const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+/// The `Table Size` bits of the MSI-X Message Control register, encoded as N - 1.
+pub const MSIX_TABLE_SIZE_MASK: u16 = 0x07FF;
+
+/// The `F` flag of the VPD Address register, which drives the VPD handshake.
+const VPD_FLAG: u16 = 1 << 15;
+
+/// The address bits of the VPD Address register.
+const VPD_ADDRESS_MASK: u16 = 0x7FFF;
+
+/// The extended capability list always starts at offset 0x100.
+pub const EXTENDED_CAPABILITIES_REGISTER: usize =
+    CONFIGURATION_HEADER_SIZE + CAPABILITY_REGISTERS_SIZE;
+
+/// The `Capabilities List` bit of the Status register.
+pub const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
 // https://pci-ids.ucw.cz/read/PC/1d94/1452
 pub const VENDOR_ID_DUMMY_HOST_BRIDGE: u16 = 0x1D94;
 pub const DEVICE_ID_DUMMY_HOST_BRIDGE: u16 = 0x1452;
 
+/// Errors for the Pci Function.
+#[derive(Debug, PartialEq)]
+pub enum PciFunctionError {
+    /// There is not enough room left in the capability registers for the capability,
+    /// as (needed, available) dwords.
+    CapabilitySpaceFull(usize, usize),
+    /// There is not enough room left in the extended configuration space for the capability.
+    ExtendedCapabilitySpaceFull,
+    /// Invalid BAR index provided (or the second register of a 64-bit BAR is out of range).
+    InvalidBarIndex(usize),
+    /// The BAR can not describe a region of the provided size.
+    InvalidBarSize(u64),
+    /// Valid BAR index but the register is already used.
+    AlreadyInUseBarSlot(usize),
+    /// The configuration space image has an invalid length (in bytes).
+    InvalidConfigurationSpaceSize(usize),
+    /// Invalid function number provided.
+    InvalidFunctionNumber(usize),
+    /// The region of the BAR overlaps with another device of the system bus.
+    BarHandlerOverlap(usize),
+    /// The capability list loops back to the capability at this register.
+    CapabilityListCycle(usize),
+    /// A Next Capability pointer leads outside the capability area (as a byte offset).
+    InvalidCapabilityPointer(usize),
+    /// The capability list already holds the maximum number of capabilities.
+    TooManyCapabilities(usize),
+    /// The initial value of the Command register sets reserved bits.
+    InvalidCommand(u16),
+}
+
+pub type Result<T> = std::result::Result<T, PciFunctionError>;
+
+/// Errors for the checked writes of the configuration space.
+#[derive(Debug, PartialEq)]
+pub enum ConfigAccessError {
+    /// The register is outside the configuration space.
+    InvalidRegister(usize),
+    /// The access does not fit within the register at this offset.
+    InvalidOffset(usize),
+    /// The write changes read-only bits, as the mask of those bits within the register.
+    ReadOnly(u32),
+}
+
+// The parameters of the 64-bit FNV-1a hash used for the configuration space fingerprints.
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01B3;
+
+/// Return the number of sizes, from 1MB, a Resizable BAR capability can select for a BAR.
+/// A 32-bit BAR describes up to 2GB, a 64-bit BAR up to the 28 sizes of the capability.
+fn resizable_bar_size_count(region_type: PciBarRegionType) -> u32 {
+    match region_type {
+        PciBarRegionType::Memory32BitRegion => 12,
+        _ => 28,
+    }
+}
+
+/// Return the mask of an access of `width` bytes (1 to 4) within a register, before its
+/// shift to `offset`. Return `None` if the width is not supported or if the access does not
+/// fit within the register.
+pub(crate) fn access_mask(offset: usize, width: usize) -> Option<u32> {
+    let mask = match width {
+        1 => 0x0000_00FF,
+        2 => 0x0000_FFFF,
+        3 => 0x00FF_FFFF,
+        4 => 0xFFFF_FFFF,
+        _ => return None,
+    };
+
+    // Make sure to be protected against overflow, even for offsets close to `usize::MAX`.
+    if offset > 4 - width {
+        return None;
+    }
+
+    Some(mask)
+}
+
+/// The dwords of the Type 0 configuration header, so device models do not have to deal with
+/// raw register indices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigRegister {
+    /// `Device ID` and `Vendor ID`.
+    VendorDevice = 0,
+    /// `Status` and `Command`.
+    CommandStatus = 1,
+    /// `Class Code` and `Revision ID`.
+    ClassRevision = 2,
+    /// `BIST`, `Header Type`, `Latency Timer` and `Cache Line Size`.
+    BistHeaderType = 3,
+    Bar0 = 4,
+    Bar1 = 5,
+    Bar2 = 6,
+    Bar3 = 7,
+    Bar4 = 8,
+    Bar5 = 9,
+    /// `Cardbus CIS Pointer`.
+    CardbusCisPointer = 10,
+    /// `Subsystem ID` and `Subsystem Vendor ID`.
+    Subsystem = 11,
+    /// `Expansion ROM Base Address`.
+    ExpansionRom = 12,
+    /// `Capabilities Pointer`.
+    CapabilitiesPointer = 13,
+    /// `Max_Lat`, `Min_Gnt`, `Interrupt Pin` and `Interrupt Line`.
+    Interrupt = 15,
+}
+
+impl ConfigRegister {
+    /// Return the index of the register within the configuration space.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The effect a guest write would have on the configuration space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteOutcome {
+    /// Every bit of the write is applied.
+    Applied,
+    /// Some bits of the write are read-only and left untouched, as the mask of those bits
+    /// within the register.
+    Masked(u32),
+    /// The write is ignored: it is out of bounds, or the function does not accept writes.
+    Dropped,
+}
+
+/// The reasons a guest configuration access is illegal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFaultKind {
+    /// The write changes bits of a read-only register, as the mask of those bits.
+    ReadOnlyWrite(u32),
+    /// The write targets a register beyond the capabilities of the function.
+    UnimplementedRegister,
+}
+
+/// The description of an illegal configuration access of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConfigFault {
+    /// The index of the register.
+    pub register: usize,
+    /// The offset of the access within the register.
+    pub offset: usize,
+    /// The size of the access, in bytes.
+    pub len: usize,
+    /// Why the access is illegal.
+    pub kind: ConfigFaultKind,
+}
+
+/// The storage of the configuration space seen by the guest, by dword register. Without one,
+/// the guest accesses the registers of the function; a backing can forward them elsewhere,
+/// such as to the configuration space of a passed through device.
+pub trait PciConfigBacking: Send {
+    /// Read a register, if it exists.
+    fn read(&self, register: usize) -> Option<u32>;
+
+    /// Write a register. The read-only bits are already merged in the value.
+    fn write(&mut self, register: usize, value: u32);
+}
+
+/// The configuration space of a function, as accessed by the guest. It lets a device model
+/// implemented outside of this crate define its own behavior, such as side effects on writes
+/// or computed reads, and be plugged in a `PciDevice` like a built-in `PciFunction`.
+pub trait PciConfigurable: Send {
+    /// Read `width` bytes (1 to 4) at `offset` within a register, returned in the low bytes.
+    /// The invalid accesses read all ones.
+    fn read_config(&self, register: usize, offset: usize, width: usize) -> u32;
+
+    /// Write the bytes of `data` at `offset` within a register.
+    fn write_config(&mut self, register: usize, offset: usize, data: &[u8]);
+
+    /// Return false if the function must be seen as absent by the guest.
+    fn is_present(&self) -> bool {
+        true
+    }
+
+    /// Reset the configuration space to its initial state, such as during a warm reboot.
+    fn reset(&mut self) {}
+
+    /// Set or clear the Multi-Function bit of the Header Type register, as the other functions
+    /// of the device are added and removed.
+    fn set_multi_function(&mut self, _multi_function: bool) {}
+
+    /// Remove the regions mapped for the function from the system buses, when the VM shuts
+    /// down.
+    fn teardown(&mut self, _mmio_bus: &mut Bus, _pio_bus: &mut Bus) {}
+
+    /// Return the built-in function behind the configuration space, if it is one.
+    fn as_function(&self) -> Option<&PciFunction> {
+        None
+    }
+
+    /// Return the built-in function behind the configuration space, if it is one.
+    fn as_function_mut(&mut self) -> Option<&mut PciFunction> {
+        None
+    }
+}
+
+/// Compute the value of a register read by the guest, from the stored value.
+pub type PciReadCallback = Arc<dyn Fn(u32) -> u32 + Send + Sync>;
+
+/// A change of the regions decoded by a function, caused by a write of the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PciMappingEvent {
+    /// The guest enabled or disabled the decoding of the memory or IO BARs.
+    Decode { memory: bool, io: bool },
+    /// The Expansion ROM region got mapped or unmapped, at its programmed address.
+    Rom { mapped: bool, address: u64 },
+    /// The regions of the enabled BARs have to be registered again.
+    Bars(Vec<PciBarConfiguration>),
+}
+
+/// React to the changes of the regions decoded by a function.
+pub type PciMappingCallback = Arc<dyn Fn(&PciMappingEvent) + Send + Sync>;
+
+/// The mapping events of a function, taken with its lock held and fired once it is released,
+/// so the callback can access the function.
+pub struct PciMappingNotifications {
+    callback: Option<PciMappingCallback>,
+    events: Vec<PciMappingEvent>,
+}
+
+impl PciMappingNotifications {
+    /// Call the mapping callback with every event, in the order they happened.
+    pub fn fire(self) {
+        if let Some(callback) = self.callback {
+            for event in self.events.iter() {
+                callback(event);
+            }
+        }
+    }
+}
+
+/// Report an illegal configuration access of the guest.
+pub type PciConfigFaultCallback = Arc<dyn Fn(&ConfigFault) + Send + Sync>;
+
+/// The device handling the accesses to the region of a BAR.
+pub type PciBarHandler = Arc<Mutex<dyn BusDevice>>;
+
+/// The fields of the configuration header which identify a function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PciFunctionHeader {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// The class, subclass and programming interface, as the upper 24 bits of the register.
+    pub class_code: u32,
+    /// The layout of the header, without the Multi-Function bit.
+    pub header_type: u8,
+}
+
+impl PciFunctionHeader {
+    /// Pack the fields in a single value, so they are shared without tearing.
+    pub(crate) fn to_bits(self) -> u64 {
+        u64::from(self.vendor_id)
+            | u64::from(self.device_id) << 16
+            | u64::from(self.class_code & 0xFF_FFFF) << 32
+            | u64::from(self.header_type) << 56
+    }
+
+    /// Unpack the fields packed by `to_bits`.
+    pub(crate) fn from_bits(bits: u64) -> Self {
+        PciFunctionHeader {
+            vendor_id: bits as u16,
+            device_id: (bits >> 16) as u16,
+            class_code: (bits >> 32) as u32 & 0xFF_FFFF,
+            header_type: (bits >> 56) as u8,
+        }
+    }
+}
+
 /// Type 0 is required for every Function, except for the Bridge Functions.
 /// Type 1 is required for the Bridge (Switch) Functions.
 #[derive(Clone, Copy)]
@@ -60,12 +429,22 @@ pub enum PciHeaderType {
 /// More information at:
 /// https://pcisig.com/sites/default/files/files/PCI_Code-ID_r_1_11__v24_Jan_2019.pdf
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[repr(u32)]
 pub enum PciClassCode {
     // Base Class - 0x00 (Unclassified Devices).
     AllImplementedExceptVGACompatible = 0x00_00_00_00,
     VGAComptabile = 0x00_01_00_00,
 
+    // Base Class - 0x01 (Mass Storage Controllers).
+    IdeController = 0x01_01_00_00,
+    SerialAtaController = 0x01_06_00_00,
+    NonVolatileMemoryController = 0x01_08_00_00,
+    OtherMassStorageController = 0x01_80_00_00,
+
+    // Base Class - 0x02 (Network Controllers).
+    EthernetController = 0x02_00_00_00,
+
     // Base Class - 0x06 (Bridge Devices).
     HostBridge = 0x06_00_00_00,
     IsaBridge = 0x06_01_00_00,
@@ -84,6 +463,9 @@ pub enum PciClassCode {
     AdvancedSwitchingToPciHostAsiSig = 0x06_0B_01_00,
     OtherBridgeDevice = 0x06_80_00_00,
 
+    // Base Class - 0x07 (Simple Communication Controllers).
+    OtherCommunicationController = 0x07_80_00_00,
+
     // Base Class - 0x09 (Input Devices).
     KeyboardController = 0x09_00_00_00,
     DigitizerPen = 0x09_01_00_00,
@@ -92,6 +474,12 @@ pub enum PciClassCode {
     GameportControllerGeneric = 0x09_04_00_00,
     GameportController = 0x09_04_10_00,
     OtherInputController = 0x09_80_00_00,
+
+    // Base Class - 0x0C (Serial Bus Controllers).
+    UsbController = 0x0C_03_00_00,
+
+    // Base Class - 0xFF (Unassigned Class).
+    UnassignedClass = 0xFF_00_00_00,
 }
 
 impl PciClassCode {
@@ -100,6 +488,159 @@ impl PciClassCode {
     }
 }
 
+/// The Programming Interface of a function, which tells how to drive a function of a given
+/// class and subclass (e.g. AHCI for a Serial ATA controller).
+pub trait PciProgrammingInterface {
+    /// Return the value of the `Programming Interface` byte of the class code.
+    fn get_register_value(&self) -> u8;
+}
+
+/// The Base Class codes, which tell the broad category of a function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciBaseClass {
+    Unclassified = 0x00,
+    MassStorageController = 0x01,
+    NetworkController = 0x02,
+    DisplayController = 0x03,
+    MultimediaController = 0x04,
+    MemoryController = 0x05,
+    BridgeDevice = 0x06,
+    SimpleCommunicationController = 0x07,
+    BaseSystemPeripheral = 0x08,
+    InputDevice = 0x09,
+    DockingStation = 0x0A,
+    Processor = 0x0B,
+    SerialBusController = 0x0C,
+    WirelessController = 0x0D,
+    IntelligentIoController = 0x0E,
+    SatelliteCommunicationController = 0x0F,
+    EncryptionController = 0x10,
+    SignalProcessingController = 0x11,
+    ProcessingAccelerator = 0x12,
+    NonEssentialInstrumentation = 0x13,
+    Coprocessor = 0x40,
+    UnassignedClass = 0xFF,
+}
+
+/// Build a PCI function step by step, declaring its Base Address Registers up front.
+pub struct PciFunctionBuilder {
+    number: usize,
+    device_id: u16,
+    vendor_id: u16,
+    class_code: PciClassCode,
+    revision_id: u8,
+    header_type: PciHeaderType,
+    subsystem_id: u16,
+    subsystem_vendor_id: u16,
+    bars: Vec<(usize, PciBarRegionType, u64, bool)>,
+    max_capabilities: usize,
+    command: u16,
+    programming_interface: Option<u8>,
+}
+
+impl PciFunctionBuilder {
+    /// Start building a PCI function with the mandatory identification fields.
+    pub fn new(
+        number: usize,
+        device_id: u16,
+        vendor_id: u16,
+        class_code: PciClassCode,
+        header_type: PciHeaderType,
+    ) -> Self {
+        PciFunctionBuilder {
+            number,
+            device_id,
+            vendor_id,
+            class_code,
+            revision_id: 0,
+            header_type,
+            subsystem_id: 0,
+            subsystem_vendor_id: 0,
+            bars: Vec::new(),
+            max_capabilities: MAX_CAPABILITIES,
+            command: 0,
+            programming_interface: None,
+        }
+    }
+
+    /// Set the Revision ID of the function.
+    pub fn revision_id(mut self, revision_id: u8) -> Self {
+        self.revision_id = revision_id;
+        self
+    }
+
+    /// Set the Subsystem ID and the Subsystem Vendor ID of the function (Type 0 only).
+    pub fn subsystem(mut self, subsystem_id: u16, subsystem_vendor_id: u16) -> Self {
+        self.subsystem_id = subsystem_id;
+        self.subsystem_vendor_id = subsystem_vendor_id;
+        self
+    }
+
+    /// Limit the number of capabilities of the function (`MAX_CAPABILITIES` by default).
+    pub fn max_capabilities(mut self, max_capabilities: usize) -> Self {
+        self.max_capabilities = max_capabilities;
+        self
+    }
+
+    /// Set the value of the Command register at power-on (0 by default, so nothing is decoded),
+    /// such as the Memory Space bit of a boot device. The reserved bits can not be set.
+    pub fn command(mut self, command: u16) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Set the Programming Interface of the function, which is otherwise the one of its class
+    /// code. The base class and the subclass are kept.
+    pub fn programming_interface(
+        mut self,
+        programming_interface: &dyn PciProgrammingInterface,
+    ) -> Self {
+        self.programming_interface = Some(programming_interface.get_register_value());
+        self
+    }
+
+    /// Declare the Base Address Registers of the function.
+    /// * `bars` - A list of (index, region type, size, prefetchable) tuples.
+    pub fn bars(mut self, bars: &[(usize, PciBarRegionType, u64, bool)]) -> Self {
+        self.bars.extend_from_slice(bars);
+        self
+    }
+
+    /// Create the PCI function.
+    pub fn build(self) -> Result<PciFunction> {
+        if self.command & COMMAND_RESERVED != 0 {
+            return Err(PciFunctionError::InvalidCommand(self.command));
+        }
+
+        let mut function = PciFunction::new(
+            self.number,
+            self.device_id,
+            self.vendor_id,
+            self.class_code,
+            self.revision_id,
+            self.header_type,
+            self.subsystem_id,
+            self.subsystem_vendor_id,
+        );
+
+        if let Some(programming_interface) = self.programming_interface {
+            function.write_configuration_byte(
+                CLASS_CODE_REGISTER,
+                PROGRAMMING_INTERFACE_OFFSET,
+                programming_interface,
+            );
+        }
+
+        for (index, region_type, size, prefetchable) in self.bars {
+            function.set_bar(index, region_type, size, prefetchable)?;
+        }
+        function.set_max_capabilities(self.max_capabilities);
+        function.write_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET, self.command);
+
+        Ok(function)
+    }
+}
+
 /// Functions are designed into every Device.
 /// These Functions may include hard drive interfaces, display controllers, etc.
 /// Each Function has its own configuration address space which size is 256 bytes (in PCI).
@@ -107,13 +648,93 @@ impl PciClassCode {
 #[allow(dead_code)]
 pub struct PciFunction {
     /// The number of the function within the device.
-    number: usize,
+    pub(crate) number: usize,
 
     /// The PCIe Configuration Space. It has 1024 dwords (so 4KB) and contains:
     /// - `PCI Configuration Header` - 16 dwords.
     /// - `PCI Device-specific & New Capability registers` - 48 dwords.
     /// - `PCIe Extended Configuration Register Space` - 960 dwords.
-    configuration_space: Vec<u32>,
+    pub(crate) configuration_space: Vec<u32>,
+
+    /// The first free register within the capability registers.
+    pub(crate) next_capability_register: usize,
+
+    /// The register of the last capability added in the capability list.
+    pub(crate) last_capability_register: Option<usize>,
+
+    /// The first free register within the extended configuration space.
+    pub(crate) next_extended_capability_register: usize,
+
+    /// The register of the last capability added in the extended capability list.
+    pub(crate) last_extended_capability_register: Option<usize>,
+
+    /// The bits of each register which can be changed by the guest.
+    pub(crate) write_masks: Vec<u32>,
+
+    /// The Base Address Registers declared for this function.
+    pub(crate) bars: [Option<PciBarConfiguration>; NUM_BAR_REGISTERS],
+
+    /// The register of the Vital Product Data capability and the data it exposes.
+    pub(crate) vpd: Option<(usize, Vec<u8>)>,
+
+    /// The register of the Power Budgeting capability and the Data register of each entry.
+    pub(crate) power_budget: Option<(usize, Vec<u32>)>,
+
+    /// The registers whose value is computed when the guest reads them.
+    pub(crate) read_callbacks: BTreeMap<usize, PciReadCallback>,
+
+    /// The devices handling the accesses to the BAR regions, by BAR index, along with the
+    /// region (address, size) they are registered at on the system bus.
+    pub(crate) bar_handlers: BTreeMap<usize, (PciBarHandler, Option<(u64, u64)>)>,
+
+    /// Whether the guest can access the function. It is cleared when the function is removed.
+    pub(crate) present: bool,
+
+    /// Whether the guest writes are applied. The writes of the VMM always are.
+    pub(crate) guest_writable: bool,
+
+    /// The storage of the guest accesses, instead of the configuration space, if any.
+    pub(crate) backing: Option<Box<dyn PciConfigBacking>>,
+
+    /// An opaque ID chosen by the owner of the function, to find its device model back.
+    pub(crate) user_tag: Option<u64>,
+
+    /// Notified of the changes of the regions decoded by the function.
+    pub(crate) mapping_callback: Option<PciMappingCallback>,
+
+    /// The mapping events not fired yet.
+    pub(crate) mapping_events: Vec<PciMappingEvent>,
+
+    /// Whether the BAR writes are staged until the memory decoding is enabled or committed.
+    pub(crate) defer_bar_registration: bool,
+
+    /// Whether BAR writes are staged and not yet registered.
+    pub(crate) pending_bar_writes: bool,
+
+    /// Notified of the illegal accesses of the guest.
+    pub(crate) fault_callback: Option<PciConfigFaultCallback>,
+
+    /// The MSI-X vectors whose Mask bit is cleared in the MSI-X table. The table lives in a
+    /// BAR, so the device model handling it reports the masks. The vectors start masked.
+    pub(crate) msix_unmasked_vectors: BTreeSet<u16>,
+
+    /// The maximum number of capabilities in the capability list.
+    pub(crate) max_capabilities: usize,
+
+    /// The size of the Expansion ROM region, or 0 if the function has no Expansion ROM.
+    pub(crate) rom_size: u32,
+
+    /// Whether the saved state of the function changed since it was last saved.
+    pub(crate) dirty: AtomicBool,
+
+    /// A copy of the identification fields of the header, packed by
+    /// `PciFunctionHeader::to_bits`. It is shared with the device, which reads it without
+    /// locking the function.
+    pub(crate) shared_header: Arc<AtomicU64>,
+
+    /// The configuration space restored by `reset`, captured before the first write of the
+    /// guest. The later writes of the VMM are applied to it as well.
+    pub(crate) defaults: Option<Vec<u32>>,
 }
 
 impl PciFunction {
@@ -131,13 +752,38 @@ impl PciFunction {
         let mut function = PciFunction {
             number,
             configuration_space: vec![0; CONFIGURATION_SPACE_SIZE],
+            next_capability_register: CONFIGURATION_HEADER_SIZE,
+            last_capability_register: None,
+            next_extended_capability_register: EXTENDED_CAPABILITIES_REGISTER,
+            last_extended_capability_register: None,
+            write_masks: vec![0xFFFF_FFFF; CONFIGURATION_SPACE_SIZE],
+            bars: [None; NUM_BAR_REGISTERS],
+            vpd: None,
+            power_budget: None,
+            read_callbacks: BTreeMap::new(),
+            bar_handlers: BTreeMap::new(),
+            present: true,
+            guest_writable: true,
+            backing: None,
+            user_tag: None,
+            mapping_callback: None,
+            mapping_events: Vec::new(),
+            defer_bar_registration: false,
+            pending_bar_writes: false,
+            fault_callback: None,
+            msix_unmasked_vectors: BTreeSet::new(),
+            max_capabilities: MAX_CAPABILITIES,
+            rom_size: 0,
+            dirty: AtomicBool::new(true),
+            shared_header: Arc::new(AtomicU64::new(0)),
+            defaults: None,
         };
 
         function.write_configuration_word(DEVICE_ID_REGISTER, DEVICE_ID_OFFSET, device_id);
         function.write_configuration_word(VENDOR_ID_REGISTER, VENDOR_ID_OFFSET, vendor_id);
 
-        function.write_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET, 0xFFFF);
-        function.write_configuration_word(STATUS_REGISTER, STATUS_OFFSET, 0xFFFF);
+        function.write_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET, 0x0000);
+        function.write_configuration_word(STATUS_REGISTER, STATUS_OFFSET, !STATUS_INTERRUPT_STATUS);
 
         function.write_configuration_dword(CLASS_CODE_REGISTER, class_code.get_register_value());
         function.write_configuration_byte(REVISION_ID_REGISTER, REVISION_ID_OFFSET, revision_id);
@@ -163,6 +809,7 @@ impl PciFunction {
                 function.write_configuration_byte(HEADER_TYPE_REGISTER, HEADER_TYPE_OFFSET, 0x01);
             }
         }
+        function.update_write_masks();
 
         function
     }
@@ -170,10 +817,22 @@ impl PciFunction {
     /// Create a dummy PCI Host Bridge function.
     /// - `number` - the number of the function.
     pub fn new_dummy_host_bridge(number: usize) -> PciFunction {
-        PciFunction::new(
+        PciFunction::new_host_bridge(
             number,
             DEVICE_ID_DUMMY_HOST_BRIDGE,
             VENDOR_ID_DUMMY_HOST_BRIDGE,
+        )
+    }
+
+    /// Create a PCI Host Bridge function with the given IDs, e.g. to mimic a known chipset.
+    /// - `number` - the number of the function.
+    /// - `device_id` - the Device ID of the host bridge.
+    /// - `vendor_id` - the Vendor ID of the host bridge.
+    pub fn new_host_bridge(number: usize, device_id: u16, vendor_id: u16) -> PciFunction {
+        PciFunction::new(
+            number,
+            device_id,
+            vendor_id,
             PciClassCode::HostBridge,
             0,
             PciHeaderType::Type0,
@@ -187,6 +846,105 @@ impl PciFunction {
         self.number
     }
 
+    /// Change the number of this function, before adding it to a device.
+    /// * `number` - The new number of the function.
+    pub fn set_number(&mut self, number: usize) -> Result<()> {
+        if number >= MAX_FUNCTION_NUMBER {
+            return Err(PciFunctionError::InvalidFunctionNumber(number));
+        }
+
+        self.number = number;
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Return the function with another number, such as a copy of a template function.
+    /// * `number` - The new number of the function within the device.
+    pub fn with_number(mut self, number: usize) -> Result<Self> {
+        self.set_number(number)?;
+        Ok(self)
+    }
+
+    /// Tag the function with an opaque ID, so the owner can correlate the configuration
+    /// accesses with its own device model without a table indexed by the function address.
+    /// * `tag` - The ID chosen by the owner.
+    pub fn set_tag(&mut self, tag: u64) {
+        self.user_tag = Some(tag);
+    }
+
+    /// Return the opaque ID the function was tagged with, if any.
+    pub fn tag(&self) -> Option<u64> {
+        self.user_tag
+    }
+
+    /// Return the identity of this function, as (vendor ID, device ID).
+    pub fn identity(&self) -> (u16, u16) {
+        let register = self.configuration_space[VENDOR_ID_REGISTER];
+
+        (register as u16, (register >> 16) as u16)
+    }
+
+    /// Return the subsystem of this function, as (subsystem ID, subsystem vendor ID).
+    /// Bridges have no subsystem fields in their Type 1 header.
+    pub fn subsystem(&self) -> Option<(u16, u16)> {
+        if self.is_bridge() {
+            return None;
+        }
+
+        Some((
+            self.read_configuration_word(SUBSYSTEM_ID_REGISTER, SUBSYSTEM_ID_OFFSET)?,
+            self.read_configuration_word(SUBSYSTEM_VENDOR_ID_REGISTER, SUBSYSTEM_VENDOR_ID_OFFSET)?,
+        ))
+    }
+
+    /// Return the fields of the configuration header which identify the function.
+    pub fn header(&self) -> PciFunctionHeader {
+        let (vendor_id, device_id) = self.identity();
+
+        PciFunctionHeader {
+            vendor_id,
+            device_id,
+            class_code: self.configuration_space[CLASS_CODE_REGISTER] >> 8,
+            header_type: self.configuration_space[HEADER_TYPE_REGISTER].to_le_bytes()
+                [HEADER_TYPE_OFFSET]
+                & !HEADER_TYPE_MULTI_FUNCTION,
+        }
+    }
+
+    /// Return true if the Base Class code of the function matches.
+    /// * `base` - The Base Class code.
+    pub fn is_base_class(&self, base: PciBaseClass) -> bool {
+        self.header().class_code >> 16 == base as u32
+    }
+
+    /// Return a fingerprint of the configuration space, so identical functions can be
+    /// detected cheaply. It is a 64-bit FNV-1a hash of the registers, which does not depend
+    /// on the process or the toolchain, so it stays the same across runs.
+    pub fn config_fingerprint(&self) -> u64 {
+        self.configuration_space
+            .iter()
+            .flat_map(|register| register.to_le_bytes().to_vec())
+            .fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Set or clear the Multi-Function bit of the Header Type register.
+    /// * `multi_function` - Whether the device of this function implements more functions.
+    pub fn set_multi_function(&mut self, multi_function: bool) {
+        let header_type = self
+            .read_configuration_byte(HEADER_TYPE_REGISTER, HEADER_TYPE_OFFSET)
+            .unwrap_or(0);
+
+        let header_type = if multi_function {
+            header_type | HEADER_TYPE_MULTI_FUNCTION
+        } else {
+            header_type & !HEADER_TYPE_MULTI_FUNCTION
+        };
+
+        self.write_configuration_byte(HEADER_TYPE_REGISTER, HEADER_TYPE_OFFSET, header_type);
+    }
+
     /// Read a byte from the configuration space.
     /// * `register` - The index of the register within the given space.
     /// * `offset` - The offset within the register. It is in range 0-3 (byte align).
@@ -227,6 +985,175 @@ impl PciFunction {
         }
     }
 
+    /// Read 1 to 4 bytes from the configuration space, depending on the width.
+    /// Return `None` if the width is not supported, or if the access does not fit within the
+    /// register.
+    /// * `register` - The index of the register within the given space.
+    /// * `offset` - The offset within the register.
+    /// * `width` - The number of bytes to read.
+    pub fn read_configuration(&self, register: usize, offset: usize, width: usize) -> Option<u32> {
+        let mask = access_mask(offset, width)?;
+
+        match width {
+            1 => self
+                .read_configuration_byte(register, offset)
+                .map(u32::from),
+            2 => self
+                .read_configuration_word(register, offset)
+                .map(u32::from),
+            _ => self
+                .read_configuration_dword(register)
+                .map(|value| (value >> (offset * 8)) & mask),
+        }
+    }
+
+    /// Read the whole configuration header, as stored (the read callbacks are not applied).
+    pub fn read_header(&self) -> [u32; CONFIGURATION_HEADER_SIZE] {
+        let mut header = [0u32; CONFIGURATION_HEADER_SIZE];
+        header.copy_from_slice(&self.configuration_space[..CONFIGURATION_HEADER_SIZE]);
+
+        header
+    }
+
+    /// Return a copy of the whole configuration space, as stored (the read callbacks are not
+    /// applied). The copy can be inspected after releasing the lock of the function, without
+    /// contending with the accesses of the guest.
+    pub fn config_copy(&self) -> [u32; CONFIGURATION_SPACE_SIZE] {
+        let mut copy = [0u32; CONFIGURATION_SPACE_SIZE];
+        copy.copy_from_slice(&self.configuration_space);
+
+        copy
+    }
+
+    /// Read a range of registers, as stored (the read callbacks are not applied).
+    /// Return `None` if the range does not fit in the configuration space.
+    /// * `start` - The index of the first register.
+    /// * `len` - The number of registers.
+    pub fn read_config_range(&self, start: usize, len: usize) -> Option<Vec<u32>> {
+        let end = start.checked_add(len)?;
+
+        self.configuration_space
+            .get(start..end)
+            .map(<[u32]>::to_vec)
+    }
+
+    /// Read a register of the configuration header.
+    /// * `register` - The register to be read.
+    pub fn read_register(&self, register: ConfigRegister) -> u32 {
+        self.configuration_space[register.index()]
+    }
+
+    /// Write a register of the configuration header.
+    /// * `register` - The register to be written.
+    /// * `data` - The dword to be written.
+    pub fn write_register(&mut self, register: ConfigRegister, data: u32) {
+        self.configuration_space[register.index()] = data;
+        self.mark_dirty();
+    }
+
+    /// Read a register the way the guest does, computing its value if the device model
+    /// registered a read callback for it.
+    /// * `register` - The index of the register within the given space.
+    pub fn read_configuration_register(&self, register: usize) -> Option<u32> {
+        let value = match self.backing.as_ref() {
+            Some(backing) => backing.read(register)?,
+            None => self.read_configuration_dword(register)?,
+        };
+
+        // A function being removed only returns all ones, like a surprise removed device.
+        if !self.present {
+            return Some(0xFFFF_FFFF);
+        }
+
+        match self.read_callbacks.get(&register) {
+            Some(callback) => Some(callback(value)),
+            None => Some(value),
+        }
+    }
+
+    /// Return true if a register holds something: the configuration header always does, the
+    /// capability registers and the extended configuration space only up to the end of their
+    /// last capability. The other registers are free space.
+    /// * `register` - The index of the register within the configuration space.
+    pub fn is_register_implemented(&self, register: usize) -> bool {
+        if register < CONFIGURATION_HEADER_SIZE {
+            true
+        } else if register < EXTENDED_CAPABILITIES_REGISTER {
+            register < self.next_capability_register
+        } else {
+            register < self.next_extended_capability_register
+        }
+    }
+
+    /// Return true if the saved state of the function changed since it was last saved (or if
+    /// it was never saved), so the incremental snapshots can skip the unmodified functions.
+    pub fn is_dirty_since_snapshot(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Record that the saved state of the function was captured.
+    pub(crate) fn clear_dirty(&self) {
+        self.dirty.store(false, Ordering::Release);
+    }
+
+    /// Record a change of the saved state of the function. Every change of the configuration
+    /// space goes through here, so the shared copy of the header is refreshed as well.
+    fn mark_dirty(&mut self) {
+        *self.dirty.get_mut() = true;
+        self.shared_header
+            .store(self.header().to_bits(), Ordering::Release);
+    }
+
+    /// Set whether the guest can access the function. The reads of an absent function return
+    /// all ones and the writes are dropped, whatever its configuration space contains.
+    /// * `present` - Whether the function is accessible.
+    pub fn set_present(&mut self, present: bool) {
+        self.present = present;
+        self.mark_dirty();
+    }
+
+    /// Return true if the guest can access the function.
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Forward the guest accesses to another backing than the configuration space of the
+    /// function. The write masks and the read callbacks still apply, but the side effects
+    /// emulated for the registers are left to the backing.
+    /// * `backing` - The storage of the guest accesses, or `None` for the configuration space.
+    pub fn set_config_backing(&mut self, backing: Option<Box<dyn PciConfigBacking>>) {
+        self.backing = backing;
+    }
+
+    /// Set whether the guest can write the configuration space. When it can not, the writes
+    /// made the way the guest does (`write_configuration_register` and `write_bytes`) are
+    /// dropped, while the direct writes of the VMM (`write_configuration_byte`, `_word`,
+    /// `_dword` and `write_register`) still apply.
+    /// * `writable` - Whether the guest writes are applied.
+    pub fn set_guest_writable(&mut self, writable: bool) {
+        self.guest_writable = writable;
+        self.mark_dirty();
+    }
+
+    /// Return true if the guest writes to the configuration space are applied.
+    pub fn is_guest_writable(&self) -> bool {
+        self.guest_writable
+    }
+
+    /// Compute the value of a register each time the guest reads it.
+    /// The callback receives the stored value of the register.
+    /// * `register` - The index of the register within the given space.
+    /// * `callback` - The function computing the value of the register.
+    pub fn set_read_callback(&mut self, register: usize, callback: PciReadCallback) {
+        self.read_callbacks.insert(register, callback);
+    }
+
+    /// Return to reading the stored value of a register.
+    /// * `register` - The index of the register within the given space.
+    pub fn clear_read_callback(&mut self, register: usize) {
+        self.read_callbacks.remove(&register);
+    }
+
     /// Write a byte to the configuration space.
     /// * `register` - The index of the register within the given space.
     /// * `offset` - The offset within the register. It is in range 0-3 (byte align).
@@ -236,10 +1163,13 @@ impl PciFunction {
             return;
         }
 
-        if let Some(register) = self.configuration_space.get_mut(register) {
+        if let Some(value) = self.configuration_space.get_mut(register) {
             // Clean the old value and write the new one.
-            *register &= !(0xFF << (offset * 8));
-            *register |= (data as u32) << (offset * 8);
+            *value &= !(0xFF << (offset * 8));
+            *value |= (data as u32) << (offset * 8);
+            let value = *value;
+            self.update_default(register, value);
+            self.mark_dirty();
         }
     }
 
@@ -252,10 +1182,13 @@ impl PciFunction {
             return;
         }
 
-        if let Some(register) = self.configuration_space.get_mut(register) {
+        if let Some(value) = self.configuration_space.get_mut(register) {
             // Clean the old value and write the new one.
-            *register &= !(0xFFFF << (offset * 8));
-            *register |= (data as u32) << (offset * 8);
+            *value &= !(0xFFFF << (offset * 8));
+            *value |= (data as u32) << (offset * 8);
+            let value = *value;
+            self.update_default(register, value);
+            self.mark_dirty();
         }
     }
 
@@ -263,117 +1196,3503 @@ impl PciFunction {
     /// * `register` - The index of the register within the given space.
     /// * `data` - The dword to be written.
     pub fn write_configuration_dword(&mut self, register: usize, data: u32) {
-        if let Some(register) = self.configuration_space.get_mut(register) {
-            *register = data;
+        if let Some(value) = self.configuration_space.get_mut(register) {
+            *value = data;
+            self.update_default(register, data);
+            self.mark_dirty();
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{PciFunction, CLASS_CODE_REGISTER, CONFIGURATION_SPACE_SIZE};
-    use utils::rand::xor_rng_u32;
+    /// Apply a write of the VMM to the defaults restored by `reset`, once they are captured.
+    fn update_default(&mut self, register: usize, value: u32) {
+        if let Some(defaults) = self.defaults.as_mut() {
+            defaults[register] = value;
+        }
+    }
+
+    /// Update the bits of a register which follow the state of the function at runtime, such
+    /// as the completion of an access started by the guest. Unlike the writes of the VMM, they
+    /// are not kept by `reset`.
+    /// * `register` - The index of the register within the given space.
+    /// * `mask` - The bits of the register to update.
+    /// * `value` - The new value of the bits.
+    fn update_state_bits(&mut self, register: usize, mask: u32, value: u32) {
+        if let Some(old) = self.configuration_space.get_mut(register) {
+            *old = (*old & !mask) | (value & mask);
+            self.mark_dirty();
+        }
+    }
+
+    /// Reset the function to its state before the first write of the guest, such as during a
+    /// warm reboot of the VM, without building it again. The configuration done by the VMM
+    /// is kept, while the BAR addresses, the Command register and the other registers
+    /// programmed by the guest get back their initial values.
+    pub fn reset(&mut self) {
+        let rom_was_mapped = self.is_rom_mapped();
+        let old_command = self.configuration_space[COMMAND_REGISTER];
+
+        if let Some(defaults) = self.defaults.take() {
+            self.configuration_space = defaults;
+        }
+        // The BARs resized by the guest get back the size selected in the restored capability.
+        for register in self.resizable_bar_capabilities() {
+            self.run_bar_resize(register);
+        }
+        self.update_write_masks();
+        self.msix_unmasked_vectors.clear();
+        self.pending_bar_writes = false;
+        self.mark_dirty();
+
+        self.notify_rom_mapping(rom_was_mapped);
+        self.notify_decode_change(old_command);
+    }
+
+    /// Write 1 to 4 bytes to the configuration space, depending on the width. Only the bytes
+    /// covered by the access are changed.
+    /// The write is dropped if the width is not supported, or if the access does not fit within
+    /// the register.
+    /// * `register` - The index of the register within the given space.
+    /// * `offset` - The offset within the register.
+    /// * `width` - The number of bytes to write.
+    /// * `data` - The value to be written, in its low bytes.
+    pub fn write_configuration(&mut self, register: usize, offset: usize, width: usize, data: u32) {
+        let mask = match access_mask(offset, width) {
+            Some(mask) => mask,
+            None => return,
+        };
+
+        match width {
+            1 => self.write_configuration_byte(register, offset, data as u8),
+            2 => self.write_configuration_word(register, offset, data as u16),
+            _ => {
+                if let Some(old) = self.read_configuration_dword(register) {
+                    let mask = mask << (offset * 8);
+                    self.write_configuration_dword(
+                        register,
+                        (old & !mask) | ((data << (offset * 8)) & mask),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Write a byte to the configuration space, failing instead of dropping the write if it is
+    /// invalid or changes read-only bits. Nothing is written on failure.
+    /// * `register` - The index of the register within the given space.
+    /// * `offset` - The offset within the register. It is in range 0-3 (byte align).
+    /// * `data` - The byte to be written.
+    pub fn try_write_configuration_byte(
+        &mut self,
+        register: usize,
+        offset: usize,
+        data: u8,
+    ) -> std::result::Result<(), ConfigAccessError> {
+        self.try_write_configuration(register, offset, 0xFF, u32::from(data))
+    }
+
+    /// Write a word to the configuration space, failing instead of dropping the write if it is
+    /// invalid or changes read-only bits. Nothing is written on failure.
+    /// * `register` - The index of the register within the given space.
+    /// * `offset` - The offset within the register. It is in range 0-2 (byte align).
+    /// * `data` - The word to be written.
+    pub fn try_write_configuration_word(
+        &mut self,
+        register: usize,
+        offset: usize,
+        data: u16,
+    ) -> std::result::Result<(), ConfigAccessError> {
+        self.try_write_configuration(register, offset, 0xFFFF, u32::from(data))
+    }
+
+    /// Write a dword to the configuration space, failing instead of dropping the write if it
+    /// is invalid or changes read-only bits. Nothing is written on failure.
+    /// * `register` - The index of the register within the given space.
+    /// * `data` - The dword to be written.
+    pub fn try_write_configuration_dword(
+        &mut self,
+        register: usize,
+        data: u32,
+    ) -> std::result::Result<(), ConfigAccessError> {
+        self.try_write_configuration(register, 0, 0xFFFF_FFFF, data)
+    }
+
+    /// Write the bits of a register selected by the mask shifted at the offset. The read-only
+    /// bits may be written with their current value.
+    fn try_write_configuration(
+        &mut self,
+        register: usize,
+        offset: usize,
+        mask: u32,
+        data: u32,
+    ) -> std::result::Result<(), ConfigAccessError> {
+        let old = match self.configuration_space.get(register) {
+            Some(old) => *old,
+            None => return Err(ConfigAccessError::InvalidRegister(register)),
+        };
+        if offset > 3 || (mask << (offset * 8)) >> (offset * 8) != mask {
+            return Err(ConfigAccessError::InvalidOffset(offset));
+        }
+
+        let mask = mask << (offset * 8);
+        let value = (old & !mask) | ((data << (offset * 8)) & mask);
+        let read_only = (old ^ value) & !self.write_masks[register];
+        if read_only != 0 {
+            return Err(ConfigAccessError::ReadOnly(read_only));
+        }
+
+        self.configuration_space[register] = value;
+        self.update_default(register, value);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Report what a guest write of this shape would do, without writing anything. This tells
+    /// why a write of the guest did not take.
+    /// * `register` - The index of the register within the given space.
+    /// * `offset` - The offset within the register.
+    /// * `width` - The size of the write (1 to 4 bytes).
+    pub fn would_write_apply(&self, register: usize, offset: usize, width: usize) -> WriteOutcome {
+        let mask = match access_mask(offset, width) {
+            Some(mask) => mask,
+            None => return WriteOutcome::Dropped,
+        };
+
+        if !self.present || !self.guest_writable {
+            return WriteOutcome::Dropped;
+        }
+
+        match self.write_masks.get(register) {
+            Some(writable) => {
+                let read_only = (mask << (offset * 8)) & !writable;
+                if read_only == 0 {
+                    WriteOutcome::Applied
+                } else {
+                    WriteOutcome::Masked(read_only)
+                }
+            }
+            None => WriteOutcome::Dropped,
+        }
+    }
+
+    /// Report the illegal accesses of the guest, such as writes changing read-only registers
+    /// or writes to the free space after the capabilities. The guest is not affected: the
+    /// writes are still partially applied or dropped as usual.
+    /// * `callback` - The function receiving the description of each illegal access.
+    pub fn set_fault_callback(&mut self, callback: PciConfigFaultCallback) {
+        self.fault_callback = Some(callback);
+    }
+
+    /// Call the fault callback if a guest write is illegal. The writes of all ones to the
+    /// unused BARs are legal, as the guest sizes every BAR.
+    fn check_write_fault(
+        &self,
+        register: usize,
+        offset: usize,
+        len: usize,
+        value: u32,
+        writable: u32,
+    ) {
+        let callback = match self.fault_callback.as_ref() {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        let mask = (0xFFFF_FFFF >> (32 - len * 8)) << (offset * 8);
+        let old = self.read_configuration_dword(register).unwrap_or(0);
+        // A write may cover writable and read-only bits of the same register.
+        let read_only = (old ^ (value << (offset * 8))) & mask & !writable;
+        let is_bar = (BAR0_REGISTER..BAR0_REGISTER + self.bar_count()).contains(&register);
+
+        let kind = if !self.is_register_implemented(register) {
+            ConfigFaultKind::UnimplementedRegister
+        } else if read_only != 0 && !is_bar {
+            ConfigFaultKind::ReadOnlyWrite(read_only)
+        } else {
+            return;
+        };
+
+        callback(&ConfigFault {
+            register,
+            offset,
+            len,
+            kind,
+        });
+    }
+
+    /// Write to a register the way the guest does, leaving the read-only bits untouched.
+    /// * `register` - The index of the register within the given space.
+    /// * `offset` - The offset within the register.
+    /// * `data` - The actual bytes of data (1 to 4 bytes). Only the bytes covered by the write
+    ///   are changed, so that a partial write at any offset within the register is supported.
+    pub fn write_configuration_register(&mut self, register: usize, offset: usize, data: &[u8]) {
+        if !self.present || !self.guest_writable {
+            return;
+        }
+
+        let (mask, value): (u32, u32) = match access_mask(offset, data.len()) {
+            Some(mask) if data.len() == 1 => (mask, u32::from(data[0])),
+            Some(mask) if data.len() == 2 => (mask, u32::from(read_le_u16(data))),
+            Some(mask) if data.len() == 3 => (
+                mask,
+                u32::from(read_le_u16(data)) | (u32::from(data[2]) << 16),
+            ),
+            Some(mask) => (mask, read_le_u32(data)),
+            None => return,
+        };
+
+        let writable = match self.write_masks.get(register) {
+            Some(writable) => (mask << (offset * 8)) & writable,
+            None => return,
+        };
+
+        self.check_write_fault(register, offset, data.len(), value, writable);
+
+        // The backing device implements the side effects of its own registers.
+        if let Some(backing) = self.backing.as_mut() {
+            if let Some(old) = backing.read(register) {
+                backing.write(
+                    register,
+                    (old & !writable) | ((value << (offset * 8)) & writable),
+                );
+            }
+            return;
+        }
+
+        if self.defaults.is_none() {
+            self.defaults = Some(self.configuration_space.clone());
+        }
+
+        let rom_was_mapped = self.is_rom_mapped();
+        let old = self.configuration_space[register];
+        self.configuration_space[register] =
+            (old & !writable) | ((value << (offset * 8)) & writable);
+        self.mark_dirty();
+
+        if register == COMMAND_REGISTER || register == EXPANSION_ROM_REGISTER {
+            self.notify_rom_mapping(rom_was_mapped);
+        }
+
+        if register == BIST_REGISTER {
+            self.run_bist();
+        }
+
+        if register == COMMAND_REGISTER {
+            self.notify_decode_change(old);
+            self.notify_bar_registration_on_decode_change(old);
+        }
+
+        if register >= BAR0_REGISTER && self.get_bar_using_slot(register - BAR0_REGISTER).is_some()
+        {
+            self.stage_bar_write();
+        }
+
+        // The VPD Address register is the upper word of the first register of the capability.
+        if self.vpd.as_ref().map(|(vpd, _)| *vpd) == Some(register) && offset + data.len() > 2 {
+            self.run_vpd_access(register);
+        }
+
+        // The Data Select register follows the header of the Power Budgeting capability.
+        if self.power_budget.as_ref().map(|(budget, _)| *budget + 1) == Some(register) {
+            self.select_power_budget_entry(register - 1);
+        }
+
+        // The Control register is the third register of a Resizable BAR capability.
+        if register >= EXTENDED_CAPABILITIES_REGISTER + 2
+            && self
+                .resizable_bar_capabilities()
+                .any(|capability| capability + 2 == register)
+        {
+            self.run_bar_resize(register - 2);
+        }
+    }
+
+    /// Read a naturally aligned byte, word or dword the way the guest does, as the data port
+    /// and the ECAM region do. Return `None` for an invalid width, an unaligned offset or an
+    /// offset outside the configuration space.
+    /// * `byte_offset` - The offset of the value within the configuration space.
+    /// * `width` - The size of the value, in bytes (1, 2 or 4).
+    pub fn read(&self, byte_offset: usize, width: usize) -> Option<u32> {
+        let mask = match width {
+            1 => 0xFF,
+            2 => 0xFFFF,
+            4 => 0xFFFF_FFFF,
+            _ => return None,
+        };
+
+        // The widths are powers of two.
+        if byte_offset & (width - 1) != 0 {
+            return None;
+        }
+
+        let value = self.read_configuration_register(byte_offset / 4)?;
+
+        Some((value >> ((byte_offset % 4) * 8)) & mask)
+    }
+
+    /// Compare two captures of a configuration space, such as the ones of `read_config_range`.
+    /// Return the registers which changed, as (register, old value, new value).
+    /// The registers missing from the shorter capture are compared as zero.
+    /// * `before` - The first capture.
+    /// * `after` - The second capture.
+    pub fn diff(before: &[u32], after: &[u32]) -> Vec<(usize, u32, u32)> {
+        (0..before.len().max(after.len()))
+            .filter_map(|register| {
+                let old = before.get(register).cloned().unwrap_or(0);
+                let new = after.get(register).cloned().unwrap_or(0);
+
+                if old != new {
+                    Some((register, old, new))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Return the registers which changed since a capture of the configuration space, as
+    /// (register, old value, new value).
+    /// * `snapshot` - The capture, starting at register 0.
+    pub fn diff_from(&self, snapshot: &[u32]) -> Vec<(usize, u32, u32)> {
+        PciFunction::diff(snapshot, &self.configuration_space)
+    }
+
+    /// Read bytes the way the guest does, from any byte offset of the configuration space,
+    /// as in the ECAM region. The bytes outside the configuration space read as all ones.
+    /// * `byte_offset` - The offset of the first byte within the configuration space.
+    /// * `buf` - The buffer filled with the bytes read, in little-endian order.
+    pub fn read_bytes(&self, byte_offset: usize, buf: &mut [u8]) {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte = byte_offset
+                .checked_add(index)
+                .and_then(|offset| {
+                    self.read_configuration_register(offset / 4)
+                        .map(|value| value.to_le_bytes()[offset % 4])
+                })
+                .unwrap_or(0xFF);
+        }
+    }
+
+    /// Write bytes the way the guest does, at any byte offset of the configuration space,
+    /// as in the ECAM region. The data is split in the largest naturally aligned accesses,
+    /// so the write masks and the side effects of the registers apply as usual.
+    /// * `byte_offset` - The offset of the first byte within the configuration space.
+    /// * `data` - The bytes to be written, in little-endian order.
+    pub fn write_bytes(&mut self, byte_offset: usize, data: &[u8]) {
+        let mut written = 0;
+
+        while written < data.len() {
+            let offset = match byte_offset.checked_add(written) {
+                Some(offset) => offset,
+                None => return,
+            };
+            let remaining = data.len() - written;
+
+            let len = if offset % 4 == 0 && remaining >= 4 {
+                4
+            } else if offset % 2 == 0 && remaining >= 2 {
+                2
+            } else {
+                1
+            };
+
+            self.write_configuration_register(
+                offset / 4,
+                offset % 4,
+                &data[written..written + len],
+            );
+            written += len;
+        }
+    }
+
+    /// Notify the changes of the regions decoded by the function: the guest toggling the
+    /// decoding of the BARs, mapping the Expansion ROM region, or moving the enabled BARs.
+    /// The enabled BARs are notified on every BAR write while the decoding is enabled, and
+    /// when the guest enables or disables the decoding, so the VMM calls
+    /// `register_bar_handlers` to follow them. When their registration is deferred, the BAR
+    /// writes are staged and notified at once when the guest toggles the decoding, or when
+    /// `commit_bar_writes` is called, so a guest programming several BARs in a row does not
+    /// register the intermediate regions.
+    ///
+    /// The events happen while the function is locked, so they are only queued. `PciDevice`
+    /// fires them once the access of the guest completed and the function is unlocked; the
+    /// owner of a function accessed directly fires them with `take_mapping_notifications`.
+    /// * `callback` - The function receiving the mapping events.
+    /// * `deferred` - Whether the BAR writes are staged.
+    pub fn set_mapping_callback(&mut self, callback: PciMappingCallback, deferred: bool) {
+        self.mapping_callback = Some(callback);
+        self.mapping_events.clear();
+        self.defer_bar_registration = deferred;
+        self.pending_bar_writes = false;
+    }
+
+    /// Take the mapping events queued since the last call, so they are fired once the lock of
+    /// the function is released.
+    pub fn take_mapping_notifications(&mut self) -> PciMappingNotifications {
+        PciMappingNotifications {
+            callback: self.mapping_callback.clone(),
+            events: std::mem::take(&mut self.mapping_events),
+        }
+    }
+
+    /// Queue a mapping event, if someone is notified.
+    fn queue_mapping_event(&mut self, event: PciMappingEvent) {
+        if self.mapping_callback.is_some() {
+            self.mapping_events.push(event);
+        }
+    }
+
+    /// Notify the BAR writes staged since the last registration, if any.
+    pub fn commit_bar_writes(&mut self) {
+        if self.pending_bar_writes {
+            self.notify_bar_registration();
+        }
+    }
+
+    /// Notify the enabled BARs and clear the staged writes.
+    fn notify_bar_registration(&mut self) {
+        self.pending_bar_writes = false;
+        self.queue_mapping_event(PciMappingEvent::Bars(self.enabled_bars()));
+    }
+
+    /// Stage a guest write of a BAR, or notify it right away if the registration is not
+    /// deferred and the memory decoding is enabled.
+    fn stage_bar_write(&mut self) {
+        if self.mapping_callback.is_none() {
+            return;
+        }
+
+        if self.defer_bar_registration {
+            self.pending_bar_writes = true;
+        } else if self.is_memory_space_enabled() || self.is_io_space_enabled() {
+            self.notify_bar_registration();
+        }
+    }
+
+    /// Notify the enabled BARs if a write changed the Memory or IO Space bits, since the
+    /// regions have to be registered or removed.
+    /// * `old_command` - The Command register before the write.
+    fn notify_bar_registration_on_decode_change(&mut self, old_command: u32) {
+        let decode_bits = u32::from(COMMAND_MEMORY_SPACE | COMMAND_IO_SPACE);
+        if (old_command ^ self.configuration_space[COMMAND_REGISTER]) & decode_bits != 0 {
+            self.notify_bar_registration();
+        }
+    }
+
+    /// Declare the Expansion ROM of this function, so the guest can size it and program its
+    /// address through the Expansion ROM Base Address register. A size of 0 removes it.
+    /// * `size` - The size of the region, in bytes. It has to be a power of 2, of at least 2KB.
+    pub fn set_expansion_rom(&mut self, size: u32) -> Result<()> {
+        if self.is_bridge()
+            || (size != 0 && (!size.is_power_of_two() || size & EXPANSION_ROM_ADDRESS_MASK == 0))
+        {
+            return Err(PciFunctionError::InvalidBarSize(u64::from(size)));
+        }
+
+        self.rom_size = size;
+        self.update_write_masks();
+
+        let rom = self.configuration_space[EXPANSION_ROM_REGISTER] & self.rom_write_mask();
+        self.write_configuration_dword(EXPANSION_ROM_REGISTER, rom);
+
+        Ok(())
+    }
+
+    /// Return the writable bits of the Expansion ROM Base Address register: the address bits
+    /// above the size of the region and the enable bit, or none without an Expansion ROM.
+    fn rom_write_mask(&self) -> u32 {
+        if self.rom_size == 0 {
+            return 0;
+        }
+
+        (!(self.rom_size - 1) & EXPANSION_ROM_ADDRESS_MASK) | EXPANSION_ROM_ENABLE
+    }
+
+    /// Return true if the Expansion ROM region is mapped: both the Expansion ROM Enable bit
+    /// and the Memory Space bit of the Command register have to be set.
+    pub fn is_rom_mapped(&self) -> bool {
+        !self.is_bridge()
+            && self.configuration_space[EXPANSION_ROM_REGISTER] & EXPANSION_ROM_ENABLE != 0
+            && self.is_memory_space_enabled()
+    }
+
+    /// Notify the mapping of the Expansion ROM region if a write mapped or unmapped it.
+    /// * `was_mapped` - Whether the region was mapped before the write.
+    fn notify_rom_mapping(&mut self, was_mapped: bool) {
+        let mapped = self.is_rom_mapped();
+        if mapped == was_mapped {
+            return;
+        }
+
+        let address = self.configuration_space[EXPANSION_ROM_REGISTER] & EXPANSION_ROM_ADDRESS_MASK;
+        self.queue_mapping_event(PciMappingEvent::Rom {
+            mapped,
+            address: u64::from(address),
+        });
+    }
+
+    /// Notify the decoding of the BARs if a write changed the Memory or IO Space bits.
+    /// * `old_command` - The Command register before the write.
+    fn notify_decode_change(&mut self, old_command: u32) {
+        let decode_bits = u32::from(COMMAND_MEMORY_SPACE | COMMAND_IO_SPACE);
+        if (old_command ^ self.configuration_space[COMMAND_REGISTER]) & decode_bits == 0 {
+            return;
+        }
+
+        self.queue_mapping_event(PciMappingEvent::Decode {
+            memory: self.is_memory_space_enabled(),
+            io: self.is_io_space_enabled(),
+        });
+    }
+
+    /// Set the Min_Gnt register, the burst period length needed by a legacy bus master, in
+    /// units of 0.25us. It is hardwired to 0 for PCI Express functions, which is the default.
+    /// The register only exists in a Type 0 header, so bridges are left untouched.
+    /// * `min_gnt` - The value of the register.
+    pub fn set_min_gnt(&mut self, min_gnt: u8) {
+        if !self.is_bridge() {
+            self.write_configuration_byte(MIN_GNT_REGISTER, MIN_GNT_OFFSET, min_gnt);
+        }
+    }
+
+    /// Return the Min_Gnt register of a Type 0 function.
+    pub fn get_min_gnt(&self) -> Option<u8> {
+        if self.is_bridge() {
+            return None;
+        }
+
+        self.read_configuration_byte(MIN_GNT_REGISTER, MIN_GNT_OFFSET)
+    }
+
+    /// Set the Max_Lat register, how often a legacy bus master needs to access the bus, in
+    /// units of 0.25us. It is hardwired to 0 for PCI Express functions, which is the default.
+    /// The register only exists in a Type 0 header, so bridges are left untouched.
+    /// * `max_lat` - The value of the register.
+    pub fn set_max_lat(&mut self, max_lat: u8) {
+        if !self.is_bridge() {
+            self.write_configuration_byte(MAX_LAT_REGISTER, MAX_LAT_OFFSET, max_lat);
+        }
+    }
+
+    /// Return the Max_Lat register of a Type 0 function.
+    pub fn get_max_lat(&self) -> Option<u8> {
+        if self.is_bridge() {
+            return None;
+        }
+
+        self.read_configuration_byte(MAX_LAT_REGISTER, MAX_LAT_OFFSET)
+    }
+
+    /// Set or clear the BIST Capable bit of the BIST register.
+    /// Only a BIST capable function lets the guest start the self-test.
+    /// * `capable` - Whether the function supports the Built-In Self Test.
+    pub fn set_bist_capable(&mut self, capable: bool) {
+        let bist = if capable { BIST_CAPABLE } else { 0 };
+
+        self.write_configuration_byte(BIST_REGISTER, BIST_OFFSET, bist);
+        self.update_write_masks();
+    }
+
+    /// Return true if the function supports the Built-In Self Test.
+    pub fn is_bist_capable(&self) -> bool {
+        self.read_configuration_byte(BIST_REGISTER, BIST_OFFSET)
+            .unwrap_or(0)
+            & BIST_CAPABLE
+            != 0
+    }
+
+    /// Complete a self-test started by the guest. The self-test always passes, so the
+    /// Start BIST bit is cleared and the completion code is 0.
+    fn run_bist(&mut self) {
+        let bist = self
+            .read_configuration_byte(BIST_REGISTER, BIST_OFFSET)
+            .unwrap_or(0);
+
+        if bist & BIST_START != 0 {
+            self.update_state_bits(
+                BIST_REGISTER,
+                u32::from(BIST_START | BIST_COMPLETION_CODE) << (BIST_OFFSET * 8),
+                0,
+            );
+        }
+    }
+
+    /// Declare a Base Address Register of this function.
+    /// The read-only type bits are written in the register and the address bits are made
+    /// writable, so the guest can size the region and program its address.
+    /// * `index` - The index of the BAR (0-5, or 0-1 for a bridge). A 64-bit BAR also uses the
+    ///   next index.
+    /// * `region_type` - The kind of region described by the BAR.
+    /// * `size` - The size of the region, in bytes. It has to be a power of 2.
+    /// * `prefetchable` - Whether the memory region is prefetchable.
+    pub fn set_bar(
+        &mut self,
+        index: usize,
+        region_type: PciBarRegionType,
+        size: u64,
+        prefetchable: bool,
+    ) -> Result<()> {
+        let bar = PciBarConfiguration {
+            index,
+            region_type,
+            size,
+            prefetchable: prefetchable && region_type != PciBarRegionType::IoRegion,
+            address: 0,
+        };
+
+        // The registers following the BARs of a bridge hold its bus numbers and windows.
+        if index + bar.register_count() > self.bar_count() {
+            return Err(PciFunctionError::InvalidBarIndex(index));
+        }
+
+        if !bar.is_size_valid() {
+            return Err(PciFunctionError::InvalidBarSize(size));
+        }
+
+        for slot in index..index + bar.register_count() {
+            if self.get_bar_using_slot(slot).is_some() {
+                return Err(PciFunctionError::AlreadyInUseBarSlot(slot));
+            }
+        }
+
+        for (register, _) in bar.write_masks() {
+            self.write_configuration_dword(register, 0);
+        }
+        self.write_configuration_dword(BAR0_REGISTER + index, bar.type_bits());
+        self.register_bar(bar);
+
+        Ok(())
+    }
+
+    /// Return the number of BAR registers of the header: 6 for a Type 0 header, 2 for a
+    /// Type 1 header.
+    pub fn bar_count(&self) -> usize {
+        if self.is_bridge() {
+            NUM_BRIDGE_BAR_REGISTERS
+        } else {
+            NUM_BAR_REGISTERS
+        }
+    }
+
+    /// Return the Base Address Register declared at the given index, if any.
+    /// * `index` - The index of the BAR (0-5).
+    pub fn get_bar(&self, index: usize) -> Option<PciBarConfiguration> {
+        self.bars.get(index).cloned().unwrap_or(None)
+    }
+
+    /// Return every declared Base Address Register, along with the address currently
+    /// programmed in it. A 64-bit BAR is returned once, at the index of its lower half.
+    pub fn bars(&self) -> Vec<PciBarConfiguration> {
+        self.bars
+            .iter()
+            .filter_map(|bar| *bar)
+            .map(|bar| PciBarConfiguration {
+                address: self.bar_address(bar.index).unwrap_or(0),
+                ..bar
+            })
+            .collect()
+    }
+
+    /// Return the address programmed in a Base Address Register, if the BAR is declared.
+    /// * `index` - The index of the BAR (0-5).
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        let bar = self.get_bar(index)?;
+        let low = self.read_configuration_dword(BAR0_REGISTER + index)?;
+
+        match bar.region_type {
+            PciBarRegionType::IoRegion => Some(u64::from(low & BAR_IO_ADDRESS_MASK)),
+            PciBarRegionType::Memory32BitRegion => Some(u64::from(low & BAR_MEMORY_ADDRESS_MASK)),
+            PciBarRegionType::Memory64BitRegion => {
+                let high = self.read_configuration_dword(BAR0_REGISTER + index + 1)?;
+                Some(u64::from(low & BAR_MEMORY_ADDRESS_MASK) | (u64::from(high) << 32))
+            }
+        }
+    }
+
+    /// Return the port range programmed in an IO Base Address Register, as (base, size).
+    /// Return `None` if the BAR is not declared, is a memory BAR, or is programmed beyond the
+    /// 64KB of IO ports.
+    /// * `index` - The index of the BAR (0-5).
+    pub fn io_bar_range(&self, index: usize) -> Option<(u16, u16)> {
+        let bar = self.get_bar(index)?;
+        if bar.region_type != PciBarRegionType::IoRegion {
+            return None;
+        }
+
+        let base = self.bar_address(index)?;
+        if base > u64::from(u16::MAX) {
+            return None;
+        }
+
+        Some((base as u16, bar.size as u16))
+    }
+
+    /// Return true if the function responds to memory accesses (the `Memory Space` bit of the
+    /// Command register is set).
+    pub fn is_memory_space_enabled(&self) -> bool {
+        self.read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET)
+            .unwrap_or(0)
+            & COMMAND_MEMORY_SPACE
+            != 0
+    }
+
+    /// Return true if the function responds to IO accesses (the `IO Space` bit of the
+    /// Command register is set).
+    pub fn is_io_space_enabled(&self) -> bool {
+        self.read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET)
+            .unwrap_or(0)
+            & COMMAND_IO_SPACE
+            != 0
+    }
+
+    /// Set the state of the legacy interrupt of the function. The `Interrupt Status` bit of the
+    /// Status register follows it, so the guest can find the interrupting function.
+    /// * `asserted` - Whether the function has a pending legacy interrupt.
+    pub fn set_intx_asserted(&mut self, asserted: bool) {
+        let mask = u32::from(STATUS_INTERRUPT_STATUS) << (STATUS_OFFSET * 8);
+
+        self.update_state_bits(STATUS_REGISTER, mask, if asserted { mask } else { 0 });
+    }
+
+    /// Return true if the function drives its INTx pin: it has a pending legacy interrupt and
+    /// the `Interrupt Disable` bit of the Command register is clear.
+    pub fn is_intx_asserted(&self) -> bool {
+        let status = self
+            .read_configuration_word(STATUS_REGISTER, STATUS_OFFSET)
+            .unwrap_or(0);
+        let command = self
+            .read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET)
+            .unwrap_or(0);
+
+        status & STATUS_INTERRUPT_STATUS != 0 && command & COMMAND_INTERRUPT_DISABLE == 0
+    }
+
+    /// Return the BARs the function decodes: they are programmed with an address and their
+    /// space is enabled in the Command register.
+    pub fn enabled_bars(&self) -> Vec<PciBarConfiguration> {
+        self.bars()
+            .into_iter()
+            .filter(|bar| bar.address != 0)
+            .filter(|bar| match bar.region_type {
+                PciBarRegionType::IoRegion => self.is_io_space_enabled(),
+                _ => self.is_memory_space_enabled(),
+            })
+            .collect()
+    }
+
+    /// Set the device handling the accesses to the region of a BAR.
+    /// The handler is registered on the system bus by `register_bar_handlers`, while the
+    /// guest has programmed the BAR and enabled its decoding.
+    /// * `index` - The index of the BAR (0-5).
+    /// * `handler` - The device handling the accesses, at offsets relative to the BAR.
+    pub fn set_bar_handler(&mut self, index: usize, handler: PciBarHandler) -> Result<()> {
+        if self.get_bar(index).is_none() {
+            return Err(PciFunctionError::InvalidBarIndex(index));
+        }
+
+        self.bar_handlers.insert(index, (handler, None));
+        Ok(())
+    }
+
+    /// Bring the handlers registered on the system buses in line with the BARs: the handlers
+    /// of the enabled BARs are registered at the programmed addresses, the ones of the BARs
+    /// which moved are registered again, and the ones of the BARs no longer decoded are
+    /// removed. It is called on each `PciMappingEvent::Bars`. A BAR programmed at the top of
+    /// the address space, whose region would wrap around, is left unregistered.
+    /// * `mmio_bus` - The bus receiving the handlers of the memory BARs.
+    /// * `pio_bus` - The bus receiving the handlers of the IO BARs.
+    pub fn register_bar_handlers(&mut self, mmio_bus: &mut Bus, pio_bus: &mut Bus) -> Result<()> {
+        let command = self
+            .read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET)
+            .unwrap_or(0);
+
+        for index in self.bar_handlers.keys().cloned().collect::<Vec<usize>>() {
+            let bar = match self.get_bar(index) {
+                Some(bar) => bar,
+                None => continue,
+            };
+            let address = self.bar_address(index).unwrap_or(0);
+
+            let (bus, enabled) = match bar.region_type {
+                PciBarRegionType::IoRegion => (&mut *pio_bus, command & COMMAND_IO_SPACE != 0),
+                _ => (&mut *mmio_bus, command & COMMAND_MEMORY_SPACE != 0),
+            };
+            let region = if enabled && address != 0 && address.checked_add(bar.size).is_some() {
+                Some((address, bar.size))
+            } else {
+                None
+            };
+
+            let (handler, registered) = &mut self.bar_handlers.get_mut(&index).unwrap();
+            if *registered == region {
+                continue;
+            }
+
+            if let Some((old, _)) = registered.take() {
+                bus.remove(old);
+            }
+            if let Some((address, size)) = region {
+                bus.insert(handler.clone(), address, size)
+                    .map_err(|_| PciFunctionError::BarHandlerOverlap(index))?;
+                *registered = region;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove from the system buses the handlers registered by `register_bar_handlers`.
+    /// * `mmio_bus` - The bus holding the handlers of the memory BARs.
+    /// * `pio_bus` - The bus holding the handlers of the IO BARs.
+    pub fn unregister_bar_handlers(&mut self, mmio_bus: &mut Bus, pio_bus: &mut Bus) {
+        for (index, (_, registered)) in self.bar_handlers.iter_mut() {
+            let address = match registered.take() {
+                Some((address, _)) => address,
+                None => continue,
+            };
+
+            match self.bars[*index].map(|bar| bar.region_type) {
+                Some(PciBarRegionType::IoRegion) => pio_bus.remove(address),
+                _ => mmio_bus.remove(address),
+            };
+        }
+    }
+
+    /// Return the index of the memory BAR which contains the address, if any.
+    /// * `address` - The physical address of the access.
+    pub fn find_memory_bar(&self, address: u64) -> Option<usize> {
+        if !self.is_memory_space_enabled() {
+            return None;
+        }
+
+        self.bars
+            .iter()
+            .filter_map(|bar| *bar)
+            .filter(|bar| bar.region_type != PciBarRegionType::IoRegion)
+            .find(|bar| match self.bar_address(bar.index) {
+                Some(base) => address >= base && address - base < bar.size,
+                None => false,
+            })
+            .map(|bar| bar.index)
+    }
+
+    /// Return the Base Address Register which uses the given register slot, if any.
+    fn get_bar_using_slot(&self, slot: usize) -> Option<PciBarConfiguration> {
+        self.bars
+            .iter()
+            .filter_map(|bar| *bar)
+            .find(|bar| (bar.index..bar.index + bar.register_count()).contains(&slot))
+    }
+
+    /// Store the BAR description and make its address bits writable.
+    pub(crate) fn register_bar(&mut self, bar: PciBarConfiguration) {
+        self.bars[bar.index] = Some(bar);
+        self.update_write_masks();
+    }
+
+    /// Derive the bits of each register the guest can write from the declared BARs.
+    pub(crate) fn update_write_masks(&mut self) {
+        // The masks follow the changes of the BAR descriptions and of the capabilities.
+        self.mark_dirty();
+        self.write_masks = vec![0xFFFF_FFFF; CONFIGURATION_SPACE_SIZE];
+
+        // The Vendor ID and Device ID identify the function, they are read-only.
+        self.write_masks[VENDOR_ID_REGISTER] = 0;
+
+        // So do the Class Code and the Revision ID.
+        self.write_masks[CLASS_CODE_REGISTER] = 0;
+
+        // The Header Type describes the layout of the header.
+        self.write_masks[HEADER_TYPE_REGISTER] &= !(0xFF << (HEADER_TYPE_OFFSET * 8));
+
+        // The capability list is built by the VMM.
+        self.write_masks[CAPABILITIES_POINTER_REGISTER] = 0;
+
+        // Only the Interrupt Line is writable, along with the Bridge Control register of a
+        // bridge. The Interrupt Pin, Min_Gnt and Max_Lat are read-only.
+        self.write_masks[INTERRUPT_LINE_REGISTER] = 0xFF << (INTERRUPT_LINE_OFFSET * 8);
+        if self.is_bridge() {
+            self.write_masks[BRIDGE_CONTROL_REGISTER] |= 0xFFFF << (BRIDGE_CONTROL_OFFSET * 8);
+        } else {
+            self.write_masks[EXPANSION_ROM_REGISTER] = self.rom_write_mask();
+        }
+
+        // The reserved bits of the Command register are hardwired to 0.
+        self.write_masks[COMMAND_REGISTER] &=
+            !(u32::from(COMMAND_RESERVED) << (COMMAND_OFFSET * 8));
+
+        // The Interrupt Status bit reflects the state of the legacy interrupt.
+        self.write_masks[STATUS_REGISTER] &=
+            !(u32::from(STATUS_INTERRUPT_STATUS) << (STATUS_OFFSET * 8));
+
+        // Only the Start BIST bit is writable, and only if the function is BIST capable.
+        let bist_writable = if self.is_bist_capable() {
+            BIST_START
+        } else {
+            0
+        };
+        self.write_masks[BIST_REGISTER] &= !(0xFF << (BIST_OFFSET * 8));
+        self.write_masks[BIST_REGISTER] |= u32::from(bist_writable) << (BIST_OFFSET * 8);
+
+        // The registers of the undeclared BARs are hardwired to 0, so they size as unused.
+        for register in BAR0_REGISTER..BAR0_REGISTER + self.bar_count() {
+            self.write_masks[register] = 0;
+        }
+
+        for bar in self.bars.iter().filter_map(|bar| *bar) {
+            for (register, mask) in bar.write_masks() {
+                self.write_masks[register] = mask;
+            }
+        }
+
+        // Only the Data Select register of the Power Budgeting capability is writable.
+        if let Some((register, _)) = self.power_budget {
+            self.write_masks[register + 1] = 0xFF;
+            self.write_masks[register + 2] = 0;
+            self.write_masks[register + 3] = 0;
+        }
+
+        // Only the BAR Size field of a Resizable BAR capability is writable.
+        for register in self.resizable_bar_capabilities() {
+            self.write_masks[register + 1] = 0;
+            self.write_masks[register + 2] = RESIZABLE_BAR_SIZE_MASK;
+        }
+    }
+
+    /// Replace the whole configuration space in one operation.
+    /// The caller must hold the lock of the function, so concurrent readers observe either
+    /// the old or the new image. The BAR descriptions and the capability layout are kept,
+    /// and the write masks are derived again from them.
+    /// * `bytes` - The new configuration space, as little-endian bytes (4KB).
+    pub fn replace_config(&mut self, bytes: &[u8]) -> Result<()> {
+        if bytes.len() != CONFIGURATION_SPACE_SIZE * 4 {
+            return Err(PciFunctionError::InvalidConfigurationSpaceSize(bytes.len()));
+        }
+
+        self.configuration_space = bytes.chunks(4).map(read_le_u32).collect();
+        self.update_write_masks();
+
+        Ok(())
+    }
+
+    /// Add a Vital Product Data capability exposing the given data.
+    /// Return the register where the capability starts.
+    /// * `data` - The backing store of the VPD, read and written by the guest.
+    pub fn add_vpd_capability(&mut self, data: Vec<u8>) -> Result<usize> {
+        let register = self.add_capability(&VpdCapability)?;
+        self.vpd = Some((register, data));
+
+        Ok(register)
+    }
+
+    /// Complete the VPD access requested by the guest through the VPD Address register.
+    /// A clear flag requests a read: the data register is filled and the flag is set.
+    /// A set flag requests a write: the data register is stored and the flag is cleared.
+    fn run_vpd_access(&mut self, register: usize) {
+        let address = self.read_configuration_word(register, 2).unwrap_or(0);
+        let start = usize::from(address & VPD_ADDRESS_MASK);
+        let data = self.read_configuration_dword(register + 1).unwrap_or(0);
+
+        let store = match self.vpd.as_mut() {
+            Some((_, store)) => store,
+            None => return,
+        };
+
+        if address & VPD_FLAG == 0 {
+            let mut bytes = [0u8; 4];
+            for (index, byte) in bytes.iter_mut().enumerate() {
+                *byte = store.get(start + index).cloned().unwrap_or(0);
+            }
+
+            self.update_state_bits(register + 1, 0xFFFF_FFFF, u32::from_le_bytes(bytes));
+            self.update_state_bits(
+                register,
+                u32::from(VPD_FLAG) << 16,
+                u32::from(VPD_FLAG) << 16,
+            );
+        } else {
+            for (index, byte) in data.to_le_bytes().iter().enumerate() {
+                if let Some(stored) = store.get_mut(start + index) {
+                    *stored = *byte;
+                }
+            }
+
+            self.update_state_bits(register, u32::from(VPD_FLAG) << 16, 0);
+        }
+    }
+
+    /// Add a Resizable BAR extended capability for a memory BAR, starting at its declared size.
+    /// Return the register where the capability starts.
+    /// * `bar_index` - The index of the BAR (0-5).
+    /// * `supported_sizes_mask` - The supported sizes, bit `n` being set if 2^n MB is supported.
+    ///   A 32-bit BAR supports up to 2GB.
+    pub fn add_resizable_bar_capability(
+        &mut self,
+        bar_index: u8,
+        supported_sizes_mask: u32,
+    ) -> Result<usize> {
+        let bar = self
+            .get_bar(usize::from(bar_index))
+            .filter(|bar| bar.region_type != PciBarRegionType::IoRegion)
+            .ok_or_else(|| PciFunctionError::InvalidBarIndex(usize::from(bar_index)))?;
+
+        // The BAR has to be able to describe every supported size.
+        if supported_sizes_mask >> resizable_bar_size_count(bar.region_type) != 0 {
+            let largest = 31 - supported_sizes_mask.leading_zeros();
+            return Err(PciFunctionError::InvalidBarSize(
+                1 << (largest + RESIZABLE_BAR_MIN_SIZE_SHIFT),
+            ));
+        }
+
+        // The declared size has to be one of the supported sizes.
+        let size = bar.size.trailing_zeros();
+        if size < RESIZABLE_BAR_MIN_SIZE_SHIFT
+            || supported_sizes_mask & (1 << (size - RESIZABLE_BAR_MIN_SIZE_SHIFT)) == 0
+        {
+            return Err(PciFunctionError::InvalidBarSize(bar.size));
+        }
+
+        let register = self.add_extended_capability(&ResizableBarCapability {
+            bar_index,
+            supported_sizes: supported_sizes_mask,
+            size: (size - RESIZABLE_BAR_MIN_SIZE_SHIFT) as u8,
+        })?;
+        self.update_write_masks();
+
+        Ok(register)
+    }
+
+    /// Return the registers of the Resizable BAR capabilities of this function.
+    fn resizable_bar_capabilities(&self) -> impl Iterator<Item = usize> {
+        self.extended_capabilities()
+            .filter(|(id, _, _)| *id == PciExtendedCapabilityId::ResizableBar as u16)
+            .map(|(_, _, register)| register)
+            .collect::<Vec<usize>>()
+            .into_iter()
+    }
+
+    /// Apply the BAR size selected by the guest through a Resizable BAR capability.
+    /// An unsupported size is ignored and the Control register keeps the current size.
+    /// The address bits which become read-only with the new size are cleared.
+    /// The BARs smaller than the minimum size, which the capability cannot describe, are
+    /// never resized, and the sizes the BAR cannot describe are ignored. A new size is notified
+    /// like a guest write of the BAR, since its region changes.
+    fn run_bar_resize(&mut self, register: usize) {
+        let supported = self.configuration_space[register + 1] >> 4;
+        let control = self.configuration_space[register + 2];
+        let index = (control & 0x7) as usize;
+        let size = (control & RESIZABLE_BAR_SIZE_MASK) >> RESIZABLE_BAR_SIZE_SHIFT;
+
+        let mut bar = match self.get_bar(index) {
+            Some(bar) => bar,
+            None => return,
+        };
+        let current = match bar
+            .size
+            .trailing_zeros()
+            .checked_sub(RESIZABLE_BAR_MIN_SIZE_SHIFT)
+        {
+            Some(current) => current,
+            None => return,
+        };
+
+        if size >= resizable_bar_size_count(bar.region_type) || supported & (1 << size) == 0 {
+            self.configuration_space[register + 2] =
+                (control & !RESIZABLE_BAR_SIZE_MASK) | (current << RESIZABLE_BAR_SIZE_SHIFT);
+            return;
+        }
+
+        let old_size = bar.size;
+        bar.size = 1 << (size + RESIZABLE_BAR_MIN_SIZE_SHIFT);
+        self.bars[index] = Some(bar);
+        self.update_write_masks();
+
+        for (bar_register, mask) in bar.write_masks() {
+            self.configuration_space[bar_register] &= mask;
+        }
+        self.configuration_space[BAR0_REGISTER + index] |= bar.type_bits();
+
+        if bar.size != old_size {
+            self.stage_bar_write();
+        }
+    }
+
+    /// Add a Power Budgeting extended capability exposing a power budget table.
+    /// Return the register where the capability starts.
+    /// * `entries` - The entries of the table, indexed by the Data Select register.
+    pub fn add_power_budgeting_capability(
+        &mut self,
+        entries: &[PowerBudgetEntry],
+    ) -> Result<usize> {
+        let register = self.add_extended_capability(&PowerBudgetingCapability)?;
+        self.power_budget = Some((
+            register,
+            entries.iter().map(PowerBudgetEntry::data).collect(),
+        ));
+        self.update_write_masks();
+        self.select_power_budget_entry(register);
+
+        Ok(register)
+    }
+
+    /// Load the Data register with the entry indexed by the Data Select register.
+    /// The Data register reads as 0 past the end of the table.
+    fn select_power_budget_entry(&mut self, register: usize) {
+        let select = (self.configuration_space[register + 1] & 0xFF) as usize;
+        let data = match self.power_budget.as_ref() {
+            Some((_, entries)) => entries.get(select).cloned().unwrap_or(0),
+            None => return,
+        };
+
+        self.configuration_space[register + 2] = data;
+    }
+
+    /// Limit the number of capabilities of the capability list, to defend against the
+    /// pathologically long lists. It does not remove the capabilities already added.
+    /// * `max_capabilities` - The maximum number of capabilities.
+    pub fn set_max_capabilities(&mut self, max_capabilities: usize) {
+        self.max_capabilities = max_capabilities;
+        self.mark_dirty();
+    }
+
+    /// Add a capability at the end of the capability list of this function.
+    /// Return the register where the capability starts.
+    /// * `capability` - The capability to be added.
+    pub fn add_capability(&mut self, capability: &dyn PciCapability) -> Result<usize> {
+        let mut bytes = vec![capability.id() as u8, 0];
+        bytes.extend(capability.bytes());
+
+        // The capabilities are dword aligned.
+        let size = (bytes.len() + 3) / 4;
+        let register = self.next_capability_register;
+        // Never spill over the extended configuration space.
+        let available =
+            (CONFIGURATION_HEADER_SIZE + CAPABILITY_REGISTERS_SIZE).saturating_sub(register);
+        if size > available {
+            return Err(PciFunctionError::CapabilitySpaceFull(size, available));
+        }
+
+        // Never link a capability into a broken list, or into a list which already leads to
+        // its register, so the list stays acyclic.
+        self.validate_capability_list()?;
+        if self
+            .capabilities()
+            .any(|(_, capability)| capability == register)
+        {
+            return Err(PciFunctionError::CapabilityListCycle(register));
+        }
+        if self.capabilities().count() >= self.max_capabilities {
+            return Err(PciFunctionError::TooManyCapabilities(self.max_capabilities));
+        }
+
+        for (index, byte) in bytes.iter().enumerate() {
+            self.write_configuration_byte(register + index / 4, index % 4, *byte);
+        }
+
+        // Link the capability at the end of the list. The pointers are single bytes, written
+        // alone so the neighbouring fields (and reserved bytes) are left intact.
+        let pointer = (register * 4) as u8;
+        match self.last_capability_register {
+            Some(last) => self.write_configuration_byte(last, 1, pointer),
+            None => self.write_configuration_byte(
+                CAPABILITIES_POINTER_REGISTER,
+                CAPABILITIES_POINTER_OFFSET,
+                pointer,
+            ),
+        }
+
+        let status = self
+            .read_configuration_word(STATUS_REGISTER, STATUS_OFFSET)
+            .unwrap_or(0);
+        self.write_configuration_word(
+            STATUS_REGISTER,
+            STATUS_OFFSET,
+            status | STATUS_CAPABILITIES_LIST,
+        );
+
+        self.last_capability_register = Some(register);
+        self.next_capability_register = register + size;
+
+        Ok(register)
+    }
+
+    /// Add a capability at the end of the extended capability list of this function.
+    /// Return the register where the capability starts.
+    /// * `capability` - The extended capability to be added.
+    pub fn add_extended_capability(
+        &mut self,
+        capability: &dyn PciExtendedCapability,
+    ) -> Result<usize> {
+        let bytes = capability.bytes();
+
+        // The extended capabilities are dword aligned, including their header.
+        let size = 1 + (bytes.len() + 3) / 4;
+        let register = self.next_extended_capability_register;
+        if register + size > CONFIGURATION_SPACE_SIZE {
+            return Err(PciFunctionError::ExtendedCapabilitySpaceFull);
+        }
+
+        // Extended Capability ID, Capability Version and a null Next Capability Offset.
+        let header = capability.id() as u32 | (u32::from(capability.version() & 0xF) << 16);
+        self.write_configuration_dword(register, header);
+
+        for (index, byte) in bytes.iter().enumerate() {
+            self.write_configuration_byte(register + 1 + index / 4, index % 4, *byte);
+        }
+
+        // Link the capability at the end of the list. The first one is always at offset 0x100.
+        if let Some(last) = self.last_extended_capability_register {
+            let header = self.read_configuration_dword(last).unwrap_or(0);
+            self.write_configuration_dword(
+                last,
+                (header & 0x000F_FFFF) | ((register as u32 * 4) << 20),
+            );
+        }
+
+        self.last_extended_capability_register = Some(register);
+        self.next_extended_capability_register = register + size;
+
+        Ok(register)
+    }
+
+    /// Return the Message Control register of the MSI-X capability, if the function has one.
+    fn msix_message_control(&self) -> Option<u16> {
+        let (_, register) = self
+            .capabilities()
+            .find(|(id, _)| *id == PciCapabilityId::MsiX as u8)?;
+
+        self.read_configuration_word(register, 2)
+    }
+
+    /// Record the Mask bit of an entry of the MSI-X table, as programmed by the guest.
+    /// * `vector` - The index of the entry within the table.
+    /// * `masked` - Whether the vector is masked.
+    pub fn set_msix_vector_masked(&mut self, vector: u16, masked: bool) {
+        if masked {
+            self.msix_unmasked_vectors.remove(&vector);
+        } else {
+            self.msix_unmasked_vectors.insert(vector);
+        }
+        self.mark_dirty();
+    }
+
+    /// Return the number of MSI-X vectors which can raise interrupts: MSI-X has to be enabled
+    /// without the Function Mask bit, and the vector itself has to be unmasked.
+    pub fn msix_enabled_vectors(&self) -> u16 {
+        let control = match self.msix_message_control() {
+            Some(control) => control,
+            None => return 0,
+        };
+        if control & MSIX_ENABLE == 0 || control & MSIX_FUNCTION_MASK != 0 {
+            return 0;
+        }
+
+        let table_size = (control & MSIX_TABLE_SIZE_MASK) + 1;
+        self.msix_unmasked_vectors.range(..table_size).count() as u16
+    }
+
+    /// Add a Designated Vendor-Specific extended capability.
+    /// Return the register where the capability starts.
+    /// * `vendor_id` - The vendor which defines the layout of the capability.
+    /// * `dvsec_id` - The vendor-defined ID of the capability.
+    /// * `data` - The vendor-specific registers.
+    pub fn add_dvsec(&mut self, vendor_id: u16, dvsec_id: u16, data: &[u8]) -> Result<usize> {
+        self.add_extended_capability(&DvsecCapability {
+            vendor_id,
+            dvsec_id,
+            revision: 0,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Add a Device Serial Number extended capability.
+    /// Return the register where the capability starts.
+    /// * `serial` - The serial number of the device.
+    pub fn add_serial_number_capability(&mut self, serial: u64) -> Result<usize> {
+        self.add_extended_capability(&SerialNumberCapability { serial })
+    }
+
+    /// Return an iterator over the capability list, as (capability ID, register) pairs.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, usize)> {
+        let mut capabilities = Vec::new();
+
+        if self
+            .read_configuration_word(STATUS_REGISTER, STATUS_OFFSET)
+            .unwrap_or(0)
+            & STATUS_CAPABILITIES_LIST
+            != 0
+        {
+            let mut pointer = self
+                .read_configuration_byte(CAPABILITIES_POINTER_REGISTER, CAPABILITIES_POINTER_OFFSET)
+                .unwrap_or(0) as usize;
+
+            // Stop on a null pointer, on a pointer outside the capability registers or after
+            // visiting as many capabilities as the capability registers can hold.
+            while pointer != 0 && capabilities.len() < CAPABILITY_REGISTERS_SIZE {
+                let register = pointer / 4;
+                if !(CONFIGURATION_HEADER_SIZE
+                    ..CONFIGURATION_HEADER_SIZE + CAPABILITY_REGISTERS_SIZE)
+                    .contains(&register)
+                {
+                    break;
+                }
+
+                let id = self.read_configuration_byte(register, 0).unwrap_or(0);
+                capabilities.push((id, register));
+
+                pointer = self.read_configuration_byte(register, 1).unwrap_or(0) as usize;
+            }
+        }
+
+        capabilities.into_iter()
+    }
+
+    /// Check the consistency of the configuration space: the capability lists have to end
+    /// within their area without looping, so the capability walks of the guest terminate.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_capability_list()?;
+        self.validate_extended_capability_list()
+    }
+
+    /// Check that the capability list ends within the capability registers, without cycle.
+    fn validate_capability_list(&self) -> Result<()> {
+        if self
+            .read_configuration_word(STATUS_REGISTER, STATUS_OFFSET)
+            .unwrap_or(0)
+            & STATUS_CAPABILITIES_LIST
+            == 0
+        {
+            return Ok(());
+        }
+
+        let mut visited = BTreeSet::new();
+        let mut pointer = self
+            .read_configuration_byte(CAPABILITIES_POINTER_REGISTER, CAPABILITIES_POINTER_OFFSET)
+            .unwrap_or(0) as usize;
+
+        while pointer != 0 {
+            let register = pointer / 4;
+            if !(CONFIGURATION_HEADER_SIZE..CONFIGURATION_HEADER_SIZE + CAPABILITY_REGISTERS_SIZE)
+                .contains(&register)
+            {
+                return Err(PciFunctionError::InvalidCapabilityPointer(pointer));
+            }
+            if !visited.insert(register) {
+                return Err(PciFunctionError::CapabilityListCycle(register));
+            }
+
+            pointer = self.read_configuration_byte(register, 1).unwrap_or(0) as usize;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the extended capability list ends within the extended configuration space,
+    /// without cycle.
+    fn validate_extended_capability_list(&self) -> Result<()> {
+        let mut visited = BTreeSet::new();
+        let mut register = EXTENDED_CAPABILITIES_REGISTER;
+
+        loop {
+            let header = self.read_configuration_dword(register).unwrap_or(0);
+            if header == 0 || header == 0xFFFF_FFFF {
+                return Ok(());
+            }
+            if !visited.insert(register) {
+                return Err(PciFunctionError::CapabilityListCycle(register));
+            }
+
+            let pointer = (header >> 20) as usize;
+            if pointer == 0 {
+                return Ok(());
+            }
+            register = pointer / 4;
+            if !(EXTENDED_CAPABILITIES_REGISTER..CONFIGURATION_SPACE_SIZE).contains(&register) {
+                return Err(PciFunctionError::InvalidCapabilityPointer(pointer));
+            }
+        }
+    }
+
+    /// Return an iterator over the extended capability list, as (extended capability ID,
+    /// capability version, register) tuples.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = (u16, u8, usize)> {
+        let mut capabilities: Vec<(u16, u8, usize)> = Vec::new();
+        let mut register = EXTENDED_CAPABILITIES_REGISTER;
+
+        // Stop on a null pointer, on a pointer outside the extended configuration space or
+        // on a pointer to an already visited capability.
+        loop {
+            let header = self.read_configuration_dword(register).unwrap_or(0);
+            // An absent extended capability list is marked by a null header.
+            if header == 0 || header == 0xFFFF_FFFF {
+                break;
+            }
+
+            capabilities.push((header as u16, ((header >> 16) & 0xF) as u8, register));
+
+            register = (header >> 20) as usize / 4;
+            if !(EXTENDED_CAPABILITIES_REGISTER..CONFIGURATION_SPACE_SIZE).contains(&register)
+                || capabilities
+                    .iter()
+                    .any(|(_, _, visited)| *visited == register)
+            {
+                break;
+            }
+        }
+
+        capabilities.into_iter()
+    }
+
+    /// Return a human readable description of every capability in the capability list.
+    pub fn describe_capabilities(&self) -> Vec<String> {
+        let end = CONFIGURATION_HEADER_SIZE + CAPABILITY_REGISTERS_SIZE;
+
+        self.capabilities()
+            .map(|(id, register)| {
+                let bytes: Vec<u8> = self.configuration_space[register..end]
+                    .iter()
+                    .flat_map(|dword| dword.to_le_bytes().to_vec())
+                    .collect();
+
+                describe_capability(id, &bytes)
+            })
+            .collect()
+    }
+}
+
+/// The copy has the same configuration space, capabilities, BARs and write masks.
+/// The state tied to the device model of the original function is not copied: the copy has
+/// no backing, no BAR handlers, no callbacks and no user tag. It has not been saved yet, so
+/// it is dirty.
+impl Clone for PciFunction {
+    fn clone(&self) -> Self {
+        PciFunction {
+            number: self.number,
+            configuration_space: self.configuration_space.clone(),
+            next_capability_register: self.next_capability_register,
+            last_capability_register: self.last_capability_register,
+            next_extended_capability_register: self.next_extended_capability_register,
+            last_extended_capability_register: self.last_extended_capability_register,
+            write_masks: self.write_masks.clone(),
+            bars: self.bars,
+            vpd: self.vpd.clone(),
+            power_budget: self.power_budget.clone(),
+            read_callbacks: BTreeMap::new(),
+            bar_handlers: BTreeMap::new(),
+            present: self.present,
+            guest_writable: self.guest_writable,
+            backing: None,
+            user_tag: None,
+            mapping_callback: None,
+            mapping_events: Vec::new(),
+            defer_bar_registration: false,
+            pending_bar_writes: false,
+            fault_callback: None,
+            msix_unmasked_vectors: self.msix_unmasked_vectors.clone(),
+            max_capabilities: self.max_capabilities,
+            rom_size: self.rom_size,
+            dirty: AtomicBool::new(true),
+            shared_header: Arc::new(AtomicU64::new(self.header().to_bits())),
+            defaults: self.defaults.clone(),
+        }
+    }
+}
+
+impl PciConfigurable for PciFunction {
+    fn read_config(&self, register: usize, offset: usize, width: usize) -> u32 {
+        match (
+            access_mask(offset, width),
+            self.read_configuration_register(register),
+        ) {
+            (Some(mask), Some(value)) => (value >> (offset * 8)) & mask,
+            _ => 0xFFFF_FFFF,
+        }
+    }
+
+    fn write_config(&mut self, register: usize, offset: usize, data: &[u8]) {
+        self.write_configuration_register(register, offset, data);
+    }
+
+    fn is_present(&self) -> bool {
+        self.present
+    }
+
+    fn reset(&mut self) {
+        PciFunction::reset(self);
+    }
+
+    fn set_multi_function(&mut self, multi_function: bool) {
+        PciFunction::set_multi_function(self, multi_function);
+    }
+
+    fn teardown(&mut self, mmio_bus: &mut Bus, pio_bus: &mut Bus) {
+        self.unregister_bar_handlers(mmio_bus, pio_bus);
+    }
+
+    fn as_function(&self) -> Option<&PciFunction> {
+        Some(self)
+    }
+
+    fn as_function_mut(&mut self) -> Option<&mut PciFunction> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::{
+        MsixCapability, PciExpressCapability, PciExpressDeviceType, PowerManagementCapability,
+        VendorCapability,
+    };
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use utils::rand::xor_rng_u32;
+
+    fn get_function() -> PciFunction {
+        PciFunction::new_dummy_host_bridge(0)
+    }
+
+    /// Record the mapping events of a function, once they are fired.
+    fn record_mapping_events(
+        function: &mut PciFunction,
+        deferred: bool,
+    ) -> Arc<Mutex<Vec<PciMappingEvent>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        function.set_mapping_callback(
+            Arc::new(move |event: &PciMappingEvent| recorded.lock().unwrap().push(event.clone())),
+            deferred,
+        );
+
+        events
+    }
+
+    /// Fire the mapping events queued by a function and return the recorded ones.
+    fn fire_mapping_events(
+        function: &mut PciFunction,
+        events: &Arc<Mutex<Vec<PciMappingEvent>>>,
+    ) -> Vec<PciMappingEvent> {
+        function.take_mapping_notifications().fire();
+        std::mem::take(&mut *events.lock().unwrap())
+    }
+
+    #[test]
+    fn function_configuration_read_write_invalid() {
+        let mut function = get_function();
+        let value = xor_rng_u32();
+
+        // Test the register index - out of bounds.
+        assert!(function
+            .read_configuration_dword(CONFIGURATION_SPACE_SIZE)
+            .is_none());
+
+        // Test invalid offsets.
+        function.write_configuration_dword(0, value);
+        function.write_configuration_byte(0, 4, xor_rng_u32() as u8);
+        function.write_configuration_word(0, 3, xor_rng_u32() as u16);
+        function.write_configuration_word(0, 4, xor_rng_u32() as u16);
+        assert_eq!(function.read_configuration_dword(0), Some(value));
+    }
+
+    #[test]
+    fn function_configuration_read_write_byte() {
+        let mut function = get_function();
+        let values = vec![xor_rng_u32() as u8; 4];
+
+        for (pos, value) in values.iter().enumerate() {
+            function.write_configuration_byte(pos, pos, *value);
+        }
+
+        for (pos, value) in values.iter().enumerate() {
+            assert_eq!(function.read_configuration_byte(pos, pos), Some(*value));
+        }
+    }
+
+    #[test]
+    fn function_configuration_read_write_word() {
+        let mut function = get_function();
+        let values = vec![xor_rng_u32() as u16; 2];
+
+        function.write_configuration_word(0, 1, values[0]);
+        function.write_configuration_word(1, 2, values[1]);
+
+        assert_eq!(function.read_configuration_word(0, 1), Some(values[0]));
+        assert_eq!(function.read_configuration_word(1, 2), Some(values[1]));
+    }
+
+    #[test]
+    fn function_configuration_read_write_dword() {
+        let mut function = get_function();
+        let value = xor_rng_u32();
+
+        function.write_configuration_dword(0, value);
+        assert_eq!(function.read_configuration_dword(0), Some(value));
+    }
+
+    #[test]
+    fn function_configuration_read_write_all() {
+        let mut function = get_function();
+        let value = xor_rng_u32();
+
+        // Write `value` as u8 pieces in the first register.
+        for index in 0..4 {
+            function.write_configuration_byte(0, index, value.to_le_bytes()[index]);
+        }
+
+        // Write `value` as u16 pieces in the second register.
+        function.write_configuration_word(1, 0, (value & 0xFFFF) as u16);
+        function.write_configuration_word(1, 2, ((value >> 16) & 0xFFFF) as u16);
+
+        assert_eq!(
+            function.read_configuration_dword(0),
+            function.read_configuration_dword(1)
+        );
+    }
+
+    #[test]
+    fn dummy_class_code() {
+        let function = get_function();
+
+        assert_eq!(
+            function
+                .read_configuration_byte(CLASS_CODE_REGISTER, 3)
+                .unwrap(),
+            0x06
+        );
+
+        assert_eq!(
+            function
+                .read_configuration_byte(CLASS_CODE_REGISTER, 2)
+                .unwrap(),
+            0x00
+        );
+
+        assert_eq!(
+            function
+                .read_configuration_byte(CLASS_CODE_REGISTER, 1)
+                .unwrap(),
+            0x00
+        );
+    }
+
+    #[test]
+    fn describe_msix_capability() {
+        let mut function = get_function();
+
+        function
+            .add_capability(&MsixCapability {
+                table_size: 4,
+                table_bar: 1,
+                table_offset: 0x2000,
+                pba_bar: 1,
+                pba_offset: 0x3000,
+            })
+            .unwrap();
+
+        let descriptions = function.describe_capabilities();
+        assert_eq!(descriptions.len(), 1);
+        assert!(descriptions[0].starts_with("MSI-X: table size 4, BAR 1, offset 0x2000"));
+    }
+
+    #[test]
+    fn builder_bars() {
+        let function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[
+            (0, PciBarRegionType::IoRegion, 0x20, false),
+            (2, PciBarRegionType::Memory64BitRegion, 0x10_0000, true),
+        ])
+        .build()
+        .unwrap();
+
+        // IO Space indicator for BAR 0.
+        assert_eq!(function.read_configuration_dword(BAR0_REGISTER), Some(0x1));
+        // 64-bit, prefetchable memory for BAR 2, followed by its upper half.
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 2),
+            Some(0xC)
+        );
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 3),
+            Some(0x0)
+        );
+        assert!(function.get_bar(1).is_none());
+    }
+
+    #[test]
+    fn bar_sizing() {
+        let mut function = get_function();
+        function
+            .set_bar(0, PciBarRegionType::IoRegion, 0x20, false)
+            .unwrap();
+        function
+            .set_bar(1, PciBarRegionType::Memory64BitRegion, 0x10_0000, false)
+            .unwrap();
+
+        for register in BAR0_REGISTER..BAR0_REGISTER + 3 {
+            function.write_configuration_register(register, 0, &[0xFF; 4]);
+        }
+
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER),
+            Some(0xFFFF_FFE1)
+        );
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 1),
+            Some(0xFFF0_0004)
+        );
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 2),
+            Some(0xFFFF_FFFF)
+        );
+
+        // The upper half of BAR 1 is taken, and sizes must be powers of 2.
+        assert!(function
+            .set_bar(2, PciBarRegionType::Memory32BitRegion, 0x1000, false)
+            .is_err());
+        assert!(function
+            .set_bar(3, PciBarRegionType::Memory32BitRegion, 0x1800, false)
+            .is_err());
+        assert!(function
+            .set_bar(5, PciBarRegionType::Memory64BitRegion, 0x1000, false)
+            .is_err());
+    }
+
+    #[test]
+    fn bar_sizing_64bit() {
+        let mut function = get_function();
+        // A 2GB BAR fits below 4GB: the upper half sizes as all ones.
+        function
+            .set_bar(0, PciBarRegionType::Memory64BitRegion, 0x8000_0000, true)
+            .unwrap();
+        // An 8GB BAR: the two lowest bits of the upper half are part of the size.
+        function
+            .set_bar(2, PciBarRegionType::Memory64BitRegion, 0x2_0000_0000, false)
+            .unwrap();
+
+        for register in BAR0_REGISTER..BAR0_REGISTER + 4 {
+            function.write_configuration_register(register, 0, &[0xFF; 4]);
+        }
+
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER),
+            Some(0x8000_000C)
+        );
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 1),
+            Some(0xFFFF_FFFF)
+        );
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 2),
+            Some(0x0000_0004)
+        );
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 3),
+            Some(0xFFFF_FFFE)
+        );
+
+        // The guest decodes the size from both halves.
+        let size = |low: u32, high: u32| {
+            !((u64::from(high) << 32) | u64::from(low & BAR_MEMORY_ADDRESS_MASK)) + 1
+        };
+        assert_eq!(size(0x8000_000C, 0xFFFF_FFFF), 0x8000_0000);
+        assert_eq!(size(0x0000_0004, 0xFFFF_FFFE), 0x2_0000_0000);
+
+        // Programming an 8GB aligned address is kept in both halves.
+        function.write_configuration_register(BAR0_REGISTER + 2, 0, &[0; 4]);
+        function.write_configuration_register(BAR0_REGISTER + 3, 0, &[0x06, 0, 0, 0]);
+        assert_eq!(function.bar_address(2), Some(0x6_0000_0000));
+    }
+
+    #[test]
+    fn extended_dvsec_capability() {
+        let mut function = get_function();
+
+        let first = function.add_dvsec(0x1AF4, 0x0001, &[0xAA; 6]).unwrap();
+        let second = function.add_dvsec(0x1E98, 0x0002, &[]).unwrap();
+        assert_eq!(first, EXTENDED_CAPABILITIES_REGISTER);
+        assert_eq!(second, first + 4);
+
+        // Extended Capability ID, version and the link to the second DVSEC.
+        let header = function.read_configuration_dword(first).unwrap();
+        assert_eq!(header & 0xFFFF, 0x0023);
+        assert_eq!((header >> 16) & 0xF, 1);
+        assert_eq!((header >> 20) as usize, second * 4);
+
+        // Vendor ID, DVSEC length and DVSEC ID.
+        assert_eq!(function.read_configuration_word(first + 1, 0), Some(0x1AF4));
+        assert_eq!(
+            function.read_configuration_word(first + 1, 2),
+            Some(16 << 4)
+        );
+        assert_eq!(function.read_configuration_word(first + 2, 0), Some(0x0001));
+        assert_eq!(
+            function.read_configuration_word(second + 1, 0),
+            Some(0x1E98)
+        );
+        assert_eq!(
+            function.read_configuration_word(second + 2, 0),
+            Some(0x0002)
+        );
+        assert_eq!(function.read_configuration_dword(second).unwrap() >> 20, 0);
+    }
+
+    #[test]
+    fn replace_configuration_space() {
+        let mut function = get_function();
+        function
+            .set_bar(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)
+            .unwrap();
+
+        let mut image = vec![0u8; CONFIGURATION_SPACE_SIZE * 4];
+        for (index, byte) in image.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        assert!(function.replace_config(&image[1..]).is_err());
+        assert_eq!(function.read_configuration_dword(0), Some(0x1452_1D94));
+
+        function.replace_config(&image).unwrap();
+        assert_eq!(function.read_configuration_dword(0), Some(0x0302_0100));
+        assert_eq!(
+            function.read_configuration_dword(CONFIGURATION_SPACE_SIZE - 1),
+            Some(0xFFFE_FDFC)
+        );
+
+        // The BAR keeps its size, while the read-only bits come from the new image.
+        function.write_configuration_register(BAR0_REGISTER, 0, &[0xFF; 4]);
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER),
+            Some(0xFFFF_F000 | 0x110)
+        );
+    }
+
+    #[test]
+    fn config_register_enum() {
+        let mut function = get_function();
+
+        assert_eq!(
+            function.read_register(ConfigRegister::VendorDevice),
+            function.read_configuration_dword(0).unwrap()
+        );
+        assert_eq!(ConfigRegister::Interrupt.index(), 15);
+
+        function.write_register(ConfigRegister::Subsystem, 0x1234_5678);
+        assert_eq!(function.read_configuration_dword(11), Some(0x1234_5678));
+    }
+
+    #[test]
+    fn bist_self_test() {
+        let mut function = get_function();
+
+        // Without BIST support, the guest can not start the self-test.
+        function.write_configuration_register(BIST_REGISTER, BIST_OFFSET, &[0xFF]);
+        assert_eq!(
+            function.read_configuration_byte(BIST_REGISTER, BIST_OFFSET),
+            Some(0)
+        );
+
+        function.set_bist_capable(true);
+        function.write_configuration_register(BIST_REGISTER, BIST_OFFSET, &[0xFF]);
+        let bist = function
+            .read_configuration_byte(BIST_REGISTER, BIST_OFFSET)
+            .unwrap();
+        assert_eq!(bist & BIST_START, 0);
+        assert_eq!(bist & BIST_COMPLETION_CODE, 0);
+        assert_ne!(bist & BIST_CAPABLE, 0);
+    }
+
+    #[test]
+    fn list_bars() {
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[
+            (0, PciBarRegionType::IoRegion, 0x20, false),
+            (2, PciBarRegionType::Memory64BitRegion, 0x1_0000, true),
+        ])
+        .build()
+        .unwrap();
+
+        function.write_configuration_register(4, 0, &0xC020u32.to_le_bytes());
+        function.write_configuration_register(6, 0, &0xF000_0000u32.to_le_bytes());
+        function.write_configuration_register(7, 0, &0x1u32.to_le_bytes());
+
+        assert_eq!(
+            function.bars(),
+            vec![
+                PciBarConfiguration {
+                    index: 0,
+                    region_type: PciBarRegionType::IoRegion,
+                    size: 0x20,
+                    prefetchable: false,
+                    address: 0xC020,
+                },
+                PciBarConfiguration {
+                    index: 2,
+                    region_type: PciBarRegionType::Memory64BitRegion,
+                    size: 0x1_0000,
+                    prefetchable: true,
+                    address: 0x1_F000_0000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn extended_capabilities_walk() {
+        let mut function = get_function();
+        assert_eq!(function.extended_capabilities().count(), 0);
+
+        let first = function.add_dvsec(0x1AF4, 1, &[0; 4]).unwrap();
+        let second = function.add_dvsec(0x1AF4, 2, &[0; 8]).unwrap();
+
+        assert_eq!(
+            function.extended_capabilities().collect::<Vec<_>>(),
+            vec![(0x0023, 1, first), (0x0023, 1, second)]
+        );
+
+        // A list which loops back to its start must not be walked forever.
+        let header = function.read_configuration_dword(second).unwrap();
+        function.write_configuration_dword(second, (header & 0x000F_FFFF) | (0x100 << 20));
+        assert_eq!(function.extended_capabilities().count(), 2);
+    }
+
+    #[test]
+    fn vpd_handshake() {
+        let mut function = get_function();
+        let register = function
+            .add_vpd_capability(vec![0x82, 0x05, 0x00, b'P', b'C', b'I'])
+            .unwrap();
+
+        // Request a read of the bytes 3-6 and poll the flag.
+        function.write_configuration_register(register, 2, &[0x03, 0x00]);
+        let address = function.read_configuration_word(register, 2).unwrap();
+        assert_eq!(address, 0x8003);
+        assert_eq!(
+            function.read_configuration_dword(register + 1),
+            Some(u32::from_le_bytes([b'P', b'C', b'I', 0x00]))
+        );
+
+        // Write the bytes 0-3, the flag is cleared once the data is stored.
+        function.write_configuration_register(register + 1, 0, &[0x90, 0x01, 0x02, 0x03]);
+        function.write_configuration_register(register, 2, &[0x00, 0x80]);
+        assert_eq!(function.read_configuration_word(register, 2), Some(0x0000));
+
+        function.write_configuration_register(register, 2, &[0x00, 0x00]);
+        assert_eq!(
+            function.read_configuration_dword(register + 1),
+            Some(0x0302_0190)
+        );
+    }
+
+    #[test]
+    fn function_set_number() {
+        let mut function = get_function();
+
+        function.set_number(MAX_FUNCTION_NUMBER - 1).unwrap();
+        assert_eq!(function.get_number(), MAX_FUNCTION_NUMBER - 1);
+
+        match function.set_number(MAX_FUNCTION_NUMBER) {
+            Err(PciFunctionError::InvalidFunctionNumber(number)) => {
+                assert_eq!(number, MAX_FUNCTION_NUMBER)
+            }
+            _ => panic!("A function number out of range must be rejected."),
+        }
+        assert_eq!(function.get_number(), MAX_FUNCTION_NUMBER - 1);
+    }
+
+    #[test]
+    fn read_callback() {
+        let mut function = get_function();
+        let register = function
+            .add_capability(&VendorCapability { data: vec![0; 5] })
+            .unwrap();
+        let doorbell = Arc::new(AtomicU32::new(0x1234));
+
+        let counter = doorbell.clone();
+        function.set_read_callback(
+            register + 1,
+            Arc::new(move |_| counter.load(Ordering::Relaxed)),
+        );
+
+        assert_eq!(
+            function.read_configuration_register(register + 1),
+            Some(0x1234)
+        );
+        doorbell.store(0x5678, Ordering::Relaxed);
+        assert_eq!(
+            function.read_configuration_register(register + 1),
+            Some(0x5678)
+        );
+
+        // The stored value is kept and the other registers are not affected.
+        assert_eq!(function.read_configuration_dword(register + 1), Some(0));
+        assert_eq!(
+            function.read_configuration_register(register),
+            function.read_configuration_dword(register)
+        );
+
+        function.clear_read_callback(register + 1);
+        assert_eq!(function.read_configuration_register(register + 1), Some(0));
+    }
+
+    #[test]
+    fn dummy_host_bridge_identity() {
+        assert_eq!(
+            PciFunction::new_dummy_host_bridge(0).identity(),
+            (
+                crate::VENDOR_ID_DUMMY_HOST_BRIDGE,
+                crate::DEVICE_ID_DUMMY_HOST_BRIDGE
+            )
+        );
+    }
+
+    #[test]
+    fn bar_handler_registration() {
+        struct Region;
+        impl BusDevice for Region {
+            fn read(&mut self, offset: u64, data: &mut [u8]) {
+                data[0] = offset as u8;
+            }
+        }
+
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)])
+        .build()
+        .unwrap();
+        let mut mmio_bus = Bus::new();
+        let mut pio_bus = Bus::new();
+
+        assert!(function
+            .set_bar_handler(1, Arc::new(Mutex::new(Region)))
+            .is_err());
+        function
+            .set_bar_handler(0, Arc::new(Mutex::new(Region)))
+            .unwrap();
+
+        // The handlers follow the BARs on each notification of the enabled BARs.
+        let events = record_mapping_events(&mut function, false);
+        let mut sync = |function: &mut PciFunction, mmio_bus: &mut Bus| {
+            let mut notified = false;
+            for event in fire_mapping_events(function, &events) {
+                if let PciMappingEvent::Bars(_) = event {
+                    function
+                        .register_bar_handlers(mmio_bus, &mut pio_bus)
+                        .unwrap();
+                    notified = true;
+                }
+            }
+            notified
+        };
+
+        // The memory decoding is disabled at power-on.
+        function.write_configuration_register(4, 0, &0xD000_0000u32.to_le_bytes());
+        assert!(!sync(&mut function, &mut mmio_bus));
+        assert!(mmio_bus.get_device(0xD000_0000).is_none());
+
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x02]);
+        assert!(sync(&mut function, &mut mmio_bus));
+        // Registering again is a no-op.
+        function
+            .register_bar_handlers(&mut mmio_bus, &mut Bus::new())
+            .unwrap();
+
+        let mut data = [0u8; 1];
+        assert!(mmio_bus.read(0xD000_0010, &mut data));
+        assert_eq!(data, [0x10]);
+        assert!(mmio_bus.get_device(0xD000_1000).is_none());
+
+        // The handler moves along with the BAR.
+        function.write_configuration_register(4, 0, &0xD000_4000u32.to_le_bytes());
+        assert!(sync(&mut function, &mut mmio_bus));
+        assert!(mmio_bus.get_device(0xD000_0000).is_none());
+        assert!(mmio_bus.read(0xD000_4020, &mut data));
+        assert_eq!(data, [0x20]);
+
+        // And it is removed when the decoding is disabled.
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x00]);
+        assert!(sync(&mut function, &mut mmio_bus));
+        assert!(mmio_bus.get_device(0xD000_4000).is_none());
+        assert!(pio_bus.get_device(0xD000_0000).is_none());
+    }
+
+    #[test]
+    fn bar_handler_at_top_of_address_space() {
+        struct Region;
+        impl BusDevice for Region {}
+
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[(0, PciBarRegionType::Memory64BitRegion, 0x4000, false)])
+        .build()
+        .unwrap();
+        function
+            .set_bar_handler(0, Arc::new(Mutex::new(Region)))
+            .unwrap();
+        let mut mmio_bus = Bus::new();
+
+        // The size-aligned region ends at 2^64, the guest may legally program it.
+        function.write_configuration_register(4, 0, &0xFFFF_C000u32.to_le_bytes());
+        function.write_configuration_register(5, 0, &0xFFFF_FFFFu32.to_le_bytes());
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x02]);
+        assert_eq!(function.bar_address(0), Some(0xFFFF_FFFF_FFFF_C000));
+        assert_eq!(
+            function.register_bar_handlers(&mut mmio_bus, &mut Bus::new()),
+            Ok(())
+        );
+        assert!(mmio_bus.get_device(0xFFFF_FFFF_FFFF_C000).is_none());
+
+        // The handler is registered once the BAR moves to a valid address.
+        function.write_configuration_register(5, 0, &[0; 4]);
+        function
+            .register_bar_handlers(&mut mmio_bus, &mut Bus::new())
+            .unwrap();
+        assert!(mmio_bus.get_device(0xFFFF_C000).is_some());
+    }
+
+    #[test]
+    fn capability_space_exhaustion() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        let pcie = PciExpressCapability {
+            device_type: PciExpressDeviceType::RootPort,
+        };
+
+        // Each PCI Express capability takes 15 dwords out of the 48 available.
+        for _ in 0..3 {
+            function.add_capability(&pcie).unwrap();
+        }
+        assert_eq!(
+            function.add_capability(&pcie).unwrap_err(),
+            PciFunctionError::CapabilitySpaceFull(15, 3)
+        );
+
+        // The smaller capabilities still fit in the remaining dwords.
+        assert_eq!(function.add_capability(&PowerManagementCapability), Ok(61));
+        assert_eq!(
+            function.add_capability(&PowerManagementCapability),
+            Err(PciFunctionError::CapabilitySpaceFull(2, 1))
+        );
+
+        // The failed additions left the list and the extended space untouched.
+        assert_eq!(function.capabilities().count(), 4);
+        assert_eq!(function.read_configuration_dword(64), Some(0));
+    }
+
+    #[test]
+    fn configuration_fingerprint() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        let other = PciFunction::new_dummy_host_bridge(0);
+
+        // Equal configuration spaces have equal fingerprints.
+        assert_eq!(function.config_fingerprint(), other.config_fingerprint());
+        assert_eq!(
+            function.config_fingerprint(),
+            function.clone().config_fingerprint()
+        );
+
+        // A write changes the fingerprint, writing the old value back restores it.
+        function.write_configuration_byte(CONFIGURATION_HEADER_SIZE, 0, 0x01);
+        assert_ne!(function.config_fingerprint(), other.config_fingerprint());
+        function.write_configuration_byte(CONFIGURATION_HEADER_SIZE, 0, 0x00);
+        assert_eq!(function.config_fingerprint(), other.config_fingerprint());
+        function.write_configuration_byte(CONFIGURATION_HEADER_SIZE, 0, 0x01);
+
+        // The layout of the registers matters, not only their bytes.
+        let mut swapped = PciFunction::new_dummy_host_bridge(0);
+        swapped.write_configuration_byte(CONFIGURATION_HEADER_SIZE, 1, 0x01);
+        assert_ne!(function.config_fingerprint(), swapped.config_fingerprint());
+    }
+
+    #[test]
+    fn resizable_bar() {
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[
+            (0, PciBarRegionType::IoRegion, 0x100, false),
+            (1, PciBarRegionType::Memory32BitRegion, 0x10_0000, false),
+        ])
+        .build()
+        .unwrap();
+
+        // Only memory BARs with a supported size can be resized.
+        assert_eq!(
+            function.add_resizable_bar_capability(0, 0b111),
+            Err(PciFunctionError::InvalidBarIndex(0))
+        );
+        assert_eq!(
+            function.add_resizable_bar_capability(1, 0b110),
+            Err(PciFunctionError::InvalidBarSize(0x10_0000))
+        );
+
+        // A 32-bit BAR cannot describe more than 2GB.
+        assert_eq!(
+            function.add_resizable_bar_capability(1, 0b1_0000_0000_0111),
+            Err(PciFunctionError::InvalidBarSize(0x1_0000_0000))
+        );
+
+        // 1MB, 2MB and 4MB are supported.
+        let register = function.add_resizable_bar_capability(1, 0b111).unwrap();
+        assert_eq!(function.read_configuration_dword(register + 1), Some(0x70));
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0x21));
+        function.write_configuration_register(5, 0, &[0xFF; 4]);
+        assert_eq!(function.read_configuration_dword(5), Some(0xFFF0_0000));
+
+        // The guest selects 4MB.
+        function.write_configuration_register(register + 2, 1, &[0x02]);
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0x221));
+        assert_eq!(function.get_bar(1).unwrap().size, 0x40_0000);
+        assert_eq!(function.read_configuration_dword(5), Some(0xFFC0_0000));
+        function.write_configuration_register(5, 0, &0xE010_0000u32.to_le_bytes());
+        assert_eq!(function.read_configuration_dword(5), Some(0xE000_0000));
+
+        // The new region is notified once the memory decoding is enabled.
+        let events = record_mapping_events(&mut function, false);
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x02]);
+        fire_mapping_events(&mut function, &events);
+        function.write_configuration_register(register + 2, 1, &[0x01]);
+        match fire_mapping_events(&mut function, &events).as_slice() {
+            [PciMappingEvent::Bars(bars)] => {
+                assert_eq!(bars.len(), 1);
+                assert_eq!((bars[0].address, bars[0].size), (0xE000_0000, 0x20_0000));
+            }
+            events => panic!("Unexpected mapping events: {:?}", events),
+        }
+        // Selecting the current size again changes nothing.
+        function.write_configuration_register(register + 2, 1, &[0x01]);
+        assert!(fire_mapping_events(&mut function, &events).is_empty());
+        function.write_configuration_register(register + 2, 1, &[0x02]);
+        fire_mapping_events(&mut function, &events);
+
+        // Unsupported sizes and writes outside the BAR Size field are ignored.
+        function.write_configuration_register(register + 2, 1, &[0x05]);
+        function.write_configuration_register(register + 1, 0, &[0xFF; 4]);
+        function.write_configuration_register(register + 2, 0, &[0x07]);
+        assert_eq!(function.read_configuration_dword(register + 1), Some(0x70));
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0x221));
+        assert_eq!(function.get_bar(1).unwrap().size, 0x40_0000);
+
+        // A capability added directly for a BAR smaller than 1MB never resizes it.
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)])
+        .build()
+        .unwrap();
+        let register = function
+            .add_extended_capability(&ResizableBarCapability {
+                bar_index: 0,
+                supported_sizes: 0b1,
+                size: 0,
+            })
+            .unwrap();
+        function.write_configuration_register(register + 2, 1, &[0x05]);
+        function.write_configuration_register(register + 2, 1, &[0x00]);
+        assert_eq!(function.get_bar(0).unwrap().size, 0x1000);
+
+        // Nor does one added directly with sizes the 32-bit BAR cannot describe.
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[(0, PciBarRegionType::Memory32BitRegion, 0x10_0000, false)])
+        .build()
+        .unwrap();
+        let register = function
+            .add_extended_capability(&ResizableBarCapability {
+                bar_index: 0,
+                supported_sizes: 0b1_0000_0000_0001,
+                size: 0,
+            })
+            .unwrap();
+        function.write_configuration_register(register + 2, 1, &[0x0C]);
+        assert_eq!(function.get_bar(0).unwrap().size, 0x10_0000);
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0x20));
+        function.write_configuration_register(4, 0, &[0xFF; 4]);
+        assert_eq!(function.read_configuration_dword(4), Some(0xFFF0_0000));
+    }
+
+    #[test]
+    fn reset_after_guest_state() {
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[(0, PciBarRegionType::Memory32BitRegion, 0x10_0000, false)])
+        .build()
+        .unwrap();
+        let register = function.add_resizable_bar_capability(0, 0b111).unwrap();
+        function.set_bist_capable(true);
+
+        // The guest resizes the BAR to 4MB, and the function raises its interrupt.
+        function.write_configuration_register(register + 2, 1, &[0x02]);
+        assert_eq!(function.get_bar(0).unwrap().size, 0x40_0000);
+        function.set_intx_asserted(true);
+
+        // The reset brings back the declared size and the runtime state is not kept.
+        function.reset();
+        assert_eq!(function.get_bar(0).unwrap().size, 0x10_0000);
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0x20));
+        function.write_configuration_register(BAR0_REGISTER, 0, &[0xFF; 4]);
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER),
+            Some(0xFFF0_0000)
+        );
+        function.set_intx_asserted(true);
+        function.write_configuration_register(BIST_REGISTER, BIST_OFFSET, &[BIST_START]);
+        function.reset();
+        assert_eq!(
+            function
+                .read_configuration_word(STATUS_REGISTER, STATUS_OFFSET)
+                .unwrap()
+                & STATUS_INTERRUPT_STATUS,
+            0
+        );
+        assert_eq!(
+            function.read_configuration_byte(BIST_REGISTER, BIST_OFFSET),
+            Some(BIST_CAPABLE)
+        );
+    }
+
+    #[test]
+    fn read_header_and_ranges() {
+        let function = PciFunction::new_dummy_host_bridge(0);
+
+        let header = function.read_header();
+        assert_eq!(header[VENDOR_ID_REGISTER], 0x1452_1D94);
+        assert_eq!(
+            header[CLASS_CODE_REGISTER],
+            function
+                .read_configuration_dword(CLASS_CODE_REGISTER)
+                .unwrap()
+        );
+
+        assert_eq!(
+            function.read_config_range(0, CONFIGURATION_HEADER_SIZE),
+            Some(header.to_vec())
+        );
+        assert_eq!(
+            function.read_config_range(CONFIGURATION_SPACE_SIZE - 2, 2),
+            Some(vec![0, 0])
+        );
+        assert_eq!(
+            function.read_config_range(CONFIGURATION_SPACE_SIZE, 0),
+            Some(vec![])
+        );
+        assert_eq!(
+            function.read_config_range(CONFIGURATION_SPACE_SIZE - 2, 3),
+            None
+        );
+        assert_eq!(function.read_config_range(usize::MAX, 2), None);
+    }
+
+    #[test]
+    fn capability_pointers_are_bytes() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        function.write_configuration_dword(CAPABILITIES_POINTER_REGISTER, 0xAAAA_AA00);
+
+        let first = function.add_capability(&PowerManagementCapability).unwrap();
+        assert_eq!(
+            function.read_configuration_dword(CAPABILITIES_POINTER_REGISTER),
+            Some(0xAAAA_AA00 | (first * 4) as u32)
+        );
+        let pm = function.read_configuration_dword(first).unwrap();
+
+        // Linking the next capability only changes the next pointer of the previous one.
+        let second = function.add_capability(&VpdCapability).unwrap();
+        assert_eq!(
+            function.read_configuration_dword(first),
+            Some(pm | ((second * 4) << 8) as u32)
+        );
+        assert_eq!(
+            function.read_configuration_dword(CAPABILITIES_POINTER_REGISTER),
+            Some(0xAAAA_AA00 | (first * 4) as u32)
+        );
+    }
+
+    #[test]
+    fn byte_offset_accesses() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        let offset = CONFIGURATION_HEADER_SIZE * 4 + 2;
+
+        function.write_bytes(offset, &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        let mut buf = [0u8; 6];
+        function.read_bytes(offset, &mut buf);
+        assert_eq!(buf, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        assert_eq!(
+            function.read_config_range(CONFIGURATION_HEADER_SIZE, 3),
+            Some(vec![0x2211_0000, 0x6655_4433, 0])
+        );
+
+        // The write masks still apply: the Header Type is read-only, and so is the BIST
+        // register without BIST support.
+        function.write_bytes(BIST_REGISTER * 4 + 1, &[0xFF; 3]);
+        let mut buf = [0u8; 3];
+        function.read_bytes(BIST_REGISTER * 4 + 1, &mut buf);
+        assert_eq!(buf, [0xFF, 0x00, 0x00]);
+
+        // The bytes outside the configuration space read as all ones.
+        let mut buf = [0u8; 4];
+        function.read_bytes(CONFIGURATION_SPACE_SIZE * 4 - 2, &mut buf);
+        assert_eq!(buf, [0x00, 0x00, 0xFF, 0xFF]);
+        function.write_bytes(usize::MAX, &[0x00; 2]);
+    }
+
+    #[test]
+    fn decode_change_callback() {
+        let mut function = get_function();
+        let events = record_mapping_events(&mut function, false);
+
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x02]);
+        // Writing the same decode bits, or other bits, does not notify again.
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x02]);
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x06]);
+        function.write_configuration_register(COMMAND_REGISTER, 2, &[0xFF, 0xFF]);
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x00]);
+
+        // The events are only fired once the write completed.
+        assert!(events.lock().unwrap().is_empty());
+        function.take_mapping_notifications().fire();
+        let changes: Vec<PciMappingEvent> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| matches!(event, PciMappingEvent::Decode { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                PciMappingEvent::Decode {
+                    memory: true,
+                    io: false
+                },
+                PciMappingEvent::Decode {
+                    memory: false,
+                    io: false
+                }
+            ]
+        );
+        // Both changes also notify the enabled BARs.
+        function.take_mapping_notifications().fire();
+        assert_eq!(events.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn power_budget_table() {
+        let mut function = get_function();
+        let entries = [
+            PowerBudgetEntry {
+                base_power: 25,
+                pm_state: 0,
+                power_rail: 1,
+                ..Default::default()
+            },
+            PowerBudgetEntry {
+                base_power: 150,
+                data_scale: 1,
+                pm_state: 3,
+                power_type: 1,
+                ..Default::default()
+            },
+        ];
+
+        let register = function.add_power_budgeting_capability(&entries).unwrap();
+        assert_eq!(
+            function.read_configuration_dword(register),
+            Some(0x0001_0004)
+        );
+        assert_eq!(
+            function.read_configuration_dword(register + 2),
+            Some(0x0004_0019)
+        );
+
+        // The guest walks the table through the Data Select register.
+        function.write_configuration_register(register + 1, 0, &[0x01]);
+        assert_eq!(
+            function.read_configuration_dword(register + 2),
+            Some(0x0000_E196)
+        );
+        function.write_configuration_register(register + 1, 0, &[0x02]);
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0));
+
+        // The Data register is read-only.
+        function.write_configuration_register(register + 2, 0, &[0xFF; 4]);
+        assert_eq!(function.read_configuration_dword(register + 2), Some(0));
+        assert_eq!(
+            function.extended_capabilities().collect::<Vec<_>>(),
+            vec![(0x0004, 1, register)]
+        );
+    }
+
+    #[test]
+    fn sized_reads() {
+        let function = get_function();
+
+        assert_eq!(function.read(0, 4), Some(0x1452_1D94));
+        assert_eq!(function.read(0, 2), Some(0x1D94));
+        assert_eq!(function.read(2, 2), Some(0x1452));
+        assert_eq!(function.read(1, 1), Some(0x1D));
+        assert_eq!(function.read(3, 1), Some(0x14));
+        assert_eq!(function.read(CLASS_CODE_REGISTER * 4 + 3, 1), Some(0x06));
+
+        // Unaligned accesses and invalid widths are rejected.
+        assert_eq!(function.read(1, 2), None);
+        assert_eq!(function.read(2, 4), None);
+        assert_eq!(function.read(0, 3), None);
+        assert_eq!(function.read(0, 8), None);
+        assert_eq!(function.read(CONFIGURATION_SPACE_SIZE * 4, 4), None);
+    }
+
+    #[test]
+    fn rom_map_callback() {
+        let mut function = get_function();
+        function.set_expansion_rom(0x10_0000).unwrap();
+        let events = record_mapping_events(&mut function, false);
+        let is_rom = |event: &PciMappingEvent| matches!(event, PciMappingEvent::Rom { .. });
+
+        // The ROM enable bit alone does not map the ROM.
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x00]);
+        function.write_configuration_register(
+            EXPANSION_ROM_REGISTER,
+            0,
+            &(0xFEB0_0000 | EXPANSION_ROM_ENABLE).to_le_bytes(),
+        );
+        assert!(!fire_mapping_events(&mut function, &events)
+            .iter()
+            .any(is_rom));
+
+        // Neither does the Memory Space bit alone.
+        function.write_configuration_register(EXPANSION_ROM_REGISTER, 0, &[0x00]);
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x02]);
+        assert!(!fire_mapping_events(&mut function, &events)
+            .iter()
+            .any(is_rom));
+
+        function.write_configuration_register(EXPANSION_ROM_REGISTER, 0, &[0x01]);
+        assert!(function.is_rom_mapped());
+        function.write_configuration_register(COMMAND_REGISTER, 0, &[0x00]);
+        assert!(!function.is_rom_mapped());
+
+        let mappings: Vec<PciMappingEvent> = fire_mapping_events(&mut function, &events)
+            .into_iter()
+            .filter(is_rom)
+            .collect();
+        assert_eq!(
+            mappings,
+            vec![
+                PciMappingEvent::Rom {
+                    mapped: true,
+                    address: 0xFEB0_0000
+                },
+                PciMappingEvent::Rom {
+                    mapped: false,
+                    address: 0xFEB0_0000
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn configuration_space_diff() {
+        let mut function = get_function();
+        let snapshot = function
+            .read_config_range(0, CONFIGURATION_SPACE_SIZE)
+            .unwrap();
+        assert!(function.diff_from(&snapshot).is_empty());
+
+        function.write_configuration_byte(15, 0, 0x0A);
+        function.write_configuration_dword(100, 0xDEAD_BEEF);
+
+        assert_eq!(
+            function.diff_from(&snapshot),
+            vec![(15, 0, 0x0A), (100, 0, 0xDEAD_BEEF)]
+        );
+        assert_eq!(
+            PciFunction::diff(&[1, 2, 3], &[1, 4]),
+            vec![(1, 2, 4), (2, 3, 0)]
+        );
+    }
+
+    #[test]
+    fn config_backing() {
+        type AccessLog = Arc<Mutex<Vec<(usize, Option<u32>)>>>;
+
+        struct RecordingBacking {
+            accesses: AccessLog,
+        }
+
+        impl PciConfigBacking for RecordingBacking {
+            fn read(&self, register: usize) -> Option<u32> {
+                self.accesses.lock().unwrap().push((register, None));
+                Some(0xABCD_0000 | register as u32)
+            }
+
+            fn write(&mut self, register: usize, value: u32) {
+                self.accesses.lock().unwrap().push((register, Some(value)));
+            }
+        }
+
+        let mut function = get_function();
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        function.set_config_backing(Some(Box::new(RecordingBacking {
+            accesses: accesses.clone(),
+        })));
+
+        assert_eq!(function.read_configuration_register(0), Some(0xABCD_0000));
+        assert_eq!(function.read(8, 2), Some(0x0002));
+        // The write is merged with the value of the backing.
+        function.write_configuration_register(15, 0, &[0x0A]);
+        // The BIST register of a function without BIST support is read-only.
+        function.write_configuration_register(BIST_REGISTER, 3, &[0xFF]);
+
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            vec![
+                (0, None),
+                (2, None),
+                (15, None),
+                (15, Some(0xABCD_000A)),
+                (3, None),
+                (3, Some(0xABCD_0003)),
+            ]
+        );
+        // The configuration space of the function is untouched.
+        assert_eq!(function.read_configuration_dword(15), Some(0));
+
+        function.set_config_backing(None);
+        assert_eq!(function.read_configuration_register(0), Some(0x1452_1D94));
+    }
+
+    #[test]
+    fn min_gnt_max_lat() {
+        let mut function = get_function();
+        assert_eq!(function.get_min_gnt(), Some(0));
+        assert_eq!(function.get_max_lat(), Some(0));
+
+        function.set_min_gnt(0x08);
+        function.set_max_lat(0x20);
+        assert_eq!(function.get_min_gnt(), Some(0x08));
+        assert_eq!(function.get_max_lat(), Some(0x20));
+        assert_eq!(
+            function.read_configuration_register(ConfigRegister::Interrupt.index()),
+            Some(0x2008_0000)
+        );
+    }
+
+    #[test]
+    fn would_write_apply() {
+        let mut function = get_function();
+
+        assert_eq!(
+            function.would_write_apply(VENDOR_ID_REGISTER, VENDOR_ID_OFFSET, 2),
+            WriteOutcome::Masked(0x0000_FFFF)
+        );
+        assert_eq!(
+            function.would_write_apply(COMMAND_REGISTER, COMMAND_OFFSET, 2),
+            WriteOutcome::Masked(u32::from(COMMAND_RESERVED))
+        );
+        assert_eq!(
+            function.would_write_apply(COMMAND_REGISTER, COMMAND_OFFSET, 1),
+            WriteOutcome::Applied
+        );
+        // Only the Start BIST bit of a BIST capable function is writable.
+        function.set_bist_capable(true);
+        assert_eq!(
+            function.would_write_apply(BIST_REGISTER, 0, 4),
+            WriteOutcome::Masked(0xBFFF_0000)
+        );
+
+        // The Class Code, the Capabilities Pointer, the Interrupt Pin, Min_Gnt and Max_Lat
+        // are read-only.
+        assert_eq!(
+            function.would_write_apply(CLASS_CODE_REGISTER, 0, 4),
+            WriteOutcome::Masked(0xFFFF_FFFF)
+        );
+        assert_eq!(
+            function.would_write_apply(CAPABILITIES_POINTER_REGISTER, 0, 1),
+            WriteOutcome::Masked(0xFF)
+        );
+        assert_eq!(
+            function.would_write_apply(INTERRUPT_LINE_REGISTER, 0, 4),
+            WriteOutcome::Masked(0xFFFF_FF00)
+        );
+
+        // The Expansion ROM Base Address register is read-only without an Expansion ROM.
+        assert_eq!(
+            function.would_write_apply(EXPANSION_ROM_REGISTER, 0, 4),
+            WriteOutcome::Masked(0xFFFF_FFFF)
+        );
+        assert!(function.set_expansion_rom(0x1000 + 1).is_err());
+        function.set_expansion_rom(0x1_0000).unwrap();
+        assert_eq!(
+            function.would_write_apply(EXPANSION_ROM_REGISTER, 0, 4),
+            WriteOutcome::Masked(0x0000_FFFE)
+        );
+        function.write_configuration_register(EXPANSION_ROM_REGISTER, 0, &[0xFF; 4]);
+        assert_eq!(
+            function.read_configuration_dword(EXPANSION_ROM_REGISTER),
+            Some(0xFFFF_0001)
+        );
+
+        assert_eq!(
+            function.would_write_apply(CONFIGURATION_SPACE_SIZE, 0, 4),
+            WriteOutcome::Dropped
+        );
+        assert_eq!(
+            function.would_write_apply(COMMAND_REGISTER, 3, 2),
+            WriteOutcome::Dropped
+        );
+        assert_eq!(
+            function.would_write_apply(COMMAND_REGISTER, 0, 3),
+            WriteOutcome::Masked(0x0008_F800)
+        );
+        assert_eq!(
+            function.would_write_apply(COMMAND_REGISTER, 2, 3),
+            WriteOutcome::Dropped
+        );
+        function.set_guest_writable(false);
+        assert_eq!(
+            function.would_write_apply(COMMAND_REGISTER, COMMAND_OFFSET, 2),
+            WriteOutcome::Dropped
+        );
+    }
 
-    fn get_function() -> PciFunction {
-        PciFunction::new_dummy_host_bridge(0)
+    #[test]
+    fn serial_number_capability() {
+        let mut function = get_function();
+        let register = function
+            .add_serial_number_capability(0x0011_2233_4455_6677)
+            .unwrap();
+        assert_eq!(register, EXTENDED_CAPABILITIES_REGISTER);
+
+        assert_eq!(
+            function.read_configuration_register(register),
+            Some(0x0001_0003)
+        );
+        assert_eq!(
+            function.read_configuration_register(register + 1),
+            Some(0x4455_6677)
+        );
+        assert_eq!(
+            function.read_configuration_register(register + 2),
+            Some(0x0011_2233)
+        );
+        assert_eq!(
+            function.extended_capabilities().collect::<Vec<_>>(),
+            vec![(
+                PciExtendedCapabilityId::DeviceSerialNumber as u16,
+                1,
+                register
+            )]
+        );
+        // The next extended capability follows the serial number.
+        assert_eq!(function.add_dvsec(0x1AF4, 1, &[]).unwrap(), register + 3);
     }
 
     #[test]
-    fn function_configuration_read_write_invalid() {
+    fn deferred_bar_registration() {
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::OtherBridgeDevice,
+            PciHeaderType::Type0,
+        )
+        .bars(&[
+            (0, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+            (1, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+            (2, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+        ])
+        .build()
+        .unwrap();
+        function.write_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET, 0);
+
+        let events = record_mapping_events(&mut function, true);
+        let batches = |function: &mut PciFunction| -> Vec<Vec<u64>> {
+            fire_mapping_events(function, &events)
+                .into_iter()
+                .filter_map(|event| match event {
+                    PciMappingEvent::Bars(bars) => {
+                        Some(bars.iter().map(|bar| bar.address).collect())
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        for index in 0..3 {
+            let address = 0xE000_0000u32 + index as u32 * 0x1000;
+            function.write_configuration_register(BAR0_REGISTER + index, 0, &address.to_le_bytes());
+        }
+        assert!(batches(&mut function).is_empty());
+
+        function.write_configuration_register(
+            COMMAND_REGISTER,
+            COMMAND_OFFSET,
+            &[COMMAND_MEMORY_SPACE as u8, 0],
+        );
+        assert_eq!(
+            batches(&mut function),
+            vec![vec![0xE000_0000, 0xE000_1000, 0xE000_2000]]
+        );
+
+        // A BAR moved while the decoding is enabled is staged until committed.
+        function.write_configuration_register(BAR0_REGISTER, 0, &0xE000_8000u32.to_le_bytes());
+        assert!(batches(&mut function).is_empty());
+        function.commit_bar_writes();
+        function.commit_bar_writes();
+        let committed = batches(&mut function);
+        assert_eq!(committed.len(), 1);
+        assert_eq!(committed[0][0], 0xE000_8000);
+    }
+
+    #[test]
+    fn implemented_registers() {
         let mut function = get_function();
-        let value = xor_rng_u32();
+        for register in 0..CONFIGURATION_HEADER_SIZE {
+            assert!(function.is_register_implemented(register));
+        }
+        assert!(!function.is_register_implemented(CONFIGURATION_HEADER_SIZE));
+        assert!(!function.is_register_implemented(EXTENDED_CAPABILITIES_REGISTER));
+        assert!(!function.is_register_implemented(CONFIGURATION_SPACE_SIZE));
 
-        // Test the register index - out of bounds.
-        assert!(function
-            .read_configuration_dword(CONFIGURATION_SPACE_SIZE)
-            .is_none());
+        let register = function.add_serial_number_capability(1).unwrap();
+        for register in register..register + 3 {
+            assert!(function.is_register_implemented(register));
+        }
+        assert!(!function.is_register_implemented(register + 3));
+        assert!(!function.is_register_implemented(CONFIGURATION_HEADER_SIZE));
+    }
 
-        // Test invalid offsets.
-        function.write_configuration_dword(0, value);
-        function.write_configuration_byte(0, 4, xor_rng_u32() as u8);
-        function.write_configuration_word(0, 3, xor_rng_u32() as u16);
-        function.write_configuration_word(0, 4, xor_rng_u32() as u16);
-        assert_eq!(function.read_configuration_dword(0), Some(value));
+    #[test]
+    fn checked_configuration_writes() {
+        let mut function = get_function();
+
+        assert_eq!(function.try_write_configuration_word(3, 0, 0x4010), Ok(()));
+        assert_eq!(function.read_configuration_word(3, 0), Some(0x4010));
+        assert_eq!(function.try_write_configuration_byte(15, 0, 0x0A), Ok(()));
+        assert_eq!(function.read_configuration_byte(15, 0), Some(0x0A));
+        // The Interrupt Pin is read-only.
+        assert_eq!(
+            function.try_write_configuration_byte(15, 1, 0x02),
+            Err(ConfigAccessError::ReadOnly(0x0000_0200))
+        );
+
+        assert_eq!(
+            function.try_write_configuration_dword(CONFIGURATION_SPACE_SIZE, 0),
+            Err(ConfigAccessError::InvalidRegister(CONFIGURATION_SPACE_SIZE))
+        );
+        assert_eq!(
+            function.try_write_configuration_byte(15, 4, 0),
+            Err(ConfigAccessError::InvalidOffset(4))
+        );
+        assert_eq!(
+            function.try_write_configuration_word(15, 3, 0),
+            Err(ConfigAccessError::InvalidOffset(3))
+        );
+
+        // The Device ID (0x1452) is read-only, but writing its current value is allowed.
+        assert_eq!(
+            function.try_write_configuration_word(DEVICE_ID_REGISTER, DEVICE_ID_OFFSET, 0x1234),
+            Err(ConfigAccessError::ReadOnly(0x0666_0000))
+        );
+        let identity = function
+            .read_configuration_dword(VENDOR_ID_REGISTER)
+            .unwrap();
+        assert_eq!(
+            function.try_write_configuration_dword(VENDOR_ID_REGISTER, identity),
+            Ok(())
+        );
+        assert_eq!(
+            function.read_configuration_dword(VENDOR_ID_REGISTER),
+            Some(identity)
+        );
+
+        // Once the guest wrote to the function, the checked writes are still kept by a reset.
+        function.write_configuration_register(COMMAND_REGISTER, COMMAND_OFFSET, &[0x02, 0]);
+        assert_eq!(function.try_write_configuration_byte(15, 0, 0x0B), Ok(()));
+        function.write_configuration_register(15, 0, &[0x0C]);
+        function.reset();
+        assert_eq!(function.read_configuration_byte(15, 0), Some(0x0B));
+        assert_eq!(
+            function.read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET),
+            Some(0)
+        );
     }
 
     #[test]
-    fn function_configuration_read_write_byte() {
+    fn msix_enabled_vectors() {
         let mut function = get_function();
-        let values = vec![xor_rng_u32() as u8; 4];
+        assert_eq!(function.msix_enabled_vectors(), 0);
 
-        for (pos, value) in values.iter().enumerate() {
-            function.write_configuration_byte(pos, pos, *value);
+        let register = function
+            .add_capability(&MsixCapability {
+                table_size: 4,
+                table_bar: 0,
+                table_offset: 0,
+                pba_bar: 0,
+                pba_offset: 0x800,
+            })
+            .unwrap();
+        for vector in 0..4 {
+            function.set_msix_vector_masked(vector, false);
         }
+        // MSI-X is disabled.
+        assert_eq!(function.msix_enabled_vectors(), 0);
 
-        for (pos, value) in values.iter().enumerate() {
-            assert_eq!(function.read_configuration_byte(pos, pos), Some(*value));
+        let control = function.read_configuration_word(register, 2).unwrap();
+        function.write_configuration_register(register, 2, &(control | MSIX_ENABLE).to_le_bytes());
+        assert_eq!(function.msix_enabled_vectors(), 4);
+
+        function.set_msix_vector_masked(1, true);
+        function.set_msix_vector_masked(3, true);
+        assert_eq!(function.msix_enabled_vectors(), 2);
+
+        function.write_configuration_register(
+            register,
+            2,
+            &(control | MSIX_ENABLE | MSIX_FUNCTION_MASK).to_le_bytes(),
+        );
+        assert_eq!(function.msix_enabled_vectors(), 0);
+    }
+
+    #[test]
+    fn subsystem() {
+        let function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::EthernetController,
+            PciHeaderType::Type0,
+        )
+        .subsystem(0x0042, 0x1AF4)
+        .build()
+        .unwrap();
+        assert_eq!(function.subsystem(), Some((0x0042, 0x1AF4)));
+
+        assert_eq!(get_function().subsystem(), Some((0, 0)));
+        assert_eq!(PciFunction::new_bridge(0, 1).subsystem(), None);
+    }
+
+    #[test]
+    fn fault_callback() {
+        let mut function = get_function();
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let recorded = faults.clone();
+        function.set_fault_callback(Arc::new(move |fault: &ConfigFault| {
+            recorded.lock().unwrap().push(*fault);
+        }));
+
+        // The legal writes, including the sizing of an unused BAR.
+        function.write_configuration_register(COMMAND_REGISTER, COMMAND_OFFSET, &[0x06, 0]);
+        function.write_configuration_register(BAR0_REGISTER + 2, 0, &[0xFF; 4]);
+        function.write_configuration_register(VENDOR_ID_REGISTER, 0, &[0x94, 0x1D]);
+        assert!(faults.lock().unwrap().is_empty());
+
+        function.write_configuration_register(DEVICE_ID_REGISTER, DEVICE_ID_OFFSET, &[0, 0]);
+        function.write_configuration_register(CONFIGURATION_HEADER_SIZE + 4, 1, &[0xAA]);
+        // The Interrupt Line is writable, the Interrupt Pin is not.
+        let pin = function
+            .read_configuration_byte(INTERRUPT_LINE_REGISTER, INTERRUPT_LINE_OFFSET + 1)
+            .unwrap();
+        function.write_configuration_register(
+            INTERRUPT_LINE_REGISTER,
+            INTERRUPT_LINE_OFFSET,
+            &[0x0A, !pin],
+        );
+        assert_eq!(
+            *faults.lock().unwrap(),
+            vec![
+                ConfigFault {
+                    register: DEVICE_ID_REGISTER,
+                    offset: DEVICE_ID_OFFSET,
+                    len: 2,
+                    kind: ConfigFaultKind::ReadOnlyWrite(0x1452_0000),
+                },
+                ConfigFault {
+                    register: CONFIGURATION_HEADER_SIZE + 4,
+                    offset: 1,
+                    len: 1,
+                    kind: ConfigFaultKind::UnimplementedRegister,
+                },
+                ConfigFault {
+                    register: INTERRUPT_LINE_REGISTER,
+                    offset: INTERRUPT_LINE_OFFSET,
+                    len: 2,
+                    kind: ConfigFaultKind::ReadOnlyWrite(0xFF00),
+                },
+            ]
+        );
+        // The guest still sees the writes applied as usual.
+        assert_eq!(function.identity(), (0x1D94, 0x1452));
+        assert_eq!(
+            function.read_configuration_byte(CONFIGURATION_HEADER_SIZE + 4, 1),
+            Some(0xAA)
+        );
+    }
+
+    #[test]
+    fn undeclared_bars_size_as_unused() {
+        let mut function = get_function();
+        function
+            .set_bar(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)
+            .unwrap();
+        for register in BAR0_REGISTER..BAR0_REGISTER + NUM_BAR_REGISTERS {
+            function.write_configuration_register(register, 0, &[0xFF; 4]);
+        }
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER),
+            Some(0xFFFF_F000)
+        );
+        for register in BAR0_REGISTER + 1..BAR0_REGISTER + NUM_BAR_REGISTERS {
+            assert_eq!(function.read_configuration_dword(register), Some(0));
+        }
+
+        // A bridge only has two BARs, the registers after them are the bus numbers.
+        let mut bridge = PciFunction::new_bridge(0, 1);
+        for register in BAR0_REGISTER..BAR0_REGISTER + NUM_BRIDGE_BAR_REGISTERS {
+            bridge.write_configuration_register(register, 0, &[0xFF; 4]);
+            assert_eq!(bridge.read_configuration_dword(register), Some(0));
         }
+        bridge.write_configuration_register(
+            BAR0_REGISTER + NUM_BRIDGE_BAR_REGISTERS,
+            0,
+            &[0, 1, 1, 0],
+        );
+        assert_eq!(
+            bridge.read_configuration_dword(BAR0_REGISTER + NUM_BRIDGE_BAR_REGISTERS),
+            Some(0x0001_0100)
+        );
     }
 
     #[test]
-    fn function_configuration_read_write_word() {
+    fn bridge_bar_count() {
+        let mut bridge = PciFunction::new_bridge(0, 1);
+        assert_eq!(bridge.bar_count(), 2);
+        assert_eq!(get_function().bar_count(), 6);
+
+        assert_eq!(
+            bridge.set_bar(2, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+            Err(PciFunctionError::InvalidBarIndex(2))
+        );
+        assert_eq!(
+            bridge.set_bar(1, PciBarRegionType::Memory64BitRegion, 0x1000, false),
+            Err(PciFunctionError::InvalidBarIndex(1))
+        );
+        assert_eq!(
+            bridge.set_bar(0, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+            Ok(())
+        );
+        // The bus numbers are left untouched.
+        assert_eq!(bridge.get_secondary_bus(), Some(1));
+    }
+
+    #[test]
+    fn capability_list_cycles() {
         let mut function = get_function();
-        let values = vec![xor_rng_u32() as u16; 2];
+        assert_eq!(function.validate(), Ok(()));
 
-        function.write_configuration_word(0, 1, values[0]);
-        function.write_configuration_word(1, 2, values[1]);
+        let first = function.add_capability(&VpdCapability).unwrap();
+        let second = function.add_capability(&VpdCapability).unwrap();
+        function.add_dvsec(0x1AF4, 1, &[]).unwrap();
+        assert_eq!(function.validate(), Ok(()));
 
-        assert_eq!(function.read_configuration_word(0, 1), Some(values[0]));
-        assert_eq!(function.read_configuration_word(1, 2), Some(values[1]));
+        // A device model patching the second capability to point to itself.
+        function.write_configuration_byte(second, 1, (second * 4) as u8);
+        assert_eq!(
+            function.validate(),
+            Err(PciFunctionError::CapabilityListCycle(second))
+        );
+        assert_eq!(
+            function.add_capability(&VpdCapability),
+            Err(PciFunctionError::CapabilityListCycle(second))
+        );
+
+        // A pointer back into the configuration header.
+        function.write_configuration_byte(second, 1, 0x10);
+        assert_eq!(
+            function.validate(),
+            Err(PciFunctionError::InvalidCapabilityPointer(0x10))
+        );
+
+        // A list leading to the free space would be looped by the next capability.
+        function.write_configuration_byte(second, 1, ((second + 2) * 4) as u8);
+        assert_eq!(
+            function.add_capability(&VpdCapability),
+            Err(PciFunctionError::CapabilityListCycle(second + 2))
+        );
+        function.write_configuration_byte(second, 1, 0);
+        assert!(function.add_capability(&VpdCapability).is_ok());
+        assert_eq!(function.validate(), Ok(()));
+
+        // An extended capability pointing to itself.
+        let header = function
+            .read_configuration_dword(EXTENDED_CAPABILITIES_REGISTER)
+            .unwrap();
+        function.write_configuration_dword(
+            EXTENDED_CAPABILITIES_REGISTER,
+            header | ((EXTENDED_CAPABILITIES_REGISTER as u32 * 4) << 20),
+        );
+        assert_eq!(
+            function.validate(),
+            Err(PciFunctionError::CapabilityListCycle(
+                EXTENDED_CAPABILITIES_REGISTER
+            ))
+        );
+        assert_eq!(function.capabilities().next().unwrap().1, first);
     }
 
     #[test]
-    fn function_configuration_read_write_dword() {
+    fn config_copy() {
+        let function = Mutex::new(get_function());
+        function
+            .lock()
+            .unwrap()
+            .write_configuration_dword(CONFIGURATION_HEADER_SIZE, 0x1234_5678);
+
+        let copy = function.lock().unwrap().config_copy();
+        assert_eq!(copy[CONFIGURATION_HEADER_SIZE], 0x1234_5678);
+        assert_eq!(copy[..], function.lock().unwrap().configuration_space[..]);
+
+        // The copy is not affected by the later writes.
+        function
+            .lock()
+            .unwrap()
+            .write_configuration_dword(CONFIGURATION_HEADER_SIZE, 0x9ABC_DEF0);
+        assert_eq!(copy[CONFIGURATION_HEADER_SIZE], 0x1234_5678);
+        assert_eq!(
+            function
+                .lock()
+                .unwrap()
+                .read_configuration_dword(CONFIGURATION_HEADER_SIZE),
+            Some(0x9ABC_DEF0)
+        );
+    }
+
+    #[test]
+    fn intx_status() {
         let mut function = get_function();
-        let value = xor_rng_u32();
+        let status = |function: &PciFunction| {
+            function
+                .read_configuration_word(STATUS_REGISTER, STATUS_OFFSET)
+                .unwrap()
+                & STATUS_INTERRUPT_STATUS
+        };
+        function.write_configuration_register(COMMAND_REGISTER, COMMAND_OFFSET, &[0, 0]);
+        assert_eq!(status(&function), 0);
+        assert!(!function.is_intx_asserted());
 
-        function.write_configuration_dword(0, value);
-        assert_eq!(function.read_configuration_dword(0), Some(value));
+        function.set_intx_asserted(true);
+        assert_ne!(status(&function), 0);
+        assert!(function.is_intx_asserted());
+        function.set_intx_asserted(false);
+        assert_eq!(status(&function), 0);
+        assert!(!function.is_intx_asserted());
+
+        // The Interrupt Disable bit masks the pin, but not the pending state.
+        function.write_configuration_register(
+            COMMAND_REGISTER,
+            COMMAND_OFFSET,
+            &COMMAND_INTERRUPT_DISABLE.to_le_bytes(),
+        );
+        function.set_intx_asserted(true);
+        assert_ne!(status(&function), 0);
+        assert!(!function.is_intx_asserted());
+        function.write_configuration_register(COMMAND_REGISTER, COMMAND_OFFSET, &[0, 0]);
+        assert!(function.is_intx_asserted());
+
+        // The guest can not change the Interrupt Status bit.
+        function.write_configuration_register(STATUS_REGISTER, STATUS_OFFSET, &[0, 0]);
+        assert_ne!(status(&function), 0);
+        function.set_intx_asserted(false);
+        function.write_configuration_register(STATUS_REGISTER, STATUS_OFFSET, &[0xFF, 0xFF]);
+        assert_eq!(status(&function), 0);
     }
 
     #[test]
-    fn function_configuration_read_write_all() {
+    fn max_capabilities() {
         let mut function = get_function();
-        let value = xor_rng_u32();
+        function.set_max_capabilities(3);
+        for _ in 0..3 {
+            function.add_capability(&VpdCapability).unwrap();
+        }
+        assert_eq!(
+            function.add_capability(&VpdCapability),
+            Err(PciFunctionError::TooManyCapabilities(3))
+        );
+        assert_eq!(function.capabilities().count(), 3);
 
-        // Write `value` as u8 pieces in the first register.
-        for index in 0..4 {
-            function.write_configuration_byte(0, index, value.to_le_bytes()[index]);
+        // The limit can be set when building the function.
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::EthernetController,
+            PciHeaderType::Type0,
+        )
+        .max_capabilities(1)
+        .build()
+        .unwrap();
+        function.add_capability(&VpdCapability).unwrap();
+        assert!(function.add_capability(&VpdCapability).is_err());
+
+        // The default limit is the number of capability registers, which each capability
+        // needs one of at least.
+        assert_eq!(get_function().max_capabilities, MAX_CAPABILITIES);
+    }
+
+    #[test]
+    fn io_bar_range() {
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::EthernetController,
+            PciHeaderType::Type0,
+        )
+        .bars(&[
+            (0, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+            (1, PciBarRegionType::IoRegion, 0x40, false),
+        ])
+        .build()
+        .unwrap();
+
+        function.write_configuration_register(BAR0_REGISTER + 1, 0, &0xC040u32.to_le_bytes());
+        assert_eq!(
+            function.read_configuration_dword(BAR0_REGISTER + 1),
+            Some(0xC041)
+        );
+        assert_eq!(function.io_bar_range(1), Some((0xC040, 0x40)));
+
+        // Memory BARs and undeclared BARs have no port range.
+        function.write_configuration_register(BAR0_REGISTER, 0, &0xE000_0000u32.to_le_bytes());
+        assert_eq!(function.io_bar_range(0), None);
+        assert_eq!(function.io_bar_range(2), None);
+        assert_eq!(function.io_bar_range(7), None);
+
+        // A port beyond 64KB can not be decoded.
+        function.write_configuration_register(BAR0_REGISTER + 1, 0, &0x1_0000u32.to_le_bytes());
+        assert_eq!(function.io_bar_range(1), None);
+    }
+
+    #[test]
+    fn width_aware_accesses() {
+        let mut function = get_function();
+        let register = CONFIGURATION_HEADER_SIZE;
+
+        function.write_configuration(register, 0, 4, 0x1122_3344);
+        assert_eq!(
+            function.read_configuration(register, 0, 4),
+            Some(0x1122_3344)
+        );
+        function.write_configuration(register, 2, 2, 0xAABB);
+        assert_eq!(function.read_configuration(register, 2, 2), Some(0xAABB));
+        assert_eq!(function.read_configuration(register, 1, 2), Some(0xBB33));
+        function.write_configuration(register, 3, 1, 0xCC);
+        assert_eq!(function.read_configuration(register, 3, 1), Some(0xCC));
+        assert_eq!(
+            function.read_configuration(register, 0, 4),
+            Some(0xCCBB_3344)
+        );
+
+        // The accesses which do not fit within the register, or have another width, are
+        // rejected.
+        for (offset, width) in [
+            (1, 4),
+            (3, 2),
+            (4, 1),
+            (2, 3),
+            (0, 0),
+            (0, 8),
+            (usize::MAX, 1),
+        ]
+        .iter()
+        .cloned()
+        {
+            assert_eq!(function.read_configuration(register, offset, width), None);
+            function.write_configuration(register, offset, width, 0);
         }
+        assert_eq!(
+            function.read_configuration(register, 0, 4),
+            Some(0xCCBB_3344)
+        );
+        assert_eq!(
+            function.read_configuration(CONFIGURATION_SPACE_SIZE, 0, 4),
+            None
+        );
+    }
 
-        // Write `value` as u16 pieces in the second register.
-        function.write_configuration_word(1, 0, (value & 0xFFFF) as u16);
-        function.write_configuration_word(1, 2, ((value >> 16) & 0xFFFF) as u16);
+    #[test]
+    fn partial_writes() {
+        let mut function = get_function();
+        let register = CONFIGURATION_HEADER_SIZE;
 
+        // A 3-byte write at offset 0 only changes the three low bytes.
+        function.write_configuration_dword(register, 0x1122_3344);
+        function.write_configuration_register(register, 0, &[0xAA, 0xBB, 0xCC]);
         assert_eq!(
-            function.read_configuration_dword(0),
-            function.read_configuration_dword(1)
+            function.read_configuration_dword(register),
+            Some(0x11CC_BBAA)
+        );
+
+        // A 2-byte write at offset 1 only changes the two middle bytes.
+        function.write_configuration_dword(register, 0x1122_3344);
+        function.write_configuration_register(register, 1, &[0xAA, 0xBB]);
+        assert_eq!(
+            function.read_configuration_dword(register),
+            Some(0x11BB_AA44)
+        );
+
+        // A 3-byte write at offset 1 reaches the end of the register.
+        function.write_configuration_register(register, 1, &[0x01, 0x02, 0x03]);
+        assert_eq!(
+            function.read_configuration_dword(register),
+            Some(0x0302_0144)
+        );
+
+        // The writes crossing the end of the register are still dropped.
+        function.write_configuration_register(register, 2, &[0xFF, 0xFF, 0xFF]);
+        function.write_configuration_register(register, 3, &[0xFF, 0xFF]);
+        assert_eq!(
+            function.read_configuration_dword(register),
+            Some(0x0302_0144)
+        );
+
+        // The host writes of 3 bytes behave the same way.
+        function.write_configuration(register, 1, 3, 0x00DD_EEFF);
+        assert_eq!(
+            function.read_configuration(register, 1, 3),
+            Some(0x00DD_EEFF)
+        );
+        assert_eq!(
+            function.read_configuration_dword(register),
+            Some(0xDDEE_FF44)
         );
     }
 
     #[test]
-    fn dummy_class_code() {
-        let function = get_function();
+    fn clone_with_number() {
+        let mut template = get_function();
+        template
+            .set_bar(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)
+            .unwrap();
+        template
+            .add_capability(&VendorCapability { data: vec![0; 5] })
+            .unwrap();
+        template.set_tag(7);
+        template.set_read_callback(CONFIGURATION_SPACE_SIZE - 1, Arc::new(|_| 0xAA));
+        template.set_fault_callback(Arc::new(|_| ()));
+        template.set_mapping_callback(Arc::new(|_| ()), true);
 
+        let copy = template.clone().with_number(3).unwrap();
+        assert_eq!(copy.get_number(), 3);
+        assert_eq!(template.get_number(), 0);
+        assert_eq!(copy.tag(), None);
+        assert!(copy.is_dirty_since_snapshot());
+
+        // The callbacks belong to the device model of the template.
+        assert!(copy.read_callbacks.is_empty());
+        assert!(copy.fault_callback.is_none());
+        assert!(copy.mapping_callback.is_none());
+        assert!(!copy.defer_bar_registration);
+
+        // Only the identity of the function differs.
+        assert_eq!(copy.configuration_space, template.configuration_space);
+        assert_eq!(copy.write_masks, template.write_masks);
+        assert_eq!(copy.bars(), template.bars());
         assert_eq!(
-            function
-                .read_configuration_byte(CLASS_CODE_REGISTER, 3)
-                .unwrap(),
-            0x06
+            copy.capabilities().collect::<Vec<_>>(),
+            template.capabilities().collect::<Vec<_>>()
         );
 
+        // The copies are independent.
+        let mut copy = copy;
+        template.clear_read_callback(CONFIGURATION_SPACE_SIZE - 1);
+        copy.write_configuration_dword(CONFIGURATION_SPACE_SIZE - 1, 0x1234_5678);
         assert_eq!(
-            function
-                .read_configuration_byte(CLASS_CODE_REGISTER, 2)
-                .unwrap(),
-            0x00
+            template.read_configuration_dword(CONFIGURATION_SPACE_SIZE - 1),
+            Some(0)
+        );
+
+        assert!(template.clone().with_number(MAX_FUNCTION_NUMBER).is_err());
+    }
+
+    #[test]
+    fn initial_command() {
+        let builder = || {
+            PciFunctionBuilder::new(
+                0,
+                0x1234,
+                0x5678,
+                PciClassCode::EthernetController,
+                PciHeaderType::Type0,
+            )
+        };
+
+        // Nothing is decoded at power-on by default.
+        let function = builder().build().unwrap();
+        assert_eq!(
+            function.read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET),
+            Some(0)
+        );
+        assert!(!function.is_memory_space_enabled());
+        assert_eq!(
+            get_function().read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET),
+            Some(0)
         );
 
+        let function = builder().command(COMMAND_MEMORY_SPACE).build().unwrap();
+        assert!(function.is_memory_space_enabled());
+        assert!(!function.is_io_space_enabled());
+
+        match builder().command(COMMAND_MEMORY_SPACE | 1 << 11).build() {
+            Err(PciFunctionError::InvalidCommand(command)) => {
+                assert_eq!(command, COMMAND_MEMORY_SPACE | 1 << 11)
+            }
+            _ => panic!("The reserved bits of the Command register should be rejected"),
+        }
+
+        // The guest can not set the reserved bits either.
+        let mut function = builder().build().unwrap();
+        function.write_configuration_register(COMMAND_REGISTER, COMMAND_OFFSET, &[0xFF, 0xFF]);
         assert_eq!(
-            function
-                .read_configuration_byte(CLASS_CODE_REGISTER, 1)
-                .unwrap(),
-            0x00
+            function.read_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET),
+            Some(!COMMAND_RESERVED)
         );
     }
 }
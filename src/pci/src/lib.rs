@@ -2,15 +2,59 @@
 // SPDX-License-Identifier: Apache-2.0
 
 extern crate devices;
+#[cfg(feature = "trace")]
+#[macro_use]
+extern crate logger;
 extern crate polly;
+extern crate rate_limiter;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate snapshot;
 extern crate utils;
+extern crate versionize;
+extern crate versionize_derive;
 
+mod bar;
+mod bridge;
 mod bus;
+mod capability;
+mod constants;
 mod device;
 mod function;
 mod pci;
+pub mod persist;
+mod topology;
+mod virtio;
+mod visitor;
 
-pub use self::bus::PciBus;
-pub use self::device::PciDevice;
-pub use self::function::PciFunction;
-pub use self::pci::{PciRootComplex, PCI_IO_PORT, PCI_IO_PORT_SIZE};
+pub use self::bar::{PciBarConfiguration, PciBarRegionType};
+pub use self::bus::{PciBus, PciBusError, PciHotplugCallback};
+pub use self::capability::{
+    DvsecCapability, MsiCapability, MsixCapability, PciCapability, PciCapabilityId,
+    PciExpressCapability, PciExpressDeviceType, PciExtendedCapability, PciExtendedCapabilityId,
+    PowerBudgetEntry, PowerBudgetingCapability, PowerManagementCapability, ResizableBarCapability,
+    SerialNumberCapability, VendorCapability, VpdCapability,
+};
+pub use self::constants::{
+    PciIdeProgrammingInterface, PciNvmProgrammingInterface, PciSataProgrammingInterface,
+    PciUsbProgrammingInterface,
+};
+pub use self::device::{PciDevice, PciDeviceError};
+pub use self::function::{
+    ConfigAccessError, ConfigFault, ConfigFaultKind, ConfigRegister, PciBarHandler, PciBaseClass,
+    PciClassCode, PciConfigBacking, PciConfigFaultCallback, PciConfigurable, PciFunction,
+    PciFunctionBuilder, PciFunctionError, PciFunctionHeader, PciHeaderType, PciMappingCallback,
+    PciMappingEvent, PciMappingNotifications, PciProgrammingInterface, PciReadCallback,
+    WriteOutcome, DEVICE_ID_DUMMY_HOST_BRIDGE, MAX_CAPABILITIES, VENDOR_ID_DUMMY_HOST_BRIDGE,
+};
+pub use self::pci::{
+    encode_configuration_address, ConfigAddress, PciConfigMechanism, PciError, PciMechanism2Ports,
+    PciRootComplex, PCI_IO_PORT, PCI_IO_PORT_SIZE, PCI_MECHANISM2_CONTROL_IO_PORT,
+    PCI_MECHANISM2_CONTROL_IO_PORT_SIZE, PCI_MECHANISM2_IO_PORT, PCI_MECHANISM2_IO_PORT_SIZE,
+};
+pub use self::topology::{
+    BridgeSpec, DeviceSpec, FunctionSpec, TopologyError, TopologySnapshot, TopologySpec,
+};
+pub use self::virtio::VirtioDeviceType;
+pub use self::visitor::PciTreeVisitor;
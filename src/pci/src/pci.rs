@@ -1,10 +1,14 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::bus::PciBus;
-use crate::device::PciDevice;
-use devices::BusDevice;
+use crate::bus::{PciBus, PciBusError, MAX_BUS_NUMBER};
+use crate::device::{PciDevice, PciDeviceError};
+use crate::function::{PciBaseClass, PciFunction, PciFunctionError, CONFIGURATION_SPACE_SIZE};
+use devices::{Bus, BusDevice};
 use polly::event_manager::{EventManager, Subscriber};
+use rate_limiter::TokenBucket;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use utils::byte_order::{read_le_u16, read_le_u32};
 use utils::epoll::EpollEvent;
@@ -12,6 +16,22 @@ use utils::epoll::EpollEvent;
 pub const PCI_IO_PORT: usize = 0xCF8;
 pub const PCI_IO_PORT_SIZE: usize = 0x8;
 
+/// With the configuration mechanism #2, the configuration space of the devices is mapped in
+/// the 0xC000-0xCFFF IO range.
+pub const PCI_MECHANISM2_IO_PORT: usize = 0xC000;
+pub const PCI_MECHANISM2_IO_PORT_SIZE: usize = 0x1000;
+
+/// With the configuration mechanism #2, the CSE (0xCF8) and Forward (0xCFA) ports select the
+/// function and the bus. They are decoded by a `PciMechanism2Ports` node.
+pub const PCI_MECHANISM2_CONTROL_IO_PORT: usize = 0xCF8;
+pub const PCI_MECHANISM2_CONTROL_IO_PORT_SIZE: usize = 0x4;
+
+/// Offset of the Configuration Space Enable register (port 0xCF8) for the mechanism #2.
+const OFFSET_MECHANISM2_CSE: u64 = 0;
+
+/// Offset of the Forward register (port 0xCFA) for the mechanism #2.
+const OFFSET_MECHANISM2_FORWARD: u64 = 2;
+
 /// Offset of the CONFIG_ADDRESS port (port 0xCF8),
 const OFFSET_ADDRESS: u64 = 0;
 const OFFSET_ADDRESS_END: u64 = 3;
@@ -20,6 +40,36 @@ const OFFSET_ADDRESS_END: u64 = 3;
 const OFFSET_DATA: u64 = 4;
 const OFFSET_DATA_END: u64 = 7;
 
+/// Errors for the PCI Root Complex.
+#[derive(Debug)]
+pub enum PciError {
+    /// Failed to update a bus of the topology.
+    Bus(PciBusError),
+    /// Failed to update a device of the topology.
+    Device(PciDeviceError),
+    /// The bus number can not be rooted by a host bridge.
+    InvalidRootBusNumber(usize),
+    /// Failed to update a function of the topology.
+    Function(PciFunctionError),
+    /// The bus number behind a bridge is already used, or is not above the bus of the bridge.
+    InvalidBridgeBusNumber(usize),
+    /// The Vendor ID of the host bridge would hide it (and the whole topology) from the guest.
+    InvalidHostBridgeVendorId(u16),
+}
+
+pub type Result<T> = std::result::Result<T, PciError>;
+
+/// The ways a guest can reach the configuration space through the IO ports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciConfigMechanism {
+    /// CONFIG_ADDRESS (0xCF8) and CONFIG_DATA (0xCFC) ports. This is the standard mechanism.
+    Mechanism1,
+    /// Deprecated mechanism used by some legacy firmware: the CSE (0xCF8) and Forward (0xCFA)
+    /// ports select the function and the bus, while the registers of the devices are mapped
+    /// in the 0xC000-0xCFFF IO range.
+    Mechanism2,
+}
+
 /// Emulate the PCI Root Complex node of the PCIe topology.
 /// This component generates transaction requests on behalf of the processor.
 /// This hardware component may contain different interfaces (CPU, DRAM) and chips.
@@ -27,38 +77,451 @@ const OFFSET_DATA_END: u64 = 7;
 /// This component is the first PCI bus, the bus with number 0. It will be connected to the PIO.
 pub struct PciRootComplex {
     /// The bus connected to the PCI Root Complex component (bus number 0).
-    bus: Arc<Mutex<PciBus>>,
+    pub(crate) bus: Arc<Mutex<PciBus>>,
 
     /// The last value written to the port 0xCF8.
-    config_address: u32,
+    pub(crate) config_address: ConfigAddress,
+
+    /// The buses rooted by the host bridge functions of bus 0, device 0 (function -> bus).
+    /// Function 0 always roots bus 0.
+    pub(crate) root_buses: BTreeMap<usize, usize>,
+
+    /// The number of configuration writes which targeted an absent function.
+    pub(crate) dropped_writes: u64,
+
+    /// The configuration mechanism decoded on the IO ports.
+    pub(crate) mechanism: PciConfigMechanism,
+
+    /// The last value written to the CSE port (mechanism #2 only).
+    pub(crate) cse: u8,
+
+    /// The last value written to the Forward port (mechanism #2 only).
+    pub(crate) forward: u8,
+
+    /// Limits the rate of the port accesses made by the guest. Disabled by default.
+    pub(crate) rate_limiter: Option<TokenBucket>,
+
+    /// The highest bus number decoded for the guest.
+    pub(crate) max_bus: usize,
+
+    /// The value returned by the configuration reads which target an absent function.
+    pub(crate) absent_read_value: u32,
 }
 
 impl PciRootComplex {
     /// Return a new PCI Root Complex node which does not have any device attached.
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
+        PciRootComplex::with_mechanism(PciConfigMechanism::Mechanism1)
+    }
+
+    /// Return a new PCI Root Complex node which decodes the given configuration mechanism.
+    /// With the mechanism #2, the node has to be registered at `PCI_MECHANISM2_IO_PORT`, and
+    /// a `PciMechanism2Ports` node at `PCI_MECHANISM2_CONTROL_IO_PORT`.
+    /// - `mechanism` - the configuration mechanism used by the guest.
+    pub fn with_mechanism(mechanism: PciConfigMechanism) -> Result<Self> {
+        PciRootComplex::with_host_bridge_function(mechanism, PciFunction::new_dummy_host_bridge(0))
+    }
+
+    /// Return a new PCI Root Complex node whose host bridge presents the given IDs, e.g. to
+    /// mimic a known chipset. The class code of the host bridge stays HostBridge.
+    /// - `device_id` - the Device ID of the host bridge.
+    /// - `vendor_id` - the Vendor ID of the host bridge. It can not be 0xFFFF, which is read
+    ///   from the absent functions.
+    pub fn with_host_bridge(device_id: u16, vendor_id: u16) -> Result<Self> {
+        if vendor_id == 0xFFFF {
+            return Err(PciError::InvalidHostBridgeVendorId(vendor_id));
+        }
+
+        PciRootComplex::with_host_bridge_function(
+            PciConfigMechanism::Mechanism1,
+            PciFunction::new_host_bridge(0, device_id, vendor_id),
+        )
+    }
+
+    /// Return a new PCI Root Complex node with the given host bridge on bus 0, device 0.
+    fn with_host_bridge_function(
+        mechanism: PciConfigMechanism,
+        host_bridge: PciFunction,
+    ) -> Result<Self> {
         let mut bus = PciBus::new(0);
 
         // Add the Host Bridge device on bus 0, device 0, function 0.
-        bus.add_device(PciDevice::new_dummy_host_bridge(0)).unwrap();
+        let mut device = PciDevice::new(0);
+        device.add_function(host_bridge).map_err(PciError::Device)?;
+        bus.add_device(device).map_err(PciError::Bus)?;
 
-        PciRootComplex {
+        let mut root_buses = BTreeMap::new();
+        root_buses.insert(0, 0);
+
+        Ok(PciRootComplex {
             bus: Arc::new(Mutex::new(bus)),
-            config_address: 0x0000_0000,
+            config_address: ConfigAddress::default(),
+            root_buses,
+            dropped_writes: 0,
+            mechanism,
+            cse: 0,
+            forward: 0,
+            rate_limiter: None,
+            max_bus: MAX_BUS_NUMBER,
+            absent_read_value: 0xFFFF_FFFF,
+        })
+    }
+
+    /// Set the value returned by the configuration reads which target an absent function.
+    /// The standard value is all ones, which is the default.
+    /// - `value` - the value returned for the absent functions.
+    pub fn with_absent_read_value(mut self, value: u32) -> Self {
+        self.absent_read_value = value;
+        self
+    }
+
+    /// Return the configuration mechanism decoded by this node.
+    pub fn get_mechanism(&self) -> PciConfigMechanism {
+        self.mechanism
+    }
+
+    /// Return the IO ports this node has to be registered at, as (base, size). They depend on
+    /// the configuration mechanism decoded by the node.
+    pub fn io_port_range(&self) -> (u64, u64) {
+        match self.mechanism {
+            PciConfigMechanism::Mechanism1 => (PCI_IO_PORT as u64, PCI_IO_PORT_SIZE as u64),
+            PciConfigMechanism::Mechanism2 => (
+                PCI_MECHANISM2_IO_PORT as u64,
+                PCI_MECHANISM2_IO_PORT_SIZE as u64,
+            ),
+        }
+    }
+
+    /// Return the IO ports the `PciMechanism2Ports` node of this node has to be registered
+    /// at, as (base, size), if the configuration mechanism needs one.
+    pub fn control_port_range(&self) -> Option<(u64, u64)> {
+        match self.mechanism {
+            PciConfigMechanism::Mechanism1 => None,
+            PciConfigMechanism::Mechanism2 => Some((
+                PCI_MECHANISM2_CONTROL_IO_PORT as u64,
+                PCI_MECHANISM2_CONTROL_IO_PORT_SIZE as u64,
+            )),
+        }
+    }
+
+    /// Add another host bridge function on bus 0, device 0, which may root a new bus.
+    /// All the host bridges share device 0, which becomes a multi-function device.
+    /// - `function` - the number of the host bridge function.
+    /// - `bus` - the bus rooted by the host bridge, if any.
+    pub fn add_host_bridge(&mut self, function: usize, bus: Option<PciBus>) -> Result<()> {
+        let mut root_bus = self.bus.lock().unwrap();
+
+        let rooted = match bus {
+            Some(bus) => {
+                let number = bus.get_number();
+                // Check the bus before touching the host bridge device, so a failure leaves
+                // the topology unchanged.
+                if number == 0 || number >= MAX_BUS_NUMBER || root_bus.get_bus(number).is_some() {
+                    return Err(PciError::InvalidRootBusNumber(number));
+                }
+
+                Some(bus)
+            }
+            None => None,
+        };
+
+        root_bus
+            .get_mut_device(0)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .add_function(PciFunction::new_dummy_host_bridge(function))
+            .map_err(PciError::Device)?;
+
+        if let Some(bus) = rooted {
+            let number = bus.get_number();
+            root_bus.add_bus(bus).map_err(PciError::Bus)?;
+            self.root_buses.insert(function, number);
+        }
+
+        Ok(())
+    }
+
+    /// Limit the rate of the port accesses made by the guest. Above the limit, reads return
+    /// all ones and writes are dropped until the bucket refills.
+    /// - `accesses` - the number of accesses allowed in a refill period (0 disables the limit).
+    /// - `refill_time_ms` - the refill period, in milliseconds (0 disables the limit).
+    pub fn set_rate_limit(&mut self, accesses: u64, refill_time_ms: u64) {
+        self.rate_limiter = TokenBucket::new(accesses, 0, refill_time_ms);
+    }
+
+    /// Consume a token for a port access, returning false if the access must be rejected.
+    fn consume_access(&mut self) -> bool {
+        match self.rate_limiter.as_mut() {
+            Some(bucket) => bucket.reduce(1),
+            None => true,
+        }
+    }
+
+    /// Limit the buses decoded for the guest. The configuration reads targeting a bus above
+    /// the limit return all ones and the writes are dropped, without walking the topology.
+    /// - `max` - the highest bus number decoded (`MAX_BUS_NUMBER` by default).
+    pub fn set_max_bus(&mut self, max: usize) {
+        self.max_bus = max;
+    }
+
+    /// Set the subordinate bus of every bridge of the topology to the highest bus number
+    /// reachable through it, so the configuration accesses reach the deepest buses.
+    pub fn recompute_subordinate_buses(&mut self) {
+        self.bus.lock().unwrap().recompute_subordinate_buses();
+    }
+
+    /// Call a closure on every function of the topology whose Base Class code matches, for
+    /// bulk operations on a category of devices (such as resetting the network controllers).
+    /// - `base` - the Base Class code of the visited functions.
+    /// - `f` - the closure called on each matching function. The bus is not locked while it
+    ///   runs, so it may access the rest of the topology.
+    pub fn for_each_function_of_class(
+        &self,
+        base: PciBaseClass,
+        mut f: impl FnMut(&mut PciFunction),
+    ) {
+        let functions: Vec<_> = self
+            .bus
+            .lock()
+            .unwrap()
+            .functions()
+            .into_iter()
+            .filter(|function| {
+                matches!(
+                    function.lock().unwrap().as_function(),
+                    Some(function) if function.is_base_class(base)
+                )
+            })
+            .collect();
+
+        for function in functions {
+            if let Some(function) = function.lock().unwrap().as_function_mut() {
+                f(function);
+            }
         }
     }
 
+    /// Bring the BAR handlers of every function of the topology registered on the system
+    /// buses in line with the BARs which the guest programmed and enabled. The VMM calls it
+    /// on each `PciMappingEvent::Bars`, once the configuration access completed.
+    /// - `mmio_bus` - the bus receiving the handlers of the memory BARs.
+    /// - `pio_bus` - the bus receiving the handlers of the IO BARs.
+    pub fn register_bar_handlers(&self, mmio_bus: &mut Bus, pio_bus: &mut Bus) -> Result<()> {
+        for function in self.bus.lock().unwrap().functions() {
+            if let Some(function) = function.lock().unwrap().as_function_mut() {
+                function
+                    .register_bar_handlers(mmio_bus, pio_bus)
+                    .map_err(PciError::Function)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reset the node during a warm reboot of the VM: every function of the topology gets
+    /// back its initial state and the configuration address is cleared, so the guest finds
+    /// the configuration space as it was at boot.
+    pub fn reset(&mut self) {
+        self.bus.lock().unwrap().reset();
+        self.config_address = ConfigAddress::default();
+        self.cse = 0;
+        self.forward = 0;
+    }
+
+    /// Tear down the node when the VM shuts down: the handlers of the BARs of every function
+    /// are removed from the system buses, along with the registration of the node at its IO
+    /// ports, so no stale mapping is left behind. The device registered at the IO ports is
+    /// only removed if it is this node.
+    /// - `root` - the node, as registered on the IO port bus.
+    /// - `mmio_bus` - the bus holding the handlers of the memory BARs.
+    /// - `pio_bus` - the bus holding the handlers of the IO BARs and the node itself.
+    pub fn teardown(root: &Arc<Mutex<PciRootComplex>>, mmio_bus: &mut Bus, pio_bus: &mut Bus) {
+        let ((base, _), control) = {
+            let root = root.lock().unwrap();
+            for function in root.bus.lock().unwrap().functions() {
+                function.lock().unwrap().teardown(mmio_bus, pio_bus);
+            }
+
+            (root.io_port_range(), root.control_port_range())
+        };
+
+        if let Some((control_base, _)) = control {
+            let registered = match pio_bus.get_device(control_base) {
+                // The guard itself implements AsAny, so the device behind it is downcast.
+                Some((0, device)) => matches!(
+                    (*device.lock().unwrap()).as_any().downcast_ref::<PciMechanism2Ports>(),
+                    Some(ports) if Arc::ptr_eq(&ports.root, root)
+                ),
+                _ => false,
+            };
+            if registered {
+                pio_bus.remove(control_base);
+            }
+        }
+
+        let registered = match pio_bus.get_device(base) {
+            Some((0, device)) => {
+                device as *const Mutex<dyn BusDevice> as *const u8
+                    == &**root as *const Mutex<PciRootComplex> as *const u8
+            }
+            _ => false,
+        };
+        if registered {
+            pio_bus.remove(base);
+        }
+    }
+
+    /// Return the bus rooted by a host bridge function, if any.
+    /// - `function` - the number of the host bridge function.
+    pub fn get_root_bus_number(&self, function: usize) -> Option<usize> {
+        self.root_buses.get(&function).cloned()
+    }
+
+    /// Renumber a bus, as the firmware does when it reassigns the bus numbers. The buses behind
+    /// it are moved by the same offset, the bridges leading to the moved buses are updated and
+    /// the subordinate buses are recomputed. Bus 0 keeps its number.
+    /// - `old` - the current number of the bus.
+    /// - `new` - the new number of the bus. It has to be above the number of its parent bus,
+    ///   none of the moved buses may collide with another bus, and the range of buses behind
+    ///   the renumbered bus may not overlap the range of another bridge.
+    pub fn renumber_bus(&mut self, old: usize, new: usize) -> Result<()> {
+        self.shift_bus_numbers(old, new)?;
+
+        // The moved range may overlap the range of another bridge, so it is checked on the
+        // renumbered topology and undone if invalid.
+        let overlaps = self.bus.lock().unwrap().check_bridge_bus_ranges().is_err();
+        if overlaps {
+            self.shift_bus_numbers(new, old)?;
+            return Err(PciError::InvalidBridgeBusNumber(new));
+        }
+
+        Ok(())
+    }
+
+    /// Move a bus and the buses behind it by the same offset, as `renumber_bus` does, without
+    /// checking the bus ranges of the renumbered topology.
+    fn shift_bus_numbers(&mut self, old: usize, new: usize) -> Result<()> {
+        let mut bus = self.bus.lock().unwrap();
+
+        let (parent, renumbered) = match old {
+            0 => None,
+            _ => bus.find_bus(old),
+        }
+        .ok_or(PciError::Bus(PciBusError::InvalidPciBusNumber(old)))?;
+        if new == old {
+            return Ok(());
+        }
+
+        let moved = renumbered.lock().unwrap().bus_numbers();
+        let used = bus.bus_numbers();
+        let collides = moved.iter().any(|number| {
+            let shifted = *number as isize + new as isize - old as isize;
+            shifted < 0
+                || shifted as usize >= MAX_BUS_NUMBER
+                || (used.contains(&(shifted as usize)) && !moved.contains(&(shifted as usize)))
+        });
+        if new <= parent || collides {
+            return Err(PciError::InvalidBridgeBusNumber(new));
+        }
+
+        bus.renumber_connected_bus(old, new);
+        for number in self.root_buses.values_mut() {
+            if *number == old {
+                *number = new;
+            }
+        }
+        bus.recompute_subordinate_buses();
+
+        Ok(())
+    }
+
+    /// Return the maximum number of bridge hops beneath any bus rooted by a host bridge.
+    /// The buses rooted by the other host bridges are not counted as hops beneath bus 0.
+    pub fn depth(&self) -> usize {
+        let bus = self.bus.lock().unwrap();
+        let other_roots: Vec<usize> = self
+            .root_buses
+            .values()
+            .cloned()
+            .filter(|number| *number != 0)
+            .collect();
+
+        self.root_buses
+            .values()
+            .map(|number| {
+                if *number == 0 {
+                    bus.depth_excluding(&other_roots)
+                } else {
+                    bus.get_bus(*number)
+                        .map_or(0, |root| root.lock().unwrap().depth())
+                }
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Return the range of buses rooted by each host bridge, as (first, last) bus numbers,
+    /// sorted by host bridge function. The firmware describes each range in the ACPI tables.
+    /// The buses rooted by the other host bridges are not part of the range of bus 0.
+    pub fn root_bus_ranges(&self) -> Vec<(u8, u8)> {
+        let bus = self.bus.lock().unwrap();
+        let other_roots: Vec<usize> = self
+            .root_buses
+            .values()
+            .cloned()
+            .filter(|number| *number != 0)
+            .collect();
+
+        self.root_buses
+            .values()
+            .map(|number| {
+                let last = if *number == 0 {
+                    bus.highest_bus_number(&other_roots)
+                } else {
+                    bus.get_bus(*number)
+                        .map_or(*number, |root| root.lock().unwrap().highest_bus_number(&[]))
+                };
+
+                (*number as u8, last as u8)
+            })
+            .collect()
+    }
+
+    /// Find the function which claims a memory access, as (bus, device, function, BAR).
+    /// Every bus rooted by a host bridge is searched, descending through the bridge windows.
+    /// - `address` - the physical address of the access.
+    pub fn route_mmio(&self, address: u64) -> Option<(usize, usize, usize, usize)> {
+        let bus = self.bus.lock().unwrap();
+
+        self.root_buses.values().find_map(|number| {
+            if *number == 0 {
+                bus.route_mmio(address)
+            } else {
+                bus.get_bus(*number)
+                    .and_then(|root| root.lock().unwrap().route_mmio(address))
+            }
+        })
+    }
+
+    /// Return the number of configuration writes which targeted an absent function.
+    pub fn get_dropped_writes(&self) -> u64 {
+        self.dropped_writes
+    }
+
     /// Return the last value written to the `0xCF8` port.
     pub fn get_configuration_address(&self) -> u32 {
-        self.config_address
+        self.config_address.raw()
     }
 
     /// Store the last value written to the `0xCF8` port.
+    /// Return true if the write toggled the enable bit (bit 31) of the address.
     /// - `offset` - offset from where to start writing within the address.
     /// - `data` - array of bytes to be written.
-    pub fn set_configuration_address(&mut self, offset: u64, data: &[u8]) {
-        // Make sure the boundary is respected.
-        if offset as usize + data.len() > 4 {
-            return;
+    pub fn set_configuration_address(&mut self, offset: u64, data: &[u8]) -> bool {
+        // Make sure the boundary is respected, without overflowing on large offsets.
+        if data.len() > 4 || offset > 4 - data.len() as u64 {
+            return false;
         }
 
         let (mask, config_address): (u32, u32) = match data.len() {
@@ -66,89 +529,331 @@ impl PciRootComplex {
                 0x0000_00FF << (offset * 8),
                 (data[0] as u32) << (offset * 8),
             ),
-            2 => (0x0000_FFFF, (read_le_u16(data) as u32) << (offset * 16)),
+            2 => (
+                0x0000_FFFF << (offset * 8),
+                (read_le_u16(data) as u32) << (offset * 8),
+            ),
             4 => (0xFFFF_FFFF, read_le_u32(data)),
-            _ => return,
+            _ => return false,
         };
 
-        self.config_address = (self.config_address & !mask) | config_address;
+        let previous = self.config_address;
+        self.config_address = ConfigAddress::new((previous.raw() & !mask) | config_address);
+
+        previous.enable() != self.config_address.enable()
     }
 
     /// Read a dword from the configuration space.
     /// Get the address from `self.config_address` field.
     pub fn read_configuration_space(&self) -> u32 {
         // Probe if the Enable Configuration Space Mapping is set, otherwise ignore transaction.
-        let enabled = (self.config_address & 0x8000_0000) != 0;
-        if !enabled {
+        if !self.config_address.enable() {
             return 0xFFFF_FFFF;
         }
 
         let (bus, device, function, register) = self.parse_configuration_address();
 
-        self.bus
-            .lock()
-            .unwrap()
-            .read_configuration_register(bus, device, function, register)
-            .unwrap_or(0xFFFF_FFFF)
+        self.read_configuration_register(bus, device, function, register)
     }
 
     /// Write to the configure space.
     /// - `offset`- offset from where to start writing the data.
     /// - `data` - array of bytes to be written.
     pub fn write_configuration_space(&mut self, offset: u64, data: &[u8]) {
-        // Make sure the boundaries are respected.
-        if offset as usize + data.len() > 4 {
+        // Make sure the boundaries are respected, without overflowing on large offsets.
+        if data.len() > 4 || offset > 4 - data.len() as u64 {
             return;
         }
 
         // Probe if the Enable Configuration Space Mapping is set, otherwise ignore transaction.
-        let enabled = (self.config_address & 0x8000_0000) != 0;
-        if !enabled {
+        if !self.config_address.enable() {
             return;
         }
 
         let (bus, device, function, register) = self.parse_configuration_address();
 
-        self.bus.lock().unwrap().write_configuration_register(
-            bus,
-            device,
-            function,
-            register,
-            offset as usize,
-            data,
-        )
+        self.write_configuration_register(bus, device, function, register, offset as usize, data)
+    }
+
+    /// Read a register of a function, returning `absent_read_value` if the function is absent.
+    fn read_configuration_register(
+        &self,
+        bus: usize,
+        device: usize,
+        function: usize,
+        register: usize,
+    ) -> u32 {
+        let value = if bus > self.max_bus || register >= CONFIGURATION_SPACE_SIZE {
+            self.absent_read_value
+        } else {
+            self.bus
+                .lock()
+                .unwrap()
+                .read_configuration_register(bus, device, function, register)
+                .unwrap_or(self.absent_read_value)
+        };
+
+        #[cfg(feature = "trace")]
+        trace!(
+            "config read {} width 4 value {:#010x}",
+            ConfigAddress::new(encode_configuration_address(
+                bus, device, function, register, true
+            )),
+            value
+        );
+
+        value
+    }
+
+    /// Write a register of a function, counting the writes which target an absent function.
+    fn write_configuration_register(
+        &mut self,
+        bus: usize,
+        device: usize,
+        function: usize,
+        register: usize,
+        offset: usize,
+        data: &[u8],
+    ) {
+        #[cfg(feature = "trace")]
+        trace!(
+            "config write {} offset {} width {} value {:#x}",
+            ConfigAddress::new(encode_configuration_address(
+                bus, device, function, register, true
+            )),
+            offset,
+            data.len(),
+            data.iter()
+                .rev()
+                .fold(0u32, |value, byte| (value << 8) | u32::from(*byte))
+        );
+
+        if bus > self.max_bus || register >= CONFIGURATION_SPACE_SIZE {
+            self.dropped_writes += 1;
+            return;
+        }
+
+        let mut root_bus = self.bus.lock().unwrap();
+        if !root_bus.has_function(bus, device, function) {
+            // Nothing to write to, but keep track of the guests poking at absent functions.
+            self.dropped_writes += 1;
+            return;
+        }
+
+        root_bus.write_configuration_register(bus, device, function, register, offset, data)
+    }
+
+    /// Decode an access to the 0xC000-0xCFFF IO range made with the mechanism #2.
+    /// Return a tuple of (bus, device, function, register pointer), or `None` if the
+    /// configuration space is not mapped.
+    /// - `offset` - offset of the access relative to 0xC000.
+    fn decode_mechanism2_address(&self, offset: u64) -> Option<(usize, usize, usize, usize)> {
+        // A null key in the upper nibble of the CSE register disables the mapping.
+        if self.cse & 0xF0 == 0 {
+            return None;
+        }
+
+        Some((
+            self.forward as usize,
+            ((offset >> 8) & 0xF) as usize,
+            ((self.cse >> 1) & 0x7) as usize,
+            ((offset & 0xFF) >> 2) as usize,
+        ))
+    }
+
+    /// Read from the configuration space mapped by the mechanism #2.
+    /// - `offset` - offset of the access relative to 0xC000.
+    fn read_mechanism2(&self, offset: u64) -> u32 {
+        match self.decode_mechanism2_address(offset) {
+            Some((bus, device, function, register)) => {
+                self.read_configuration_register(bus, device, function, register)
+            }
+            None => 0xFFFF_FFFF,
+        }
+    }
+
+    /// Write to the configuration space mapped by the mechanism #2.
+    /// - `offset` - offset of the access relative to 0xC000.
+    /// - `data` - array of bytes to be written.
+    fn write_mechanism2(&mut self, offset: u64, data: &[u8]) {
+        // Make sure the boundaries are respected.
+        if data.is_empty() || data.len() > 4 || offset % 4 > 4 - data.len() as u64 {
+            return;
+        }
+
+        if let Some((bus, device, function, register)) = self.decode_mechanism2_address(offset) {
+            self.write_configuration_register(
+                bus,
+                device,
+                function,
+                register,
+                offset as usize % 4,
+                data,
+            );
+        }
+    }
+
+    /// Check if a configuration access to the given address would hit a present function,
+    /// without performing the access.
+    /// - `config_address` - a value as it would be written to `0xCF8`.
+    pub fn decodes_to_present(&self, config_address: u32) -> bool {
+        let address = ConfigAddress::new(config_address);
+        if !address.enable() {
+            return false;
+        }
+
+        self.bus
+            .lock()
+            .unwrap()
+            .has_function(address.bus(), address.device(), address.function())
     }
 
     /// Parse the stored configuration address (the last value written to `0xCF8`).
     /// Return a tuple of (bus, device, function, register pointer).
     pub fn parse_configuration_address(&self) -> (usize, usize, usize, usize) {
-        const BUS_NUMBER_OFFSET: usize = 16;
-        const BUS_NUMBER_MASK: u32 = 0x00FF;
+        decode_configuration_address(self.config_address.raw())
+    }
+}
 
-        const DEVICE_NUMBER_OFFSET: usize = 11;
-        const DEVICE_NUMBER_MASK: u32 = 0x1F;
+// The fields of a configuration address (a value written to `0xCF8`).
+const ENABLE_BIT: u32 = 0x8000_0000;
 
-        const FUNCTION_NUMBER_OFFSET: usize = 8;
-        const FUNCTION_NUMBER_MASK: u32 = 0x07;
+const BUS_NUMBER_OFFSET: usize = 16;
+const BUS_NUMBER_MASK: u32 = 0x00FF;
 
-        const REGISTER_NUMBER_OFFSET: usize = 2;
-        const REGISTER_NUMBER_MASK: u32 = 0x3F;
+const DEVICE_NUMBER_OFFSET: usize = 11;
+const DEVICE_NUMBER_MASK: u32 = 0x1F;
 
-        (
-            ((self.config_address >> BUS_NUMBER_OFFSET) & BUS_NUMBER_MASK) as usize,
-            ((self.config_address >> DEVICE_NUMBER_OFFSET) & DEVICE_NUMBER_MASK) as usize,
-            ((self.config_address >> FUNCTION_NUMBER_OFFSET) & FUNCTION_NUMBER_MASK) as usize,
-            ((self.config_address >> REGISTER_NUMBER_OFFSET) & REGISTER_NUMBER_MASK) as usize,
+const FUNCTION_NUMBER_OFFSET: usize = 8;
+const FUNCTION_NUMBER_MASK: u32 = 0x07;
+
+const REGISTER_NUMBER_OFFSET: usize = 2;
+const REGISTER_NUMBER_MASK: u32 = 0x3F;
+const REGISTER_NUMBER_WIDTH: usize = 6;
+
+// The enhanced scheme uses the reserved bits 24-27 as the upper bits of the register index,
+// so that the whole extended configuration space is reachable through `0xCF8`.
+const EXTENDED_REGISTER_NUMBER_OFFSET: usize = 24;
+const EXTENDED_REGISTER_NUMBER_MASK: u32 = 0x0F;
+
+/// A configuration address (a value written to `0xCF8`), with accessors for its fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConfigAddress(u32);
+
+impl ConfigAddress {
+    /// Wrap the raw value of a configuration address.
+    pub fn new(raw: u32) -> Self {
+        ConfigAddress(raw)
+    }
+
+    /// Return the raw value, as read from `0xCF8`.
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+
+    /// Return true if the configuration space accesses are enabled.
+    pub fn enable(self) -> bool {
+        self.0 & ENABLE_BIT != 0
+    }
+
+    /// Set or clear the Enable bit.
+    pub fn set_enable(&mut self, enable: bool) {
+        if enable {
+            self.0 |= ENABLE_BIT;
+        } else {
+            self.0 &= !ENABLE_BIT;
+        }
+    }
+
+    /// Return the number of the bus.
+    pub fn bus(self) -> usize {
+        ((self.0 >> BUS_NUMBER_OFFSET) & BUS_NUMBER_MASK) as usize
+    }
+
+    /// Return the number of the device on the bus.
+    pub fn device(self) -> usize {
+        ((self.0 >> DEVICE_NUMBER_OFFSET) & DEVICE_NUMBER_MASK) as usize
+    }
+
+    /// Return the number of the function of the device.
+    pub fn function(self) -> usize {
+        ((self.0 >> FUNCTION_NUMBER_OFFSET) & FUNCTION_NUMBER_MASK) as usize
+    }
+
+    /// Return the index of the register within the configuration space, including the upper
+    /// bits of the enhanced scheme (bits 24-27).
+    pub fn register(self) -> usize {
+        let extended = (self.0 >> EXTENDED_REGISTER_NUMBER_OFFSET) & EXTENDED_REGISTER_NUMBER_MASK;
+        let register = (self.0 >> REGISTER_NUMBER_OFFSET) & REGISTER_NUMBER_MASK;
+
+        ((extended << REGISTER_NUMBER_WIDTH) | register) as usize
+    }
+}
+
+/// The address of the register, as `bus:device.function [byte offset]`.
+impl fmt::Display for ConfigAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{:x} [{:#05x}]",
+            self.bus(),
+            self.device(),
+            self.function(),
+            self.register() * 4
         )
     }
 }
 
+/// Build a configuration address (a value to be written to `0xCF8`).
+/// The fields are truncated to their width, which makes this the inverse of
+/// `PciRootComplex::parse_configuration_address`.
+/// - `bus` - the number of the bus.
+/// - `device` - the number of the device on the bus.
+/// - `function` - the number of the function of the device.
+/// - `register` - the index of the register within the configuration space. The registers
+///   after the header are encoded with the enhanced scheme (bits 24-27).
+/// - `enable` - whether the Enable bit is set.
+pub fn encode_configuration_address(
+    bus: usize,
+    device: usize,
+    function: usize,
+    register: usize,
+    enable: bool,
+) -> u32 {
+    let enable = if enable { ENABLE_BIT } else { 0 };
+
+    enable
+        | ((bus as u32 & BUS_NUMBER_MASK) << BUS_NUMBER_OFFSET)
+        | ((device as u32 & DEVICE_NUMBER_MASK) << DEVICE_NUMBER_OFFSET)
+        | ((function as u32 & FUNCTION_NUMBER_MASK) << FUNCTION_NUMBER_OFFSET)
+        | ((register as u32 & REGISTER_NUMBER_MASK) << REGISTER_NUMBER_OFFSET)
+        | (((register >> REGISTER_NUMBER_WIDTH) as u32 & EXTENDED_REGISTER_NUMBER_MASK)
+            << EXTENDED_REGISTER_NUMBER_OFFSET)
+}
+
+/// Parse a configuration address (a value written to `0xCF8`).
+/// Return a tuple of (bus, device, function, register pointer).
+fn decode_configuration_address(config_address: u32) -> (usize, usize, usize, usize) {
+    let address = ConfigAddress::new(config_address);
+
+    (
+        address.bus(),
+        address.device(),
+        address.function(),
+        address.register(),
+    )
+}
+
 impl BusDevice for PciRootComplex {
     /// Read from Address Port or Data Port deciding by the offset relative to 0xCF8.
     fn read(&mut self, offset: u64, data: &mut [u8]) {
         let result: u32 = match offset {
+            // The guest exceeded the access rate, return all ones.
+            _ if !self.consume_access() => 0xFFFF_FFFF,
+            // The ports are decoded differently with the mechanism #2.
+            _ if self.mechanism == PciConfigMechanism::Mechanism2 => self.read_mechanism2(offset),
             // Return the configuration address.
-            OFFSET_ADDRESS..=OFFSET_ADDRESS_END => self.config_address,
+            OFFSET_ADDRESS..=OFFSET_ADDRESS_END => self.config_address.raw(),
             // Return data from the device.
             OFFSET_DATA..=OFFSET_DATA_END => self.read_configuration_space(),
             // Error, return all ones.
@@ -156,6 +861,8 @@ impl BusDevice for PciRootComplex {
         };
 
         // Allow only if the boundary is respected and start from the beginning of the space.
+        // Both ports are dwords, so a narrower access returns the bytes of the latched value
+        // (or of the data) at the offset within the port, the LSB first.
         let start = offset as usize % 4;
         let end = start + data.len();
 
@@ -175,9 +882,18 @@ impl BusDevice for PciRootComplex {
     /// Write to Address Port or Data Port deciding by the offset relative to 0xCF8.
     fn write(&mut self, offset: u64, data: &[u8]) {
         match offset {
+            // The guest exceeded the access rate, drop the write.
+            _ if !self.consume_access() => {}
+            // The ports are decoded differently with the mechanism #2.
+            _ if self.mechanism == PciConfigMechanism::Mechanism2 => {
+                self.write_mechanism2(offset, data)
+            }
             // Set a new configuration address.
-            OFFSET_ADDRESS..=OFFSET_ADDRESS_END => self.set_configuration_address(offset, data),
-            // Make a write in a memory of a device.
+            OFFSET_ADDRESS..=OFFSET_ADDRESS_END => {
+                self.set_configuration_address(offset, data);
+            }
+            // Make a write in a memory of a device. The register comes from CONFIG_ADDRESS
+            // (its two low bits are reserved) and the data port offset selects the byte.
             OFFSET_DATA..=OFFSET_DATA_END => self.write_configuration_space(offset - 4, data),
             _ => {}
         }
@@ -193,3 +909,1210 @@ impl Subscriber for PciRootComplex {
         vec![]
     }
 }
+
+/// Decode the CSE (0xCF8) and Forward (0xCFA) ports of the configuration mechanism #2 on
+/// behalf of a root complex, which maps the selected configuration space in the 0xC000-0xCFFF
+/// IO range. It has to be registered at `PCI_MECHANISM2_CONTROL_IO_PORT`.
+pub struct PciMechanism2Ports {
+    root: Arc<Mutex<PciRootComplex>>,
+}
+
+impl PciMechanism2Ports {
+    /// Return the node decoding the CSE and Forward ports of a root complex.
+    /// - `root` - the root complex decoding the mechanism #2.
+    pub fn new(root: Arc<Mutex<PciRootComplex>>) -> Self {
+        PciMechanism2Ports { root }
+    }
+}
+
+impl BusDevice for PciMechanism2Ports {
+    /// Read the CSE and Forward registers, deciding by the offset relative to 0xCF8.
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let root = self.root.lock().unwrap();
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = match offset + index as u64 {
+                OFFSET_MECHANISM2_CSE => root.cse,
+                OFFSET_MECHANISM2_FORWARD => root.forward,
+                _ => 0xFF,
+            };
+        }
+    }
+
+    /// Write the CSE and Forward registers, deciding by the offset relative to 0xCF8.
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut root = self.root.lock().unwrap();
+        for (index, byte) in data.iter().enumerate() {
+            match offset + index as u64 {
+                OFFSET_MECHANISM2_CSE => root.cse = *byte,
+                OFFSET_MECHANISM2_FORWARD => root.forward = *byte,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::{
+        PciClassCode, PciFunctionBuilder, PciHeaderType, COMMAND_MEMORY_SPACE, COMMAND_OFFSET,
+        COMMAND_REGISTER, CONFIGURATION_HEADER_SIZE, DEVICE_ID_DUMMY_HOST_BRIDGE,
+        VENDOR_ID_DUMMY_HOST_BRIDGE,
+    };
+
+    #[test]
+    fn root_complex_decodes_to_present() {
+        let root = PciRootComplex::new().unwrap();
+
+        // The Host Bridge lives on bus 0, device 0, function 0.
+        assert!(root.decodes_to_present(0x8000_0000));
+        assert!(root.decodes_to_present(0x8000_0008));
+
+        // Same address, but without the Enable bit.
+        assert!(!root.decodes_to_present(0x0000_0000));
+
+        // Empty slot: bus 0, device 1, function 0.
+        assert!(!root.decodes_to_present(0x8000_0800));
+    }
+
+    #[test]
+    fn root_complex_dropped_writes() {
+        let mut root = PciRootComplex::new().unwrap();
+
+        // Write to an empty slot: bus 0, device 1, function 0, register 1.
+        root.set_configuration_address(0, &0x8000_0804u32.to_le_bytes());
+        root.write_configuration_space(0, &[0x12, 0x34]);
+        assert_eq!(root.get_dropped_writes(), 1);
+
+        // Write to the Host Bridge: bus 0, device 0, function 0, register 1.
+        root.set_configuration_address(0, &0x8000_0004u32.to_le_bytes());
+        root.write_configuration_space(0, &[0x12, 0x04]);
+        assert_eq!(root.get_dropped_writes(), 1);
+        assert_eq!(root.read_configuration_space() & 0xFFFF, 0x0412);
+    }
+
+    #[test]
+    fn root_complex_io_port_range() {
+        assert_eq!(
+            PciRootComplex::new().unwrap().io_port_range(),
+            (PCI_IO_PORT as u64, PCI_IO_PORT_SIZE as u64)
+        );
+        assert_eq!(
+            PciRootComplex::with_host_bridge(0x29C0, 0x8086)
+                .unwrap()
+                .io_port_range(),
+            (0xCF8, 0x8)
+        );
+        assert_eq!(
+            PciRootComplex::with_mechanism(PciConfigMechanism::Mechanism2)
+                .unwrap()
+                .io_port_range(),
+            (0xC000, 0x1000)
+        );
+        assert_eq!(PciRootComplex::new().unwrap().control_port_range(), None);
+        assert_eq!(
+            PciRootComplex::with_mechanism(PciConfigMechanism::Mechanism2)
+                .unwrap()
+                .control_port_range(),
+            Some((0xCF8, 0x4))
+        );
+    }
+
+    #[test]
+    fn root_complex_mechanism2() {
+        let root = Arc::new(Mutex::new(
+            PciRootComplex::with_mechanism(PciConfigMechanism::Mechanism2).unwrap(),
+        ));
+        // Device 0xC has registers at the ports 0xCCF8-0xCCFF.
+        let mut device = PciDevice::new(0xC);
+        device
+            .add_function(
+                PciFunctionBuilder::new(
+                    0,
+                    0x1000,
+                    0x1AF4,
+                    PciClassCode::EthernetController,
+                    PciHeaderType::Type0,
+                )
+                .build()
+                .unwrap(),
+            )
+            .unwrap();
+        root.lock()
+            .unwrap()
+            .bus
+            .lock()
+            .unwrap()
+            .add_device(device)
+            .unwrap();
+
+        // The guest reaches the root complex through the absolute IO ports.
+        let mut pio_bus = Bus::new();
+        let (base, size) = root.lock().unwrap().io_port_range();
+        pio_bus.insert(root.clone(), base, size).unwrap();
+        let (base, size) = root.lock().unwrap().control_port_range().unwrap();
+        pio_bus
+            .insert(
+                Arc::new(Mutex::new(PciMechanism2Ports::new(root.clone()))),
+                base,
+                size,
+            )
+            .unwrap();
+        let mut data = [0u8; 4];
+
+        // The configuration space is not mapped yet.
+        assert!(pio_bus.read(0xC000, &mut data));
+        assert_eq!(data, [0xFF; 4]);
+
+        // Enable the mapping for function 0 and forward the accesses to bus 0.
+        assert!(pio_bus.write(0xCF8, &[0x80]));
+        assert!(pio_bus.write(0xCFA, &[0x00]));
+
+        // Bus 0, device 0, register 0 (port 0xC000): the Host Bridge Vendor and Device IDs.
+        pio_bus.read(0xC000, &mut data);
+        assert_eq!(read_le_u32(&data), 0x1452_1D94);
+
+        // Word read of the Device ID (port 0xC002).
+        let mut word = [0u8; 2];
+        pio_bus.read(0xC002, &mut word);
+        assert_eq!(read_le_u16(&word), 0x1452);
+
+        // Bus 0, device 1 (port 0xC100) is empty.
+        pio_bus.read(0xC100, &mut data);
+        assert_eq!(data, [0xFF; 4]);
+
+        // Write the Command register (port 0xC004) and read it back.
+        pio_bus.write(0xC004, &[0x06, 0x00]);
+        pio_bus.read(0xC004, &mut word);
+        assert_eq!(read_le_u16(&word), 0x0006);
+
+        // The ports 0xCCF8-0xCCFF are the registers 62 and 63 of device 0xC, not the CSE
+        // and Forward registers.
+        pio_bus.write(0xCCF8, &[0x00]);
+        pio_bus.write(0xCCFA, &[0x01]);
+        pio_bus.read(0xCC00, &mut data);
+        assert_eq!(read_le_u32(&data), 0x1000_1AF4);
+        assert_eq!(
+            root.lock()
+                .unwrap()
+                .read_configuration_register(0, 0xC, 0, 62),
+            0x0001_0000
+        );
+
+        // Read back the CSE register, then disable the mapping.
+        let mut byte = [0u8; 1];
+        pio_bus.read(0xCF8, &mut byte);
+        assert_eq!(byte[0], 0x80);
+
+        // Empty writes are ignored.
+        pio_bus.write(0xCF8, &[]);
+        pio_bus.write(0xCFA, &[]);
+        pio_bus.write(0xC000, &[]);
+        pio_bus.read(0xCF8, &mut byte);
+        assert_eq!(byte[0], 0x80);
+
+        pio_bus.write(0xCF8, &[0x00]);
+        pio_bus.read(0xC000, &mut data);
+        assert_eq!(data, [0xFF; 4]);
+
+        // Both registrations are removed along with the root complex.
+        PciRootComplex::teardown(&root, &mut Bus::new(), &mut pio_bus);
+        assert!(pio_bus.get_device(0xC000).is_none());
+        assert!(pio_bus.get_device(0xCF8).is_none());
+    }
+
+    #[test]
+    fn root_complex_with_host_bridge() {
+        // The IDs of the Q35 host bridge, read by the guest from bus 0, device 0, function 0.
+        let mut root = PciRootComplex::with_host_bridge(0x29C0, 0x8086).unwrap();
+        let mut data = [0u8; 4];
+        root.write(0, &0x8000_0000u32.to_le_bytes());
+        root.read(4, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x29C0_8086);
+
+        let bus = root.bus.lock().unwrap();
+        let class = bus.read_configuration_register(0, 0, 0, 2).unwrap();
+        assert_eq!(class >> 8, 0x06_0000);
+
+        let root = PciRootComplex::new().unwrap();
+        let bus = root.bus.lock().unwrap();
+        assert_eq!(
+            bus.read_configuration_register(0, 0, 0, 0),
+            Some(
+                u32::from(DEVICE_ID_DUMMY_HOST_BRIDGE) << 16
+                    | u32::from(VENDOR_ID_DUMMY_HOST_BRIDGE)
+            )
+        );
+
+        // A host bridge reading like an absent function is refused instead of panicking.
+        match PciRootComplex::with_host_bridge(0x29C0, 0xFFFF) {
+            Err(PciError::InvalidHostBridgeVendorId(0xFFFF)) => (),
+            _ => panic!("The Vendor ID of the host bridge should be rejected"),
+        }
+    }
+
+    #[test]
+    fn root_complex_renumber_bus() {
+        let mut root = PciRootComplex::new().unwrap();
+
+        // Bus 0 -> bridge (device 1) -> bus 1 -> bridge (device 2) -> bus 2 -> device 3.
+        {
+            let mut bus = root.bus.lock().unwrap();
+            let mut bridge = PciDevice::new(1);
+            bridge.add_function(PciFunction::new_bridge(0, 1)).unwrap();
+            bus.add_device(bridge).unwrap();
+
+            let mut secondary = PciBus::new(1);
+            let mut bridge = PciDevice::new(2);
+            bridge.add_function(PciFunction::new_bridge(0, 2)).unwrap();
+            secondary.add_device(bridge).unwrap();
+
+            let mut tertiary = PciBus::new(2);
+            tertiary
+                .add_device(PciDevice::new_dummy_host_bridge(3))
+                .unwrap();
+            secondary.add_bus(tertiary).unwrap();
+            bus.add_bus(secondary).unwrap();
+            bus.add_bus(PciBus::new(8)).unwrap();
+            bus.recompute_subordinate_buses();
+        }
+
+        root.renumber_bus(1, 5).unwrap();
+        {
+            let bus = root.bus.lock().unwrap();
+            assert_eq!(
+                bus.bus_numbers().into_iter().collect::<Vec<_>>(),
+                vec![0, 5, 6, 8]
+            );
+
+            let bridge = bus.get_device(1).unwrap().lock().unwrap().functions[&0].clone();
+            assert_eq!(
+                bridge
+                    .lock()
+                    .unwrap()
+                    .as_function_mut()
+                    .unwrap()
+                    .get_secondary_bus(),
+                Some(5)
+            );
+            assert_eq!(
+                bridge
+                    .lock()
+                    .unwrap()
+                    .as_function_mut()
+                    .unwrap()
+                    .get_subordinate_bus(),
+                Some(6)
+            );
+
+            let secondary = bus.get_bus(5).unwrap().lock().unwrap();
+            let bridge = secondary.get_device(2).unwrap().lock().unwrap().functions[&0].clone();
+            assert_eq!(
+                bridge
+                    .lock()
+                    .unwrap()
+                    .as_function_mut()
+                    .unwrap()
+                    .get_secondary_bus(),
+                Some(6)
+            );
+            assert_eq!(
+                bridge
+                    .lock()
+                    .unwrap()
+                    .as_function_mut()
+                    .unwrap()
+                    .get_subordinate_bus(),
+                Some(6)
+            );
+        }
+
+        // The configuration accesses still reach the device behind both bridges.
+        let mut data = [0u8; 4];
+        root.write(
+            0,
+            &encode_configuration_address(6, 3, 0, 0, true).to_le_bytes(),
+        );
+        root.read(4, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1452_1D94);
+        root.write(
+            0,
+            &encode_configuration_address(2, 3, 0, 0, true).to_le_bytes(),
+        );
+        root.read(4, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xFFFF_FFFF);
+
+        // The moved buses can not collide with another bus, nor go below their parent bus.
+        match root.renumber_bus(5, 7) {
+            Err(PciError::InvalidBridgeBusNumber(7)) => (),
+            _ => panic!("The bus behind should collide with bus 8"),
+        }
+        match root.renumber_bus(6, 5) {
+            Err(PciError::InvalidBridgeBusNumber(5)) => (),
+            _ => panic!("The bus should stay above its parent bus"),
+        }
+        match root.renumber_bus(3, 4) {
+            Err(PciError::Bus(PciBusError::InvalidPciBusNumber(3))) => (),
+            _ => panic!("The bus should not exist"),
+        }
+        assert!(root.renumber_bus(0, 4).is_err());
+
+        // Moving down over the previous numbers of the moved buses is allowed.
+        root.renumber_bus(5, 4).unwrap();
+        assert!(root.bus.lock().unwrap().has_function(5, 3, 0));
+
+        // Bus 0 -> bridge (device 4) -> bus 8 -> bridge (device 1) -> bus 10.
+        {
+            let mut bus = root.bus.lock().unwrap();
+            let mut bridge = PciDevice::new(4);
+            bridge.add_function(PciFunction::new_bridge(0, 8)).unwrap();
+            bus.add_device(bridge).unwrap();
+
+            let mut bridge = PciDevice::new(1);
+            bridge.add_function(PciFunction::new_bridge(0, 10)).unwrap();
+            let mut secondary = bus.get_bus(8).unwrap().lock().unwrap();
+            secondary.add_device(bridge).unwrap();
+            secondary.add_bus(PciBus::new(10)).unwrap();
+            drop(secondary);
+            bus.recompute_subordinate_buses();
+        }
+
+        // Bus 9 is free, but the range of the bridge to bus 4 would then overlap 8-10.
+        match root.renumber_bus(5, 9) {
+            Err(PciError::InvalidBridgeBusNumber(9)) => (),
+            _ => panic!("The bus range should overlap the range of the other bridge"),
+        }
+        let bus = root.bus.lock().unwrap();
+        assert_eq!(
+            bus.bus_numbers().into_iter().collect::<Vec<_>>(),
+            vec![0, 4, 5, 8, 10]
+        );
+        assert!(bus.has_function(5, 3, 0));
+        assert!(bus.check_bridge_bus_ranges().is_ok());
+    }
+
+    #[test]
+    fn root_complex_multiple_host_bridges() {
+        let mut root = PciRootComplex::new().unwrap();
+
+        root.add_host_bridge(1, Some(PciBus::new(0x10))).unwrap();
+        assert_eq!(root.get_root_bus_number(0), Some(0));
+        assert_eq!(root.get_root_bus_number(1), Some(0x10));
+
+        // Both host bridges are discoverable on bus 0, device 0.
+        assert!(root.decodes_to_present(0x8000_0000));
+        assert!(root.decodes_to_present(0x8000_0100));
+
+        // Check the Multi-Function bit in the Header Type register of both functions.
+        for function in 0..2 {
+            let header_type = root
+                .bus
+                .lock()
+                .unwrap()
+                .read_configuration_register(0, 0, function, 3)
+                .unwrap();
+            assert_ne!(header_type & 0x0080_0000, 0);
+        }
+
+        // The host bridge function slot and the rooted bus must be unique.
+        assert!(root.add_host_bridge(1, None).is_err());
+        assert!(root.add_host_bridge(2, Some(PciBus::new(0x10))).is_err());
+        assert!(root.add_host_bridge(2, Some(PciBus::new(0))).is_err());
+    }
+
+    #[test]
+    fn root_complex_reset() {
+        use crate::bar::PciBarRegionType;
+
+        let mut root = PciRootComplex::new().unwrap();
+        {
+            let mut device = PciDevice::new(1);
+            for number in 0..2 {
+                let mut function = PciFunction::new_dummy_host_bridge(number);
+                function
+                    .set_bar(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)
+                    .unwrap();
+                device.add_function(function).unwrap();
+            }
+            root.bus.lock().unwrap().add_device(device).unwrap();
+        }
+        let initial = root.topology_snapshot();
+
+        // The guest programs the BARs and enables the decoding of both functions.
+        for function in 0..2 {
+            let address = encode_configuration_address(0, 1, function, 4, true);
+            root.write(OFFSET_ADDRESS, &address.to_le_bytes());
+            root.write(OFFSET_DATA, &0xE000_0000u32.to_le_bytes());
+            let address = encode_configuration_address(0, 1, function, 1, true);
+            root.write(OFFSET_ADDRESS, &address.to_le_bytes());
+            root.write(OFFSET_DATA, &[0x06]);
+        }
+        let address = encode_configuration_address(0, 0, 0, CONFIGURATION_HEADER_SIZE, true);
+        root.write(OFFSET_ADDRESS, &address.to_le_bytes());
+        root.write(OFFSET_DATA, &0x1234_5678u32.to_le_bytes());
+        assert_ne!(root.topology_snapshot(), initial);
+
+        // The VMM writes are kept, even after the guest ones.
+        let function = root
+            .bus
+            .lock()
+            .unwrap()
+            .get_device(1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .functions[&1]
+            .clone();
+        function
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_dword(CONFIGURATION_SPACE_SIZE - 1, 0xCAFE);
+
+        root.reset();
+        assert_eq!(root.get_configuration_address(), 0);
+
+        let snapshot = root.topology_snapshot();
+        assert_eq!(
+            snapshot.read(0, 1, 1, CONFIGURATION_SPACE_SIZE - 1),
+            Some(0xCAFE)
+        );
+        function
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_dword(CONFIGURATION_SPACE_SIZE - 1, 0);
+        assert_eq!(root.topology_snapshot(), initial);
+        assert_eq!(
+            function
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .get_bar(0)
+                .unwrap()
+                .address,
+            0
+        );
+    }
+
+    #[test]
+    fn root_complex_teardown() {
+        use crate::bar::PciBarRegionType;
+
+        struct Region;
+        impl BusDevice for Region {}
+
+        // A device with a memory BAR at 0xE000_0000 and an IO BAR at 0xC000, both enabled.
+        let mut function = PciFunctionBuilder::new(
+            0,
+            0x1234,
+            0x5678,
+            PciClassCode::EthernetController,
+            PciHeaderType::Type0,
+        )
+        .bars(&[
+            (0, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+            (1, PciBarRegionType::IoRegion, 0x20, false),
+        ])
+        .build()
+        .unwrap();
+        function
+            .set_bar_handler(0, Arc::new(Mutex::new(Region)))
+            .unwrap();
+        function
+            .set_bar_handler(1, Arc::new(Mutex::new(Region)))
+            .unwrap();
+        function.write_configuration_register(4, 0, &0xE000_0000u32.to_le_bytes());
+        function.write_configuration_register(5, 0, &0xC000u32.to_le_bytes());
+        function.write_configuration_register(1, 0, &[0x03, 0x00]);
+        let mut device = PciDevice::new(1);
+        device.add_function(function).unwrap();
+
+        let root = Arc::new(Mutex::new(PciRootComplex::new().unwrap()));
+        root.lock()
+            .unwrap()
+            .bus
+            .lock()
+            .unwrap()
+            .add_device(device)
+            .unwrap();
+
+        let mut mmio_bus = Bus::new();
+        let mut pio_bus = Bus::new();
+        let (base, size) = root.lock().unwrap().io_port_range();
+        pio_bus.insert(root.clone(), base, size).unwrap();
+        root.lock()
+            .unwrap()
+            .register_bar_handlers(&mut mmio_bus, &mut pio_bus)
+            .unwrap();
+        assert!(mmio_bus.get_device(0xE000_0000).is_some());
+        assert!(pio_bus.get_device(0xC000).is_some());
+
+        PciRootComplex::teardown(&root, &mut mmio_bus, &mut pio_bus);
+        assert!(mmio_bus.get_device(0xE000_0000).is_none());
+        assert!(pio_bus.get_device(0xC000).is_none());
+        assert!(pio_bus.get_device(base).is_none());
+
+        // Another device registered at the IO ports is left alone.
+        let other = Arc::new(Mutex::new(PciRootComplex::new().unwrap()));
+        pio_bus.insert(other, base, size).unwrap();
+        PciRootComplex::teardown(&root, &mut mmio_bus, &mut pio_bus);
+        assert!(pio_bus.get_device(base).is_some());
+    }
+
+    #[test]
+    fn root_complex_route_mmio() {
+        use crate::bar::PciBarRegionType;
+        use crate::bridge::{
+            MEMORY_BASE_OFFSET, MEMORY_BASE_REGISTER, MEMORY_LIMIT_OFFSET, MEMORY_LIMIT_REGISTER,
+            SECONDARY_BUS_OFFSET, SECONDARY_BUS_REGISTER,
+        };
+
+        let root = PciRootComplex::new().unwrap();
+
+        // A bridge on bus 0, device 1, forwarding 0xE000_0000-0xE00F_FFFF to bus 1.
+        let mut bridge = PciFunction::new(
+            0,
+            0x0001,
+            0x1B36,
+            PciClassCode::PciToPciBridge,
+            0,
+            PciHeaderType::Type1,
+            0,
+            0,
+        );
+        bridge.write_configuration_byte(SECONDARY_BUS_REGISTER, SECONDARY_BUS_OFFSET, 1);
+        bridge.write_configuration_word(MEMORY_BASE_REGISTER, MEMORY_BASE_OFFSET, 0xE000);
+        bridge.write_configuration_word(MEMORY_LIMIT_REGISTER, MEMORY_LIMIT_OFFSET, 0xE000);
+        bridge.write_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET, COMMAND_MEMORY_SPACE);
+        let mut bridge_device = PciDevice::new(1);
+        bridge_device.add_function(bridge).unwrap();
+
+        // A device on bus 1, device 2, with a 4KB memory BAR programmed at 0xE000_1000.
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        function
+            .set_bar(0, PciBarRegionType::Memory32BitRegion, 0x1000, false)
+            .unwrap();
+        function.write_configuration_register(4, 0, &0xE000_1000u32.to_le_bytes());
+        function.write_configuration_word(COMMAND_REGISTER, COMMAND_OFFSET, COMMAND_MEMORY_SPACE);
+        let mut device = PciDevice::new(2);
+        device.add_function(function).unwrap();
+
+        let mut secondary = PciBus::new(1);
+        secondary.add_device(device).unwrap();
+        {
+            let mut bus = root.bus.lock().unwrap();
+            bus.add_device(bridge_device).unwrap();
+            bus.add_bus(secondary).unwrap();
+        }
+
+        assert_eq!(root.route_mmio(0xE000_1000), Some((1, 2, 0, 0)));
+        assert_eq!(root.route_mmio(0xE000_1FFF), Some((1, 2, 0, 0)));
+        assert_eq!(root.route_mmio(0xE000_2000), None);
+        assert_eq!(root.route_mmio(0xD000_1000), None);
+
+        // Closing the window of the bridge hides the device behind it.
+        root.bus
+            .lock()
+            .unwrap()
+            .get_device(1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get_function(0)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_word(MEMORY_BASE_REGISTER, MEMORY_BASE_OFFSET, 0xE010);
+        assert_eq!(root.route_mmio(0xE000_1000), None);
+    }
+
+    #[test]
+    fn root_complex_rate_limit() {
+        let mut root = PciRootComplex::new().unwrap();
+        let mut data = [0u8; 4];
+
+        root.set_rate_limit(2, 100);
+        root.write(OFFSET_ADDRESS, &0x8000_0000u32.to_le_bytes());
+        root.read(OFFSET_DATA, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1452_1D94);
+
+        // The bucket is empty.
+        root.read(OFFSET_DATA, &mut data);
+        assert_eq!(data, [0xFF; 4]);
+        root.write(OFFSET_ADDRESS, &0x8000_0800u32.to_le_bytes());
+        assert_eq!(root.get_configuration_address(), 0x8000_0000);
+
+        // Wait for the bucket to refill.
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        root.read(OFFSET_DATA, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1452_1D94);
+    }
+
+    #[test]
+    fn root_complex_encode_configuration_address() {
+        let tuples = [
+            (0, 0, 0, 0),
+            (0, 3, 1, 2),
+            (1, 31, 7, 63),
+            (255, 16, 4, 15),
+            (2, 1, 0, 64),
+            (3, 2, 1, 1023),
+        ];
+
+        for (bus, device, function, register) in tuples.iter().cloned() {
+            let config_address =
+                encode_configuration_address(bus, device, function, register, true);
+            assert_eq!(config_address & 0x8000_0000, 0x8000_0000);
+            assert_eq!(
+                decode_configuration_address(config_address),
+                (bus, device, function, register)
+            );
+
+            let config_address =
+                encode_configuration_address(bus, device, function, register, false);
+            assert_eq!(config_address & 0x8000_0000, 0);
+            assert_eq!(
+                decode_configuration_address(config_address),
+                (bus, device, function, register)
+            );
+        }
+
+        assert_eq!(encode_configuration_address(0, 3, 0, 2, true), 0x8000_1808);
+        assert_eq!(encode_configuration_address(0, 3, 0, 66, true), 0x8100_1808);
+    }
+
+    #[test]
+    fn root_complex_large_offsets() {
+        let mut root = PciRootComplex::new().unwrap();
+
+        root.set_configuration_address(0, &0x8000_0000u32.to_le_bytes());
+        root.set_configuration_address(u64::MAX, &[0xFF; 2]);
+        root.write_configuration_space(u64::MAX - 1, &[0x00; 4]);
+        assert_eq!(root.get_configuration_address(), 0x8000_0000);
+        assert_eq!(root.read_configuration_space(), 0x1452_1D94);
+
+        // A word written in the upper half of the address lands in the upper half.
+        root.set_configuration_address(2, &[0x01, 0x80]);
+        assert_eq!(root.get_configuration_address(), 0x8001_0000);
+    }
+
+    #[test]
+    fn root_complex_extended_registers() {
+        let mut root = PciRootComplex::new().unwrap();
+        let register = CONFIGURATION_SPACE_SIZE - 2;
+
+        // The register is beyond the 64 registers reachable without the enhanced scheme.
+        let config_address = encode_configuration_address(0, 0, 0, register, true);
+        assert_eq!(config_address, 0x8F00_00F8);
+        root.write(OFFSET_ADDRESS, &config_address.to_le_bytes());
+        root.write(OFFSET_DATA, &0x1234_5678u32.to_le_bytes());
+
+        let mut data = [0u8; 4];
+        root.read(OFFSET_DATA, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1234_5678);
+
+        assert_eq!(
+            root.bus
+                .lock()
+                .unwrap()
+                .read_configuration_register(0, 0, 0, register),
+            Some(0x1234_5678)
+        );
+
+        // The register with the same lower bits is left untouched.
+        let config_address = encode_configuration_address(0, 0, 0, register % 64, true);
+        root.write(OFFSET_ADDRESS, &config_address.to_le_bytes());
+        root.read(OFFSET_DATA, &mut data);
+        assert_ne!(u32::from_le_bytes(data), 0x1234_5678);
+    }
+
+    #[test]
+    fn root_complex_data_port_alignment() {
+        let mut root = PciRootComplex::new().unwrap();
+        // The pattern avoids the reserved bits of the Command register.
+        let pattern: u32 = 0x4433_0711;
+
+        // Select bus 0, device 0, function 0, register 1 (Command and Status).
+        root.write(OFFSET_ADDRESS, &0x8000_0004u32.to_le_bytes());
+
+        for width in [1usize, 2, 4].iter().cloned() {
+            for start in (0..4).filter(|start| start + width <= 4) {
+                let offset = OFFSET_DATA + start as u64;
+                let bytes = &pattern.to_le_bytes()[start..start + width];
+
+                root.write(OFFSET_DATA, &[0x00; 4]);
+                root.write(offset, bytes);
+
+                // Only the selected bytes of the register are written.
+                let mut data = [0u8; 4];
+                root.read(OFFSET_DATA, &mut data);
+                let mask = (0xFFFF_FFFFu64 >> (32 - width * 8) << (start * 8)) as u32;
+                assert_eq!(u32::from_le_bytes(data), pattern & mask);
+
+                // The same bytes are read back at the same data port offset.
+                let mut data = vec![0u8; width];
+                root.read(offset, &mut data);
+                assert_eq!(data.as_slice(), bytes);
+            }
+        }
+
+        // An access crossing the end of the data port is ignored.
+        root.write(OFFSET_DATA, &[0x00; 4]);
+        root.write(OFFSET_DATA_END, &[0xFF; 2]);
+        let mut data = [0u8; 2];
+        root.read(OFFSET_DATA_END, &mut data);
+        assert_eq!(data, [0xFF; 2]);
+        let mut data = [0u8; 4];
+        root.read(OFFSET_DATA, &mut data);
+        assert_eq!(data, [0x00; 4]);
+    }
+
+    #[test]
+    fn root_complex_max_bus() {
+        let mut root = PciRootComplex::new().unwrap();
+        root.bus
+            .lock()
+            .unwrap()
+            .add_bus({
+                let mut bus = PciBus::new(4);
+                bus.add_device(PciDevice::new_dummy_host_bridge(0)).unwrap();
+                bus
+            })
+            .unwrap();
+
+        root.set_configuration_address(
+            0,
+            &encode_configuration_address(4, 0, 0, 0, true).to_le_bytes(),
+        );
+        assert_eq!(root.read_configuration_space(), 0x1452_1D94);
+
+        root.set_max_bus(3);
+        assert_eq!(root.read_configuration_space(), 0xFFFF_FFFF);
+        root.write_configuration_space(0, &[0x00; 4]);
+        assert_eq!(root.get_dropped_writes(), 1);
+
+        // The buses below the limit are still decoded.
+        root.set_configuration_address(
+            0,
+            &encode_configuration_address(0, 0, 0, 0, true).to_le_bytes(),
+        );
+        assert_eq!(root.read_configuration_space(), 0x1452_1D94);
+    }
+
+    #[test]
+    fn root_complex_enable_bit_transitions() {
+        let mut root = PciRootComplex::new().unwrap();
+
+        // Setting the enable bit is a transition, keeping it set is not.
+        assert!(root.set_configuration_address(0, &0x8000_0000u32.to_le_bytes()));
+        assert!(!root.set_configuration_address(0, &0x8000_0804u32.to_le_bytes()));
+        // The bit is only affected by the accesses covering the high byte.
+        assert!(!root.set_configuration_address(0, &[0x00]));
+        assert!(root.set_configuration_address(2, &[0x00, 0x00]));
+        assert!(!root.set_configuration_address(0, &0x0000_0000u32.to_le_bytes()));
+        assert!(root.set_configuration_address(3, &[0x80]));
+        // Out of bounds writes are ignored.
+        assert!(!root.set_configuration_address(3, &[0x00, 0x00]));
+        assert!(root.set_configuration_address(0, &0x0000_0000u32.to_le_bytes()));
+    }
+
+    #[test]
+    fn root_complex_absent_read_value() {
+        let mut root = PciRootComplex::new()
+            .unwrap()
+            .with_absent_read_value(0x0000_0000);
+
+        // An absent device.
+        root.set_configuration_address(
+            0,
+            &encode_configuration_address(0, 3, 0, 0, true).to_le_bytes(),
+        );
+        assert_eq!(root.read_configuration_space(), 0x0000_0000);
+
+        // An absent bus, or a bus above the decoded range.
+        root.set_configuration_address(
+            0,
+            &encode_configuration_address(5, 0, 0, 0, true).to_le_bytes(),
+        );
+        assert_eq!(root.read_configuration_space(), 0x0000_0000);
+        root.set_max_bus(0);
+        assert_eq!(root.read_configuration_space(), 0x0000_0000);
+
+        // The present functions are not affected.
+        root.set_configuration_address(
+            0,
+            &encode_configuration_address(0, 0, 0, 0, true).to_le_bytes(),
+        );
+        assert_eq!(root.read_configuration_space(), 0x1452_1D94);
+        assert_eq!(
+            PciRootComplex::new().unwrap().absent_read_value,
+            0xFFFF_FFFF
+        );
+    }
+
+    #[test]
+    fn root_complex_absent_and_present_reads() {
+        let mut root = PciRootComplex::new().unwrap();
+        let mut device = PciDevice::new(1);
+        // A present function with only the mandatory fields set.
+        device
+            .add_function(
+                PciFunctionBuilder::new(
+                    0,
+                    0x1041,
+                    0x1AF4,
+                    PciClassCode::EthernetController,
+                    PciHeaderType::Type0,
+                )
+                .build()
+                .unwrap(),
+            )
+            .unwrap();
+        // A function which is not present any more, like one being hot removed.
+        let mut hidden = PciFunction::new_dummy_host_bridge(1);
+        hidden.set_present(false);
+        device.add_function(hidden).unwrap();
+        root.bus.lock().unwrap().add_device(device).unwrap();
+
+        let read = |root: &mut PciRootComplex, function: usize, register: usize| {
+            root.set_configuration_address(
+                0,
+                &encode_configuration_address(0, 1, function, register, true).to_le_bytes(),
+            );
+            root.read_configuration_space()
+        };
+
+        // The absent functions read all ones everywhere.
+        for register in 0..CONFIGURATION_HEADER_SIZE {
+            assert_eq!(read(&mut root, 1, register), 0xFFFF_FFFF);
+            assert_eq!(read(&mut root, 2, register), 0xFFFF_FFFF);
+        }
+
+        // The present function reads its identity, and 0 in the registers it does not use.
+        assert_eq!(read(&mut root, 0, 0), 0x1041_1AF4);
+        assert_eq!(read(&mut root, 0, 2), 0x0200_0000);
+        for register in 4..CONFIGURATION_HEADER_SIZE {
+            assert_eq!(read(&mut root, 0, register), 0);
+        }
+
+        // The writes to a function which is not present are dropped like the absent ones.
+        assert_eq!(read(&mut root, 1, 15), 0xFFFF_FFFF);
+        root.write(4, &[0xAA]);
+        assert_eq!(root.get_dropped_writes(), 1);
+        let mut root = root.with_absent_read_value(0);
+        assert_eq!(read(&mut root, 1, 0), 0);
+    }
+
+    #[test]
+    fn root_complex_for_each_function_of_class() {
+        let root = PciRootComplex::new().unwrap();
+        for (number, class_code) in [
+            (1, PciClassCode::EthernetController),
+            (2, PciClassCode::OtherMassStorageController),
+        ]
+        .iter()
+        {
+            let mut device = PciDevice::new(*number);
+            device
+                .add_function(
+                    PciFunctionBuilder::new(0, 0x1000, 0x1AF4, *class_code, PciHeaderType::Type0)
+                        .build()
+                        .unwrap(),
+                )
+                .unwrap();
+            root.bus.lock().unwrap().add_device(device).unwrap();
+        }
+
+        let mut visited = Vec::new();
+        root.for_each_function_of_class(PciBaseClass::NetworkController, |function| {
+            function.write_configuration_byte(15, 0, 0x0A);
+            visited.push(function.header().class_code);
+            // The closure may reach the other functions through the bus.
+            let bus = root.bus.lock().unwrap();
+            assert!(bus.read_configuration_register(0, 2, 0, 0).is_some());
+        });
+        assert_eq!(visited, vec![0x02_00_00]);
+
+        // Only the matching function was modified.
+        let bus = root.bus.lock().unwrap();
+        assert_eq!(
+            bus.read_configuration_register(0, 1, 0, 15),
+            Some(0x0000_000A)
+        );
+        assert_eq!(bus.read_configuration_register(0, 2, 0, 15), Some(0));
+        drop(bus);
+
+        let mut bridges = 0;
+        root.for_each_function_of_class(PciBaseClass::BridgeDevice, |_| bridges += 1);
+        assert_eq!(bridges, 1);
+    }
+
+    #[test]
+    fn config_address_fields() {
+        let mut address = ConfigAddress::new(0x8001_1A08);
+        assert!(address.enable());
+        assert_eq!(address.bus(), 1);
+        assert_eq!(address.device(), 3);
+        assert_eq!(address.function(), 2);
+        assert_eq!(address.register(), 2);
+
+        // The reserved bits are ignored by the accessors.
+        let reserved = ConfigAddress::new(0x7000_0003);
+        assert!(!reserved.enable());
+        assert_eq!(
+            (
+                reserved.bus(),
+                reserved.device(),
+                reserved.function(),
+                reserved.register()
+            ),
+            (0, 0, 0, 0)
+        );
+
+        let full = ConfigAddress::new(0x80FF_FFFC);
+        assert_eq!(
+            (full.bus(), full.device(), full.function(), full.register()),
+            (255, 31, 7, 63)
+        );
+
+        // The bits 24-27 are the upper bits of the register index.
+        let extended = ConfigAddress::new(0x8F00_0008);
+        assert_eq!(extended.register(), 0x3C2);
+
+        assert_eq!(address.to_string(), "01:03.2 [0x008]");
+        assert_eq!(extended.to_string(), "00:00.0 [0xf08]");
+
+        address.set_enable(false);
+        assert_eq!(address.raw(), 0x0001_1A08);
+        address.set_enable(true);
+        assert_eq!(address.raw(), 0x8001_1A08);
+        assert_eq!(ConfigAddress::default().raw(), 0);
+    }
+
+    #[test]
+    fn root_complex_recompute_subordinate_buses() {
+        let mut root = PciRootComplex::new().unwrap();
+        let bridge = |number: usize, secondary: u8| {
+            let mut device = PciDevice::new(number);
+            device
+                .add_function(PciFunction::new_bridge(0, secondary))
+                .unwrap();
+            device
+        };
+        let subordinate = |bus: &PciBus, device: usize| {
+            bus.get_device(device)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .get_function(0)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .get_subordinate_bus()
+        };
+
+        // Bus 0 -> bridge 1 -> bus 1 -> bridge 0 -> bus 2 -> device 3.
+        //                             -> bridge 1 (empty, bus 3).
+        let mut deepest = PciBus::new(2);
+        deepest
+            .add_device(PciDevice::new_dummy_host_bridge(3))
+            .unwrap();
+        let mut middle = PciBus::new(1);
+        middle.add_device(bridge(0, 2)).unwrap();
+        middle.add_device(bridge(1, 3)).unwrap();
+        middle.add_bus(deepest).unwrap();
+        {
+            let mut bus = root.bus.lock().unwrap();
+            bus.add_device(bridge(1, 1)).unwrap();
+            bus.add_bus(middle).unwrap();
+        }
+
+        // The deepest bus is not routed until the subordinate buses are computed.
+        root.set_configuration_address(
+            0,
+            &encode_configuration_address(2, 3, 0, 0, true).to_le_bytes(),
+        );
+        assert_eq!(root.read_configuration_space(), 0xFFFF_FFFF);
+
+        root.recompute_subordinate_buses();
+        let bus = root.bus.lock().unwrap();
+        assert_eq!(subordinate(&bus, 1), Some(3));
+        let middle = bus.get_bus(1).unwrap().lock().unwrap();
+        assert_eq!(subordinate(&middle, 0), Some(2));
+        assert_eq!(subordinate(&middle, 1), Some(3));
+        drop(middle);
+        drop(bus);
+
+        assert_eq!(root.read_configuration_space(), 0x1452_1D94);
+    }
+
+    #[test]
+    fn root_complex_root_bus_ranges() {
+        let mut root = PciRootComplex::new().unwrap();
+        assert_eq!(root.root_bus_ranges(), vec![(0, 0)]);
+        assert_eq!(root.depth(), 0);
+
+        // Bus 0 -> bus 1, and the second host bridge roots bus 0x10 -> bus 0x11 -> bus 0x14.
+        root.bus.lock().unwrap().add_bus(PciBus::new(1)).unwrap();
+        let mut second = PciBus::new(0x10);
+        let mut middle = PciBus::new(0x11);
+        middle.add_bus(PciBus::new(0x14)).unwrap();
+        second.add_bus(middle).unwrap();
+        root.add_host_bridge(1, Some(second)).unwrap();
+
+        assert_eq!(root.root_bus_ranges(), vec![(0, 1), (0x10, 0x14)]);
+        // The bus 0x10 is not a hop beneath bus 0.
+        assert_eq!(root.depth(), 2);
+    }
+
+    #[test]
+    fn root_complex_guest_write_protection() {
+        let mut root = PciRootComplex::new().unwrap();
+        let function = root
+            .bus
+            .lock()
+            .unwrap()
+            .get_device(0)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get_function(0)
+            .unwrap()
+            .clone();
+        function
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .set_guest_writable(false);
+
+        // The data port write of the Interrupt Line is dropped.
+        root.write(
+            OFFSET_ADDRESS,
+            &encode_configuration_address(0, 0, 0, 15, true).to_le_bytes(),
+        );
+        root.write(OFFSET_DATA, &[0x0A]);
+        assert_eq!(root.read_configuration_space(), 0);
+
+        // The VMM can still change the register.
+        function
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .write_configuration_byte(15, 0, 0x0B);
+        assert_eq!(root.read_configuration_space(), 0x0B);
+
+        function
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .set_guest_writable(true);
+        root.write(OFFSET_DATA, &[0x0A]);
+        assert_eq!(root.read_configuration_space(), 0x0A);
+    }
+
+    #[test]
+    fn root_complex_address_port_reads() {
+        let mut root = PciRootComplex::new().unwrap();
+        // Bus 0x12, device 7, function 2, register 3, with the enable bit.
+        let address = 0x8012_3A0Cu32;
+        assert_eq!(encode_configuration_address(0x12, 7, 2, 3, true), address);
+        root.write(OFFSET_ADDRESS, &address.to_le_bytes());
+
+        let mut dword = [0u8; 4];
+        root.read(OFFSET_ADDRESS, &mut dword);
+        assert_eq!(u32::from_le_bytes(dword), address);
+
+        // Byte reads: register, function and device, bus, enable bit.
+        for (offset, expected) in [0x0C, 0x3A, 0x12, 0x80].iter().enumerate() {
+            let mut byte = [0u8; 1];
+            root.read(OFFSET_ADDRESS + offset as u64, &mut byte);
+            assert_eq!(byte[0], *expected);
+        }
+
+        // Word reads: device and function with the register, then bus with the enable bit.
+        for (offset, expected) in [(0, 0x3A0C), (1, 0x123A), (2, 0x8012)].iter() {
+            let mut word = [0u8; 2];
+            root.read(OFFSET_ADDRESS + offset, &mut word);
+            assert_eq!(u16::from_le_bytes(word), *expected);
+        }
+
+        // The accesses crossing the end of the port return all ones.
+        let mut word = [0u8; 2];
+        root.read(OFFSET_ADDRESS + 3, &mut word);
+        assert_eq!(word, [0xFF; 2]);
+        root.read(OFFSET_ADDRESS + 1, &mut dword);
+        assert_eq!(dword, [0xFF; 4]);
+
+        // A byte write to the bus field is latched and read back.
+        root.write(OFFSET_ADDRESS + 2, &[0x34]);
+        let mut byte = [0u8; 1];
+        root.read(OFFSET_ADDRESS + 2, &mut byte);
+        assert_eq!(byte[0], 0x34);
+        assert_eq!(root.get_configuration_address(), 0x8034_3A0C);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn root_complex_trace() {
+        use logger::{Log, Metadata, Record};
+        use std::cell::RefCell;
+
+        thread_local! {
+            static RECORDS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        }
+
+        // The records are captured per thread, so the tests running in parallel do not mix.
+        struct CapturingLogger;
+
+        impl Log for CapturingLogger {
+            fn enabled(&self, _metadata: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                RECORDS.with(|records| records.borrow_mut().push(record.args().to_string()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger;
+        // Another test may have installed the logger already.
+        let _ = logger::set_logger(&LOGGER);
+        logger::set_max_level(logger::LevelFilter::Trace);
+
+        let mut root = PciRootComplex::new().unwrap();
+        root.write(OFFSET_ADDRESS, &0x8000_0004u32.to_le_bytes());
+        root.write(OFFSET_DATA + 1, &[0x04]);
+        let mut data = [0u8; 4];
+        root.read(OFFSET_DATA, &mut data);
+        // An access to an absent function is traced too.
+        root.write(OFFSET_ADDRESS, &0x8000_0800u32.to_le_bytes());
+        root.read(OFFSET_DATA, &mut data);
+
+        RECORDS.with(|records| {
+            assert_eq!(
+                *records.borrow(),
+                vec![
+                    "config write 00:00.0 [0x004] offset 1 width 1 value 0x4".to_string(),
+                    format!(
+                        "config read 00:00.0 [0x004] width 4 value {:#010x}",
+                        0x0400 | u32::from(!crate::function::STATUS_INTERRUPT_STATUS) << 16
+                    ),
+                    "config read 00:01.0 [0x000] width 4 value 0xffffffff".to_string(),
+                ]
+            )
+        });
+    }
+}
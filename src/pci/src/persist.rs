@@ -0,0 +1,956 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the structures needed for saving/restoring the PCI topology.
+
+use crate::bar::{PciBarConfiguration, PciBarRegionType, NUM_BAR_REGISTERS};
+use crate::bus::{PciBus, PciBusError, MAX_BUS_NUMBER};
+use crate::device::{PciDevice, PciDeviceError};
+use crate::function::{
+    PciFunction, CONFIGURATION_HEADER_SIZE, CONFIGURATION_SPACE_SIZE, MAX_CAPABILITIES,
+};
+use crate::pci::{ConfigAddress, PciConfigMechanism, PciRootComplex};
+use snapshot::Persist;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use versionize_derive::Versionize;
+
+/// Return the map of the versions of the saved PCI state:
+/// - `1` is the initial layout of the state.
+/// - `2` adds the VPD and Power Budgeting data, the presence, the write protection, the
+///   unmasked MSI-X vectors, the capability limit and the reset defaults of the functions,
+///   the reserved slots of the buses, and the decoded buses and the absent read value of
+///   the root complex. The version number recorded by the root complex is dropped.
+pub fn version_map() -> VersionMap {
+    let mut version_map = VersionMap::new();
+    version_map
+        .new_version()
+        .set_type_version(PciFunctionState::type_id(), 2)
+        .set_type_version(PciFunctionCompactState::type_id(), 2)
+        .set_type_version(PciBusState::type_id(), 2)
+        .set_type_version(PciRootComplexState::type_id(), 2);
+
+    version_map
+}
+
+/// Errors for restoring the PCI topology.
+#[derive(Debug)]
+pub enum Error {
+    /// The saved configuration space of a function has an invalid length.
+    InvalidConfigurationSpaceSize(usize),
+    /// A saved device could not be added to its bus.
+    Bus(PciBusError),
+    /// A saved function could not be added to its device.
+    Device(PciDeviceError),
+    /// The saved description of a BAR is invalid.
+    InvalidBar(usize),
+    /// The state was saved by a newer version of this crate.
+    UnsupportedVersion(u16),
+    /// The state could not be deserialized.
+    Deserialize(VersionizeError),
+    /// A saved register is outside the configuration space.
+    InvalidRegister(usize),
+}
+
+/// State for saving a Base Address Register description.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciBarState {
+    index: u64,
+    region_type: u32,
+    size: u64,
+    prefetchable: bool,
+}
+
+impl PciBarState {
+    fn new(bar: &PciBarConfiguration) -> Self {
+        PciBarState {
+            index: bar.index as u64,
+            region_type: bar.region_type as u32,
+            size: bar.size,
+            prefetchable: bar.prefetchable,
+        }
+    }
+
+    fn to_configuration(&self) -> Result<PciBarConfiguration, Error> {
+        let index = self.index as usize;
+        let region_type = match self.region_type {
+            0 => PciBarRegionType::Memory32BitRegion,
+            1 => PciBarRegionType::IoRegion,
+            2 => PciBarRegionType::Memory64BitRegion,
+            _ => return Err(Error::InvalidBar(index)),
+        };
+
+        let bar = PciBarConfiguration {
+            index,
+            region_type,
+            size: self.size,
+            prefetchable: self.prefetchable,
+            address: 0,
+        };
+
+        if index + bar.register_count() > NUM_BAR_REGISTERS || !bar.is_size_valid() {
+            return Err(Error::InvalidBar(index));
+        }
+
+        Ok(bar)
+    }
+}
+
+/// State for saving a PciFunction.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciFunctionState {
+    number: u64,
+    configuration_space: Vec<u32>,
+    next_capability_register: u64,
+    last_capability_register: Option<u64>,
+    next_extended_capability_register: u64,
+    last_extended_capability_register: Option<u64>,
+    bars: Vec<PciBarState>,
+    #[version(start = 2)]
+    vpd_register: Option<u64>,
+    #[version(start = 2)]
+    vpd_data: Vec<u8>,
+    #[version(start = 2)]
+    power_budget_register: Option<u64>,
+    #[version(start = 2)]
+    power_budget_data: Vec<u32>,
+    #[version(start = 2, default_fn = "default_true")]
+    present: bool,
+    #[version(start = 2, default_fn = "default_true")]
+    guest_writable: bool,
+    #[version(start = 2)]
+    msix_unmasked_vectors: Vec<u16>,
+    #[version(start = 2, default_fn = "default_max_capabilities")]
+    max_capabilities: u64,
+    #[version(start = 2)]
+    defaults: Option<Vec<u32>>,
+    #[version(start = 2)]
+    rom_size: u32,
+}
+
+impl PciFunctionState {
+    fn default_true(_source_version: u16) -> bool {
+        true
+    }
+
+    fn default_max_capabilities(_source_version: u16) -> u64 {
+        MAX_CAPABILITIES as u64
+    }
+}
+
+impl Persist<'_> for PciFunction {
+    type State = PciFunctionState;
+    type ConstructorArgs = ();
+    type Error = Error;
+
+    fn save(&self) -> Self::State {
+        let state = PciFunctionState {
+            number: self.number as u64,
+            configuration_space: self.configuration_space.clone(),
+            next_capability_register: self.next_capability_register as u64,
+            last_capability_register: self.last_capability_register.map(|last| last as u64),
+            next_extended_capability_register: self.next_extended_capability_register as u64,
+            last_extended_capability_register: self
+                .last_extended_capability_register
+                .map(|last| last as u64),
+            bars: self
+                .bars
+                .iter()
+                .filter_map(|bar| bar.as_ref().map(PciBarState::new))
+                .collect(),
+            vpd_register: self.vpd.as_ref().map(|(register, _)| *register as u64),
+            vpd_data: self
+                .vpd
+                .as_ref()
+                .map_or_else(Vec::new, |(_, data)| data.clone()),
+            power_budget_register: self
+                .power_budget
+                .as_ref()
+                .map(|(register, _)| *register as u64),
+            power_budget_data: self
+                .power_budget
+                .as_ref()
+                .map_or_else(Vec::new, |(_, data)| data.clone()),
+            present: self.present,
+            guest_writable: self.guest_writable,
+            msix_unmasked_vectors: self.msix_unmasked_vectors.iter().cloned().collect(),
+            max_capabilities: self.max_capabilities as u64,
+            defaults: self.defaults.clone(),
+            rom_size: self.rom_size,
+        };
+        self.clear_dirty();
+
+        state
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        if state.configuration_space.len() != CONFIGURATION_SPACE_SIZE {
+            return Err(Error::InvalidConfigurationSpaceSize(
+                state.configuration_space.len(),
+            ));
+        }
+        if let Some(defaults) = state.defaults.as_ref() {
+            if defaults.len() != CONFIGURATION_SPACE_SIZE {
+                return Err(Error::InvalidConfigurationSpaceSize(defaults.len()));
+            }
+        }
+        // The VPD and Power Budgeting capabilities span several registers from the saved one.
+        for register in state
+            .vpd_register
+            .iter()
+            .chain(state.power_budget_register.iter())
+        {
+            let register = *register as usize;
+            if register >= CONFIGURATION_SPACE_SIZE - 3 {
+                return Err(Error::InvalidRegister(register));
+            }
+        }
+
+        let mut function = PciFunction {
+            number: state.number as usize,
+            configuration_space: state.configuration_space.clone(),
+            next_capability_register: state.next_capability_register as usize,
+            last_capability_register: state.last_capability_register.map(|last| last as usize),
+            next_extended_capability_register: state.next_extended_capability_register as usize,
+            last_extended_capability_register: state
+                .last_extended_capability_register
+                .map(|last| last as usize),
+            write_masks: vec![0xFFFF_FFFF; CONFIGURATION_SPACE_SIZE],
+            bars: [None; NUM_BAR_REGISTERS],
+            vpd: state
+                .vpd_register
+                .map(|register| (register as usize, state.vpd_data.clone())),
+            power_budget: state
+                .power_budget_register
+                .map(|register| (register as usize, state.power_budget_data.clone())),
+            // The device models register their read callbacks again.
+            read_callbacks: BTreeMap::new(),
+            bar_handlers: BTreeMap::new(),
+            present: state.present,
+            guest_writable: state.guest_writable,
+            // The owner attaches its backing again.
+            backing: None,
+            // The owner tags its functions again, along with its callbacks.
+            user_tag: None,
+            mapping_callback: None,
+            mapping_events: Vec::new(),
+            defer_bar_registration: false,
+            pending_bar_writes: false,
+            fault_callback: None,
+            msix_unmasked_vectors: state.msix_unmasked_vectors.iter().cloned().collect(),
+            max_capabilities: state.max_capabilities as usize,
+            rom_size: state.rom_size,
+            dirty: AtomicBool::new(false),
+            shared_header: Arc::new(AtomicU64::new(0)),
+            defaults: state.defaults.clone(),
+        };
+
+        // The write masks are derived from the BAR descriptions.
+        for bar in state.bars.iter() {
+            let bar = bar.to_configuration()?;
+            function.bars[bar.index] = Some(bar);
+        }
+        function.update_write_masks();
+        // The restored function matches its saved state.
+        function.clear_dirty();
+
+        Ok(function)
+    }
+}
+
+/// State for saving a register of the configuration space.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciRegisterState {
+    register: u64,
+    value: u32,
+}
+
+/// Compact state for saving a PciFunction. Most of the configuration space is zero, so only
+/// the configuration header and the non-zero registers past it are recorded.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciFunctionCompactState {
+    /// The state of the function, without its configuration space.
+    function: PciFunctionState,
+    header: Vec<u32>,
+    registers: Vec<PciRegisterState>,
+    /// The registers whose value differs in the defaults restored by a reset, if captured.
+    #[version(start = 2)]
+    defaults: Option<Vec<PciRegisterState>>,
+}
+
+impl PciFunction {
+    /// Save the state of the function in its compact form, for the small snapshots.
+    pub fn save_compact(&self) -> PciFunctionCompactState {
+        let mut function = self.save();
+        let configuration_space = std::mem::take(&mut function.configuration_space);
+        let defaults = function.defaults.take().map(|defaults| {
+            defaults
+                .iter()
+                .zip(configuration_space.iter())
+                .enumerate()
+                .filter(|(_, (default, value))| default != value)
+                .map(|(register, (default, _))| PciRegisterState {
+                    register: register as u64,
+                    value: *default,
+                })
+                .collect()
+        });
+
+        PciFunctionCompactState {
+            function,
+            defaults,
+            header: configuration_space[..CONFIGURATION_HEADER_SIZE].to_vec(),
+            registers: configuration_space
+                .iter()
+                .enumerate()
+                .skip(CONFIGURATION_HEADER_SIZE)
+                .filter(|(_, value)| **value != 0)
+                .map(|(register, value)| PciRegisterState {
+                    register: register as u64,
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    /// Restore a function saved in its compact form by `save_compact`.
+    /// * `state` - The compact state of the function.
+    pub fn restore_compact(state: &PciFunctionCompactState) -> Result<Self, Error> {
+        if state.header.len() != CONFIGURATION_HEADER_SIZE {
+            return Err(Error::InvalidConfigurationSpaceSize(state.header.len()));
+        }
+
+        let mut configuration_space = vec![0; CONFIGURATION_SPACE_SIZE];
+        configuration_space[..CONFIGURATION_HEADER_SIZE].copy_from_slice(&state.header);
+        for register in state.registers.iter() {
+            let index = register.register as usize;
+            if !(CONFIGURATION_HEADER_SIZE..CONFIGURATION_SPACE_SIZE).contains(&index) {
+                return Err(Error::InvalidRegister(index));
+            }
+            configuration_space[index] = register.value;
+        }
+
+        let mut function = state.function.clone();
+        if let Some(registers) = state.defaults.as_ref() {
+            let mut defaults = configuration_space.clone();
+            for register in registers.iter() {
+                let index = register.register as usize;
+                if index >= CONFIGURATION_SPACE_SIZE {
+                    return Err(Error::InvalidRegister(index));
+                }
+                defaults[index] = register.value;
+            }
+            function.defaults = Some(defaults);
+        }
+        function.configuration_space = configuration_space;
+        PciFunction::restore((), &function)
+    }
+}
+
+/// State for saving a PciDevice.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciDeviceState {
+    number: u64,
+    functions: Vec<PciFunctionState>,
+}
+
+impl Persist<'_> for PciDevice {
+    type State = PciDeviceState;
+    type ConstructorArgs = ();
+    type Error = Error;
+
+    fn save(&self) -> Self::State {
+        // The functions implemented outside of this crate are saved by their owners, which
+        // add them again after the restore, along with their backings and callbacks.
+        let mut functions = Vec::new();
+        self.for_each_function(|_, function| functions.push(function.save()));
+
+        PciDeviceState {
+            number: self.number as u64,
+            functions,
+        }
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        let mut device = PciDevice::new(state.number as usize);
+
+        for function in state.functions.iter() {
+            device
+                .add_function(PciFunction::restore((), function)?)
+                .map_err(Error::Device)?;
+        }
+
+        Ok(device)
+    }
+}
+
+/// State for saving a PciBus.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciBusState {
+    number: u64,
+    buses: Vec<PciBusState>,
+    devices: Vec<PciDeviceState>,
+    #[version(start = 2)]
+    reserved_slots: Vec<u64>,
+}
+
+impl Persist<'_> for PciBus {
+    type State = PciBusState;
+    type ConstructorArgs = ();
+    type Error = Error;
+
+    fn save(&self) -> Self::State {
+        PciBusState {
+            number: self.number as u64,
+            buses: self
+                .buses
+                .values()
+                .map(|bus| bus.lock().unwrap().save())
+                .collect(),
+            devices: self
+                .devices
+                .values()
+                .map(|device| device.lock().unwrap().save())
+                .collect(),
+            reserved_slots: self
+                .reserved_slots
+                .iter()
+                .map(|device| *device as u64)
+                .collect(),
+        }
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        let mut bus = PciBus::new(state.number as usize);
+
+        for child in state.buses.iter() {
+            bus.add_bus(PciBus::restore((), child)?)
+                .map_err(Error::Bus)?;
+        }
+
+        for device in state.devices.iter() {
+            bus.add_device(PciDevice::restore((), device)?)
+                .map_err(Error::Bus)?;
+        }
+
+        // A reserved slot may also hold a device added explicitly.
+        bus.reserved_slots = state
+            .reserved_slots
+            .iter()
+            .map(|device| *device as usize)
+            .collect();
+
+        Ok(bus)
+    }
+}
+
+/// State for saving the bus rooted by a host bridge function.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciRootBusState {
+    function: u64,
+    bus: u64,
+}
+
+/// State for saving a PciRootComplex.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct PciRootComplexState {
+    /// The version number of the states saved before the version map, which rejected the
+    /// versions above 2.
+    #[version(start = 1, end = 2)]
+    version: u16,
+    bus: PciBusState,
+    config_address: u32,
+    root_buses: Vec<PciRootBusState>,
+    mechanism2: bool,
+    cse: u8,
+    forward: u8,
+    #[version(start = 2, default_fn = "default_max_bus")]
+    max_bus: u64,
+    #[version(start = 2, default_fn = "default_absent_read_value")]
+    absent_read_value: u32,
+}
+
+impl PciRootComplexState {
+    fn default_max_bus(_source_version: u16) -> u64 {
+        MAX_BUS_NUMBER as u64
+    }
+
+    fn default_absent_read_value(_source_version: u16) -> u32 {
+        0xFFFF_FFFF
+    }
+
+    /// Deserialize a state saved at a version of the version map, rejecting the versions this
+    /// crate does not know.
+    /// * `reader` - The serialized state.
+    /// * `version_map` - The version map of the state, such as `version_map()`.
+    /// * `version` - The version the state was saved at.
+    pub fn load<R: Read>(
+        reader: &mut R,
+        version_map: &VersionMap,
+        version: u16,
+    ) -> Result<Self, Error> {
+        if version > version_map.latest_version() {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        PciRootComplexState::deserialize(reader, version_map, version).map_err(Error::Deserialize)
+    }
+}
+
+impl Persist<'_> for PciRootComplex {
+    type State = PciRootComplexState;
+    type ConstructorArgs = ();
+    type Error = Error;
+
+    fn save(&self) -> Self::State {
+        PciRootComplexState {
+            version: 2,
+            bus: self.bus.lock().unwrap().save(),
+            config_address: self.config_address.raw(),
+            root_buses: self
+                .root_buses
+                .iter()
+                .map(|(function, bus)| PciRootBusState {
+                    function: *function as u64,
+                    bus: *bus as u64,
+                })
+                .collect(),
+            mechanism2: self.mechanism == PciConfigMechanism::Mechanism2,
+            cse: self.cse,
+            forward: self.forward,
+            max_bus: self.max_bus as u64,
+            absent_read_value: self.absent_read_value,
+        }
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        Ok(PciRootComplex {
+            bus: Arc::new(Mutex::new(PciBus::restore((), &state.bus)?)),
+            config_address: ConfigAddress::new(state.config_address),
+            root_buses: state
+                .root_buses
+                .iter()
+                .map(|root| (root.function as usize, root.bus as usize))
+                .collect(),
+            dropped_writes: 0,
+            mechanism: if state.mechanism2 {
+                PciConfigMechanism::Mechanism2
+            } else {
+                PciConfigMechanism::Mechanism1
+            },
+            cse: state.cse,
+            forward: state.forward,
+            rate_limiter: None,
+            max_bus: state.max_bus as usize,
+            absent_read_value: state.absent_read_value,
+        })
+    }
+}
+
+impl PciRootComplex {
+    /// Restore a saved state into this node, which may be registered on the bus of the guest.
+    /// The new tree is fully built before being swapped in under the lock of the bus, so the
+    /// configuration accesses see either the previous tree or the restored one, never a partial
+    /// tree. Nothing is changed if the state cannot be restored.
+    /// - `state` - the saved state of a PCI Root Complex node.
+    pub fn restore_state(&mut self, state: &PciRootComplexState) -> Result<(), Error> {
+        let PciRootComplex {
+            bus,
+            config_address,
+            root_buses,
+            mechanism,
+            cse,
+            forward,
+            max_bus,
+            absent_read_value,
+            ..
+        } = PciRootComplex::restore((), state)?;
+
+        // The rate limiter and the hotplug callback are not part of the state, so they are kept.
+        {
+            let mut current = self.bus.lock().unwrap();
+            let hotplug_callback = current.hotplug_callback.take();
+            std::mem::swap(&mut *current, &mut *bus.lock().unwrap());
+            current.hotplug_callback = hotplug_callback;
+        }
+        self.config_address = config_address;
+        self.root_buses = root_buses;
+        self.dropped_writes = 0;
+        self.mechanism = mechanism;
+        self.cse = cse;
+        self.forward = forward;
+        self.max_bus = max_bus;
+        self.absent_read_value = absent_read_value;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::PowerManagementCapability;
+
+    fn serialize(root: &PciRootComplex) -> Vec<u8> {
+        let version_map = version_map();
+        let mut mem = Vec::new();
+        root.save()
+            .serialize(&mut mem, &version_map, version_map.latest_version())
+            .unwrap();
+
+        mem
+    }
+
+    #[test]
+    fn test_root_complex_persistence() {
+        let root = PciRootComplex::new().unwrap();
+        root.bus
+            .lock()
+            .unwrap()
+            .add_device(PciDevice::new_dummy_host_bridge(3))
+            .unwrap();
+
+        let mem = serialize(&root);
+        let state = PciRootComplexState::load(&mut mem.as_slice(), &version_map(), 2).unwrap();
+        let restored = PciRootComplex::restore((), &state).unwrap();
+
+        assert_eq!(restored.save(), root.save());
+        assert_eq!(
+            restored
+                .bus
+                .lock()
+                .unwrap()
+                .read_configuration_register(0, 3, 0, 0),
+            root.bus
+                .lock()
+                .unwrap()
+                .read_configuration_register(0, 3, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_deterministic_state() {
+        let first = PciRootComplex::new().unwrap();
+        let second = PciRootComplex::new().unwrap();
+
+        {
+            let mut bus = first.bus.lock().unwrap();
+            let mut device = PciDevice::new(7);
+            device
+                .add_function(PciFunction::new_dummy_host_bridge(1))
+                .unwrap();
+            device
+                .add_function(PciFunction::new_dummy_host_bridge(5))
+                .unwrap();
+
+            bus.add_device(PciDevice::new_dummy_host_bridge(2)).unwrap();
+            bus.add_device(device).unwrap();
+            bus.add_bus(PciBus::new(1)).unwrap();
+            bus.add_bus(PciBus::new(4)).unwrap();
+        }
+
+        {
+            let mut bus = second.bus.lock().unwrap();
+            let mut device = PciDevice::new(7);
+            device
+                .add_function(PciFunction::new_dummy_host_bridge(5))
+                .unwrap();
+            device
+                .add_function(PciFunction::new_dummy_host_bridge(1))
+                .unwrap();
+
+            bus.add_bus(PciBus::new(4)).unwrap();
+            bus.add_device(device).unwrap();
+            bus.add_bus(PciBus::new(1)).unwrap();
+            bus.add_device(PciDevice::new_dummy_host_bridge(2)).unwrap();
+        }
+
+        assert_eq!(serialize(&first), serialize(&second));
+    }
+
+    #[test]
+    fn test_invalid_function_state() {
+        let mut state = PciFunction::new_dummy_host_bridge(0).save();
+        state.configuration_space.pop();
+
+        match PciFunction::restore((), &state) {
+            Err(Error::InvalidConfigurationSpaceSize(size)) => {
+                assert_eq!(size, CONFIGURATION_SPACE_SIZE - 1)
+            }
+            _ => panic!("Restoring a truncated configuration space must fail."),
+        }
+
+        let mut state = PciFunction::new_dummy_host_bridge(0).save();
+        state.vpd_register = Some(CONFIGURATION_SPACE_SIZE as u64 - 3);
+        match PciFunction::restore((), &state) {
+            Err(Error::InvalidRegister(register)) => {
+                assert_eq!(register, CONFIGURATION_SPACE_SIZE - 3)
+            }
+            _ => panic!("Restoring a VPD capability out of the configuration space must fail."),
+        }
+
+        let mut state = PciFunction::new_dummy_host_bridge(0).save();
+        state.power_budget_register = Some(u64::MAX);
+        match PciFunction::restore((), &state) {
+            Err(Error::InvalidRegister(_)) => (),
+            _ => panic!("Restoring a Power Budgeting capability out of the space must fail."),
+        }
+        state.power_budget_register = Some(CONFIGURATION_SPACE_SIZE as u64 - 4);
+        assert!(PciFunction::restore((), &state).is_ok());
+    }
+
+    // The layout of the states saved at version 1.
+    #[derive(Versionize)]
+    struct PciFunctionStateV1 {
+        number: u64,
+        configuration_space: Vec<u32>,
+        next_capability_register: u64,
+        last_capability_register: Option<u64>,
+        next_extended_capability_register: u64,
+        last_extended_capability_register: Option<u64>,
+        bars: Vec<PciBarState>,
+    }
+
+    #[derive(Versionize)]
+    struct PciDeviceStateV1 {
+        number: u64,
+        functions: Vec<PciFunctionStateV1>,
+    }
+
+    #[derive(Versionize)]
+    struct PciBusStateV1 {
+        number: u64,
+        buses: Vec<PciBusStateV1>,
+        devices: Vec<PciDeviceStateV1>,
+    }
+
+    #[derive(Versionize)]
+    struct PciRootComplexStateV1 {
+        version: u16,
+        bus: PciBusStateV1,
+        config_address: u32,
+        root_buses: Vec<PciRootBusState>,
+        mechanism2: bool,
+        cse: u8,
+        forward: u8,
+    }
+
+    #[test]
+    fn test_state_versions() {
+        let root = PciRootComplex::new().unwrap();
+        root.bus
+            .lock()
+            .unwrap()
+            .get_device(0)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get_function(0)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .add_capability(&PowerManagementCapability)
+            .unwrap();
+
+        let state = root.save();
+        let function = &state.bus.devices[0].functions[0];
+        let v1 = PciRootComplexStateV1 {
+            version: 2,
+            bus: PciBusStateV1 {
+                number: 0,
+                buses: Vec::new(),
+                devices: vec![PciDeviceStateV1 {
+                    number: 0,
+                    functions: vec![PciFunctionStateV1 {
+                        number: 0,
+                        configuration_space: function.configuration_space.clone(),
+                        next_capability_register: function.next_capability_register,
+                        last_capability_register: function.last_capability_register,
+                        next_extended_capability_register: function
+                            .next_extended_capability_register,
+                        last_extended_capability_register: function
+                            .last_extended_capability_register,
+                        bars: function.bars.clone(),
+                    }],
+                }],
+            },
+            config_address: state.config_address,
+            root_buses: state.root_buses.clone(),
+            mechanism2: false,
+            cse: 0,
+            forward: 0,
+        };
+        let mut mem = Vec::new();
+        v1.serialize(&mut mem, &VersionMap::new(), 1).unwrap();
+
+        // The fields missing from a version 1 state take the values of a new root complex.
+        let loaded = PciRootComplexState::load(&mut mem.as_slice(), &version_map(), 1).unwrap();
+        let restored = PciRootComplex::restore((), &loaded).unwrap();
+        assert_eq!(restored.save(), state);
+        {
+            let bus = restored.bus.lock().unwrap();
+            let device = bus.get_device(0).unwrap().lock().unwrap();
+            let mut function = device.get_function(0).unwrap().lock().unwrap();
+            let function = function.as_function_mut().unwrap();
+            assert!(function.is_present());
+            let register = function.add_capability(&PowerManagementCapability).unwrap();
+            assert_eq!(register, 18);
+        }
+
+        // Saving a state at version 1 drops the newer fields.
+        let mut old = Vec::new();
+        state.serialize(&mut old, &version_map(), 1).unwrap();
+        assert_eq!(old, mem);
+
+        match PciRootComplexState::load(&mut mem.as_slice(), &version_map(), 999) {
+            Err(Error::UnsupportedVersion(999)) => (),
+            _ => panic!("Loading a state from the future must fail."),
+        }
+    }
+
+    #[test]
+    fn test_restore_state_during_reads() {
+        let mut root = PciRootComplex::new().unwrap();
+        root.bus
+            .lock()
+            .unwrap()
+            .add_device(PciDevice::new_dummy_host_bridge(3))
+            .unwrap();
+
+        let other = PciRootComplex::new().unwrap();
+        other
+            .bus
+            .lock()
+            .unwrap()
+            .add_device(PciDevice::new_dummy_host_bridge(5))
+            .unwrap();
+        let state = other.save();
+
+        // The guest sees either the device 3 of the previous tree or the device 5 of the
+        // restored one, but never both or none of them.
+        let bus = root.bus.clone();
+        let reader = std::thread::spawn(move || {
+            for _ in 0..1000 {
+                let bus = bus.lock().unwrap();
+                let previous = bus.read_configuration_register(0, 3, 0, 0).is_some();
+                let restored = bus.read_configuration_register(0, 5, 0, 0).is_some();
+                assert_ne!(previous, restored);
+            }
+        });
+        for _ in 0..100 {
+            root.restore_state(&state).unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(root.save(), state);
+        assert!(root.bus.lock().unwrap().has_function(0, 5, 0));
+
+        // A state which cannot be restored leaves the tree unchanged.
+        let mut invalid = state.clone();
+        invalid.bus.devices[0].functions[0]
+            .configuration_space
+            .pop();
+        assert!(root.restore_state(&invalid).is_err());
+        assert_eq!(root.save(), state);
+    }
+
+    #[test]
+    fn test_dirty_since_snapshot() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        assert!(function.is_dirty_since_snapshot());
+
+        let state = function.save();
+        assert!(!function.is_dirty_since_snapshot());
+        assert!(!PciFunction::restore((), &state)
+            .unwrap()
+            .is_dirty_since_snapshot());
+
+        // The writes of the guest dirty the function, the dropped ones do not.
+        function.set_guest_writable(false);
+        function.save();
+        function.write_configuration_register(1, 0, &[0x06, 0x00]);
+        assert!(!function.is_dirty_since_snapshot());
+        function.set_guest_writable(true);
+        function.save();
+        function.write_configuration_register(1, 0, &[0x06, 0x00]);
+        assert!(function.is_dirty_since_snapshot());
+
+        // So do the changes of the device model.
+        function.save();
+        function.set_msix_vector_masked(0, false);
+        assert!(function.is_dirty_since_snapshot());
+        function.save();
+        function.add_capability(&PowerManagementCapability).unwrap();
+        assert!(function.is_dirty_since_snapshot());
+    }
+
+    #[test]
+    fn test_compact_function_state() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        function.add_capability(&PowerManagementCapability).unwrap();
+        function
+            .add_serial_number_capability(0x0123_4567_89AB_CDEF)
+            .unwrap();
+
+        let compact = function.save_compact();
+        let restored = PciFunction::restore_compact(&compact).unwrap();
+        assert_eq!(restored.save(), function.save());
+        assert_eq!(restored.config_copy()[..], function.config_copy()[..]);
+
+        let version_map = version_map();
+        let mut full = Vec::new();
+        function
+            .save()
+            .serialize(&mut full, &version_map, 2)
+            .unwrap();
+        let mut small = Vec::new();
+        compact.serialize(&mut small, &version_map, 2).unwrap();
+        assert!(small.len() * 10 < full.len());
+
+        let state =
+            PciFunctionCompactState::deserialize(&mut small.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(state, compact);
+
+        // The registers have to be within the configuration space, past the header.
+        let mut invalid = compact.clone();
+        invalid.registers[0].register = CONFIGURATION_SPACE_SIZE as u64;
+        match PciFunction::restore_compact(&invalid) {
+            Err(Error::InvalidRegister(register)) => assert_eq!(register, CONFIGURATION_SPACE_SIZE),
+            _ => panic!("Restoring a register out of the configuration space must fail."),
+        }
+        let mut invalid = compact;
+        invalid.header.pop();
+        assert!(PciFunction::restore_compact(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_reset_defaults_persist() {
+        let mut function = PciFunction::new_dummy_host_bridge(0);
+        let initial = function.config_copy();
+        function.write_configuration_register(1, 0, &[0x06]);
+        function.write_configuration_register(CONFIGURATION_HEADER_SIZE, 0, &[0xFF; 4]);
+
+        let mut restored = PciFunction::restore((), &function.save()).unwrap();
+        let mut compact = PciFunction::restore_compact(&function.save_compact()).unwrap();
+        assert_eq!(compact.save(), function.save());
+
+        // The defaults captured before the first write of the guest survive the snapshots.
+        for function in [&mut function, &mut restored, &mut compact].iter_mut() {
+            assert_ne!(function.config_copy()[..], initial[..]);
+            function.reset();
+            assert_eq!(function.config_copy()[..], initial[..]);
+        }
+
+        let mut invalid = function.save();
+        invalid.defaults = Some(vec![0; 4]);
+        match PciFunction::restore((), &invalid) {
+            Err(Error::InvalidConfigurationSpaceSize(size)) => assert_eq!(size, 4),
+            _ => panic!("Restoring defaults of an invalid size must fail."),
+        }
+    }
+}
@@ -0,0 +1,655 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::bus::{PciBus, MAX_BUS_NUMBER};
+use crate::device::PciDevice;
+use crate::function::{
+    PciClassCode, PciConfigurable, PciFunction, PciFunctionBuilder, PciHeaderType,
+    CONFIGURATION_SPACE_SIZE,
+};
+use crate::pci::{PciError, PciRootComplex, Result};
+use crate::visitor::PciTreeVisitor;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Errors found by the validation of the bus numbers of a topology.
+#[derive(Debug, PartialEq)]
+pub enum TopologyError {
+    /// The bus number is assigned to more than one bus.
+    DuplicateBusNumber(usize),
+    /// The bus ranges of two bridges of the same bus overlap, as their secondary buses.
+    OverlappingBusRanges(usize, usize),
+    /// The bus is connected to the tree, but no bridge forwards the accesses to it, so its
+    /// devices are not reachable by the guest.
+    UnreachableBus(usize),
+}
+
+/// The description of a whole PCI topology, as found in the configuration of a VMM.
+/// The host bridge always occupies the device 0 of bus 0.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct TopologySpec {
+    /// The devices connected to bus 0.
+    #[serde(default)]
+    pub devices: Vec<DeviceSpec>,
+
+    /// The bridges connected to bus 0.
+    #[serde(default)]
+    pub bridges: Vec<BridgeSpec>,
+}
+
+/// The description of a PCI-to-PCI bridge and of the bus behind it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BridgeSpec {
+    /// The number of the device of the bridge on its bus.
+    pub device: usize,
+
+    /// The number of the bus connected behind the bridge. It has to be greater than the
+    /// number of the bus of the bridge.
+    pub secondary_bus: usize,
+
+    /// The devices connected to the secondary bus.
+    #[serde(default)]
+    pub devices: Vec<DeviceSpec>,
+
+    /// The bridges connected to the secondary bus.
+    #[serde(default)]
+    pub bridges: Vec<BridgeSpec>,
+}
+
+/// The description of a PCI device and of its functions.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DeviceSpec {
+    /// The number of the device on its bus.
+    pub device: usize,
+
+    /// The functions of the device.
+    pub functions: Vec<FunctionSpec>,
+}
+
+/// The description of a function with a Type 0 header.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FunctionSpec {
+    /// The number of the function within its device.
+    #[serde(default)]
+    pub function: usize,
+
+    pub vendor_id: u16,
+
+    pub device_id: u16,
+
+    pub class_code: PciClassCode,
+
+    #[serde(default)]
+    pub revision_id: u8,
+
+    #[serde(default)]
+    pub subsystem_id: u16,
+
+    #[serde(default)]
+    pub subsystem_vendor_id: u16,
+}
+
+/// A point-in-time copy of a whole PCI topology. It only owns plain values, so it can be
+/// inspected or printed without locking anything in the tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TopologySnapshot {
+    /// The numbers of the buses, from bus 0 downwards.
+    pub buses: Vec<usize>,
+
+    /// The configuration space of each function, as stored, by (bus, device, function).
+    pub functions: BTreeMap<(usize, usize, usize), Vec<u32>>,
+}
+
+impl TopologySnapshot {
+    /// Return the configuration space of a function, if it exists.
+    /// - `bus` - the number of the bus.
+    /// - `device` - the number of the device on the bus.
+    /// - `function` - the number of the function of the device.
+    pub fn function(&self, bus: usize, device: usize, function: usize) -> Option<&[u32]> {
+        self.functions
+            .get(&(bus, device, function))
+            .map(|configuration_space| configuration_space.as_slice())
+    }
+
+    /// Return a register of a function, if both exist.
+    /// - `bus` - the number of the bus.
+    /// - `device` - the number of the device on the bus.
+    /// - `function` - the number of the function of the device.
+    /// - `register` - the index of the register within the configuration space.
+    pub fn read(&self, bus: usize, device: usize, function: usize, register: usize) -> Option<u32> {
+        self.function(bus, device, function)?.get(register).cloned()
+    }
+}
+
+/// One line per function, as `bus:device.function vendor:device class`.
+impl fmt::Display for TopologySnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ((bus, device, function), configuration_space) in self.functions.iter() {
+            writeln!(
+                f,
+                "{:02x}:{:02x}.{:x} {:04x}:{:04x} {:06x}",
+                bus,
+                device,
+                function,
+                configuration_space[0] & 0xFFFF,
+                configuration_space[0] >> 16,
+                configuration_space[2] >> 8
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PciBus {
+    /// Check the bus numbers of this bus and of the buses behind it, in a single walk.
+    /// - `range` - the bus numbers forwarded to this bus by its bridge, if any.
+    /// - `roots` - the buses connected to this bus without a bridge, such as the buses rooted
+    ///   by the other host bridges.
+    /// - `seen` - the bus numbers already found in the tree.
+    fn validate_bus_numbers(
+        &self,
+        range: Option<RangeInclusive<usize>>,
+        roots: &[usize],
+        seen: &mut BTreeSet<usize>,
+    ) -> std::result::Result<(), TopologyError> {
+        if !seen.insert(self.number) {
+            return Err(TopologyError::DuplicateBusNumber(self.number));
+        }
+
+        if let Some(range) = range.as_ref() {
+            if !range.contains(&self.number) {
+                return Err(TopologyError::UnreachableBus(self.number));
+            }
+        }
+
+        let bridges = self.bridge_bus_ranges();
+        check_bus_ranges(&bridges)?;
+
+        for (number, bus) in self.buses.iter() {
+            if seen.contains(number) {
+                return Err(TopologyError::DuplicateBusNumber(*number));
+            }
+
+            let range = if roots.contains(number) {
+                None
+            } else {
+                match bridges.iter().find(|range| range.start() == number) {
+                    Some(range) => Some(range.clone()),
+                    None => return Err(TopologyError::UnreachableBus(*number)),
+                }
+            };
+
+            bus.lock().unwrap().validate_bus_numbers(range, &[], seen)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the bus ranges of the bridges of each bus of the tree do not overlap.
+    pub(crate) fn check_bridge_bus_ranges(&self) -> std::result::Result<(), TopologyError> {
+        check_bus_ranges(&self.bridge_bus_ranges())?;
+        for bus in self.buses.values() {
+            bus.lock().unwrap().check_bridge_bus_ranges()?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the bus ranges (secondary to subordinate) of the bridges of this bus.
+    fn bridge_bus_ranges(&self) -> Vec<RangeInclusive<usize>> {
+        let mut bridges = Vec::new();
+        for device in self.devices.values() {
+            device.lock().unwrap().for_each_function(|_, function| {
+                if let Some(secondary) = function.get_secondary_bus() {
+                    let secondary = usize::from(secondary);
+                    let subordinate = function
+                        .get_subordinate_bus()
+                        .map_or(secondary, |subordinate| {
+                            usize::from(subordinate).max(secondary)
+                        });
+                    bridges.push(secondary..=subordinate);
+                }
+            });
+        }
+
+        bridges
+    }
+}
+
+/// Check that the bus ranges of the bridges of a bus do not overlap.
+/// * `bridges` - The bus ranges of the bridges.
+fn check_bus_ranges(bridges: &[RangeInclusive<usize>]) -> std::result::Result<(), TopologyError> {
+    for (index, first) in bridges.iter().enumerate() {
+        for second in bridges[index + 1..].iter() {
+            if first.start() <= second.end() && second.start() <= first.end() {
+                return Err(TopologyError::OverlappingBusRanges(
+                    *first.start(),
+                    *second.start(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy every bus and function of the tree while it is visited.
+#[derive(Default)]
+struct SnapshotVisitor(TopologySnapshot);
+
+impl PciTreeVisitor for SnapshotVisitor {
+    fn visit_bus(&mut self, bus: &PciBus) {
+        self.0.buses.push(bus.get_number());
+    }
+
+    fn visit_function(
+        &mut self,
+        bus: usize,
+        device: usize,
+        number: usize,
+        function: &mut dyn PciConfigurable,
+    ) {
+        // The functions implemented outside of this crate are copied the way the guest reads
+        // them.
+        let configuration_space = match function.as_function() {
+            Some(function) => function.config_copy().to_vec(),
+            None => (0..CONFIGURATION_SPACE_SIZE)
+                .map(|register| function.read_config(register, 0, 4))
+                .collect(),
+        };
+
+        self.0
+            .functions
+            .insert((bus, device, number), configuration_space);
+    }
+}
+
+impl PciRootComplex {
+    /// Copy the whole topology in a single walk of the tree. The locks are taken in the order
+    /// of `PciTreeVisitor`, from the top of the tree downwards, and each one is released
+    /// before the next device is locked, so the walk cannot deadlock with the configuration
+    /// accesses, which lock the tree in the same order.
+    pub fn topology_snapshot(&self) -> TopologySnapshot {
+        let mut visitor = SnapshotVisitor::default();
+        self.visit(&mut visitor);
+
+        visitor.0
+    }
+
+    /// Check that every bus number is assigned once, that the bus ranges of the bridges of a
+    /// bus do not overlap, and that every bus is reachable through a bridge (or a host bridge).
+    /// The tree is walked once, so it can be checked after every hotplug.
+    pub fn validate_topology(&self) -> std::result::Result<(), TopologyError> {
+        let roots: Vec<usize> = self
+            .root_buses
+            .values()
+            .cloned()
+            .filter(|number| *number != 0)
+            .collect();
+
+        self.bus
+            .lock()
+            .unwrap()
+            .validate_bus_numbers(None, &roots, &mut BTreeSet::new())
+    }
+
+    /// Build a PCI Root Complex node with the topology described by the spec. The subordinate
+    /// buses of the bridges are computed from the buses behind them.
+    /// - `spec` - the description of the topology.
+    pub fn from_topology(spec: &TopologySpec) -> Result<Self> {
+        let root = PciRootComplex::new()?;
+        let mut used_buses = BTreeSet::new();
+        used_buses.insert(0);
+
+        {
+            let mut bus = root.bus.lock().unwrap();
+            populate_bus(&mut bus, &spec.devices, &spec.bridges, &mut used_buses)?;
+            bus.recompute_subordinate_buses();
+        }
+
+        Ok(root)
+    }
+}
+
+/// Add the devices and the bridges of a spec to a bus, then the buses behind the bridges.
+/// - `used_buses` - the bus numbers already part of the topology.
+fn populate_bus(
+    bus: &mut PciBus,
+    devices: &[DeviceSpec],
+    bridges: &[BridgeSpec],
+    used_buses: &mut BTreeSet<usize>,
+) -> Result<()> {
+    for spec in devices {
+        let mut device = PciDevice::new(spec.device);
+        for function in spec.functions.iter() {
+            device
+                .add_function(build_function(function)?)
+                .map_err(PciError::Device)?;
+        }
+        bus.add_device(device).map_err(PciError::Bus)?;
+    }
+
+    for spec in bridges {
+        let secondary = spec.secondary_bus;
+        if secondary <= bus.get_number()
+            || secondary >= MAX_BUS_NUMBER
+            || !used_buses.insert(secondary)
+        {
+            return Err(PciError::InvalidBridgeBusNumber(secondary));
+        }
+
+        let mut device = PciDevice::new(spec.device);
+        device
+            .add_function(PciFunction::new_bridge(0, secondary as u8))
+            .map_err(PciError::Device)?;
+        bus.add_device(device).map_err(PciError::Bus)?;
+
+        let mut secondary_bus = PciBus::new(secondary);
+        populate_bus(&mut secondary_bus, &spec.devices, &spec.bridges, used_buses)?;
+        bus.add_bus(secondary_bus).map_err(PciError::Bus)?;
+    }
+
+    Ok(())
+}
+
+/// Create the function described by a spec.
+fn build_function(spec: &FunctionSpec) -> Result<PciFunction> {
+    PciFunctionBuilder::new(
+        spec.function,
+        spec.device_id,
+        spec.vendor_id,
+        spec.class_code,
+        PciHeaderType::Type0,
+    )
+    .revision_id(spec.revision_id)
+    .subsystem(spec.subsystem_id, spec.subsystem_vendor_id)
+    .build()
+    .map_err(PciError::Function)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use crate::bus::PciBusError;
+
+    #[test]
+    fn from_topology() {
+        let spec: TopologySpec = serde_json::from_str(
+            r#"{
+                "devices": [
+                    {
+                        "device": 1,
+                        "functions": [
+                            {
+                                "vendor_id": 4660,
+                                "device_id": 22136,
+                                "class_code": "EthernetController"
+                            }
+                        ]
+                    }
+                ],
+                "bridges": [
+                    {
+                        "device": 2,
+                        "secondary_bus": 1,
+                        "devices": [
+                            {
+                                "device": 3,
+                                "functions": [
+                                    {
+                                        "function": 0,
+                                        "vendor_id": 6900,
+                                        "device_id": 4161,
+                                        "class_code": "OtherMassStorageController"
+                                    },
+                                    {
+                                        "function": 1,
+                                        "vendor_id": 6900,
+                                        "device_id": 4161,
+                                        "class_code": "OtherMassStorageController"
+                                    }
+                                ]
+                            }
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let root = PciRootComplex::from_topology(&spec).unwrap();
+        let bus = root.bus.lock().unwrap();
+        assert!(bus.has_function(0, 0, 0));
+        assert!(bus.has_function(0, 1, 0));
+        assert!(bus.has_function(0, 2, 0));
+        assert!(bus.has_function(1, 3, 0));
+        assert!(bus.has_function(1, 3, 1));
+        assert!(!bus.has_function(1, 1, 0));
+        assert_eq!(
+            bus.read_configuration_register(1, 3, 1, 0),
+            Some(0x1041_1AF4)
+        );
+
+        let bridge = bus.get_device(2).unwrap().lock().unwrap().functions[&0].clone();
+        assert_eq!(
+            bridge
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .get_secondary_bus(),
+            Some(1)
+        );
+        assert_eq!(
+            bridge
+                .lock()
+                .unwrap()
+                .as_function_mut()
+                .unwrap()
+                .get_subordinate_bus(),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn from_topology_invalid() {
+        let bridge = |device: usize, secondary_bus: usize, bridges: Vec<BridgeSpec>| BridgeSpec {
+            device,
+            secondary_bus,
+            devices: Vec::new(),
+            bridges,
+        };
+
+        // The host bridge occupies the device 0 of bus 0.
+        let spec = TopologySpec {
+            devices: Vec::new(),
+            bridges: vec![bridge(0, 1, Vec::new())],
+        };
+        match PciRootComplex::from_topology(&spec) {
+            Err(PciError::Bus(PciBusError::AlreadyInUsePciDeviceSlot(0))) => (),
+            _ => panic!("The device slot should be in use"),
+        }
+
+        // The bus numbers are unique, and increase away from the root.
+        let spec = TopologySpec {
+            devices: Vec::new(),
+            bridges: vec![bridge(1, 2, Vec::new()), bridge(2, 2, Vec::new())],
+        };
+        match PciRootComplex::from_topology(&spec) {
+            Err(PciError::InvalidBridgeBusNumber(2)) => (),
+            _ => panic!("The bus number should be rejected"),
+        }
+        let spec = TopologySpec {
+            devices: Vec::new(),
+            bridges: vec![bridge(1, 2, vec![bridge(0, 1, Vec::new())])],
+        };
+        match PciRootComplex::from_topology(&spec) {
+            Err(PciError::InvalidBridgeBusNumber(1)) => (),
+            _ => panic!("The bus number should be rejected"),
+        }
+    }
+
+    #[test]
+    fn topology_snapshot() {
+        let spec = TopologySpec {
+            devices: vec![DeviceSpec {
+                device: 1,
+                functions: vec![FunctionSpec {
+                    function: 0,
+                    vendor_id: 0x1234,
+                    device_id: 0x5678,
+                    class_code: PciClassCode::EthernetController,
+                    revision_id: 0,
+                    subsystem_id: 0,
+                    subsystem_vendor_id: 0,
+                }],
+            }],
+            bridges: vec![BridgeSpec {
+                device: 2,
+                secondary_bus: 1,
+                devices: vec![DeviceSpec {
+                    device: 3,
+                    functions: vec![FunctionSpec {
+                        function: 1,
+                        vendor_id: 0x1AF4,
+                        device_id: 0x1041,
+                        class_code: PciClassCode::EthernetController,
+                        revision_id: 0,
+                        subsystem_id: 0,
+                        subsystem_vendor_id: 0,
+                    }],
+                }],
+                bridges: Vec::new(),
+            }],
+        };
+        let root = PciRootComplex::from_topology(&spec).unwrap();
+
+        let snapshot = root.topology_snapshot();
+        assert_eq!(snapshot.buses, vec![0, 1]);
+        assert_eq!(
+            snapshot.functions.keys().cloned().collect::<Vec<_>>(),
+            vec![(0, 0, 0), (0, 1, 0), (0, 2, 0), (1, 3, 1)]
+        );
+
+        // The snapshot matches the direct reads of every register.
+        let bus = root.bus.lock().unwrap();
+        for (&(bus_number, device, function), configuration_space) in snapshot.functions.iter() {
+            for (register, value) in configuration_space.iter().enumerate() {
+                assert_eq!(
+                    bus.read_configuration_register(bus_number, device, function, register),
+                    Some(*value)
+                );
+            }
+        }
+        drop(bus);
+
+        assert_eq!(snapshot.read(1, 3, 1, 0), Some(0x1041_1AF4));
+        assert_eq!(snapshot.read(1, 3, 0, 0), None);
+        assert_eq!(snapshot.read(0, 1, 0, 4096), None);
+        let lines = snapshot.to_string();
+        assert_eq!(lines.lines().count(), 4);
+        assert!(lines.contains("00:01.0 1234:5678 020000\n"));
+        assert!(lines.contains("01:03.1 1af4:1041 020000\n"));
+
+        // The snapshot is not affected by the later changes to the tree.
+        root.bus.lock().unwrap().remove_device(1);
+        assert!(snapshot.function(0, 1, 0).is_some());
+        assert!(root.topology_snapshot().function(0, 1, 0).is_none());
+    }
+
+    #[test]
+    fn validate_topology() {
+        let bridge = |device: usize, secondary_bus: usize, bridges: Vec<BridgeSpec>| BridgeSpec {
+            device,
+            secondary_bus,
+            devices: vec![DeviceSpec {
+                device: 5,
+                functions: vec![FunctionSpec {
+                    function: 0,
+                    vendor_id: 0x1234,
+                    device_id: 0x5678,
+                    class_code: PciClassCode::EthernetController,
+                    revision_id: 0,
+                    subsystem_id: 0,
+                    subsystem_vendor_id: 0,
+                }],
+            }],
+            bridges,
+        };
+        let spec = TopologySpec {
+            devices: Vec::new(),
+            bridges: vec![
+                bridge(1, 1, vec![bridge(0, 2, Vec::new())]),
+                bridge(2, 3, Vec::new()),
+            ],
+        };
+
+        let mut root = PciRootComplex::from_topology(&spec).unwrap();
+        root.add_host_bridge(1, Some(PciBus::new(8))).unwrap();
+        assert_eq!(root.validate_topology(), Ok(()));
+
+        // The bridge of bus 3 claims the buses behind the bridge of bus 1.
+        let bridge_function = |root: &PciRootComplex, device: usize| {
+            root.bus
+                .lock()
+                .unwrap()
+                .get_device(device)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .functions[&0]
+                .clone()
+        };
+        bridge_function(&root, 2)
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .set_secondary_bus(2);
+        assert_eq!(
+            root.validate_topology(),
+            Err(TopologyError::OverlappingBusRanges(1, 2))
+        );
+        bridge_function(&root, 2)
+            .lock()
+            .unwrap()
+            .as_function_mut()
+            .unwrap()
+            .set_secondary_bus(3);
+        assert_eq!(root.validate_topology(), Ok(()));
+
+        // The same bus number behind two bridges.
+        root.bus
+            .lock()
+            .unwrap()
+            .get_bus(3)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .add_bus(PciBus::new(2))
+            .unwrap();
+        assert_eq!(
+            root.validate_topology(),
+            Err(TopologyError::DuplicateBusNumber(2))
+        );
+        root.bus
+            .lock()
+            .unwrap()
+            .get_bus(3)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .remove_bus(2);
+
+        // A bus without a bridge leading to it.
+        root.bus.lock().unwrap().add_bus(PciBus::new(9)).unwrap();
+        assert_eq!(
+            root.validate_topology(),
+            Err(TopologyError::UnreachableBus(9))
+        );
+    }
+}
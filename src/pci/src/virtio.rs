@@ -0,0 +1,207 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::bar::PciBarRegionType;
+use crate::capability::{MsixCapability, VendorCapability};
+use crate::function::{PciClassCode, PciFunction, PciFunctionBuilder, PciHeaderType};
+
+/// The vendor ID of the virtio devices.
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1AF4;
+
+/// The device ID of a modern (non-transitional) virtio device is 0x1040 + device type.
+pub const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// Modern virtio devices have a revision ID of at least 1.
+const VIRTIO_PCI_REVISION_ID: u8 = 1;
+
+// The `cfg_type` of the virtio vendor-specific capabilities.
+pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// The BAR holding all the virtio structures and the MSI-X table.
+const VIRTIO_BAR_INDEX: u8 = 0;
+
+/// Every structure within the BAR starts on its own page.
+const VIRTIO_REGION_ALIGNMENT: u64 = 0x1000;
+
+/// The offset between the notification addresses of two consecutive queues.
+const VIRTIO_NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+/// An MSI-X table can not have more than 2048 entries.
+const MSIX_MAX_VECTORS: u64 = 2048;
+
+/// The virtio device types which can be exposed through a virtio-pci function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VirtioDeviceType {
+    Net = 1,
+    Block = 2,
+    Console = 3,
+    Rng = 4,
+    Balloon = 5,
+    Vsock = 19,
+}
+
+impl VirtioDeviceType {
+    /// Return the class code matching the device type.
+    fn class_code(self) -> PciClassCode {
+        match self {
+            VirtioDeviceType::Net => PciClassCode::EthernetController,
+            VirtioDeviceType::Block => PciClassCode::OtherMassStorageController,
+            VirtioDeviceType::Console => PciClassCode::OtherCommunicationController,
+            _ => PciClassCode::UnassignedClass,
+        }
+    }
+}
+
+/// Return the value rounded up to the region alignment.
+fn align_region(value: u64) -> u64 {
+    (value + VIRTIO_REGION_ALIGNMENT - 1) & !(VIRTIO_REGION_ALIGNMENT - 1)
+}
+
+/// Build the vendor-specific capability describing a virtio structure.
+/// * `cfg_type` - The kind of the virtio structure.
+/// * `offset` - The offset of the structure within the BAR.
+/// * `length` - The length of the structure.
+fn virtio_capability(cfg_type: u8, offset: u64, length: u64) -> VendorCapability {
+    // cfg_type, bar, 3 bytes of padding, offset and length.
+    let mut data = vec![cfg_type, VIRTIO_BAR_INDEX, 0, 0, 0];
+    data.extend_from_slice(&(offset as u32).to_le_bytes());
+    data.extend_from_slice(&(length as u32).to_le_bytes());
+
+    VendorCapability { data }
+}
+
+impl PciFunction {
+    /// Create a modern virtio-pci function, following the virtio 1.0 specification.
+    /// A single 64-bit memory BAR holds, each in its own pages, the common configuration,
+    /// the ISR status, the device configuration, the queue notification addresses and the
+    /// MSI-X table and PBA (one vector per queue, plus one for the configuration changes).
+    /// - `device_type` - the type of the virtio device.
+    /// - `num_queues` - the number of virtqueues of the device.
+    pub fn new_virtio(device_type: VirtioDeviceType, num_queues: u16) -> PciFunction {
+        let vectors = (u64::from(num_queues) + 1).min(MSIX_MAX_VECTORS);
+
+        let common_offset = 0;
+        let isr_offset = common_offset + VIRTIO_REGION_ALIGNMENT;
+        let device_offset = isr_offset + VIRTIO_REGION_ALIGNMENT;
+        let notify_offset = device_offset + VIRTIO_REGION_ALIGNMENT;
+        let notify_length = u64::from(num_queues).max(1) * u64::from(VIRTIO_NOTIFY_OFF_MULTIPLIER);
+        let table_offset = notify_offset + align_region(notify_length);
+        let table_length = vectors * 16;
+        let pba_offset = table_offset + align_region(table_length);
+        let pba_length = (vectors + 63) / 64 * 8;
+        let bar_size = (pba_offset + align_region(pba_length)).next_power_of_two();
+
+        // The layout is fixed, so the BAR and the capabilities always fit.
+        let mut function = PciFunctionBuilder::new(
+            0,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+            VIRTIO_PCI_VENDOR_ID,
+            device_type.class_code(),
+            PciHeaderType::Type0,
+        )
+        .revision_id(VIRTIO_PCI_REVISION_ID)
+        .subsystem(device_type as u16, VIRTIO_PCI_VENDOR_ID)
+        .bars(&[(
+            VIRTIO_BAR_INDEX as usize,
+            PciBarRegionType::Memory64BitRegion,
+            bar_size,
+            false,
+        )])
+        .build()
+        .unwrap();
+
+        function
+            .add_capability(&virtio_capability(
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                common_offset,
+                VIRTIO_REGION_ALIGNMENT,
+            ))
+            .unwrap();
+
+        let mut notify = virtio_capability(VIRTIO_PCI_CAP_NOTIFY_CFG, notify_offset, notify_length);
+        notify
+            .data
+            .extend_from_slice(&VIRTIO_NOTIFY_OFF_MULTIPLIER.to_le_bytes());
+        function.add_capability(&notify).unwrap();
+
+        function
+            .add_capability(&virtio_capability(
+                VIRTIO_PCI_CAP_ISR_CFG,
+                isr_offset,
+                VIRTIO_REGION_ALIGNMENT,
+            ))
+            .unwrap();
+        function
+            .add_capability(&virtio_capability(
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                device_offset,
+                VIRTIO_REGION_ALIGNMENT,
+            ))
+            .unwrap();
+
+        function
+            .add_capability(&MsixCapability {
+                table_size: vectors as u16,
+                table_bar: VIRTIO_BAR_INDEX,
+                table_offset: table_offset as u32,
+                pba_bar: VIRTIO_BAR_INDEX,
+                pba_offset: pba_offset as u32,
+            })
+            .unwrap();
+
+        function
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::PciCapabilityId;
+
+    #[test]
+    fn virtio_function() {
+        let function = PciFunction::new_virtio(VirtioDeviceType::Net, 2);
+
+        assert_eq!(function.read_configuration_dword(0), Some(0x1041_1AF4));
+        assert_eq!(function.read_configuration_dword(2), Some(0x0200_0001));
+
+        let capabilities: Vec<(u8, usize)> = function.capabilities().collect();
+        assert_eq!(capabilities.len(), 5);
+
+        // The four virtio structures, in the order they were added.
+        let cfg_types: Vec<u8> = capabilities[..4]
+            .iter()
+            .map(|(id, register)| {
+                assert_eq!(*id, PciCapabilityId::VendorSpecific as u8);
+                function.read_configuration_byte(*register, 3).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            cfg_types,
+            vec![
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                VIRTIO_PCI_CAP_ISR_CFG,
+                VIRTIO_PCI_CAP_DEVICE_CFG
+            ]
+        );
+
+        // Three MSI-X vectors: one per queue and one for the configuration changes.
+        assert_eq!(capabilities[4].0, PciCapabilityId::MsiX as u8);
+        assert_eq!(
+            function.describe_capabilities()[4],
+            "MSI-X: table size 3, BAR 0, offset 0x4000, PBA BAR 0, offset 0x5000, disabled"
+        );
+
+        // The BAR holds every structure, up to the end of the PBA.
+        let bar = function.get_bar(0).unwrap();
+        assert_eq!(bar.region_type, PciBarRegionType::Memory64BitRegion);
+        assert!(bar.size >= 0x6000);
+
+        // The capability list ends within its area without looping.
+        assert_eq!(function.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::bus::PciBus;
+use crate::device::PciDevice;
+use crate::function::PciConfigurable;
+use crate::pci::PciRootComplex;
+
+/// An operation applied on every node of the PCI tree (such as a reset, a validation or an
+/// export). Each method does nothing by default, so a visitor only implements what it needs.
+///
+/// The nodes are visited with their locks held, taken from the top of the tree downwards:
+/// the bus, then each of its devices, then each function of the device. A device (and its
+/// functions) is unlocked before the next one is visited, and the buses connected to a bus
+/// are visited after its devices. A visitor must never lock a node of the tree itself.
+pub trait PciTreeVisitor {
+    /// Called on each bus, before its devices.
+    fn visit_bus(&mut self, _bus: &PciBus) {}
+
+    /// Called on each device, before its functions.
+    /// - `bus` - the number of the bus of the device.
+    fn visit_device(&mut self, _bus: usize, _device: &PciDevice) {}
+
+    /// Called on each function, including the ones implemented outside of this crate.
+    /// - `bus` - the number of the bus of the function.
+    /// - `device` - the number of the device of the function.
+    /// - `number` - the number of the function.
+    fn visit_function(
+        &mut self,
+        _bus: usize,
+        _device: usize,
+        _number: usize,
+        _function: &mut dyn PciConfigurable,
+    ) {
+    }
+}
+
+impl PciBus {
+    /// Visit this bus, its devices and functions, then the buses connected to it.
+    /// - `visitor` - the operation applied on every node.
+    pub fn visit<V: PciTreeVisitor>(&self, visitor: &mut V) {
+        visitor.visit_bus(self);
+
+        for device in self.devices.values() {
+            let device = device.lock().unwrap();
+            visitor.visit_device(self.number, &device);
+
+            for (number, function) in device.functions.iter() {
+                visitor.visit_function(
+                    self.number,
+                    device.number,
+                    *number,
+                    &mut *function.lock().unwrap(),
+                );
+            }
+        }
+
+        for bus in self.buses.values() {
+            bus.lock().unwrap().visit(visitor);
+        }
+    }
+}
+
+impl PciRootComplex {
+    /// Visit every bus, device and function of the topology, from bus 0 downwards.
+    /// - `visitor` - the operation applied on every node.
+    pub fn visit<V: PciTreeVisitor>(&self, visitor: &mut V) {
+        self.bus.lock().unwrap().visit(visitor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::PciFunction;
+
+    #[derive(Default)]
+    struct CountingVisitor {
+        buses: Vec<usize>,
+        devices: usize,
+        functions: Vec<(usize, usize, usize)>,
+    }
+
+    impl PciTreeVisitor for CountingVisitor {
+        fn visit_bus(&mut self, bus: &PciBus) {
+            self.buses.push(bus.get_number());
+        }
+
+        fn visit_device(&mut self, _bus: usize, _device: &PciDevice) {
+            self.devices += 1;
+        }
+
+        fn visit_function(
+            &mut self,
+            bus: usize,
+            device: usize,
+            number: usize,
+            _function: &mut dyn PciConfigurable,
+        ) {
+            self.functions.push((bus, device, number));
+        }
+    }
+
+    #[test]
+    fn visit_tree() {
+        let root = PciRootComplex::new().unwrap();
+        {
+            let mut bus = root.bus.lock().unwrap();
+
+            let mut device = PciDevice::new_dummy_host_bridge(1);
+            device
+                .add_function(PciFunction::new_dummy_host_bridge(3))
+                .unwrap();
+            bus.add_device(device).unwrap();
+
+            let mut child = PciBus::new(1);
+            child
+                .add_device(PciDevice::new_dummy_host_bridge(2))
+                .unwrap();
+            bus.add_bus(child).unwrap();
+        }
+
+        let mut visitor = CountingVisitor::default();
+        root.visit(&mut visitor);
+
+        assert_eq!(visitor.buses, vec![0, 1]);
+        assert_eq!(visitor.devices, 3);
+        assert_eq!(
+            visitor.functions,
+            vec![(0, 0, 0), (0, 1, 0), (0, 1, 3), (1, 2, 0)]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+extern crate devices;
+extern crate pci;
+
+use devices::BusDevice;
+use pci::{
+    encode_configuration_address, MsixCapability, PciBarRegionType, PciBus, PciClassCode,
+    PciDevice, PciFunctionBuilder, PciHeaderType, PciRootComplex,
+};
+
+// The offsets of the data port and of the address port relative to 0xCF8.
+const ADDRESS_PORT: u64 = 0;
+const DATA_PORT: u64 = 4;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+
+const CAPABILITY_ID_MSIX: u8 = 0x11;
+
+/// A guest driving the root complex through the configuration ports, like Linux does.
+struct Guest {
+    root: PciRootComplex,
+}
+
+impl Guest {
+    fn read(&mut self, bus: usize, device: usize, function: usize, register: usize) -> u32 {
+        let address = encode_configuration_address(bus, device, function, register, true);
+        self.root.write(ADDRESS_PORT, &address.to_le_bytes());
+
+        let mut data = [0u8; 4];
+        self.root.read(DATA_PORT, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write(&mut self, bus: usize, device: usize, function: usize, register: usize, value: u32) {
+        let address = encode_configuration_address(bus, device, function, register, true);
+        self.root.write(ADDRESS_PORT, &address.to_le_bytes());
+        self.root.write(DATA_PORT, &value.to_le_bytes());
+    }
+
+    /// Write the Command register alone, without touching the Status register.
+    fn write_command(&mut self, bus: usize, device: usize, function: usize, command: u16) {
+        let address = encode_configuration_address(bus, device, function, 1, true);
+        self.root.write(ADDRESS_PORT, &address.to_le_bytes());
+        self.root.write(DATA_PORT, &command.to_le_bytes());
+    }
+
+    /// Return the functions found on a bus, as (device, function) pairs. The functions other
+    /// than 0 are only probed on multi-function devices.
+    fn scan_bus(&mut self, bus: usize) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+
+        for device in 0..32 {
+            if self.read(bus, device, 0, 0) & 0xFFFF == 0xFFFF {
+                continue;
+            }
+            found.push((device, 0));
+
+            let multi_function = (self.read(bus, device, 0, 3) >> 16) & 0x80 != 0;
+            if multi_function {
+                for function in 1..8 {
+                    if self.read(bus, device, function, 0) & 0xFFFF != 0xFFFF {
+                        found.push((device, function));
+                    }
+                }
+            }
+        }
+
+        found
+    }
+
+    /// Size the BARs of a function with its decoding disabled, as (index, region, size).
+    fn size_bars(
+        &mut self,
+        bus: usize,
+        device: usize,
+        function: usize,
+    ) -> Vec<(usize, PciBarRegionType, u64)> {
+        let command = self.read(bus, device, function, 1) as u16;
+        self.write_command(
+            bus,
+            device,
+            function,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < 6 {
+            let register = 4 + index;
+            let original = self.read(bus, device, function, register);
+            self.write(bus, device, function, register, 0xFFFF_FFFF);
+            let low = self.read(bus, device, function, register);
+            self.write(bus, device, function, register, original);
+
+            if low == 0 {
+                index += 1;
+                continue;
+            }
+
+            if low & 0x1 != 0 {
+                let size = u64::from(!(low & !0x3) & 0xFFFF) + 1;
+                bars.push((index, PciBarRegionType::IoRegion, size));
+                index += 1;
+            } else if low & 0x6 == 0x4 {
+                let original_high = self.read(bus, device, function, register + 1);
+                self.write(bus, device, function, register + 1, 0xFFFF_FFFF);
+                let high = self.read(bus, device, function, register + 1);
+                self.write(bus, device, function, register + 1, original_high);
+
+                let mask = (u64::from(high) << 32) | u64::from(low & !0xF);
+                bars.push((index, PciBarRegionType::Memory64BitRegion, !mask + 1));
+                index += 2;
+            } else {
+                let size = u64::from(!(low & !0xF)) + 1;
+                bars.push((index, PciBarRegionType::Memory32BitRegion, size));
+                index += 1;
+            }
+        }
+
+        self.write_command(bus, device, function, command);
+        bars
+    }
+
+    /// Walk the capability list, as (capability ID, register) pairs.
+    fn capabilities(&mut self, bus: usize, device: usize, function: usize) -> Vec<(u8, usize)> {
+        let mut capabilities = Vec::new();
+        if self.read(bus, device, function, 1) & STATUS_CAPABILITIES_LIST == 0 {
+            return capabilities;
+        }
+
+        let mut pointer = (self.read(bus, device, function, 13) & 0xFC) as usize;
+        while pointer != 0 && capabilities.len() < 48 {
+            let header = self.read(bus, device, function, pointer / 4);
+            capabilities.push((header as u8, pointer / 4));
+            pointer = ((header >> 8) & 0xFC) as usize;
+        }
+
+        capabilities
+    }
+}
+
+/// Return the next address aligned on the size of the region, and move the allocator past it.
+fn allocate(next: &mut u64, size: u64) -> u64 {
+    let address = (*next + size - 1) & !(size - 1);
+    *next = address + size;
+    address
+}
+
+/// A network device on bus 1, behind the second host bridge, with an MSI-X table in BAR 0.
+fn synthetic_topology() -> PciRootComplex {
+    let mut function = PciFunctionBuilder::new(
+        0,
+        0x1041,
+        0x1AF4,
+        PciClassCode::EthernetController,
+        PciHeaderType::Type0,
+    )
+    .bars(&[
+        (0, PciBarRegionType::Memory32BitRegion, 0x1000, false),
+        (1, PciBarRegionType::IoRegion, 0x20, false),
+        (2, PciBarRegionType::Memory64BitRegion, 0x4000, true),
+    ])
+    .build()
+    .unwrap();
+    function
+        .add_capability(&MsixCapability {
+            table_size: 4,
+            table_bar: 0,
+            table_offset: 0,
+            pba_bar: 0,
+            pba_offset: 0x800,
+        })
+        .unwrap();
+
+    let mut device = PciDevice::new(2);
+    device.add_function(function).unwrap();
+    let mut bus = PciBus::new(1);
+    bus.add_device(device).unwrap();
+
+    let mut root = PciRootComplex::new().unwrap();
+    root.add_host_bridge(1, Some(bus)).unwrap();
+    root
+}
+
+#[test]
+fn test_linux_probe_sequence() {
+    let mut guest = Guest {
+        root: synthetic_topology(),
+    };
+
+    // The firmware describes the buses of each host bridge.
+    let buses: Vec<usize> = guest
+        .root
+        .root_bus_ranges()
+        .iter()
+        .map(|(first, _)| usize::from(*first))
+        .collect();
+    assert_eq!(buses, vec![0, 1]);
+
+    let mut functions = Vec::new();
+    for bus in buses {
+        for (device, function) in guest.scan_bus(bus) {
+            functions.push((bus, device, function));
+        }
+    }
+    assert_eq!(functions, vec![(0, 0, 0), (0, 0, 1), (1, 2, 0)]);
+    assert_eq!(guest.read(1, 2, 0, 0), 0x1041_1AF4);
+
+    // Size and program the BARs from the allocators, then enable the decoding.
+    let mut next_memory = 0xC000_0000;
+    let mut next_io = 0x1000;
+    let mut programmed = Vec::new();
+    for (bus, device, function) in functions.iter().cloned() {
+        for (index, region_type, size) in guest.size_bars(bus, device, function) {
+            let address = match region_type {
+                PciBarRegionType::IoRegion => allocate(&mut next_io, size),
+                _ => allocate(&mut next_memory, size),
+            };
+
+            guest.write(bus, device, function, 4 + index, address as u32);
+            if region_type == PciBarRegionType::Memory64BitRegion {
+                guest.write(bus, device, function, 5 + index, (address >> 32) as u32);
+            }
+            programmed.push((bus, device, function, index, region_type, address));
+        }
+
+        let command = guest.read(bus, device, function, 1) as u16;
+        guest.write_command(
+            bus,
+            device,
+            function,
+            command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    assert_eq!(
+        programmed,
+        vec![
+            (1, 2, 0, 0, PciBarRegionType::Memory32BitRegion, 0xC000_0000),
+            (1, 2, 0, 1, PciBarRegionType::IoRegion, 0x1000),
+            (1, 2, 0, 2, PciBarRegionType::Memory64BitRegion, 0xC000_4000),
+        ]
+    );
+    assert_eq!(guest.read(1, 2, 0, 4), 0xC000_0000);
+    assert_eq!(guest.read(1, 2, 0, 5), 0x1001);
+    assert_eq!(guest.read(1, 2, 0, 6), 0xC000_400C);
+    assert_eq!(guest.read(1, 2, 0, 7), 0);
+
+    // The decoding is enabled, so the programmed regions are claimed by the device.
+    let command = guest.read(1, 2, 0, 1) as u16;
+    assert_ne!(command & COMMAND_MEMORY_SPACE, 0);
+    assert_ne!(command & COMMAND_IO_SPACE, 0);
+    assert_eq!(guest.root.route_mmio(0xC000_0800), Some((1, 2, 0, 0)));
+    assert_eq!(guest.root.route_mmio(0xC000_5000), Some((1, 2, 0, 2)));
+    assert_eq!(guest.root.route_mmio(0xC000_2000), None);
+
+    // The MSI-X capability points to its table in BAR 0.
+    let capabilities = guest.capabilities(1, 2, 0);
+    let msix = capabilities
+        .iter()
+        .find(|(id, _)| *id == CAPABILITY_ID_MSIX)
+        .map(|(_, register)| *register)
+        .expect("The MSI-X capability should be discoverable");
+    assert_eq!((guest.read(1, 2, 0, msix) >> 16) & 0x7FF, 3);
+    assert_eq!(guest.read(1, 2, 0, msix + 1), 0);
+    assert_eq!(guest.read(1, 2, 0, msix + 2), 0x800);
+}
@@ -576,7 +576,7 @@ pub fn setup_serial_device(
 pub fn setup_pci_root_complex(
     event_manager: &mut EventManager,
 ) -> super::Result<Arc<Mutex<PciRootComplex>>> {
-    let pci_root_complex = Arc::new(Mutex::new(PciRootComplex::new()));
+    let pci_root_complex = Arc::new(Mutex::new(PciRootComplex::new().map_err(Error::Pci)?));
 
     if let Err(e) = event_manager.add_subscriber(pci_root_complex.clone()) {
         // This behaviour mimics the one from the setup_serial_device.
@@ -844,7 +844,7 @@ pub mod tests {
             Arc::new(Mutex::new(Serial::new_sink(
                 EventFd::new(libc::EFD_NONBLOCK).unwrap(),
             ))),
-            Arc::new(Mutex::new(PciRootComplex::new())),
+            Arc::new(Mutex::new(PciRootComplex::new().unwrap())),
             EventFd::new(libc::EFD_NONBLOCK).unwrap(),
         )
         .unwrap()
@@ -11,7 +11,7 @@ use std::sync::{Arc, Mutex};
 
 use devices;
 use kvm_ioctls::VmFd;
-use pci::{PciRootComplex, PCI_IO_PORT, PCI_IO_PORT_SIZE};
+use pci::PciRootComplex;
 use utils::eventfd::EventFd;
 
 /// Errors corresponding to the `PortIODeviceManager`.
@@ -120,12 +120,9 @@ impl PortIODeviceManager {
             .map_err(Error::BusError)?;
 
         // Add the PCI device that will manage the space between 0xCF8 - 0xCFC.
+        let (pci_io_port, pci_io_port_size) = self.pci_root_complex.lock().unwrap().io_port_range();
         self.io_bus
-            .insert(
-                self.pci_root_complex.clone(),
-                PCI_IO_PORT as u64,
-                PCI_IO_PORT_SIZE as u64,
-            )
+            .insert(self.pci_root_complex.clone(), pci_io_port, pci_io_port_size)
             .map_err(Error::BusError)?;
 
         self.io_bus
@@ -157,7 +154,7 @@ mod tests {
         let mut vm = crate::builder::setup_kvm_vm(&guest_mem, false).unwrap();
         crate::builder::setup_interrupt_controller(&mut vm).unwrap();
         let serial = devices::legacy::Serial::new_sink(EventFd::new(libc::EFD_NONBLOCK).unwrap());
-        let pci_root_complex = PciRootComplex::new();
+        let pci_root_complex = PciRootComplex::new().unwrap();
         let mut ldm = PortIODeviceManager::new(
             Arc::new(Mutex::new(serial)),
             Arc::new(Mutex::new(pci_root_complex)),
@@ -136,6 +136,8 @@ pub enum Error {
     Logger(LoggerError),
     /// Internal metrics system error.
     Metrics(MetricsError),
+    /// Cannot create the PCI Root Complex node.
+    Pci(pci::PciError),
     /// Cannot add a device to the MMIO Bus.
     RegisterMMIODevice(device_manager::mmio::Error),
     /// Cannot build seccomp filters.
@@ -181,6 +183,7 @@ impl Display for Error {
             LegacyIOBus(e) => write!(f, "Cannot add devices to the legacy I/O Bus. {}", e),
             Logger(e) => write!(f, "Logger error: {}", e),
             Metrics(e) => write!(f, "Metrics error: {}", e),
+            Pci(e) => write!(f, "PCI error: {:?}", e),
             RegisterMMIODevice(e) => write!(f, "Cannot add a device to the MMIO Bus. {}", e),
             SeccompFilters(e) => write!(f, "Cannot build seccomp filters: {}", e),
             Serial(e) => write!(f, "Error writing to the serial console: {:?}", e),